@@ -0,0 +1,66 @@
+//! Compares prefix queries on a FilesystemDataStore with tens of thousands of keys: a
+//! segment-aware `get_prefix` that only reads the subtree for the prefix, a string-prefix
+//! `get_string_prefix`, and a full `get_prefix("")` scan.
+//!
+//! Run with `cargo bench -p datastore`.
+
+use datastore::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::{env, fs, process};
+
+const SERVICES: usize = 200;
+const SETTINGS_PER_SERVICE: usize = 100;
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let dir = env::temp_dir().join(format!("datastore-bench-prefix-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("live")).expect("create bench datastore");
+    let mut datastore = FilesystemDataStore::new(&dir)
+        .expect("open bench datastore")
+        .with_snapshot_retention(0);
+
+    let mut pairs = HashMap::new();
+    for service in 0..SERVICES {
+        for setting in 0..SETTINGS_PER_SERVICE {
+            let name = format!("settings.service{service}.setting{setting}");
+            let key = Key::new(KeyType::Data, name).expect("valid key");
+            pairs.insert(key, "\"value\"");
+        }
+    }
+    datastore
+        .set_keys(&pairs, &Committed::Live)
+        .expect("populate bench datastore");
+    println!("Populated {} keys", pairs.len());
+
+    bench("get_prefix(\"settings.service42\")", || {
+        datastore.get_prefix("settings.service42", &Committed::Live)
+    });
+    bench("get_string_prefix(\"settings.service42\")", || {
+        datastore.get_string_prefix("settings.service42", &Committed::Live)
+    });
+    bench("get_prefix(\"\")", || {
+        datastore.get_prefix("", &Committed::Live)
+    });
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// Runs the query ITERATIONS times and prints the average time and number of keys found.
+fn bench<F, T>(name: &str, query: F)
+where
+    F: Fn() -> datastore::Result<HashMap<Key, T>>,
+{
+    let mut total = Duration::ZERO;
+    let mut found = 0;
+    for _ in 0..ITERATIONS {
+        let start = Instant::now();
+        found = query().expect("query bench datastore").len();
+        total += start.elapsed();
+    }
+    println!(
+        "{name:45} {:>10.2?} per query, {found} keys",
+        total / ITERATIONS
+    );
+}
@@ -0,0 +1,437 @@
+//! AsyncDataStore is the async counterpart of DataStore, for callers running on a tokio runtime
+//! like the API server, which can't block the executor on data store I/O.
+//!
+//! Every implementation wraps a synchronous DataStore, its `Store`, and runs operations on it
+//! through `read` and `write`; the rest of the surface is built on those two, so it behaves
+//! exactly like the synchronous store.  Arguments are cloned into each operation, because it may
+//! run on another thread.
+//!
+//! * `BlockingDataStore` runs any DataStore, like `FilesystemDataStore` or a
+//!   `LockedDataStore` around one, on tokio's blocking thread pool.  Operations run one at a
+//!   time.
+//! * `AsyncMemoryDataStore` holds a `MemoryDataStore` behind an async lock and runs operations
+//!   inline, since they never wait on I/O.  Reads can run at the same time.
+//!
+//! Constraint checks passed to `commit_transaction` are synchronous, and run against the
+//! underlying store while the commit holds it, like they do for DataStore.
+
+use async_trait::async_trait;
+use snafu::ResultExt;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::constraints_check::{ConstraintCheck, ConstraintCheckResult};
+use crate::memory::MemoryDataStore;
+use crate::{error, Committed, DataStore, Key, KeyPattern, Result};
+
+#[async_trait]
+pub trait AsyncDataStore: Send + Sync {
+    /// The synchronous data store that operations run against.
+    type Store: DataStore + Send + 'static;
+
+    /// Runs the given read-only operation against the underlying store.
+    async fn read<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&Self::Store) -> Result<T> + Send + 'static,
+        T: Send + 'static;
+
+    /// Runs the given operation against the underlying store with exclusive access.
+    async fn write<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self::Store) -> Result<T> + Send + 'static,
+        T: Send + 'static;
+
+    /// Returns whether a key is present (has a value) in the datastore.
+    async fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        let (key, committed) = (key.clone(), committed.clone());
+        self.read(move |store| store.key_populated(&key, &committed))
+            .await
+    }
+
+    /// Returns a list of the populated data keys in the datastore whose names start with the
+    /// given prefix; see `DataStore::list_populated_keys`.
+    async fn list_populated_keys(
+        &self,
+        prefix: &str,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let (prefix, committed) = (prefix.to_string(), committed.clone());
+        self.read(move |store| store.list_populated_keys(prefix, &committed))
+            .await
+    }
+
+    /// Finds all metadata keys that are currently populated in the datastore whose data keys
+    /// start with the given prefix; see `DataStore::list_populated_metadata`.
+    async fn list_populated_metadata(
+        &self,
+        prefix: &str,
+        committed: &Committed,
+        metadata_key_name: Option<&str>,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        let (prefix, committed) = (prefix.to_string(), committed.clone());
+        let metadata_key_name = metadata_key_name.map(str::to_string);
+        self.read(move |store| {
+            store.list_populated_metadata(prefix, &committed, &metadata_key_name)
+        })
+        .await
+    }
+
+    /// Retrieve the value for a single data key from the datastore.
+    async fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        let (key, committed) = (key.clone(), committed.clone());
+        self.read(move |store| store.get_key(&key, &committed))
+            .await
+    }
+
+    /// Set the value of a single data key in the datastore.
+    async fn set_key(&self, key: &Key, value: &str, committed: &Committed) -> Result<()> {
+        let (key, value, committed) = (key.clone(), value.to_string(), committed.clone());
+        self.write(move |store| store.set_key(&key, value, &committed))
+            .await
+    }
+
+    /// Set multiple data keys at once in the data store.
+    async fn set_keys(&self, pairs: &HashMap<Key, String>, committed: &Committed) -> Result<()> {
+        let (pairs, committed) = (pairs.clone(), committed.clone());
+        self.write(move |store| store.set_keys(&pairs, &committed))
+            .await
+    }
+
+    /// Removes the given data key from the datastore; see `DataStore::unset_key`.
+    async fn unset_key(&self, key: &Key, committed: &Committed) -> Result<()> {
+        let (key, committed) = (key.clone(), committed.clone());
+        self.write(move |store| store.unset_key(&key, &committed))
+            .await
+    }
+
+    /// Retrieves all keys starting with the given prefix, returning them in a Key -> value map.
+    async fn get_prefix(
+        &self,
+        prefix: &str,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let (prefix, committed) = (prefix.to_string(), committed.clone());
+        self.read(move |store| store.get_prefix(prefix, &committed))
+            .await
+    }
+
+    /// Retrieve the value for a single metadata key from the datastore, inheriting from earlier
+    /// in the tree; see `DataStore::get_metadata`.
+    async fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let (metadata_key, data_key) = (metadata_key.clone(), data_key.clone());
+        let committed = committed.clone();
+        self.read(move |store| store.get_metadata(&metadata_key, &data_key, &committed))
+            .await
+    }
+
+    /// Set the value of a single metadata key in the datastore.
+    async fn set_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: &str,
+        committed: &Committed,
+    ) -> Result<()> {
+        let (metadata_key, data_key) = (metadata_key.clone(), data_key.clone());
+        let (value, committed) = (value.to_string(), committed.clone());
+        self.write(move |store| store.set_metadata(&metadata_key, &data_key, value, &committed))
+            .await
+    }
+
+    /// Removes the given metadata key from the given data key in the datastore.
+    async fn unset_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        let (metadata_key, data_key) = (metadata_key.clone(), data_key.clone());
+        let committed = committed.clone();
+        self.write(move |store| store.unset_metadata(&metadata_key, &data_key, &committed))
+            .await
+    }
+
+    /// Retrieves all metadata for data keys starting with the given prefix; see
+    /// `DataStore::get_metadata_prefix`.
+    async fn get_metadata_prefix(
+        &self,
+        prefix: &str,
+        committed: &Committed,
+        metadata_key_name: Option<&str>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>> {
+        let (prefix, committed) = (prefix.to_string(), committed.clone());
+        let metadata_key_name = metadata_key_name.map(str::to_string);
+        self.read(move |store| store.get_metadata_prefix(prefix, &committed, &metadata_key_name))
+            .await
+    }
+
+    /// Retrieves all data keys matching the given pattern; see `DataStore::query`.
+    async fn query(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let (pattern, committed) = (pattern.clone(), committed.clone());
+        self.read(move |store| store.query(&pattern, &committed))
+            .await
+    }
+
+    /// Retrieves the metadata set on data keys matching the given pattern; see
+    /// `DataStore::get_metadata_query`.
+    async fn get_metadata_query(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: Option<&str>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>> {
+        let (pattern, committed) = (pattern.clone(), committed.clone());
+        let metadata_key_name = metadata_key_name.map(str::to_string);
+        self.read(move |store| store.get_metadata_query(&pattern, &committed, &metadata_key_name))
+            .await
+    }
+
+    /// Returns a list of the names of any pending transactions in the data store.
+    async fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.read(|store| store.list_transactions()).await
+    }
+
+    /// Applies pending changes from the given transaction to the live datastore; see
+    /// `DataStore::commit_transaction`.
+    async fn commit_transaction<C>(
+        &self,
+        transaction: &str,
+        constraint_check: C,
+    ) -> Result<HashSet<Key>>
+    where
+        C: Fn(
+                &mut Self::Store,
+                &Committed,
+            )
+                -> std::result::Result<ConstraintCheckResult, Box<dyn Error + Send + Sync + 'static>>
+            + Send
+            + 'static,
+    {
+        let transaction = transaction.to_string();
+        self.write(move |store| store.commit_transaction(transaction, &constraint_check))
+            .await
+    }
+
+    /// Commits the given pending transaction, checking it with a `ConstraintCheck`; see
+    /// `DataStore::commit_transaction_checked`.
+    async fn commit_transaction_checked<C>(
+        &self,
+        transaction: &str,
+        check: C,
+    ) -> Result<HashSet<Key>>
+    where
+        Self::Store: Sized,
+        C: ConstraintCheck<Self::Store> + Send + 'static,
+    {
+        let transaction = transaction.to_string();
+        self.write(move |store| store.commit_transaction_checked(transaction, &check))
+            .await
+    }
+
+    /// Remove the given pending transaction, including its metadata, from the datastore.
+    async fn delete_transaction(&self, transaction: &str) -> Result<HashSet<Key>> {
+        let transaction = transaction.to_string();
+        self.write(move |store| store.delete_transaction(transaction))
+            .await
+    }
+}
+
+/// Runs a synchronous DataStore on tokio's blocking thread pool.  Must be used from within a
+/// tokio runtime.
+#[derive(Debug)]
+pub struct BlockingDataStore<D> {
+    inner: Arc<Mutex<D>>,
+}
+
+impl<D> BlockingDataStore<D> {
+    pub fn new(datastore: D) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(datastore)),
+        }
+    }
+
+    /// Runs the given operation with the store on the blocking thread pool.
+    async fn run<F, T>(&self, operation: F) -> Result<T>
+    where
+        D: Send + 'static,
+        F: FnOnce(&mut D) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        tokio::task::spawn_blocking(move || {
+            // A panic in an earlier operation leaves the store no worse off than a crash would,
+            // and every store handles that.
+            let mut datastore = inner.lock().unwrap_or_else(PoisonError::into_inner);
+            operation(&mut datastore)
+        })
+        .await
+        .context(error::BlockingTaskSnafu)?
+    }
+}
+
+impl<D> Clone for BlockingDataStore<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[async_trait]
+impl<D> AsyncDataStore for BlockingDataStore<D>
+where
+    D: DataStore + Send + 'static,
+{
+    type Store = D;
+
+    async fn read<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&D) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(move |datastore| operation(datastore)).await
+    }
+
+    async fn write<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut D) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(operation).await
+    }
+}
+
+/// An in-memory AsyncDataStore, for tests and for callers that don't need persistence.
+#[derive(Debug, Default)]
+pub struct AsyncMemoryDataStore {
+    inner: Arc<tokio::sync::RwLock<MemoryDataStore>>,
+}
+
+impl AsyncMemoryDataStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Clone for AsyncMemoryDataStore {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncDataStore for AsyncMemoryDataStore {
+    type Store = MemoryDataStore;
+
+    async fn read<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&MemoryDataStore) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        operation(&*self.inner.read().await)
+    }
+
+    async fn write<F, T>(&self, operation: F) -> Result<T>
+    where
+        F: FnOnce(&mut MemoryDataStore) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        operation(&mut *self.inner.write().await)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::constraints_check::{pending_write, ConstraintCheckResult};
+    use crate::test_util::TestDir;
+    use crate::{FilesystemDataStore, KeyType};
+
+    fn approve_all<D: DataStore>(
+        datastore: &mut D,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, Box<dyn Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::Approve(pending_write(
+            datastore, committed,
+        )?))
+    }
+
+    /// Runs a transaction through the given store, from several tasks at once.
+    async fn round_trip<A>(datastore: A)
+    where
+        A: AsyncDataStore + Clone + 'static,
+    {
+        let pending = Committed::Pending { tx: "tx".into() };
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let (datastore, pending) = (datastore.clone(), pending.clone());
+                tokio::spawn(async move {
+                    let key = Key::new(KeyType::Data, format!("settings.key{i}")).unwrap();
+                    datastore.set_key(&key, &format!("\"{i}\""), &pending).await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        let parent = Key::new(KeyType::Data, "settings").unwrap();
+        datastore
+            .set_metadata(&meta, &parent, "[\"x\"]", &Committed::Live)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            datastore.list_transactions().await.unwrap(),
+            HashSet::from(["tx".to_string()])
+        );
+        assert!(datastore
+            .get_prefix("settings", &Committed::Live)
+            .await
+            .unwrap()
+            .is_empty());
+        let changed = datastore
+            .commit_transaction("tx", approve_all::<A::Store>)
+            .await
+            .unwrap();
+        assert_eq!(changed.len(), 8);
+
+        let key = Key::new(KeyType::Data, "settings.key3").unwrap();
+        assert_eq!(
+            datastore.get_key(&key, &Committed::Live).await.unwrap(),
+            Some("\"3\"".to_string())
+        );
+        assert_eq!(
+            datastore
+                .get_metadata(&meta, &key, &Committed::Live)
+                .await
+                .unwrap(),
+            Some("[\"x\"]".to_string())
+        );
+        assert!(datastore.list_transactions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn memory_round_trip() {
+        round_trip(AsyncMemoryDataStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn filesystem_round_trip() {
+        let dir = TestDir::new("async");
+        let datastore = FilesystemDataStore::new(&dir.0).unwrap();
+        round_trip(BlockingDataStore::new(datastore)).await;
+    }
+}
@@ -0,0 +1,125 @@
+//! Copies a data store into a new one in the other on-disk format, so a node can be switched
+//! between FilesystemDataStore and LogDataStore.  Live data, metadata, and pending transactions
+//! are copied; snapshots aren't.  Run it against a data store nothing else is using.
+//!
+//! FORMAT is "filesystem" for a FilesystemDataStore base directory, or "log" for a LogDataStore
+//! log file.  The destination must not exist yet.
+//!
+//! If the data store has sensitive values, give the host's key file with --encryption-key, so
+//! they can be read from a FilesystemDataStore source and are encrypted in a FilesystemDataStore
+//! destination.
+
+use datastore::{copy, DataStore, EncryptionKey, FilesystemDataStore, LogDataStore};
+use std::path::Path;
+use std::{env, process};
+
+/// Informs the user about proper usage of the program and exits.
+fn usage() -> ! {
+    let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
+    eprintln!(
+        r"Usage: {program_name}
+            --from FORMAT SOURCE
+            --to FORMAT DESTINATION
+            [ --encryption-key PATH ]
+
+FORMAT is 'filesystem' or 'log'."
+    );
+    process::exit(2);
+}
+
+/// Prints a more specific message before exiting through usage().
+fn usage_msg<S: AsRef<str>>(msg: S) -> ! {
+    eprintln!("{}\n", msg.as_ref());
+    usage();
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Filesystem,
+    Log,
+}
+
+fn parse_format(arg: Option<String>) -> Format {
+    match arg.as_deref() {
+        Some("filesystem") => Format::Filesystem,
+        Some("log") => Format::Log,
+        Some(other) => usage_msg(format!("Unknown format '{other}'")),
+        None => usage(),
+    }
+}
+
+/// Gives the data store the key from the given key file, if any.
+fn with_key(
+    datastore: FilesystemDataStore,
+    key_file: Option<&str>,
+) -> datastore::Result<FilesystemDataStore> {
+    match key_file {
+        None => Ok(datastore),
+        Some(key_file) => Ok(datastore.with_encryption_key(EncryptionKey::load(key_file)?)),
+    }
+}
+
+/// Copies the source into the destination, which is created in the given format.
+fn copy_to<S: DataStore>(
+    source: &S,
+    format: Format,
+    path: &str,
+    key_file: Option<&str>,
+) -> datastore::Result<()> {
+    match format {
+        Format::Filesystem => copy(
+            source,
+            &mut with_key(FilesystemDataStore::create(path)?, key_file)?,
+        ),
+        Format::Log => copy(source, &mut LogDataStore::new(path)?),
+    }
+}
+
+fn main() {
+    let mut from = None;
+    let mut to = None;
+    let mut key_file = None;
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let target = match arg.as_ref() {
+            "--from" => &mut from,
+            "--to" => &mut to,
+            "--encryption-key" => {
+                key_file = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --encryption-key")),
+                );
+                continue;
+            }
+            _ => usage(),
+        };
+        let format = parse_format(iter.next());
+        let path = iter.next().unwrap_or_else(|| usage());
+        *target = Some((format, path));
+    }
+    let (from_format, source) = from.unwrap_or_else(|| usage_msg("Did not specify --from"));
+    let (to_format, destination) = to.unwrap_or_else(|| usage_msg("Did not specify --to"));
+
+    if !Path::new(&source).exists() {
+        eprintln!("Source data store {source} doesn't exist");
+        process::exit(1);
+    }
+    if Path::new(&destination).exists() {
+        eprintln!("Destination {destination} already exists; refusing to overwrite it");
+        process::exit(1);
+    }
+
+    let key_file = key_file.as_deref();
+    let result = match from_format {
+        Format::Filesystem => FilesystemDataStore::new(&source)
+            .and_then(|source| with_key(source, key_file))
+            .and_then(|source| copy_to(&source, to_format, &destination, key_file)),
+        Format::Log => LogDataStore::new(&source)
+            .and_then(|source| copy_to(&source, to_format, &destination, key_file)),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to copy {source} to {destination}: {e}");
+        process::exit(1);
+    }
+    println!("Copied {source} to {destination}");
+}
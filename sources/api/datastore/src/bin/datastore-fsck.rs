@@ -0,0 +1,62 @@
+//! Checks a FilesystemDataStore for damage, and with --repair, quarantines the damaged entries.
+//! See the `fsck` module.  Run it against a data store nothing else is using, for example one in
+//! an offline image.
+//!
+//! Like fsck, the exit status is 0 if no problems were found, 1 if problems were found and all
+//! of them were quarantined, 4 if problems were left, 8 if the check failed, and 16 for bad
+//! arguments.
+
+use datastore::fsck;
+use std::{env, process};
+
+/// Informs the user about proper usage of the program and exits.
+fn usage() -> ! {
+    let program_name = env::args().next().unwrap_or_else(|| "program".to_string());
+    eprintln!("Usage: {program_name} [ --repair ] DATASTORE_PATH");
+    process::exit(16);
+}
+
+fn main() {
+    let mut repair = false;
+    let mut base_path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_ref() {
+            "--repair" => repair = true,
+            _ if base_path.is_none() && !arg.starts_with("--") => base_path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let base_path = base_path.unwrap_or_else(|| usage());
+
+    let result = if repair {
+        fsck::repair(&base_path)
+    } else {
+        fsck::check(&base_path)
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Failed to check data store at {base_path}: {e}");
+            process::exit(8);
+        }
+    };
+
+    for problem in &report.problems {
+        println!("{problem}");
+    }
+    for (original, quarantined) in &report.quarantined {
+        println!(
+            "Quarantined {} to {}",
+            original.display(),
+            quarantined.display()
+        );
+    }
+
+    if report.is_clean() {
+        println!("No problems found in {base_path}");
+    } else if report.unrepaired().next().is_none() {
+        process::exit(1);
+    } else {
+        process::exit(4);
+    }
+}
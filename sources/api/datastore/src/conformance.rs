@@ -0,0 +1,716 @@
+//! A behavioral test suite for DataStore implementations, so every implementation can be held to
+//! the same semantics.
+//!
+//! `run` takes a factory that returns a new, empty data store, and runs each check against a
+//! fresh one: key and metadata CRUD, metadata inheritance, transactions, `delete_transaction`,
+//! constraint rejection, prefix listing, pattern queries, and transaction expiry.  The checks
+//! panic with a description of what went wrong, like assertions, so `run` is meant to be called
+//! from a test:
+//!
+//! ```
+//! use datastore::conformance;
+//! use datastore::memory::MemoryDataStore;
+//!
+//! conformance::run(MemoryDataStore::new);
+//! ```
+//!
+//! Each check is public too, for running one at a time.
+
+use constants::LAUNCH_TRANSACTION;
+use std::collections::{HashMap, HashSet};
+use std::thread;
+use std::time::Duration;
+
+use crate::constraints_check::{
+    pending_write, ApprovedWrite, CheckError, ConstraintCheckResult, Rejection,
+};
+use crate::{Committed, DataStore, Error, Key, KeyPattern, KeyType};
+
+/// Runs every check, each against a new data store from the given factory.
+pub fn run<D, F>(mut new_datastore: F)
+where
+    D: DataStore,
+    F: FnMut() -> D,
+{
+    key_crud(&mut new_datastore());
+    metadata_crud(&mut new_datastore());
+    metadata_inheritance(&mut new_datastore());
+    transactions(&mut new_datastore());
+    delete_transaction(&mut new_datastore());
+    constraint_rejection(&mut new_datastore());
+    prefix_listing(&mut new_datastore());
+    pattern_queries(&mut new_datastore());
+    transaction_expiry(&mut new_datastore());
+}
+
+fn key(name: &str) -> Key {
+    Key::new(KeyType::Data, name).unwrap_or_else(|e| panic!("Bad data key '{name}': {e}"))
+}
+
+fn meta(name: &str) -> Key {
+    Key::new(KeyType::Meta, name).unwrap_or_else(|e| panic!("Bad metadata key '{name}': {e}"))
+}
+
+fn keys(names: &[&str]) -> HashSet<Key> {
+    names.iter().map(|name| key(name)).collect()
+}
+
+fn pending(tx: &str) -> Committed {
+    Committed::Pending { tx: tx.to_string() }
+}
+
+/// Approves everything in the pending transaction: its settings, metadata, and unset keys and
+/// metadata.
+pub fn approve_all<D: DataStore>(
+    datastore: &mut D,
+    committed: &Committed,
+) -> Result<ConstraintCheckResult, CheckError> {
+    Ok(ConstraintCheckResult::Approve(pending_write(
+        datastore, committed,
+    )?))
+}
+
+/// Setting, reading, and unsetting data keys, live and pending.
+pub fn key_crud<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let motd = key("settings.motd");
+
+    assert_eq!(datastore.get_key(&motd, live).unwrap(), None);
+    assert!(!datastore.key_populated(&motd, live).unwrap());
+    // Unsetting a missing key isn't an error.
+    datastore.unset_key(&motd, live).unwrap();
+
+    datastore.set_key(&motd, "\"hi\"", live).unwrap();
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"hi\"".to_string()),
+        "live value wasn't stored"
+    );
+    assert!(datastore.key_populated(&motd, live).unwrap());
+    datastore.set_key(&motd, "\"hello\"", live).unwrap();
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"hello\"".to_string()),
+        "live value wasn't replaced"
+    );
+
+    // Pending values are kept apart from live ones.
+    datastore.set_key(&motd, "\"pending\"", tx).unwrap();
+    assert_eq!(
+        datastore.get_key(&motd, tx).unwrap(),
+        Some("\"pending\"".to_string())
+    );
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"hello\"".to_string()),
+        "pending value leaked into live"
+    );
+
+    datastore.unset_key(&motd, live).unwrap();
+    assert_eq!(datastore.get_key(&motd, live).unwrap(), None);
+    assert!(!datastore.key_populated(&motd, live).unwrap());
+    assert_eq!(
+        datastore.get_key(&motd, tx).unwrap(),
+        Some("\"pending\"".to_string()),
+        "unsetting live removed the pending value"
+    );
+}
+
+/// Setting, reading, listing, and unsetting metadata, live and pending.
+pub fn metadata_crud<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let motd = key("settings.motd");
+    let services = meta("affected-services");
+    let generator = meta("setting-generator");
+
+    datastore.set_key(&motd, "\"hi\"", live).unwrap();
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, live).unwrap(),
+        None
+    );
+    datastore.unset_metadata(&services, &motd, live).unwrap();
+
+    datastore
+        .set_metadata(&services, &motd, "[\"motd\"]", live)
+        .unwrap();
+    datastore
+        .set_metadata(&generator, &motd, "\"motdgen\"", live)
+        .unwrap();
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, live).unwrap(),
+        Some("[\"motd\"]".to_string())
+    );
+    let listed = datastore
+        .list_populated_metadata("settings", live, &Some("affected-services"))
+        .unwrap();
+    assert_eq!(
+        listed,
+        HashMap::from([(motd.clone(), HashSet::from([services.clone()]))]),
+        "listing by metadata name"
+    );
+
+    // Pending metadata waits for the commit.
+    datastore
+        .set_metadata(&services, &motd, "[\"other\"]", tx)
+        .unwrap();
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, tx).unwrap(),
+        Some("[\"other\"]".to_string())
+    );
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, live).unwrap(),
+        Some("[\"motd\"]".to_string()),
+        "pending metadata leaked into live"
+    );
+
+    datastore.unset_metadata(&generator, &motd, live).unwrap();
+    assert_eq!(
+        datastore.get_metadata_raw(&generator, &motd, live).unwrap(),
+        None
+    );
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, live).unwrap(),
+        Some("[\"motd\"]".to_string()),
+        "unsetting one metadata key removed another"
+    );
+}
+
+/// Metadata set on a key applies to the keys under it, unless they have their own.
+pub fn metadata_inheritance<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let services = meta("affected-services");
+    let servers = key("settings.ntp.time-servers");
+    datastore.set_key(&servers, "[]", live).unwrap();
+
+    datastore
+        .set_metadata(&services, &key("settings"), "[\"all\"]", live)
+        .unwrap();
+    assert_eq!(
+        datastore.get_metadata(&services, &servers, live).unwrap(),
+        Some("[\"all\"]".to_string()),
+        "metadata wasn't inherited"
+    );
+    assert_eq!(
+        datastore
+            .get_metadata_raw(&services, &servers, live)
+            .unwrap(),
+        None
+    );
+
+    datastore
+        .set_metadata(&services, &key("settings.ntp"), "[\"chronyd\"]", live)
+        .unwrap();
+    assert_eq!(
+        datastore.get_metadata(&services, &servers, live).unwrap(),
+        Some("[\"chronyd\"]".to_string()),
+        "more specific metadata didn't win"
+    );
+    assert_eq!(
+        datastore
+            .get_metadata(&services, &key("settings.motd"), live)
+            .unwrap(),
+        Some("[\"all\"]".to_string())
+    );
+}
+
+/// Committing a transaction applies its settings, metadata, and unset keys and metadata to live,
+/// and removes it.
+pub fn transactions<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let (motd, hostname) = (key("settings.motd"), key("settings.hostname"));
+    let services = meta("affected-services");
+    datastore.set_key(&hostname, "\"box\"", live).unwrap();
+    datastore
+        .set_metadata(&services, &hostname, "[\"hostname\"]", live)
+        .unwrap();
+    assert!(datastore.list_transactions().unwrap().is_empty());
+
+    datastore.set_key(&motd, "\"hi\"", tx).unwrap();
+    datastore.unset_key(&hostname, tx).unwrap();
+    datastore
+        .set_metadata(&services, &motd, "[\"motd\"]", tx)
+        .unwrap();
+    datastore.unset_metadata(&services, &hostname, tx).unwrap();
+    assert_eq!(
+        datastore.list_transactions().unwrap(),
+        HashSet::from(["tx".to_string()])
+    );
+    assert_eq!(
+        datastore.list_deleted_keys("", tx).unwrap(),
+        HashSet::from([hostname.clone()])
+    );
+    assert_eq!(
+        datastore.list_deleted_metadata("", tx).unwrap(),
+        HashMap::from([(hostname.clone(), HashSet::from([services.clone()]))])
+    );
+    assert!(datastore.list_deleted_keys("", live).unwrap().is_empty());
+
+    let changed = datastore.commit_transaction("tx", &approve_all).unwrap();
+    assert_eq!(changed, HashSet::from([motd.clone(), hostname.clone()]));
+    assert!(
+        datastore.list_transactions().unwrap().is_empty(),
+        "committed transaction is still pending"
+    );
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"hi\"".to_string())
+    );
+    assert_eq!(datastore.get_key(&hostname, live).unwrap(), None);
+    assert_eq!(datastore.get_key(&motd, tx).unwrap(), None);
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, live).unwrap(),
+        Some("[\"motd\"]".to_string())
+    );
+    assert_eq!(
+        datastore
+            .get_metadata_raw(&services, &hostname, live)
+            .unwrap(),
+        None
+    );
+
+    // Setting a key again in the transaction cancels unsetting it.
+    datastore.unset_key(&motd, tx).unwrap();
+    datastore.set_key(&motd, "\"again\"", tx).unwrap();
+    assert!(datastore.list_deleted_keys("", tx).unwrap().is_empty());
+    datastore.commit_transaction("tx", &approve_all).unwrap();
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"again\"".to_string())
+    );
+}
+
+/// Deleting a transaction throws away its changes, leaving live alone.
+pub fn delete_transaction<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let (motd, hostname) = (key("settings.motd"), key("settings.hostname"));
+    let services = meta("affected-services");
+    datastore.set_key(&hostname, "\"box\"", live).unwrap();
+
+    datastore.set_key(&motd, "\"hi\"", tx).unwrap();
+    datastore.unset_key(&hostname, tx).unwrap();
+    datastore
+        .set_metadata(&services, &motd, "[\"motd\"]", tx)
+        .unwrap();
+    let removed = datastore.delete_transaction("tx").unwrap();
+    assert_eq!(removed, HashSet::from([motd.clone(), hostname.clone()]));
+    assert!(datastore.list_transactions().unwrap().is_empty());
+    assert_eq!(datastore.get_key(&motd, tx).unwrap(), None);
+    assert!(datastore.list_deleted_keys("", tx).unwrap().is_empty());
+    assert_eq!(
+        datastore.get_metadata_raw(&services, &motd, tx).unwrap(),
+        None
+    );
+    assert_eq!(
+        datastore.get_key(&hostname, live).unwrap(),
+        Some("\"box\"".to_string()),
+        "deleting a transaction changed live"
+    );
+
+    // Deleting a missing transaction isn't an error.
+    assert!(datastore.delete_transaction("tx").unwrap().is_empty());
+}
+
+/// A rejected or failed constraint check stops the commit without changing anything.
+pub fn constraint_rejection<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let motd = key("settings.motd");
+    datastore.set_key(&motd, "\"hi\"", live).unwrap();
+    datastore.set_key(&motd, "\"pending\"", tx).unwrap();
+
+    let expected = vec![
+        Rejection::new("motd", "too friendly").with_key(motd.clone()),
+        Rejection::new("quiet-hours", "no changes after midnight"),
+    ];
+    let result = datastore.commit_transaction("tx", &|_: &mut D, _: &Committed| {
+        Ok(ConstraintCheckResult::Reject(expected.clone()))
+    });
+    match result {
+        Err(Error::ConstraintCheckReject { rejections }) => {
+            assert_eq!(rejections, expected, "rejections weren't returned as given")
+        }
+        _ => panic!("rejected commit returned {result:?}"),
+    }
+    let result =
+        datastore.commit_transaction("tx", &|_: &mut D, _: &Committed| Err("check failed".into()));
+    assert!(
+        matches!(result, Err(Error::CheckConstraintExecution { .. })),
+        "failed check returned {result:?}"
+    );
+
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"hi\"".to_string()),
+        "rejected commit changed live"
+    );
+    assert_eq!(
+        datastore.list_transactions().unwrap(),
+        HashSet::from(["tx".to_string()]),
+        "rejected transaction was removed"
+    );
+
+    // The check can narrow the write it approves.
+    let other = key("settings.other");
+    datastore.set_key(&other, "\"x\"", tx).unwrap();
+    let changed = datastore
+        .commit_transaction("tx", &|_: &mut D, _: &Committed| {
+            Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+                settings: HashMap::from([(key("settings.motd"), "\"approved\"".to_string())]),
+                metadata: Vec::new(),
+                deleted_settings: HashSet::new(),
+                deleted_metadata: Vec::new(),
+            })))
+        })
+        .unwrap();
+    assert_eq!(changed, HashSet::from([motd.clone()]));
+    assert_eq!(
+        datastore.get_key(&motd, live).unwrap(),
+        Some("\"approved\"".to_string())
+    );
+    assert_eq!(datastore.get_key(&other, live).unwrap(), None);
+}
+
+/// Transactions record when they were created and last changed, and expire when they haven't
+/// been changed for long enough, except for the launch transaction.
+pub fn transaction_expiry<D: DataStore>(datastore: &mut D) {
+    let (tx, launch) = (&pending("tx"), &pending(LAUNCH_TRANSACTION));
+    let motd = key("settings.motd");
+    assert!(datastore.list_transaction_times().unwrap().is_empty());
+    datastore.set_key(&motd, "\"hi\"", tx).unwrap();
+    datastore.set_key(&motd, "\"hi\"", launch).unwrap();
+    let times = datastore.list_transaction_times().unwrap();
+    assert_eq!(
+        times.keys().collect::<HashSet<_>>(),
+        HashSet::from([&"tx".to_string(), &LAUNCH_TRANSACTION.to_string()])
+    );
+    let created = times["tx"];
+    assert!(created.created <= created.touched);
+
+    thread::sleep(Duration::from_millis(20));
+    datastore
+        .set_metadata(&meta("affected-services"), &motd, "[]", tx)
+        .unwrap();
+    let touched = datastore.list_transaction_times().unwrap()["tx"];
+    assert_eq!(touched.created, created.created, "creation time changed");
+    assert!(touched.touched > created.touched, "change wasn't recorded");
+
+    let hour = Duration::from_secs(60 * 60);
+    assert!(
+        datastore.expire_transactions(hour).unwrap().is_empty(),
+        "expired recent transactions"
+    );
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(
+        datastore.expire_transactions(Duration::ZERO).unwrap(),
+        HashSet::from(["tx".to_string()])
+    );
+    assert_eq!(
+        datastore.list_transactions().unwrap(),
+        HashSet::from([LAUNCH_TRANSACTION.to_string()]),
+        "launch transaction wasn't kept"
+    );
+    assert_eq!(datastore.get_key(&motd, tx).unwrap(), None);
+}
+
+/// Prefixes match whole key segments.
+pub fn prefix_listing<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    for name in ["settings.a.b", "settings.a.c.d", "settings.ab", "other.a"] {
+        datastore.set_key(&key(name), "1", live).unwrap();
+    }
+    datastore.set_key(&key("settings.a.e"), "2", tx).unwrap();
+
+    let listed =
+        |prefix: &str, committed| datastore.list_populated_keys(prefix, committed).unwrap();
+    assert_eq!(
+        listed("", live),
+        keys(&["settings.a.b", "settings.a.c.d", "settings.ab", "other.a"])
+    );
+    assert_eq!(
+        listed("settings", live),
+        keys(&["settings.a.b", "settings.a.c.d", "settings.ab"])
+    );
+    assert_eq!(
+        listed("settings.a", live),
+        keys(&["settings.a.b", "settings.a.c.d"]),
+        "prefix matched part of a segment"
+    );
+    assert_eq!(listed("settings.a.", live), listed("settings.a", live));
+    assert_eq!(listed("settings.a.b", live), keys(&["settings.a.b"]));
+    assert!(listed("settings.x", live).is_empty());
+    assert_eq!(listed("settings.a", tx), keys(&["settings.a.e"]));
+
+    let values = datastore.get_prefix("settings.a", live).unwrap();
+    assert_eq!(
+        values,
+        HashMap::from([
+            (key("settings.a.b"), "1".to_string()),
+            (key("settings.a.c.d"), "1".to_string()),
+        ])
+    );
+    let values = datastore.get_string_prefix("settings.a", live).unwrap();
+    assert_eq!(values.len(), 3, "string prefix didn't match 'settings.ab'");
+}
+
+/// Wildcards match whole segments, including quoted segments and list indexes.
+pub fn pattern_queries<D: DataStore>(datastore: &mut D) {
+    let live = &Committed::Live;
+    let tx = &pending("tx");
+    let names = [
+        "settings.host-containers.admin.enabled",
+        "settings.host-containers.admin.source",
+        "settings.host-containers.control.enabled",
+        "settings.host-containers.\"a.b\".enabled",
+        "settings.list[0].enabled",
+        "settings.enabled",
+    ];
+    for name in names {
+        datastore.set_key(&key(name), "true", live).unwrap();
+    }
+    datastore
+        .set_key(&key("settings.host-containers.new.enabled"), "false", tx)
+        .unwrap();
+    let flag = meta("affected-services");
+    datastore
+        .set_metadata(&flag, &key("settings.host-containers.admin"), "[]", live)
+        .unwrap();
+    datastore
+        .set_metadata(&flag, &key("settings.list[0].enabled"), "[]", live)
+        .unwrap();
+
+    let query = |pattern: &str, committed| {
+        let pattern = KeyPattern::new(pattern).unwrap();
+        let found = datastore.query(&pattern, committed).unwrap();
+        found.into_keys().collect::<HashSet<_>>()
+    };
+    assert_eq!(
+        query("settings.host-containers.*.enabled", live),
+        keys(&[
+            "settings.host-containers.admin.enabled",
+            "settings.host-containers.control.enabled",
+            "settings.host-containers.\"a.b\".enabled",
+        ])
+    );
+    assert_eq!(
+        query("**.enabled", live),
+        keys(&[
+            "settings.host-containers.admin.enabled",
+            "settings.host-containers.control.enabled",
+            "settings.host-containers.\"a.b\".enabled",
+            "settings.list[0].enabled",
+            "settings.enabled",
+        ])
+    );
+    assert_eq!(
+        query("settings.host-containers.\"a.b\".*", live),
+        keys(&["settings.host-containers.\"a.b\".enabled"])
+    );
+    assert_eq!(
+        query("settings.list.*.enabled", live),
+        keys(&["settings.list[0].enabled"]),
+        "wildcard didn't match list index"
+    );
+    assert!(query("settings.host-containers.*", live).is_empty());
+    assert_eq!(
+        query("settings.host-containers.*.enabled", tx),
+        keys(&["settings.host-containers.new.enabled"])
+    );
+
+    let pattern = KeyPattern::new("settings.**").unwrap();
+    let metadata = datastore
+        .get_metadata_query(&pattern, live, &Some("affected-services"))
+        .unwrap();
+    assert_eq!(
+        metadata.into_keys().collect::<HashSet<_>>(),
+        keys(&["settings.host-containers.admin", "settings.list[0].enabled"])
+    );
+    let pattern = KeyPattern::new("settings.host-containers.*.enabled").unwrap();
+    let metadata = datastore
+        .get_metadata_query(&pattern, live, &Some("affected-services"))
+        .unwrap();
+    assert!(
+        metadata.is_empty(),
+        "metadata query matched a key that only inherits metadata"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::test_util::TestDir;
+    use crate::{FilesystemDataStore, LogDataStore};
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn memory_conforms() {
+        run(MemoryDataStore::new);
+    }
+
+    #[test]
+    fn filesystem_conforms() {
+        let dir = TestDir::new("conformance-filesystem");
+        let mut count = 0;
+        run(|| {
+            count += 1;
+            FilesystemDataStore::create(dir.0.join(count.to_string())).unwrap()
+        });
+    }
+
+    #[test]
+    fn log_conforms() {
+        let dir = TestDir::new("conformance-log");
+        let mut count = 0;
+        run(|| {
+            count += 1;
+            LogDataStore::new(dir.0.join(format!("{count}.log"))).unwrap()
+        });
+    }
+
+    /// An operation for the differential test.
+    #[derive(Debug)]
+    enum Op {
+        SetKey(Key, String, Committed),
+        UnsetKey(Key, Committed),
+        SetMetadata(Key, Key, String, Committed),
+        UnsetMetadata(Key, Key, Committed),
+        Commit(String),
+        DeleteTransaction(String),
+    }
+
+    fn random_op(rng: &mut StdRng) -> Op {
+        // Data keys are all at the same depth, since a key can't have both a value and keys
+        // under it in a FilesystemDataStore; metadata can also go on the keys above them.
+        const DATA_KEYS: &[&str] = &["settings.a.x", "settings.a.y", "settings.b.x"];
+        const METADATA_TARGETS: &[&str] =
+            &["settings", "settings.a", "settings.a.x", "settings.b.x"];
+        const METADATA_KEYS: &[&str] = &["affected-services", "setting-generator"];
+        const TRANSACTIONS: &[&str] = &["tx1", "tx2"];
+
+        let tx = TRANSACTIONS.choose(rng).unwrap().to_string();
+        let committed = if rng.gen_bool(0.3) {
+            Committed::Live
+        } else {
+            Committed::Pending { tx: tx.clone() }
+        };
+        let data_key = key(DATA_KEYS.choose(rng).unwrap());
+        let target = key(METADATA_TARGETS.choose(rng).unwrap());
+        let metadata_key = meta(METADATA_KEYS.choose(rng).unwrap());
+        let value = rng.gen_range(0..3).to_string();
+        match rng.gen_range(0..10) {
+            0..=2 => Op::SetKey(data_key, value, committed),
+            3..=4 => Op::UnsetKey(data_key, committed),
+            5 => Op::SetMetadata(metadata_key, target, value, committed),
+            6 => Op::UnsetMetadata(metadata_key, target, committed),
+            7..=8 => Op::Commit(tx),
+            _ => Op::DeleteTransaction(tx),
+        }
+    }
+
+    /// Applies the operation, returning what it returned in a comparable form.
+    fn apply<D: DataStore>(datastore: &mut D, op: &Op) -> Result<Vec<String>, String> {
+        let result = match op {
+            Op::SetKey(key, value, committed) => datastore
+                .set_key(key, value, committed)
+                .map(|()| HashSet::new()),
+            Op::UnsetKey(key, committed) => {
+                datastore.unset_key(key, committed).map(|()| HashSet::new())
+            }
+            Op::SetMetadata(metadata_key, data_key, value, committed) => datastore
+                .set_metadata(metadata_key, data_key, value, committed)
+                .map(|()| HashSet::new()),
+            Op::UnsetMetadata(metadata_key, data_key, committed) => datastore
+                .unset_metadata(metadata_key, data_key, committed)
+                .map(|()| HashSet::new()),
+            Op::Commit(tx) => datastore.commit_transaction(tx, &approve_all),
+            Op::DeleteTransaction(tx) => datastore.delete_transaction(tx),
+        };
+        result
+            .map(|keys| {
+                let mut names: Vec<_> = keys.iter().map(|key| key.name().clone()).collect();
+                names.sort_unstable();
+                names
+            })
+            .map_err(|e| e.to_string())
+    }
+
+    /// Everything observable about the data store's data and metadata, live and pending.
+    fn contents<D: DataStore>(datastore: &D) -> BTreeMap<String, String> {
+        let mut contents = BTreeMap::new();
+        let mut views = vec![("live".to_string(), Committed::Live)];
+        for tx in datastore.list_transactions().unwrap() {
+            views.push((tx.clone(), Committed::Pending { tx }));
+        }
+        for (name, committed) in views {
+            contents.insert(format!("{name} exists"), String::new());
+            for (key, value) in datastore.get_prefix("", &committed).unwrap() {
+                contents.insert(format!("{name} {key}"), value);
+            }
+            for key in datastore.list_deleted_keys("", &committed).unwrap() {
+                contents.insert(format!("{name} {key} deleted"), String::new());
+            }
+            let metadata = datastore
+                .list_populated_metadata("", &committed, &None as &Option<&str>)
+                .unwrap();
+            for (data_key, metadata_keys) in metadata {
+                for metadata_key in metadata_keys {
+                    let value = datastore
+                        .get_metadata_raw(&metadata_key, &data_key, &committed)
+                        .unwrap()
+                        .unwrap_or_default();
+                    contents.insert(format!("{name} {data_key} {metadata_key}"), value);
+                }
+            }
+            for (data_key, metadata_keys) in
+                datastore.list_deleted_metadata("", &committed).unwrap()
+            {
+                for metadata_key in metadata_keys {
+                    contents.insert(
+                        format!("{name} {data_key} {metadata_key} deleted"),
+                        String::new(),
+                    );
+                }
+            }
+        }
+        contents
+    }
+
+    /// Runs random operations against a MemoryDataStore and a FilesystemDataStore and checks that
+    /// they return the same results and end up with the same contents.  The seed of a failing
+    /// run is in the panic message, so it can be reproduced.
+    #[test]
+    fn backends_agree() {
+        let dir = TestDir::new("conformance-differential");
+        for seed in 0..40 {
+            let mut memory = MemoryDataStore::new().with_snapshot_retention(0);
+            let mut filesystem = FilesystemDataStore::create(dir.0.join(seed.to_string()))
+                .unwrap()
+                .with_snapshot_retention(0);
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut ops = Vec::new();
+            for _ in 0..40 {
+                let op = random_op(&mut rng);
+                let expected = apply(&mut memory, &op);
+                let actual = apply(&mut filesystem, &op);
+                ops.push(op);
+                assert_eq!(
+                    expected, actual,
+                    "seed {seed}: results differ after {ops:#?}"
+                );
+                assert_eq!(
+                    contents(&memory),
+                    contents(&filesystem),
+                    "seed {seed}: contents differ after {ops:#?}"
+                );
+            }
+        }
+    }
+}
@@ -4,37 +4,53 @@
 //! result confirms that all constraints are satisfied and provides the required
 //! settings and metadata for the commit.
 //! Constraint checks can alter the write.
+//!
+//! Checks can be written as `ConstraintCheck` implementations, which see the write the
+//! transaction would make and return a `Rejection` for each problem they find, so a rejected
+//! commit reports every failure at once.  Checks can be chained with `and`, and a
+//! `ConstraintRegistry` runs named checks only when the write touches their settings prefix.
 
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 
-use crate::{error, Key};
+use crate::{error, Committed, DataStore, Key, Result};
 
-type RejectReason = String;
+/// The error type for failures while running a check, as opposed to rejections.
+pub type CheckError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 /// Represents a successful write operation after constraints have been approved.
 /// Contains the following fields:
 /// - `settings`: A collection of key-value pairs representing the settings to be committed.
 /// - `metadata`: A collection of metadata entries.
+/// - `deleted_settings`: The data keys to remove from live, as recorded by `unset_key` in the
+///   pending transaction; see `DataStore::list_deleted_keys`.
+/// - `deleted_metadata`: The (metadata key, data key) pairs to remove from live, as recorded by
+///   `unset_metadata` in the pending transaction; see `DataStore::list_deleted_metadata`.
 #[derive(PartialEq)]
 pub struct ApprovedWrite {
     pub settings: HashMap<Key, String>,
     pub metadata: Vec<(Key, Key, String)>,
+    pub deleted_settings: HashSet<Key>,
+    pub deleted_metadata: Vec<(Key, Key)>,
 }
 
 /// Represents the result of a constraint check.
 /// The result can either reject the operation or approve it with the required data.
 #[derive(PartialEq)]
 pub enum ConstraintCheckResult {
-    Reject(RejectReason),
+    Reject(Vec<Rejection>),
     Approve(ApprovedWrite),
 }
 
 impl TryFrom<ConstraintCheckResult> for ApprovedWrite {
     type Error = error::Error;
 
-    fn try_from(constraint_check_result: ConstraintCheckResult) -> Result<Self, Self::Error> {
+    fn try_from(constraint_check_result: ConstraintCheckResult) -> Result<Self> {
         match constraint_check_result {
-            ConstraintCheckResult::Reject(err) => error::ConstraintCheckRejectSnafu { err }.fail(),
+            ConstraintCheckResult::Reject(rejections) => {
+                error::ConstraintCheckRejectSnafu { rejections }.fail()
+            }
             ConstraintCheckResult::Approve(approved_write) => Ok(approved_write),
         }
     }
@@ -43,10 +59,383 @@ impl TryFrom<ConstraintCheckResult> for ApprovedWrite {
 impl From<Option<ApprovedWrite>> for ConstraintCheckResult {
     fn from(approved_write: Option<ApprovedWrite>) -> Self {
         match approved_write {
-            None => ConstraintCheckResult::Reject(
-                "The write for the given transaction is rejected".to_string(),
-            ),
+            None => ConstraintCheckResult::Reject(vec![Rejection::new(
+                "constraint-check",
+                "The write for the given transaction is rejected",
+            )]),
             Some(approved_write) => ConstraintCheckResult::Approve(approved_write),
         }
     }
 }
+
+/// One reason a constraint check rejected a write: which check failed, on which setting, if the
+/// problem is with one setting, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rejection {
+    pub check: String,
+    pub key: Option<Key>,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new<C, R>(check: C, reason: R) -> Self
+    where
+        C: Into<String>,
+        R: Into<String>,
+    {
+        Self {
+            check: check.into(),
+            key: None,
+            reason: reason.into(),
+        }
+    }
+
+    /// Points the rejection at the offending setting.
+    pub fn with_key(mut self, key: Key) -> Self {
+        self.key = Some(key);
+        self
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{} rejected '{}': {}", self.check, key, self.reason),
+            None => write!(f, "{} rejected the write: {}", self.check, self.reason),
+        }
+    }
+}
+
+/// Returns everything the given pending transaction would write to live: its settings and
+/// metadata, and the keys and metadata it unsets.  This is the write a `ConstraintCheck` starts
+/// from.
+pub fn pending_write<D: DataStore>(datastore: &D, committed: &Committed) -> Result<ApprovedWrite> {
+    let mut metadata = Vec::new();
+    for (data_key, metadata_keys) in
+        datastore.list_populated_metadata("", committed, &None as &Option<&str>)?
+    {
+        for metadata_key in metadata_keys {
+            if let Some(value) = datastore.get_metadata_raw(&metadata_key, &data_key, committed)? {
+                metadata.push((metadata_key, data_key.clone(), value));
+            }
+        }
+    }
+    let deleted_metadata = datastore
+        .list_deleted_metadata("", committed)?
+        .into_iter()
+        .flat_map(|(data_key, metadata_keys)| {
+            metadata_keys
+                .into_iter()
+                .map(move |metadata_key| (metadata_key, data_key.clone()))
+        })
+        .collect();
+    Ok(ApprovedWrite {
+        settings: datastore.get_prefix("", committed)?,
+        metadata,
+        deleted_settings: datastore.list_deleted_keys("", committed)?,
+        deleted_metadata,
+    })
+}
+
+/// A check that a pending write has to pass to be committed.
+///
+/// Closures taking the same arguments as `check` are checks, too.
+pub trait ConstraintCheck<D: DataStore> {
+    /// Checks the write the transaction would make, returning a `Rejection` for each problem
+    /// found, or an empty list to approve it.  The check can change the write, for example to
+    /// drop or normalize settings; later checks in a chain see the changed write.
+    fn check(
+        &self,
+        datastore: &mut D,
+        committed: &Committed,
+        write: &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError>;
+
+    /// Returns a check that runs this check and then the given one, rejecting the write with the
+    /// rejections of both.
+    fn and<C>(self, next: C) -> Chain<Self, C>
+    where
+        Self: Sized,
+        C: ConstraintCheck<D>,
+    {
+        Chain { first: self, next }
+    }
+
+    /// Runs the check against the pending write of the given transaction, giving the result
+    /// `DataStore::commit_transaction` expects from its constraint check function.
+    fn evaluate(
+        &self,
+        datastore: &mut D,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, CheckError> {
+        let mut write = pending_write(datastore, committed)?;
+        let rejections = self.check(datastore, committed, &mut write)?;
+        if rejections.is_empty() {
+            Ok(ConstraintCheckResult::Approve(write))
+        } else {
+            Ok(ConstraintCheckResult::Reject(rejections))
+        }
+    }
+}
+
+impl<D, F> ConstraintCheck<D> for F
+where
+    D: DataStore,
+    F: Fn(
+        &mut D,
+        &Committed,
+        &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError>,
+{
+    fn check(
+        &self,
+        datastore: &mut D,
+        committed: &Committed,
+        write: &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError> {
+        self(datastore, committed, write)
+    }
+}
+
+/// Two checks run one after the other; see `ConstraintCheck::and`.
+pub struct Chain<A, B> {
+    first: A,
+    next: B,
+}
+
+impl<D, A, B> ConstraintCheck<D> for Chain<A, B>
+where
+    D: DataStore,
+    A: ConstraintCheck<D>,
+    B: ConstraintCheck<D>,
+{
+    fn check(
+        &self,
+        datastore: &mut D,
+        committed: &Committed,
+        write: &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError> {
+        let mut rejections = self.first.check(datastore, committed, write)?;
+        rejections.extend(self.next.check(datastore, committed, write)?);
+        Ok(rejections)
+    }
+}
+
+/// A registered check and the settings prefix it applies to.
+struct Registration<D> {
+    prefix: Vec<String>,
+    check: Box<dyn ConstraintCheck<D>>,
+}
+
+/// A set of named checks, each of which only runs when the write touches a key under its
+/// settings prefix.  Checks run in order of their names, and their rejections are named after
+/// the name they were registered with.
+pub struct ConstraintRegistry<D> {
+    checks: BTreeMap<String, Registration<D>>,
+}
+
+impl<D: DataStore> ConstraintRegistry<D> {
+    pub fn new() -> Self {
+        Self {
+            checks: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the check under the given name, for writes to keys under the given prefix,
+    /// replacing any check already registered with that name.  Prefixes match whole key
+    /// segments, and an empty prefix matches every key.
+    pub fn register<N, P, C>(&mut self, name: N, prefix: P, check: C) -> Result<()>
+    where
+        N: Into<String>,
+        P: AsRef<str>,
+        C: ConstraintCheck<D> + 'static,
+    {
+        let registration = Registration {
+            prefix: Key::prefix_segments(prefix)?,
+            check: Box::new(check),
+        };
+        self.checks.insert(name.into(), registration);
+        Ok(())
+    }
+
+    /// Removes the check with the given name, returning whether there was one.
+    pub fn unregister<N: AsRef<str>>(&mut self, name: N) -> bool {
+        self.checks.remove(name.as_ref()).is_some()
+    }
+
+    /// Returns the names of the registered checks, in the order they run.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.checks.keys().map(String::as_str)
+    }
+}
+
+impl<D: DataStore> Default for ConstraintRegistry<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns whether the write sets or unsets any data or metadata of a key under the prefix.
+fn touches(write: &ApprovedWrite, prefix: &[String]) -> bool {
+    let mut keys = write
+        .settings
+        .keys()
+        .chain(&write.deleted_settings)
+        .chain(write.metadata.iter().map(|(_, data_key, _)| data_key))
+        .chain(write.deleted_metadata.iter().map(|(_, data_key)| data_key));
+    keys.any(|key| key.starts_with_segments(prefix))
+}
+
+impl<D: DataStore> ConstraintCheck<D> for ConstraintRegistry<D> {
+    fn check(
+        &self,
+        datastore: &mut D,
+        committed: &Committed,
+        write: &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError> {
+        let mut rejections = Vec::new();
+        for (name, registration) in &self.checks {
+            if !touches(write, &registration.prefix) {
+                continue;
+            }
+            let found = registration.check.check(datastore, committed, write)?;
+            rejections.extend(found.into_iter().map(|rejection| Rejection {
+                check: name.clone(),
+                ..rejection
+            }));
+        }
+        Ok(rejections)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::{Error, KeyType};
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    /// Rejects every setting in the write whose value is the given one.
+    fn forbid(
+        value: &'static str,
+    ) -> impl Fn(
+        &mut MemoryDataStore,
+        &Committed,
+        &mut ApprovedWrite,
+    ) -> std::result::Result<Vec<Rejection>, CheckError> {
+        move |_, _, write| {
+            let mut found: Vec<_> = write
+                .settings
+                .iter()
+                .filter(|(_, v)| *v == value)
+                .map(|(k, _)| {
+                    Rejection::new("", format!("{value} isn't allowed")).with_key(k.clone())
+                })
+                .collect();
+            found.sort_by(|a, b| {
+                a.key
+                    .as_ref()
+                    .map(Key::name)
+                    .cmp(&b.key.as_ref().map(Key::name))
+            });
+            Ok(found)
+        }
+    }
+
+    fn pending_datastore() -> MemoryDataStore {
+        let mut datastore = MemoryDataStore::new();
+        let tx = Committed::Pending {
+            tx: "tx".to_string(),
+        };
+        for (name, value) in [
+            ("settings.motd", "\"bad-motd\""),
+            ("settings.host.name", "\"bad-host\""),
+            ("settings.host.domain", "\"ok\""),
+        ] {
+            datastore.set_key(&key(name), value, &tx).unwrap();
+        }
+        datastore
+    }
+
+    #[test]
+    fn registry_reports_every_failure() {
+        let mut registry = ConstraintRegistry::new();
+        registry
+            .register("no-bad-hosts", "settings.host", forbid("\"bad-host\""))
+            .unwrap();
+        registry
+            .register("no-bad-motd", "settings.motd", forbid("\"bad-motd\""))
+            .unwrap();
+        // Doesn't run, because nothing under its prefix is written.
+        registry
+            .register("no-ok-ntp", "settings.ntp", forbid("\"ok\""))
+            .unwrap();
+
+        let mut datastore = pending_datastore();
+        let err = datastore
+            .commit_transaction_checked("tx", &registry)
+            .unwrap_err();
+        let rejections = match err {
+            Error::ConstraintCheckReject { rejections } => rejections,
+            _ => panic!("expected rejection, got {err}"),
+        };
+        assert_eq!(
+            rejections,
+            vec![
+                Rejection::new("no-bad-hosts", "\"bad-host\" isn't allowed")
+                    .with_key(key("settings.host.name")),
+                Rejection::new("no-bad-motd", "\"bad-motd\" isn't allowed")
+                    .with_key(key("settings.motd")),
+            ]
+        );
+        assert_eq!(
+            datastore
+                .get_key(&key("settings.motd"), &Committed::Live)
+                .unwrap(),
+            None
+        );
+
+        assert!(registry.unregister("no-bad-hosts"));
+        assert!(!registry.unregister("no-bad-hosts"));
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["no-bad-motd", "no-ok-ntp"]
+        );
+    }
+
+    #[test]
+    fn chained_checks() {
+        let mut datastore = pending_datastore();
+
+        // Both checks run, and the rejections of both are reported.
+        let check = forbid("\"ok\"").and(forbid("\"bad-host\""));
+        let err = datastore
+            .commit_transaction_checked("tx", &check)
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::ConstraintCheckReject { rejections } if rejections.len() == 2),
+            "unexpected result {err}"
+        );
+
+        // The motd is dropped from the write before the second check sees it.
+        let drop_motd = |_: &mut MemoryDataStore, _: &Committed, write: &mut ApprovedWrite| {
+            write.settings.remove(&key("settings.motd"));
+            Ok(Vec::new())
+        };
+        let check = drop_motd.and(forbid("\"bad-motd\""));
+        let changed = datastore.commit_transaction_checked("tx", &check).unwrap();
+        assert_eq!(
+            changed,
+            HashSet::from([key("settings.host.name"), key("settings.host.domain")])
+        );
+        assert_eq!(
+            datastore
+                .get_key(&key("settings.motd"), &Committed::Live)
+                .unwrap(),
+            None
+        );
+    }
+}
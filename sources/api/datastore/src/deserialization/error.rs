@@ -32,6 +32,12 @@ pub enum Error {
         source: Box<DataStoreError>,
     },
 
+    #[snafu(display("Key '{}' in list '{}' doesn't start with a list index", name, list))]
+    MissingIndex { list: String, name: String },
+
+    #[snafu(display("List '{}' is missing element {}", list, index))]
+    MissingElement { list: String, index: usize },
+
     #[snafu(display("Prefix '{}' is not a valid key: {}", prefix, source))]
     InvalidPrefix {
         prefix: String,
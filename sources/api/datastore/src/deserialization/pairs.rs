@@ -30,13 +30,21 @@
 //! provide the value.  We use it recursively, and at each recursion, append a dot and the name of
 //! the field to our "path" string.  In the example above, when we're looking at field "c", path
 //! would be "a.b", so we know we should look for "a.b.c" in our input mapping.
+//!
+//! Lists of scalars are stored as a single value and deserialized like any other scalar.  Lists
+//! with compound elements are stored by index, e.g. "a.b[0].c" and "a.b[1].c", so when we're
+//! asked for a list, or see that all keys under a field start with an index, we hand serde's
+//! SeqDeserializer one deserializer per element, in index order.
 
 use log::{error, trace};
-use serde::de::{value::MapDeserializer, IntoDeserializer, Visitor};
+use serde::de::{
+    value::{MapDeserializer, SeqDeserializer},
+    IntoDeserializer, Visitor,
+};
 use serde::{forward_to_deserialize_any, Deserialize};
-use snafu::ResultExt;
+use snafu::{ensure, OptionExt, ResultExt};
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 
 use super::{error, Error, Result};
@@ -135,7 +143,29 @@ where
                     .context(error::DeserializeScalarSnafu)
             }
             ValueDeserializer::Compound(compound_deserializer) => {
-                compound_deserializer.deserialize_map(visitor)
+                if compound_deserializer.is_list() {
+                    compound_deserializer.deserialize_seq(visitor)
+                } else {
+                    compound_deserializer.deserialize_map(visitor)
+                }
+            }
+        }
+    }
+
+    /// Lists of scalars are a single scalar value; other lists are stored by index.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            ValueDeserializer::Scalar(mut scalar_deserializer) => {
+                trace!("Handing off to scalar deserializer for deserialize_seq");
+                scalar_deserializer
+                    .deserialize_seq(visitor)
+                    .context(error::DeserializeScalarSnafu)
+            }
+            ValueDeserializer::Compound(compound_deserializer) => {
+                compound_deserializer.deserialize_seq(visitor)
             }
         }
     }
@@ -161,7 +191,7 @@ where
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        bytes byte_buf unit unit_struct newtype_struct tuple
         tuple_struct map struct enum identifier ignored_any
     }
 }
@@ -203,6 +233,17 @@ where
     ) -> CompoundDeserializer<'de, K, S, BH> {
         CompoundDeserializer { map, keys, path }
     }
+
+    /// Returns true if our keys are the elements of a list, meaning they all start with an index.
+    fn is_list(&self) -> bool {
+        !self.keys.is_empty()
+            && self.keys.iter().all(|key| {
+                key.segments()
+                    .first()
+                    .and_then(Key::parse_index_segment)
+                    .is_some()
+            })
+    }
 }
 
 fn bad_root<T>() -> Result<T> {
@@ -331,7 +372,7 @@ where
                         .filter(|new_key| new_key.starts_with_segments(&[&struct_name]))
                         // Remove the prefix - should always work, but log and skip the key otherwise
                         .filter_map(|new_key| new_key
-                                    .strip_prefix(&struct_name)
+                                    .strip_prefix_segments(&[&struct_name])
                                     .map_err(|e| error!("Key starting with segment '{}' couldn't remove it as prefix: {}", &struct_name, e)).ok())
                         .collect();
 
@@ -386,6 +427,84 @@ where
         visitor.visit_some(self)
     }
 
+    /// Lists with compound elements are stored by index.  Our keys start with the index of their
+    /// element, e.g. "[0].c", so we group them by index and deserialize each element like a
+    /// struct field, or as a scalar if the index is the whole key.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let path = match self.path {
+            Some(path) => path,
+            None => return bad_root(),
+        };
+
+        let mut elements: BTreeMap<usize, HashSet<Key>> = BTreeMap::new();
+        for key in &self.keys {
+            let index = key
+                .segments()
+                .first()
+                .and_then(Key::parse_index_segment)
+                .context(error::MissingIndexSnafu {
+                    list: path.name(),
+                    name: key.name(),
+                })?;
+            let element = elements.entry(index).or_default();
+            if key.segments().len() > 1 {
+                element.insert(key.strip_prefix_segments(&key.segments()[..1]).context(
+                    error::StripPrefixSnafu {
+                        prefix: Key::index_segment(index),
+                        name: key.name(),
+                    },
+                )?);
+            }
+        }
+
+        // Lists are always written whole, so a gap means something's missing.
+        for (expected, &index) in elements.keys().enumerate() {
+            ensure!(
+                index == expected,
+                error::MissingElementSnafu {
+                    list: path.name(),
+                    index: expected,
+                }
+            );
+        }
+
+        let mut values = Vec::new();
+        for (index, keys) in elements {
+            let element_path = path.append_segments(&[Key::index_segment(index)]).context(
+                error::InvalidPrefixSnafu {
+                    prefix: path.name(),
+                },
+            )?;
+            trace!("Deserializing list element '{element_path}'");
+            if keys.is_empty() {
+                let val = self
+                    .map
+                    .get(&element_path)
+                    .context(error::MissingElementSnafu {
+                        list: path.name(),
+                        index,
+                    })?;
+                values.push(ValueDeserializer::Scalar(deserializer_for_scalar(
+                    val.as_ref(),
+                )));
+            } else {
+                values.push(ValueDeserializer::Compound(CompoundDeserializer::new(
+                    self.map,
+                    keys,
+                    Some(element_path),
+                )));
+            }
+        }
+
+        let mut seq = SeqDeserializer::new(values.into_iter());
+        let value = visitor.visit_seq(&mut seq)?;
+        seq.end()?;
+        Ok(value)
+    }
+
     /// Scalar types, and compound types we can't use at the root, are forwarded here to be
     /// rejected.  (Compound types need to have a name to serve at the root level.)
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
@@ -403,7 +522,7 @@ where
     // function above that will reject them.
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        bytes byte_buf unit unit_struct newtype_struct tuple
         tuple_struct enum identifier ignored_any
     }
 }
@@ -510,6 +629,56 @@ mod test {
         );
     }
 
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Mirrors {
+        mirrors: Vec<Mirror>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Mirror {
+        registry: String,
+        endpoints: Vec<String>,
+        nested: Option<Vec<C>>,
+    }
+
+    #[test]
+    fn list_of_structs_works() {
+        let m: Mirrors = from_map(&hashmap! {
+            key!("mirrors.mirrors[1].registry") => "\"b\"".to_string(),
+            key!("mirrors.mirrors[1].endpoints") => "[]".to_string(),
+            key!("mirrors.mirrors[0].registry") => "\"a\"".to_string(),
+            key!("mirrors.mirrors[0].endpoints") => "[\"x\"]".to_string(),
+            key!("mirrors.mirrors[0].nested[0].boolean") => "true".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            m,
+            Mirrors {
+                mirrors: vec![
+                    Mirror {
+                        registry: "a".to_string(),
+                        endpoints: vec!["x".to_string()],
+                        nested: Some(vec![C { boolean: true }]),
+                    },
+                    Mirror {
+                        registry: "b".to_string(),
+                        endpoints: vec![],
+                        nested: None,
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn list_with_gap_fails() {
+        let m: Result<Mirrors, Error> = from_map(&hashmap! {
+            key!("mirrors.mirrors[1].registry") => "\"b\"".to_string(),
+            key!("mirrors.mirrors[1].endpoints") => "[]".to_string(),
+        });
+        m.unwrap_err();
+    }
+
     #[derive(Debug, Deserialize, PartialEq)]
     struct Bad {
         id: u64,
@@ -0,0 +1,47 @@
+//! Describes what committing a pending transaction would change in live data.
+//!
+//! See `DataStore::diff_transaction`.  Keys are given by name so the diff can be serialized,
+//! e.g. to JSON for display, and maps are sorted so the output is stable.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The changes a pending transaction would make to live data and metadata.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionDiff {
+    /// Data keys that aren't in live, with their pending values.
+    pub added: BTreeMap<String, String>,
+    /// Data keys whose pending values differ from live.
+    pub modified: BTreeMap<String, ValueChange>,
+    /// Data keys that were unset in the transaction and are in live, with their live values.
+    pub removed: BTreeMap<String, String>,
+    /// Metadata whose pending values differ from live, or that's unset in the transaction.
+    pub metadata: Vec<MetadataChange>,
+}
+
+impl TransactionDiff {
+    /// Returns true if committing the transaction wouldn't change anything.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.removed.is_empty()
+            && self.metadata.is_empty()
+    }
+}
+
+/// The live and pending values of a key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueChange {
+    pub old: String,
+    pub new: String,
+}
+
+/// A metadata value changed by a transaction.  `old` is None if the metadata isn't in live, and
+/// `new` is None if the transaction unsets it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct MetadataChange {
+    pub data_key: String,
+    pub metadata_key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
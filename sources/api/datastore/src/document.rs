@@ -0,0 +1,568 @@
+//! Exports a whole data store -- live data and metadata, and each pending transaction -- as one
+//! TOML or JSON document, and imports such a document back into a data store.
+//!
+//! The document uses the same layout as the default settings in `shared-defaults`: data keys
+//! form a nested tree, and metadata goes under a top-level "metadata" table keyed by data key,
+//! for example:
+//!
+//! ```toml
+//! [settings.ntp]
+//! time-servers = ["169.254.169.123"]
+//!
+//! [metadata.settings.ntp]
+//! affected-services = ["chronyd"]
+//!
+//! [transactions.apiserver]
+//! deleted-keys = ["settings.ntp.time-servers"]
+//! [transactions.apiserver.settings]
+//! motd = "hello"
+//! [transactions.apiserver.deleted-metadata]
+//! "settings.ntp" = ["affected-services"]
+//! ```
+//!
+//! Each pending transaction is a table under "transactions" with the same layout, plus a
+//! "deleted-keys" list of the keys unset in the transaction and a "deleted-metadata" table
+//! listing the metadata unset for each data key.  The names "metadata" and "transactions" are
+//! therefore reserved at the top level of the document, and "metadata", "deleted-keys", and
+//! "deleted-metadata" at the top level of a transaction.
+//!
+//! Under "metadata", a table is read as the next segment of a data key, and anything else as a
+//! metadata value.  The exception is metadata listed in TABLE_METADATA_KEYS, like
+//! "setting-generator", whose values can themselves be tables.
+//!
+//! Lists stored by index (see the `serialization` module) are exported as arrays, so a list of
+//! structures becomes an array of tables, and arrays containing tables are imported by index.
+//!
+//! Importing validates every key before writing anything, then sets each key and metadata value
+//! in the document.  Keys that aren't in the document are left alone, other than the old elements
+//! of lists in the document, and importing the same document again makes no further changes, so
+//! a known-good document can be applied to a data store that already has default settings.
+
+use serde_json::{Map, Value};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+
+use crate::sensitive::SENSITIVE_METADATA_KEY;
+use crate::{
+    deserialize_scalar, error, revision, serialize_scalar, Committed, DataStore, Key, KeyType,
+    Result, ScalarError,
+};
+
+/// Name of the table holding metadata, at the top level of the document or a transaction.
+const METADATA_TABLE: &str = "metadata";
+/// Name of the table holding pending transactions, at the top level of the document.
+const TRANSACTIONS_TABLE: &str = "transactions";
+/// Name of the list of keys unset in a transaction.
+const DELETED_KEYS_LIST: &str = "deleted-keys";
+/// Name of the table of metadata unset in a transaction, keyed by data key name.
+const DELETED_METADATA_TABLE: &str = "deleted-metadata";
+
+/// Metadata keys whose values may be tables, so they aren't mistaken for data key segments.
+pub const TABLE_METADATA_KEYS: &[&str] = &["setting-generator"];
+
+/// The formats a data store can be exported to and imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentFormat {
+    Toml,
+    Json,
+}
+
+/// Returns a document containing all data and metadata in the data store, live and pending.
+pub fn export<D: DataStore>(datastore: &D, format: DocumentFormat) -> Result<String> {
+    let mut root = export_committed(datastore, &Committed::Live)?;
+
+    let mut transactions = Map::new();
+    for tx in datastore.list_transactions()? {
+        let pending = Committed::Pending { tx: tx.clone() };
+        let mut table = export_committed(datastore, &pending)?;
+
+        let mut deleted: Vec<_> = datastore
+            .list_deleted_keys("", &pending)?
+            .into_iter()
+            .map(|key| key.name().clone())
+            .collect();
+        if !deleted.is_empty() {
+            deleted.sort_unstable();
+            table.insert(DELETED_KEYS_LIST.to_string(), deleted.into());
+        }
+
+        let mut deleted_metadata = Map::new();
+        for (data_key, metadata_keys) in datastore.list_deleted_metadata("", &pending)? {
+            let mut names: Vec<_> = metadata_keys
+                .into_iter()
+                .map(|key| key.name().clone())
+                .collect();
+            names.sort_unstable();
+            deleted_metadata.insert(data_key.name().clone(), names.into());
+        }
+        if !deleted_metadata.is_empty() {
+            table.insert(
+                DELETED_METADATA_TABLE.to_string(),
+                Value::Object(deleted_metadata),
+            );
+        }
+
+        transactions.insert(tx, Value::Object(table));
+    }
+    if !transactions.is_empty() {
+        root.insert(TRANSACTIONS_TABLE.to_string(), Value::Object(transactions));
+    }
+
+    let root = Value::Object(root);
+    match format {
+        DocumentFormat::Toml => toml::to_string(&root).context(error::ExportTomlSnafu),
+        DocumentFormat::Json => serde_json::to_string_pretty(&root).context(error::SerializeSnafu),
+    }
+}
+
+/// Writes all data and metadata in the given document into the data store.  See the module
+/// documentation for the layout and semantics.
+pub fn import<D: DataStore>(
+    datastore: &mut D,
+    document: &str,
+    format: DocumentFormat,
+) -> Result<()> {
+    let root: Value = match format {
+        DocumentFormat::Toml => toml::from_str(document).context(error::ImportTomlSnafu)?,
+        DocumentFormat::Json => serde_json::from_str(document).context(error::ImportJsonSnafu)?,
+    };
+    let mut root = into_table(root, "document")?;
+
+    // Check everything before writing anything, so a bad document doesn't leave a partial import.
+    let mut writes = vec![(Committed::Live, Writes::default())];
+    if let Some(transactions) = root.remove(TRANSACTIONS_TABLE) {
+        for (tx, table) in into_table(transactions, TRANSACTIONS_TABLE)? {
+            let mut table = into_table(table, &tx)?;
+            let mut tx_writes = Writes::default();
+            if let Some(deleted) = table.remove(DELETED_KEYS_LIST) {
+                tx_writes.deleted = parse_key_names(deleted, KeyType::Data, DELETED_KEYS_LIST)?;
+            }
+            if let Some(deleted) = table.remove(DELETED_METADATA_TABLE) {
+                for (data_key_name, metadata_names) in into_table(deleted, DELETED_METADATA_TABLE)?
+                {
+                    let data_key = Key::new(KeyType::Data, &data_key_name)?;
+                    let metadata_keys: Vec<Key> =
+                        parse_key_names(metadata_names, KeyType::Meta, DELETED_METADATA_TABLE)?;
+                    for metadata_key in metadata_keys {
+                        tx_writes
+                            .deleted_metadata
+                            .push((metadata_key, data_key.clone()));
+                    }
+                }
+            }
+            tx_writes.parse(table)?;
+            writes.push((Committed::Pending { tx }, tx_writes));
+        }
+    }
+    writes[0].1.parse(root)?;
+
+    for (committed, writes) in writes {
+        writes.apply(datastore, &committed)?;
+    }
+    Ok(())
+}
+
+/// Copies all data and metadata in one data store, live and pending, into another, for example
+/// to switch a node to a different data store implementation.  Snapshots aren't copied.
+/// Revisions aren't part of documents, so they're carried over separately; see `revision::carry`.
+pub fn copy<S: DataStore, D: DataStore>(source: &S, destination: &mut D) -> Result<()> {
+    let document = export(source, DocumentFormat::Json)?;
+    import(destination, &document, DocumentFormat::Json)?;
+    for key in source.list_populated_keys("", &Committed::Live)? {
+        revision::carry(source, destination, &key)?;
+    }
+    Ok(())
+}
+
+/// Builds the document table for live data or one pending transaction.
+fn export_committed<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+) -> Result<Map<String, Value>> {
+    let mut root = Map::new();
+    for (key, value) in datastore.get_prefix("", committed)? {
+        let value = parse_scalar(&value)?;
+        insert_value(&mut root, key.segments(), value)?;
+    }
+    for value in root.values_mut() {
+        lists_to_arrays(value);
+    }
+
+    let mut metadata = Map::new();
+    let all_metadata = datastore.get_metadata_prefix("", committed, &None as &Option<&str>)?;
+    for (data_key, metadata_values) in all_metadata {
+        for (metadata_key, value) in metadata_values {
+            let mut path = data_key.segments().clone();
+            path.push(metadata_key.name().clone());
+            insert_value(&mut metadata, &path, parse_scalar(&value)?)?;
+        }
+    }
+    if !metadata.is_empty() {
+        root.insert(METADATA_TABLE.to_string(), Value::Object(metadata));
+    }
+
+    Ok(root)
+}
+
+/// Inserts a value into a nested table at the given path, creating tables along the way.
+fn insert_value(table: &mut Map<String, Value>, path: &[String], value: Value) -> Result<()> {
+    let (last, parents) = path.split_last().context(error::InternalSnafu {
+        msg: "empty key path in document",
+    })?;
+
+    let mut table = table;
+    for segment in parents {
+        let entry = table
+            .entry(segment.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        table = entry
+            .as_object_mut()
+            .with_context(|| error::InvalidDocumentSnafu {
+                msg: format!("'{}' has both a value and keys under it", path.join(".")),
+            })?;
+    }
+    ensure!(
+        !table.contains_key(last),
+        error::InvalidDocumentSnafu {
+            msg: format!("'{}' has both a value and keys under it", path.join(".")),
+        }
+    );
+    table.insert(last.clone(), value);
+    Ok(())
+}
+
+/// Replaces tables whose keys are all list indexes, from lists stored by index, with arrays.
+fn lists_to_arrays(value: &mut Value) {
+    let table = match value {
+        Value::Object(table) => table,
+        _ => return,
+    };
+    for child in table.values_mut() {
+        lists_to_arrays(child);
+    }
+
+    let mut indexes: Vec<_> = table
+        .keys()
+        .map(Key::parse_index_segment)
+        .collect::<Option<_>>()
+        .unwrap_or_default();
+    indexes.sort_unstable();
+    let is_list = !indexes.is_empty() && indexes.iter().enumerate().all(|(i, &index)| i == index);
+    if is_list {
+        let items = indexes
+            .into_iter()
+            .filter_map(|index| table.remove(&Key::index_segment(index)))
+            .collect();
+        *value = Value::Array(items);
+    }
+}
+
+/// Everything to write for live data or one pending transaction.
+#[derive(Default)]
+struct Writes {
+    data: HashMap<Key, String>,
+    metadata: Vec<(Key, Key, String)>,
+    deleted: HashSet<Key>,
+    deleted_metadata: Vec<(Key, Key)>,
+}
+
+impl Writes {
+    /// Collects the data and metadata from a document table, validating each key.
+    fn parse(&mut self, mut table: Map<String, Value>) -> Result<()> {
+        if let Some(metadata) = table.remove(METADATA_TABLE) {
+            let metadata = into_table(metadata, METADATA_TABLE)?;
+            self.parse_metadata(metadata, &mut Vec::new())?;
+        }
+        self.parse_data(table, &mut Vec::new())
+    }
+
+    fn parse_data(&mut self, table: Map<String, Value>, path: &mut Vec<String>) -> Result<()> {
+        for (segment, value) in table {
+            path.push(segment);
+            self.parse_data_value(value, path)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    fn parse_data_value(&mut self, value: Value, path: &mut Vec<String>) -> Result<()> {
+        match value {
+            Value::Object(table) => self.parse_data(table, path),
+            // Arrays containing tables are stored by index, like the serialization module does.
+            Value::Array(items) if items.iter().any(Value::is_object) => {
+                for (index, item) in items.into_iter().enumerate() {
+                    path.push(Key::index_segment(index));
+                    match item {
+                        // An element without fields is stored as an empty object under its
+                        // index, so the list doesn't have a gap.
+                        Value::Object(table) if table.is_empty() => {
+                            let key = Key::from_segments(KeyType::Data, path)?;
+                            self.data.insert(key, "{}".to_string());
+                        }
+                        item => self.parse_data_value(item, path)?,
+                    }
+                    path.pop();
+                }
+                Ok(())
+            }
+            value => {
+                let key = Key::from_segments(KeyType::Data, path)?;
+                self.data.insert(key, serialize_value(&value)?);
+                Ok(())
+            }
+        }
+    }
+
+    fn parse_metadata(&mut self, table: Map<String, Value>, path: &mut Vec<String>) -> Result<()> {
+        for (name, value) in table {
+            match value {
+                Value::Object(table) if !TABLE_METADATA_KEYS.contains(&name.as_str()) => {
+                    path.push(name);
+                    self.parse_metadata(table, path)?;
+                    path.pop();
+                }
+                value => {
+                    ensure!(
+                        !path.is_empty(),
+                        error::InvalidDocumentSnafu {
+                            msg: format!("metadata '{name}' isn't under a data key"),
+                        }
+                    );
+                    let data_key = Key::from_segments(KeyType::Data, path)?;
+                    let metadata_key = Key::new(KeyType::Meta, &name)?;
+                    self.metadata
+                        .push((metadata_key, data_key, serialize_value(&value)?));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply<D: DataStore>(self, datastore: &mut D, committed: &Committed) -> Result<()> {
+        // Sensitive flags go first, so the values they cover are encrypted as they're written.
+        let (flags, metadata): (Vec<_>, Vec<_>) = self
+            .metadata
+            .into_iter()
+            .partition(|(metadata_key, _, _)| metadata_key.name() == SENSITIVE_METADATA_KEY);
+        for (metadata_key, data_key, value) in flags {
+            datastore.set_metadata(&metadata_key, &data_key, value, committed)?;
+        }
+        datastore.set_keys(&self.data, committed)?;
+        for (metadata_key, data_key, value) in metadata {
+            datastore.set_metadata(&metadata_key, &data_key, value, committed)?;
+        }
+        for key in self.deleted {
+            datastore.unset_key(&key, committed)?;
+        }
+        for (metadata_key, data_key) in self.deleted_metadata {
+            datastore.unset_metadata(&metadata_key, &data_key, committed)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a list of key names of the given type; `name` describes the list for errors.
+fn parse_key_names<C>(value: Value, key_type: KeyType, name: &str) -> Result<C>
+where
+    C: FromIterator<Key>,
+{
+    let names = match value {
+        Value::Array(names) => names,
+        _ => {
+            return error::InvalidDocumentSnafu {
+                msg: format!("'{name}' must list key names"),
+            }
+            .fail()
+        }
+    };
+    names
+        .into_iter()
+        .map(|key_name| {
+            let key_name = key_name
+                .as_str()
+                .with_context(|| error::InvalidDocumentSnafu {
+                    msg: format!("'{name}' must list key names"),
+                })?;
+            Key::new(key_type, key_name)
+        })
+        .collect()
+}
+
+fn into_table(value: Value, name: &str) -> Result<Map<String, Value>> {
+    match value {
+        Value::Object(table) => Ok(table),
+        _ => error::InvalidDocumentSnafu {
+            msg: format!("'{name}' must be a table"),
+        }
+        .fail(),
+    }
+}
+
+fn parse_scalar(value: &str) -> Result<Value> {
+    deserialize_scalar::<_, ScalarError>(value)
+        .context(error::DeserializeScalarSnafu { given: value })
+}
+
+fn serialize_value(value: &Value) -> Result<String> {
+    serialize_scalar::<_, ScalarError>(value).context(error::SerializeScalarSnafu {
+        given: value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::test_util::TestDir;
+    use crate::{EncryptionKey, FilesystemDataStore};
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    fn meta(name: &str) -> Key {
+        Key::new(KeyType::Meta, name).unwrap()
+    }
+
+    #[test]
+    fn import_shared_defaults_layout() {
+        let document = r#"
+            [settings.ntp]
+            time-servers = ["a", "b"]
+
+            [metadata.settings.ntp]
+            affected-services = ["chronyd"]
+
+            [metadata.settings.motd.setting-generator]
+            command = "generate-motd"
+
+            [transactions.tx.settings]
+            motd = "hello"
+            [transactions.tx]
+            deleted-keys = ["settings.ntp.time-servers"]
+            [transactions.tx.deleted-metadata]
+            "settings.ntp" = ["affected-services"]
+        "#;
+        let mut m = MemoryDataStore::new();
+        import(&mut m, document, DocumentFormat::Toml).unwrap();
+
+        assert_eq!(
+            m.get_key(&key("settings.ntp.time-servers"), &Committed::Live)
+                .unwrap(),
+            Some(r#"["a","b"]"#.to_string())
+        );
+        assert_eq!(
+            m.get_metadata_raw(
+                &meta("affected-services"),
+                &key("settings.ntp"),
+                &Committed::Live
+            )
+            .unwrap(),
+            Some(r#"["chronyd"]"#.to_string())
+        );
+        assert_eq!(
+            m.get_metadata_raw(
+                &meta("setting-generator"),
+                &key("settings.motd"),
+                &Committed::Live
+            )
+            .unwrap(),
+            Some(r#"{"command":"generate-motd"}"#.to_string())
+        );
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        assert_eq!(
+            m.get_key(&key("settings.motd"), &pending).unwrap(),
+            Some(r#""hello""#.to_string())
+        );
+        assert_eq!(
+            m.list_deleted_keys("", &pending).unwrap(),
+            HashSet::from([key("settings.ntp.time-servers")])
+        );
+        assert_eq!(
+            m.list_deleted_metadata("", &pending).unwrap(),
+            HashMap::from([(
+                key("settings.ntp"),
+                HashSet::from([meta("affected-services")])
+            )])
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut m = MemoryDataStore::new();
+        m.set_key(&key("settings.a.b"), "42", &Committed::Live)
+            .unwrap();
+        m.set_key(&key("settings.\"a.c\""), "\"x\"", &Committed::Live)
+            .unwrap();
+        m.set_key(&key("settings.l[0].x"), "1", &Committed::Live)
+            .unwrap();
+        m.set_key(&key("settings.l[1].x"), "2", &Committed::Live)
+            .unwrap();
+        m.set_key(&key("settings.l[2]"), "{}", &Committed::Live)
+            .unwrap();
+        m.set_metadata(
+            &meta("affected-services"),
+            &key("settings.a"),
+            "[\"s\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        m.set_key(&key("settings.a.b"), "43", &pending).unwrap();
+
+        // Lists stored by index are exported as arrays.
+        let json = export(&m, DocumentFormat::Json).unwrap();
+        assert!(json.contains(r#""l": ["#), "{json}");
+
+        for format in [DocumentFormat::Toml, DocumentFormat::Json] {
+            let document = export(&m, format).unwrap();
+            let mut copy = MemoryDataStore::new();
+            import(&mut copy, &document, format).unwrap();
+            // Importing again doesn't change anything.
+            import(&mut copy, &document, format).unwrap();
+            assert_eq!(export(&copy, format).unwrap(), document);
+        }
+    }
+
+    #[test]
+    fn import_validates_keys_first() {
+        let document = r#"{"settings": {"a": 1, "b!": 2}}"#;
+        let mut m = MemoryDataStore::new();
+        import(&mut m, document, DocumentFormat::Json).unwrap_err();
+        assert!(m.get_prefix("", &Committed::Live).unwrap().is_empty());
+    }
+
+    #[test]
+    fn copy_encrypts_sensitive_values() {
+        let mut m = MemoryDataStore::new();
+        m.set_metadata(
+            &meta(SENSITIVE_METADATA_KEY),
+            &key("settings.aws"),
+            "true",
+            &Committed::Live,
+        )
+        .unwrap();
+        m.set_key(
+            &key("settings.aws.credentials"),
+            "\"secret\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        let dir = TestDir::new("document-copy");
+        let encryption_key = EncryptionKey::load_or_create(dir.0.join("datastore.key")).unwrap();
+        let mut f = FilesystemDataStore::create(dir.0.join("datastore"))
+            .unwrap()
+            .with_encryption_key(encryption_key);
+        copy(&m, &mut f).unwrap();
+
+        let path = dir.0.join("datastore/live/settings/aws/credentials");
+        assert!(!std::fs::read_to_string(path).unwrap().contains("secret"));
+        assert_eq!(
+            f.get_key(&key("settings.aws.credentials"), &Committed::Live)
+                .unwrap(),
+            Some("\"secret\"".to_string())
+        );
+    }
+}
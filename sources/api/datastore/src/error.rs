@@ -2,7 +2,8 @@ use snafu::Snafu;
 use std::io;
 use std::path::PathBuf;
 
-use super::{serialization, ScalarError};
+use super::constraints_check::Rejection;
+use super::{serialization, Revision, ScalarError, SnapshotId};
 
 /// Possible errors from datastore operations.
 #[derive(Debug, Snafu)]
@@ -17,6 +18,9 @@ pub enum Error {
     #[snafu(display("Error serializing scalar {}: {} ", given, source))]
     SerializeScalar { given: String, source: ScalarError },
 
+    #[snafu(display("Error deserializing scalar {}: {} ", given, source))]
+    DeserializeScalar { given: String, source: ScalarError },
+
     #[snafu(display("Key would traverse outside data store: {}", name))]
     PathTraversal { name: String },
 
@@ -38,6 +42,55 @@ pub enum Error {
     #[snafu(display("Data store integrity violation at {}: {}", path.display(), msg))]
     Corruption { msg: String, path: PathBuf },
 
+    #[snafu(display("Unable to read commit journal at '{}': {}", path.display(), source))]
+    Journal {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Commit journal already exists at '{}'", path.display()))]
+    JournalExists { path: PathBuf },
+
+    #[snafu(display("Unable to read log '{}' at line {}: {}", path.display(), line, source))]
+    Log {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to lock data store at '{}': {}", path.display(), source))]
+    Lock { path: PathBuf, source: io::Error },
+
+    #[snafu(display(
+        "Key '{}' was changed concurrently: expected revision {}, found {}",
+        key,
+        expected,
+        actual
+    ))]
+    Conflict {
+        key: String,
+        expected: Revision,
+        actual: Revision,
+    },
+
+    #[snafu(display("Invalid revision '{}' for key '{}'", value, key))]
+    InvalidRevision { key: String, value: String },
+
+    #[snafu(display("Snapshot {} not found", id))]
+    SnapshotNotFound { id: SnapshotId },
+
+    #[snafu(display("Unable to watch for changes in '{}': {}", path.display(), source))]
+    Watch { path: PathBuf, source: io::Error },
+
+    #[snafu(display("Unable to read change record at '{}': {}", path.display(), source))]
+    ChangeRecord {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Subscription closed because its data store was dropped"))]
+    SubscriptionClosed,
+
     #[snafu(display("Error building data store path: {}", source))]
     Path { source: std::path::StripPrefixError },
 
@@ -57,12 +110,27 @@ pub enum Error {
     #[snafu(display("Key name '{}' has invalid format: {}", name, msg))]
     InvalidKey { name: String, msg: String },
 
+    #[snafu(display("Key pattern '{}' is invalid: {}", pattern, msg))]
+    InvalidKeyPattern { pattern: String, msg: String },
+
     #[snafu(display("Key name beyond maximum length {}: {}", name, max))]
     KeyTooLong { name: String, max: usize },
 
     #[snafu(display("Unable to serialize data: {}", source))]
     Serialize { source: serde_json::Error },
 
+    #[snafu(display("Unable to serialize data as TOML: {}", source))]
+    ExportToml { source: toml::ser::Error },
+
+    #[snafu(display("Unable to parse TOML document: {}", source))]
+    ImportToml { source: toml::de::Error },
+
+    #[snafu(display("Unable to parse JSON document: {}", source))]
+    ImportJson { source: serde_json::Error },
+
+    #[snafu(display("Invalid data store document: {}", msg))]
+    InvalidDocument { msg: String },
+
     #[snafu(display("Unable to run the check constraint function: {}", source))]
     CheckConstraintExecution {
         source: Box<dyn std::error::Error + Send + Sync>,
@@ -70,9 +138,31 @@ pub enum Error {
 
     #[snafu(display(
         "Check constraint function rejected the transaction. Aborting commit : {}",
-        err
+        rejections.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")
     ))]
-    ConstraintCheckReject { err: String },
+    ConstraintCheckReject { rejections: Vec<Rejection> },
+
+    #[snafu(display("Invalid encryption key in '{}': {}", path.display(), msg))]
+    InvalidEncryptionKey { path: PathBuf, msg: String },
+
+    #[snafu(display("Unable to generate encryption key for '{}'", path.display()))]
+    GenerateEncryptionKey { path: PathBuf },
+
+    #[snafu(display("Unable to encrypt value of sensitive key '{}'", key))]
+    Encryption { key: String },
+
+    #[snafu(display(
+        "Unable to decrypt value of key '{}'; was it encrypted with another key?",
+        key
+    ))]
+    Decryption { key: String },
+
+    #[snafu(display("Key '{}' is sensitive, but the data store has no encryption key", key))]
+    MissingEncryptionKey { key: String },
+
+    #[cfg(feature = "async")]
+    #[snafu(display("Data store task failed: {}", source))]
+    BlockingTask { source: tokio::task::JoinError },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -0,0 +1,75 @@
+//! Data stores record when each pending transaction was created and last changed, so that
+//! transactions abandoned by clients that died before committing them can be found and removed.
+//!
+//! `DataStore::expire_transactions` deletes the transactions that haven't been changed for
+//! longer than a given duration.  The boot-time transaction, `LAUNCH_TRANSACTION`, is never
+//! expired, because it's filled in by services over the course of boot and committed at the end.
+
+use constants::LAUNCH_TRANSACTION;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+/// When a pending transaction was created and last changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionTimes {
+    pub created: SystemTime,
+    pub touched: SystemTime,
+}
+
+impl TransactionTimes {
+    /// Times for a transaction created at the given time.
+    pub(crate) fn new(time: SystemTime) -> Self {
+        Self {
+            created: time,
+            touched: time,
+        }
+    }
+
+    /// How long ago the transaction was created.
+    pub fn age(&self) -> Duration {
+        elapsed(self.created)
+    }
+
+    /// How long ago the transaction was last changed.
+    pub fn idle(&self) -> Duration {
+        elapsed(self.touched)
+    }
+}
+
+/// Returns the time since the given time, or zero if it's in the future because the clock
+/// changed.
+fn elapsed(time: SystemTime) -> Duration {
+    time.elapsed().unwrap_or_default()
+}
+
+/// Returns whether a transaction with the given name and times should be expired, because it
+/// hasn't been changed for longer than `older_than`.
+pub(crate) fn expired(transaction: &str, times: &TransactionTimes, older_than: Duration) -> bool {
+    transaction != LAUNCH_TRANSACTION && times.idle() > older_than
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expires_idle_transactions() {
+        let hour = Duration::from_secs(60 * 60);
+        let old = SystemTime::now() - 2 * hour;
+        let times = TransactionTimes {
+            created: old,
+            touched: old,
+        };
+        assert!(expired("tx", &times, hour));
+        assert!(!expired("tx", &times, 3 * hour));
+        assert!(!expired(LAUNCH_TRANSACTION, &times, hour));
+
+        // Recent changes keep an old transaction alive.
+        let times = TransactionTimes {
+            touched: SystemTime::now(),
+            ..times
+        };
+        assert!(!expired("tx", &times, hour));
+        assert!(times.age() > hour);
+    }
+}
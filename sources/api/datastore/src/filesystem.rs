@@ -3,42 +3,487 @@
 //!
 //! Data is kept in files with paths resembling the keys, e.g. a/b/c for a.b.c, and metadata is
 //! kept in a suffixed file next to the data, e.g. a/b/c.meta for metadata "meta" about a.b.c
+//!
+//! Keys and metadata unset in a pending transaction are recorded as empty "tombstone" files under
+//! a ".tombstones" directory in the transaction, e.g. .tombstones/a/b/c for a.b.c, so the commit
+//! can remove them from live.  Encoded key segments never start with a dot, so the directory
+//! can't collide with a key.
+//!
+//! Commits are made crash-safe with a commit journal.  Before touching live data, we write
+//! everything the commit will change into a journal file and atomically rename it into place.
+//! If we're interrupted while applying it, the next `FilesystemDataStore::new` replays the
+//! journal, so live data reflects either all of a transaction or none of it.
+//!
+//! Snapshots are full copies of the live directory kept under "snapshots/<id>/live", with a
+//! "snapshot.json" file describing them.  Restoring one copies it next to live and swaps it in
+//! with renames, which are likewise finished or discarded by `FilesystemDataStore::new`.
+//!
+//! Each commit is also recorded under "changes/<id>" for subscriptions, which watch that
+//! directory with inotify; see the `watch` module.
 
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{self, Path, PathBuf};
+use std::time::SystemTime;
 use walkdir::{DirEntry, WalkDir};
 
 use crate::constraints_check::{ApprovedWrite, ConstraintCheckResult};
+use crate::expiry::TransactionTimes;
+use crate::revision;
+use crate::sensitive::{self, EncryptionKey};
+use crate::snapshot::{expired_snapshots, Snapshot, SnapshotId, DEFAULT_SNAPSHOT_RETENTION};
+use crate::watch::{self, Subscription};
 
 use super::key::{Key, KeyType};
 use super::{error, Committed, DataStore, Result};
 
-const METADATA_KEY_PREFIX: &str = ".";
+pub(crate) const METADATA_KEY_PREFIX: &str = ".";
+
+/// Names of the directories holding live data and pending transactions, inside the base path.
+pub(crate) const LIVE_DIR_NAME: &str = "live";
+pub(crate) const PENDING_DIR_NAME: &str = "pending";
+
+/// Name of the directory inside a pending transaction that holds tombstones for unset keys.
+pub(crate) const TOMBSTONES_DIR_NAME: &str = ".tombstones";
+/// Name of the file inside a pending transaction that records when it was created and last
+/// changed; see the `expiry` module.
+pub(crate) const TRANSACTION_TIMES_FILE_NAME: &str = ".transaction-times";
+
+/// Name of the commit journal file, which lives in the base path next to "live" and "pending".
+const JOURNAL_FILE_NAME: &str = "commit-journal";
+/// Name of the journal file while it's being written; it's renamed to JOURNAL_FILE_NAME once
+/// it's complete and flushed to disk.
+const JOURNAL_TMP_FILE_NAME: &str = "commit-journal.tmp";
+
+/// Name of the directory holding snapshots, next to "live" and "pending".
+const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+/// Name of the file inside each snapshot directory that describes the snapshot.
+const SNAPSHOT_INFO_FILE_NAME: &str = "snapshot.json";
+/// Suffix for snapshot directories that are still being written.
+const SNAPSHOT_TMP_SUFFIX: &str = ".tmp";
+/// Name of the directory holding records of recent commits for subscriptions; see the `watch`
+/// module.
+const CHANGES_DIR_NAME: &str = "changes";
+/// Name of the directory a snapshot is copied into before it's swapped in as live.
+const RESTORE_DIR_NAME: &str = "live.restore";
+/// Name the old live directory is given while a snapshot is being swapped in.
+const OLD_LIVE_DIR_NAME: &str = "live.old";
 
 // This describes the set of characters we encode when making the filesystem path for a given key.
 // Any non-ASCII characters, plus these ones, will be encoded.
 // We start off very strict (anything not alphanumeric) and remove characters we'll allow.
 // To make inspecting the filesystem easier, we allow any filesystem-safe characters that are
-// allowed in a Key.
-const ENCODE_CHARACTERS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'_').remove(b'-');
+// allowed in a Key, including the brackets of list indexes, so a.b[0].c is stored at a/b/[0]/c.
+const ENCODE_CHARACTERS: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'_')
+    .remove(b'-')
+    .remove(b'[')
+    .remove(b']');
 
 #[derive(Debug)]
 pub struct FilesystemDataStore {
     live_path: PathBuf,
     pending_base_path: PathBuf,
+    journal_path: PathBuf,
+    journal_tmp_path: PathBuf,
+    snapshots_path: PathBuf,
+    changes_path: PathBuf,
+    restore_path: PathBuf,
+    old_live_path: PathBuf,
+    snapshot_retention: usize,
+    encryption_key: Option<EncryptionKey>,
 }
 
 impl FilesystemDataStore {
-    pub fn new<P: AsRef<Path>>(base_path: P) -> FilesystemDataStore {
+    /// Opens the data store at the given base path.  If a previous commit was interrupted, it's
+    /// either finished or discarded before we return; see `recover`.
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Result<FilesystemDataStore> {
+        let mut datastore = Self::open_without_recovery(base_path);
+        datastore.recover()?;
+        Ok(datastore)
+    }
+
+    /// Opens the data store at the given base path without finishing or discarding an
+    /// interrupted commit, for callers that only read it and mustn't change anything on disk,
+    /// like a migration dry run.  Reads see live data as it's left on disk, so after an
+    /// interrupted commit they may see part of it.  Writes still finish an interrupted commit
+    /// first, as they always do.
+    pub fn open_without_recovery<P: AsRef<Path>>(base_path: P) -> FilesystemDataStore {
+        let base_path = base_path.as_ref();
         FilesystemDataStore {
-            live_path: base_path.as_ref().join("live"),
-            pending_base_path: base_path.as_ref().join("pending"),
+            live_path: base_path.join(LIVE_DIR_NAME),
+            pending_base_path: base_path.join(PENDING_DIR_NAME),
+            journal_path: base_path.join(JOURNAL_FILE_NAME),
+            journal_tmp_path: base_path.join(JOURNAL_TMP_FILE_NAME),
+            snapshots_path: base_path.join(SNAPSHOTS_DIR_NAME),
+            changes_path: base_path.join(CHANGES_DIR_NAME),
+            restore_path: base_path.join(RESTORE_DIR_NAME),
+            old_live_path: base_path.join(OLD_LIVE_DIR_NAME),
+            snapshot_retention: DEFAULT_SNAPSHOT_RETENTION,
+            encryption_key: None,
+        }
+    }
+
+    /// Creates an empty data store at the given base path, and opens it.  Opens the existing
+    /// data store if there's already one there.
+    pub fn create<P: AsRef<Path>>(base_path: P) -> Result<FilesystemDataStore> {
+        let live_path = base_path.as_ref().join(LIVE_DIR_NAME);
+        fs::create_dir_all(&live_path).context(error::IoSnafu { path: &live_path })?;
+        sync_parent_dir(&live_path)?;
+        Self::new(base_path)
+    }
+
+    /// Sets the number of snapshots to keep, and takes one before each commit.  If set to 0, the
+    /// default, no snapshots are taken on commit, and only the most recent snapshot requested
+    /// directly is kept.
+    pub fn with_snapshot_retention(mut self, count: usize) -> Self {
+        self.snapshot_retention = count;
+        self
+    }
+
+    /// Sets the key used to encrypt the values of sensitive keys; see the `sensitive` module.
+    pub fn with_encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Returns the value to write to disk for the given data key: encrypted, if it's sensitive.
+    fn stored_value(&self, key: &Key, value: &str, committed: &Committed) -> Result<String> {
+        if !sensitive::is_sensitive(self, key, committed)? {
+            return Ok(value.to_string());
+        }
+        self.encryption_key
+            .as_ref()
+            .context(error::MissingEncryptionKeySnafu { key: key.name() })?
+            .encrypt(key, value)
+    }
+
+    /// Finishes or discards a commit that was interrupted, e.g. by a power loss.
+    ///
+    /// The journal is only renamed into place after it's fully written and flushed, and live
+    /// data isn't touched before that.  A leftover temporary journal therefore means the commit
+    /// never started changing live data, so we discard it and the transaction stays pending.  A
+    /// complete journal means live data may be partly updated, so we replay it; every write in
+    /// the journal is idempotent.
+    ///
+    /// Interrupted snapshot restores are handled similarly; see `restore_snapshot`.
+    fn recover(&mut self) -> Result<()> {
+        self.recover_snapshots()?;
+
+        if let Err(e) = fs::remove_file(&self.journal_tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e).context(error::IoSnafu {
+                    path: &self.journal_tmp_path,
+                });
+            }
+        } else {
+            warn!(
+                "Discarded incomplete commit journal at {}",
+                self.journal_tmp_path.display()
+            );
+        }
+
+        self.finish_interrupted_commit()
+    }
+
+    /// Replays a complete journal left behind by an interrupted commit, if there is one.  Besides
+    /// `recover`, every write calls this first: another user of the data store may have been
+    /// interrupted since we opened it, and writing on top of its half-applied commit, or
+    /// starting a commit of our own, would lose it.  With LockedDataStore, the caller holds the
+    /// exclusive lock, so the journal can't be a commit that's still running.
+    fn finish_interrupted_commit(&mut self) -> Result<()> {
+        let journal_bytes = match fs::read(&self.journal_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(e).context(error::IoSnafu {
+                    path: &self.journal_path,
+                })
+            }
+        };
+        let journal: CommitJournal =
+            serde_json::from_slice(&journal_bytes).context(error::JournalSnafu {
+                path: &self.journal_path,
+            })?;
+
+        warn!(
+            "Found interrupted commit of transaction '{}', replaying it",
+            journal.transaction
+        );
+        self.replay_journal(&journal)?;
+        Ok(())
+    }
+
+    /// Finishes or discards an interrupted snapshot or restore.
+    fn recover_snapshots(&mut self) -> Result<()> {
+        if self.restore_path.exists() {
+            if self.live_path.exists() {
+                // We hadn't started swapping, so the copy may be incomplete; discard it.
+                warn!("Discarding incomplete snapshot restore");
+                remove_dir_if_present(&self.restore_path)?;
+            } else {
+                // The copy was complete and live was moved aside; finish the swap.
+                warn!("Finishing interrupted snapshot restore");
+                fs::rename(&self.restore_path, &self.live_path).context(error::IoSnafu {
+                    path: &self.live_path,
+                })?;
+                sync_parent_dir(&self.live_path)?;
+            }
+        }
+        remove_dir_if_present(&self.old_live_path)?;
+
+        for (path, _) in self.snapshot_dirs(true)? {
+            warn!("Discarding incomplete snapshot at {}", path.display());
+            remove_dir_if_present(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the directories of existing snapshots and their IDs.  If `incomplete` is true,
+    /// returns only snapshots that are still being written, otherwise only completed ones.
+    fn snapshot_dirs(&self, incomplete: bool) -> Result<Vec<(PathBuf, SnapshotId)>> {
+        let entries = match fs::read_dir(&self.snapshots_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).context(error::IoSnafu {
+                    path: &self.snapshots_path,
+                })
+            }
+        };
+
+        let mut dirs = Vec::new();
+        for entry in entries {
+            let entry = entry.context(error::IoSnafu {
+                path: &self.snapshots_path,
+            })?;
+            let file_name = entry.file_name();
+            let name = file_name.to_str().context(error::CorruptionSnafu {
+                msg: "Non-UTF8 path",
+                path: entry.path(),
+            })?;
+            let id = match name.strip_suffix(SNAPSHOT_TMP_SUFFIX) {
+                Some(id) if incomplete => id,
+                None if !incomplete => name,
+                _ => continue,
+            };
+            match id.parse() {
+                Ok(id) => dirs.push((entry.path(), id)),
+                Err(_) => trace!("Skipping non-snapshot entry {}", entry.path().display()),
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Copies live data and metadata into a new snapshot, then removes any snapshots beyond the
+    /// retention count.
+    fn take_snapshot(&mut self, transaction: Option<String>) -> Result<SnapshotId> {
+        let id = self
+            .snapshot_dirs(false)?
+            .into_iter()
+            .map(|(_, id)| id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let info = Snapshot { id, transaction };
+
+        // Write the snapshot under a temporary name and rename it once it's complete, so we
+        // never list a partial snapshot.
+        let tmp_path = self
+            .snapshots_path
+            .join(format!("{id}{SNAPSHOT_TMP_SUFFIX}"));
+        remove_dir_if_present(&tmp_path)?;
+        copy_tree_sync(&self.live_path, &tmp_path.join("live"))?;
+        let info_bytes = serde_json::to_vec(&info).context(error::SerializeSnafu)?;
+        write_file_sync(&tmp_path.join(SNAPSHOT_INFO_FILE_NAME), &info_bytes)?;
+        sync_dir(&tmp_path)?;
+
+        let path = self.snapshots_path.join(id.to_string());
+        fs::rename(&tmp_path, &path).context(error::IoSnafu { path: &path })?;
+        sync_dir(&self.snapshots_path)?;
+        debug!("Took snapshot {id} at {}", path.display());
+
+        let ids = self.snapshot_dirs(false)?.into_iter().map(|(_, id)| id);
+        for expired in expired_snapshots(ids, self.snapshot_retention) {
+            let path = self.snapshots_path.join(expired.to_string());
+            debug!("Removing expired snapshot {expired}");
+            remove_dir_if_present(&path)?;
+        }
+
+        Ok(id)
+    }
+
+    /// Replaces live with the given snapshot.  We copy the snapshot next to live, then swap it
+    /// in with two renames; `recover_snapshots` can tell how far we got from which directories
+    /// exist.
+    fn restore_snapshot(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        let snapshot_live = self
+            .snapshots_path
+            .join(snapshot_id.to_string())
+            .join("live");
+        ensure!(
+            snapshot_live.is_dir(),
+            error::SnapshotNotFoundSnafu { id: snapshot_id }
+        );
+
+        remove_dir_if_present(&self.restore_path)?;
+        copy_tree_sync(&snapshot_live, &self.restore_path)?;
+        sync_parent_dir(&self.restore_path)?;
+
+        fs::rename(&self.live_path, &self.old_live_path).context(error::IoSnafu {
+            path: &self.old_live_path,
+        })?;
+        fs::rename(&self.restore_path, &self.live_path).context(error::IoSnafu {
+            path: &self.live_path,
+        })?;
+        sync_parent_dir(&self.live_path)?;
+
+        remove_dir_if_present(&self.old_live_path)?;
+        info!("Restored live data from snapshot {snapshot_id}");
+        Ok(())
+    }
+
+    /// Durably writes the given journal to disk.  Once this returns, the commit is guaranteed to
+    /// be completed, either now or by `recover` after a crash.
+    ///
+    /// The journal is linked into place rather than renamed, so an existing journal from a commit
+    /// that hasn't been finished is never replaced; that's an error instead.
+    fn write_journal(&self, journal: &CommitJournal) -> Result<()> {
+        let bytes = serde_json::to_vec(journal).context(error::SerializeSnafu)?;
+        write_file_sync(&self.journal_tmp_path, &bytes)?;
+        let linked = fs::hard_link(&self.journal_tmp_path, &self.journal_path);
+        // Either way, the temporary journal isn't needed anymore; if we're interrupted before
+        // removing it, `recover` discards it.
+        fs::remove_file(&self.journal_tmp_path).context(error::IoSnafu {
+            path: &self.journal_tmp_path,
+        })?;
+        match linked {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return error::JournalExistsSnafu {
+                    path: &self.journal_path,
+                }
+                .fail()
+            }
+            Err(e) => {
+                return Err(e).context(error::IoSnafu {
+                    path: &self.journal_path,
+                })
+            }
+        }
+        sync_parent_dir(&self.journal_path)
+    }
+
+    /// Applies everything in the given journal to live, flushes it to disk, records the change
+    /// for subscriptions, and then removes the pending transaction and the journal itself.
+    /// Returns the data keys that were written or removed.
+    fn replay_journal(&mut self, journal: &CommitJournal) -> Result<HashSet<Key>> {
+        // Directories that need to be flushed so new files in them survive a power loss.
+        let mut dirs = BTreeSet::new();
+
+        for (metadata_key_name, data_key_name) in &journal.deleted_metadata {
+            let metadata_key = Key::new(KeyType::Meta, metadata_key_name)?;
+            let data_key = Key::new(KeyType::Data, data_key_name)?;
+            let path = self.metadata_path(&metadata_key, &data_key, &Committed::Live)?;
+            self.remove_live_file(&path, &mut dirs)?;
+        }
+
+        for (metadata_key_name, data_key_name, value) in &journal.metadata {
+            let metadata_key = Key::new(KeyType::Meta, metadata_key_name)?;
+            let data_key = Key::new(KeyType::Data, data_key_name)?;
+            let path = self.metadata_path(&metadata_key, &data_key, &Committed::Live)?;
+            self.write_live_file(&path, value, &mut dirs)?;
+        }
+
+        let mut keys = HashSet::new();
+        for data_key_name in &journal.deleted_settings {
+            let key = Key::new(KeyType::Data, data_key_name)?;
+            let path = self.data_path(&key, &Committed::Live)?;
+            self.remove_live_file(&path, &mut dirs)?;
+            info!("Removed data key {}", key.name());
+            keys.insert(key);
+        }
+
+        for (data_key_name, value) in &journal.settings {
+            let key = Key::new(KeyType::Data, data_key_name)?;
+            let path = self.data_path(&key, &Committed::Live)?;
+            self.write_live_file(&path, value, &mut dirs)?;
+            info!("Committed data key {}", key.name());
+            keys.insert(key);
+        }
+
+        // Later removals may have removed directories we recorded earlier; their removal is
+        // flushed with the nearest remaining directory.
+        for dir in dirs.iter().filter(|dir| dir.exists()) {
+            sync_dir(dir)?;
+        }
+
+        debug!("Removing old pending keys");
+        let pending = Committed::Pending {
+            tx: journal.transaction.clone(),
+        };
+        let path = self.base_path(&pending);
+        if let Err(e) = fs::remove_dir_all(&path) {
+            // We may have already removed it before being interrupted.
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e).context(error::IoSnafu { path });
+            }
+        }
+
+        // Record the commit for subscriptions before removing the journal, so an interrupted
+        // commit is still reported once it's replayed.
+        if !keys.is_empty() {
+            watch::write_change(&self.changes_path, &journal.transaction, &keys)?;
+        }
+
+        fs::remove_file(&self.journal_path).context(error::IoSnafu {
+            path: &self.journal_path,
+        })?;
+        sync_parent_dir(&self.journal_path)?;
+
+        Ok(keys)
+    }
+
+    /// Writes and flushes a file under the live path, recording the directories from its parent
+    /// up to the live path so the caller can flush them afterward.
+    fn write_live_file<S: AsRef<str>>(
+        &self,
+        path: &Path,
+        data: S,
+        dirs: &mut BTreeSet<PathBuf>,
+    ) -> Result<()> {
+        let dirname = path.parent().with_context(|| error::InternalSnafu {
+            msg: format!(
+                "Given path to write without proper prefix: {}",
+                path.display()
+            ),
+        })?;
+        fs::create_dir_all(dirname).context(error::IoSnafu { path: dirname })?;
+        write_file_sync(path, data.as_ref().as_bytes())?;
+
+        for dir in dirname.ancestors() {
+            dirs.insert(dir.to_path_buf());
+            if dir == self.live_path {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a file under the live path, recording the nearest directory that still exists
+    /// afterward, since empty directories are removed along with the file, so the caller can
+    /// flush it.
+    fn remove_live_file(&mut self, path: &Path, dirs: &mut BTreeSet<PathBuf>) -> Result<()> {
+        self.delete_key_path(path, &Committed::Live)?;
+        if let Some(dir) = path.ancestors().skip(1).find(|dir| dir.exists()) {
+            dirs.insert(dir.to_path_buf());
         }
+        Ok(())
     }
 
     /// Returns the appropriate filesystem path for pending or live data.
@@ -76,6 +521,64 @@ impl FilesystemDataStore {
         Ok(path)
     }
 
+    /// Given the path of a data or metadata key in a pending transaction, returns the path of the
+    /// tombstone recording that it was unset in the transaction.
+    fn tombstone_path(&self, path: &Path, tx: &str) -> Result<PathBuf> {
+        let pending = Committed::Pending { tx: tx.into() };
+        let relative = path
+            .strip_prefix(self.base_path(&pending))
+            .context(error::PathSnafu)?;
+        Ok(self
+            .base_path(&pending)
+            .join(TOMBSTONES_DIR_NAME)
+            .join(relative))
+    }
+
+    /// Removes the tombstone for the given pending data or metadata path, if any, because the key
+    /// is being set again.  A directory in its place holds tombstones for keys under it, like the
+    /// old elements of a list, which stay unset, and a file on the way to it is the tombstone of
+    /// a parent key, like a list that was stored as one value.
+    fn remove_tombstone(&mut self, path: &Path, tx: &str) -> Result<()> {
+        let tombstone = self.tombstone_path(path, tx)?;
+        if !tombstone.is_file() {
+            return Ok(());
+        }
+        self.delete_key_path(tombstone, &Committed::Pending { tx: tx.into() })
+    }
+
+    /// Returns when the transaction in the given directory was created and last changed.
+    /// Transactions created before times were recorded, or whose record is damaged, are treated
+    /// as created and last changed when their directory was last modified.
+    fn transaction_times(&self, tx_path: &Path) -> Result<TransactionTimes> {
+        let path = tx_path.join(TRANSACTION_TIMES_FILE_NAME);
+        match fs::read(&path) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(times) => return Ok(times),
+                Err(e) => warn!("Ignoring bad transaction times in {}: {e}", path.display()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        }
+        let modified = fs::metadata(tx_path)
+            .and_then(|metadata| metadata.modified())
+            .context(error::IoSnafu { path: tx_path })?;
+        Ok(TransactionTimes::new(modified))
+    }
+
+    /// Records a change to the given pending transaction, after the change has been written.
+    fn touch_transaction(&self, committed: &Committed) -> Result<()> {
+        if let Committed::Live = committed {
+            return Ok(());
+        }
+        let tx_path = self.base_path(committed);
+        let times = TransactionTimes {
+            touched: SystemTime::now(),
+            ..self.transaction_times(&tx_path)?
+        };
+        let json = serde_json::to_string(&times).context(error::SerializeSnafu)?;
+        write_file_mkdir(tx_path.join(TRANSACTION_TIMES_FILE_NAME), json)
+    }
+
     /// Returns the appropriate path on the filesystem for the given metadata key.
     fn metadata_path(
         &self,
@@ -124,11 +627,15 @@ impl FilesystemDataStore {
     {
         let path = path.as_ref();
 
-        // Remove the file.  If it doesn't exist, we're still OK.
+        // Remove the file.  If it doesn't exist, we're still OK; that includes when a parent
+        // directory is a file, e.g. a list stored as one value rather than by index.
         match fs::remove_file(path) {
             Ok(()) => {}
             Err(e) => {
-                if e.kind() != io::ErrorKind::NotFound {
+                if !matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+                ) {
                     return Err(e).context(error::DeleteKeySnafu { path });
                 }
             }
@@ -183,7 +690,7 @@ fn encode_path_component<S: AsRef<str>>(segment: S) -> String {
 }
 
 /// Decodes a path component, removing the encoding that's applied to make it filesystem-safe.
-fn decode_path_component<S, P>(segment: S, path: P) -> Result<String>
+pub(crate) fn decode_path_component<S, P>(segment: S, path: P) -> Result<String>
 where
     S: AsRef<str>,
     P: AsRef<Path>,
@@ -209,7 +716,12 @@ fn read_file_for_key(key: &Key, path: &Path) -> Result<Option<String>> {
     match fs::read_to_string(path) {
         Ok(s) => Ok(Some(s)),
         Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
+            // A key under another key's value, like the element of a list stored as one value,
+            // can't exist either.
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::NotADirectory
+            ) {
                 return Ok(None);
             }
 
@@ -233,6 +745,158 @@ fn write_file_mkdir<S: AsRef<str>>(path: PathBuf, data: S) -> Result<()> {
     fs::write(&path, data.as_ref().as_bytes()).context(error::IoSnafu { path: &path })
 }
 
+/// Helper for writing a file and flushing it to disk before returning.  The directory entry
+/// isn't flushed; see sync_dir.
+pub(crate) fn write_file_sync(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = fs::File::create(path).context(error::IoSnafu { path })?;
+    file.write_all(data).context(error::IoSnafu { path })?;
+    file.sync_all().context(error::IoSnafu { path })
+}
+
+/// Flushes a directory to disk, so that files created, renamed, or removed inside it survive a
+/// power loss.
+fn sync_dir(path: &Path) -> Result<()> {
+    fs::File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .context(error::IoSnafu { path })
+}
+
+/// Removes a directory tree, succeeding if it doesn't exist.
+fn remove_dir_if_present(path: &Path) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e).context(error::IoSnafu { path }),
+        _ => Ok(()),
+    }
+}
+
+/// Recursively copies the directories and regular files under `from` into `to`, flushing
+/// everything to disk before returning.
+fn copy_tree_sync(from: &Path, to: &Path) -> Result<()> {
+    let mut dirs = Vec::new();
+    let walker = WalkDir::new(from)
+        .follow_links(false) // shouldn't be links...
+        .same_file_system(true); // shouldn't be filesystems to cross...
+
+    // WalkDir yields directories before their contents, so parents are created first.
+    for entry in walker {
+        let entry = entry.context(error::ListKeysSnafu)?;
+        let relative = entry.path().strip_prefix(from).context(error::PathSnafu)?;
+        let target = to.join(relative);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target).context(error::IoSnafu { path: &target })?;
+            dirs.push(target);
+        } else if entry.file_type().is_file() {
+            let data = fs::read(entry.path()).context(error::IoSnafu { path: entry.path() })?;
+            write_file_sync(&target, &data)?;
+        } else {
+            trace!("Skipping non-file entry: {}", entry.path().display());
+        }
+    }
+
+    for dir in &dirs {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Flushes the directory containing the given path; see sync_dir.
+pub(crate) fn sync_parent_dir(path: &Path) -> Result<()> {
+    let dirname = path.parent().with_context(|| error::InternalSnafu {
+        msg: format!("Given path to sync without parent: {}", path.display()),
+    })?;
+    sync_dir(dirname)
+}
+
+/// CommitJournal records everything a commit is going to write to live, so an interrupted commit
+/// can be replayed in full.  Keys are stored by name because a Key can't be deserialized without
+/// knowing its type.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CommitJournal {
+    pub(crate) transaction: String,
+    /// Data key name -> serialized value.
+    settings: HashMap<String, String>,
+    /// (Metadata key name, data key name, serialized value).
+    metadata: Vec<(String, String, String)>,
+    /// Data key names to remove.
+    #[serde(default)]
+    deleted_settings: Vec<String>,
+    /// (Metadata key name, data key name) to remove.
+    #[serde(default)]
+    deleted_metadata: Vec<(String, String)>,
+}
+
+impl CommitJournal {
+    pub(crate) fn new<S: Into<String>>(transaction: S, approved_write: &ApprovedWrite) -> Self {
+        Self {
+            transaction: transaction.into(),
+            settings: approved_write
+                .settings
+                .iter()
+                .map(|(key, value)| (key.name().clone(), value.clone()))
+                .collect(),
+            metadata: approved_write
+                .metadata
+                .iter()
+                .map(|(metadata_key, data_key, value)| {
+                    (
+                        metadata_key.name().clone(),
+                        data_key.name().clone(),
+                        value.clone(),
+                    )
+                })
+                .collect(),
+            deleted_settings: approved_write
+                .deleted_settings
+                .iter()
+                .map(|key| key.name().clone())
+                .collect(),
+            deleted_metadata: approved_write
+                .deleted_metadata
+                .iter()
+                .map(|(metadata_key, data_key)| {
+                    (metadata_key.name().clone(), data_key.name().clone())
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the write recorded in the journal.
+    pub(crate) fn approved_write(&self) -> Result<ApprovedWrite> {
+        let data_key = |name| Key::new(KeyType::Data, name);
+        let metadata_key = |name| Key::new(KeyType::Meta, name);
+        Ok(ApprovedWrite {
+            settings: self
+                .settings
+                .iter()
+                .map(|(name, value)| Ok((data_key(name)?, value.clone())))
+                .collect::<Result<_>>()?,
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(metadata_name, data_name, value)| {
+                    Ok((
+                        metadata_key(metadata_name)?,
+                        data_key(data_name)?,
+                        value.clone(),
+                    ))
+                })
+                .collect::<Result<_>>()?,
+            deleted_settings: self
+                .deleted_settings
+                .iter()
+                .map(data_key)
+                .collect::<Result<_>>()?,
+            deleted_metadata: self
+                .deleted_metadata
+                .iter()
+                .map(|(metadata_name, data_name)| {
+                    Ok((metadata_key(metadata_name)?, data_key(data_name)?))
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
 /// KeyPath represents the filesystem path to a data or metadata key, relative to the base path of
 /// the live or pending data store.  For example, the data key "settings.a.b" would be
 /// "settings/a/b" and the metadata key "meta1" for "settings.a.b" would be "settings/a/b.meta1".
@@ -246,9 +910,9 @@ fn write_file_mkdir<S: AsRef<str>>(path: PathBuf, data: S) -> Result<()> {
 // Note: this may be useful in other parts of the FilesystemDataStore code too.  It may also be
 // useful enough to use its ideas to extend the Key type directly, instead.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct KeyPath {
-    data_key: Key,
-    metadata_key: Option<Key>,
+pub(crate) struct KeyPath {
+    pub(crate) data_key: Key,
+    pub(crate) metadata_key: Option<Key>,
 }
 
 impl KeyPath {
@@ -273,7 +937,7 @@ impl KeyPath {
         Ok(Self::from_path(key_path_raw).ok())
     }
 
-    fn from_path(path: &Path) -> Result<KeyPath> {
+    pub(crate) fn from_path(path: &Path) -> Result<KeyPath> {
         let path_str = path.to_str().context(error::CorruptionSnafu {
             msg: "Non-UTF8 path",
             path,
@@ -314,7 +978,8 @@ impl KeyPath {
 
 /// Helper to walk through the filesystem to find populated keys of the given type, starting with
 /// the given prefix.  Each item in the returned set is a KeyPath representing a data or metadata
-/// key.
+/// key.  The prefix matches whole key segments, and only the part of the tree under the prefix
+/// is read; see `walk_key_paths`.
 // Note: if we needed to list all possible keys, a walk would only work if we had empty files to
 // represent unset values, which could be ugly.
 // Another option would be to use a procedural macro to step through a structure to list possible
@@ -348,28 +1013,69 @@ fn find_populated_key_paths<S: AsRef<str>>(
         }
     }
 
-    // Walk through the filesystem.
-    let walker = WalkDir::new(&base)
+    let prefix = Key::prefix_segments(prefix)?;
+    walk_key_paths(&base, key_type, &prefix)
+}
+
+/// Walks the part of the tree under `base` that can hold keys starting with the given prefix
+/// segments, returning the KeyPaths of the given type that do.
+///
+/// The data and metadata files for a key sit next to the directory holding its children, e.g.
+/// a/b/c, a/b/c.meta, and a/b/c/d for prefix a.b.c, so we walk from the parent directory of the
+/// last prefix segment and only descend into the entries for that segment.
+fn walk_key_paths(base: &Path, key_type: KeyType, prefix: &[String]) -> Result<HashSet<KeyPath>> {
+    let encoded: Vec<_> = prefix.iter().map(encode_path_component).collect();
+    let (start, last) = match encoded.split_last() {
+        Some((last, parents)) => (
+            base.join(parents.join(path::MAIN_SEPARATOR_STR)),
+            Some(last),
+        ),
+        None => (base.to_path_buf(), None),
+    };
+    if !start.is_dir() {
+        trace!(
+            "Returning empty list because prefix path doesn't exist: {}",
+            start.display()
+        );
+        return Ok(HashSet::new());
+    }
+    let metadata_prefix = last.map(|last| format!("{last}{METADATA_KEY_PREFIX}"));
+
+    let walker = WalkDir::new(&start)
         .follow_links(false) // shouldn't be links...
-        .same_file_system(true); // shouldn't be filesystems to cross...
+        .same_file_system(true) // shouldn't be filesystems to cross...
+        .into_iter()
+        .filter_entry(|entry| {
+            if entry.depth() != 1 {
+                return true;
+            }
+            let name = entry.file_name().to_str().unwrap_or_default();
+            match (last, &metadata_prefix) {
+                (Some(last), Some(metadata_prefix)) => {
+                    name == last || name.starts_with(metadata_prefix)
+                }
+                // Skip tombstones and transaction times; they aren't populated keys.
+                _ => name != TOMBSTONES_DIR_NAME && name != TRANSACTION_TIMES_FILE_NAME,
+            }
+        });
 
     let mut key_paths = HashSet::new();
     trace!(
         "Starting walk of filesystem to list {:?} key paths under {}",
         key_type,
-        base.display()
+        start.display()
     );
 
     // For anything we find, confirm it matches the user's filters, and add it to results.
     for entry in walker {
         let entry = entry.context(error::ListKeysSnafu)?;
-        if let Some(kp) = KeyPath::from_entry(&entry, &base)? {
-            if !kp.data_key.name().starts_with(prefix.as_ref()) {
+        if let Some(kp) = KeyPath::from_entry(&entry, base)? {
+            if !kp.data_key.starts_with_segments(prefix) {
                 trace!(
-                    "Discarded {:?} key whose data_key '{}' doesn't start with prefix '{}'",
+                    "Discarded {:?} key whose data_key '{}' doesn't start with prefix {:?}",
                     kp.key_type(),
                     kp.data_key,
-                    prefix.as_ref()
+                    prefix
                 );
                 continue;
             } else if kp.key_type() != key_type {
@@ -404,6 +1110,46 @@ impl DataStore for FilesystemDataStore {
         Ok(keys)
     }
 
+    /// Returns the data keys with tombstones in the given pending transaction that start with
+    /// the given prefix.
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        if let Committed::Live = committed {
+            return Ok(HashSet::new());
+        }
+        let base = self.base_path(committed).join(TOMBSTONES_DIR_NAME);
+        let prefix = Key::prefix_segments(prefix)?;
+        let key_paths = walk_key_paths(&base, KeyType::Data, &prefix)?;
+        Ok(key_paths.into_iter().map(|kp| kp.data_key).collect())
+    }
+
+    /// Returns the metadata keys with tombstones in the given pending transaction whose data
+    /// keys start with the given prefix.
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        if let Committed::Live = committed {
+            return Ok(HashMap::new());
+        }
+        let base = self.base_path(committed).join(TOMBSTONES_DIR_NAME);
+        let prefix = Key::prefix_segments(prefix)?;
+
+        let mut result: HashMap<Key, HashSet<Key>> = HashMap::new();
+        for key_path in walk_key_paths(&base, KeyType::Meta, &prefix)? {
+            let data_key = key_path.data_key;
+            let meta_key = key_path.metadata_key.context(error::InternalSnafu {
+                msg: format!("Found meta key path with no dot: {data_key}"),
+            })?;
+            result.entry(data_key).or_default().insert(meta_key);
+        }
+        Ok(result)
+    }
+
     /// Finds all metadata keys that are currently populated in the datastore whose data keys
     /// start with the given prefix.  If you specify metadata_key_name, only metadata keys with
     /// that name will be returned.
@@ -432,6 +1178,9 @@ impl DataStore for FilesystemDataStore {
             let meta_key = key_path.metadata_key.context(error::InternalSnafu {
                 msg: format!("Found meta key path with no dot: {data_key}"),
             })?;
+            if revision::is_revision_key(&meta_key) {
+                continue;
+            }
 
             // If the user requested specific metadata, move to the next key unless it matches.
             if let Some(name) = metadata_key_name {
@@ -450,17 +1199,47 @@ impl DataStore for FilesystemDataStore {
 
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
         let path = self.data_path(key, committed)?;
-        read_file_for_key(key, &path)
+        read_file_for_key(key, &path)?
+            .map(|value| {
+                sensitive::decrypt(self, self.encryption_key.as_ref(), key, committed, value)
+            })
+            .transpose()
     }
 
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.finish_interrupted_commit()?;
+        let value = self.stored_value(key, value.as_ref(), committed)?;
         let path = self.data_path(key, committed)?;
-        write_file_mkdir(path, value)
+        if let Committed::Pending { tx } = committed {
+            self.remove_tombstone(&path, tx)?;
+        }
+        write_file_mkdir(path, value)?;
+        if let Committed::Live = committed {
+            revision::bump(self, key)?;
+        }
+        self.touch_transaction(committed)
     }
 
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.finish_interrupted_commit()?;
         let path = self.data_path(key, committed)?;
-        self.delete_key_path(path, committed)
+        match committed {
+            Committed::Pending { tx } => {
+                // Write the tombstone first so the transaction directory never disappears while
+                // the transaction still has something to commit.  If there's a directory of
+                // tombstones in its place, the key had child keys in live rather than a value,
+                // like a list stored by index, so there's nothing for a tombstone to remove.
+                let tombstone = self.tombstone_path(&path, tx)?;
+                if !tombstone.is_dir() {
+                    write_file_mkdir(tombstone, "")?;
+                }
+            }
+            Committed::Live => {
+                revision::bump(self, key)?;
+            }
+        }
+        self.delete_key_path(path, committed)?;
+        self.touch_transaction(committed)
     }
 
     fn get_metadata_raw(
@@ -480,17 +1259,36 @@ impl DataStore for FilesystemDataStore {
         value: S,
         committed: &Committed,
     ) -> Result<()> {
+        self.finish_interrupted_commit()?;
         let path = self.metadata_path(metadata_key, data_key, committed)?;
-        write_file_mkdir(path, value)
+        if let Committed::Pending { tx } = committed {
+            self.remove_tombstone(&path, tx)?;
+        }
+        write_file_mkdir(path, value)?;
+        self.touch_transaction(committed)
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        self.delete_key_path(path, &Committed::Live)
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.finish_interrupted_commit()?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        if let Committed::Pending { tx } = committed {
+            // As in unset_key, write the tombstone first.
+            let tombstone = self.tombstone_path(&path, tx)?;
+            write_file_mkdir(tombstone, "")?;
+        }
+        self.delete_key_path(path, committed)?;
+        self.touch_transaction(committed)
     }
 
-    /// We commit by copying pending keys to live, then removing pending.  Something smarter (lock,
-    /// atomic flip, etc.) will be required to make the server concurrent.
+    /// We commit by recording the approved write in a commit journal, then copying it to live
+    /// and removing pending.  If we're interrupted after the journal is written, the commit is
+    /// finished the next time the data store is opened or written.  Concurrent users should share
+    /// the data store through LockedDataStore.
     fn commit_transaction<S, C>(
         &mut self,
         transaction: S,
@@ -506,63 +1304,65 @@ impl DataStore for FilesystemDataStore {
             Box<dyn std::error::Error + Send + Sync + 'static>,
         >,
     {
-        let mut pending_keys: HashSet<Key> = Default::default();
-
+        self.finish_interrupted_commit()?;
         let transactions = self.list_transactions()?;
         if !transactions.contains(transaction.as_ref()) {
-            return Ok(pending_keys);
+            return Ok(HashSet::new());
         }
 
+        let transaction = transaction.into();
         let pending = Committed::Pending {
-            tx: transaction.into(),
+            tx: transaction.clone(),
         };
 
         let constraints_check_result =
             constraint_check(self, &pending).context(error::CheckConstraintExecutionSnafu)?;
 
-        let approved_write = ApprovedWrite::try_from(constraints_check_result)?;
+        let mut approved_write = ApprovedWrite::try_from(constraints_check_result)?;
+        for (key, value) in approved_write.settings.iter_mut() {
+            *value = self.stored_value(key, value, &pending)?;
+        }
 
         trace!(
             "commit_transaction: transaction_metadata: {:?}",
             approved_write.metadata
         );
 
-        // write the metadata.
-        for (metadata_key, data_key, value) in approved_write.metadata {
-            self.set_metadata(&metadata_key, &data_key, value, &Committed::Live)?;
+        if self.snapshot_retention > 0 {
+            self.take_snapshot(Some(transaction.clone()))?;
         }
 
-        let pending_data = approved_write.settings;
-
-        if !pending_data.is_empty() {
-            // Save Keys for return value
-            pending_keys = pending_data.keys().cloned().collect();
-
-            // Apply changes to live
-            debug!("Writing pending keys to live");
-            self.set_keys(&pending_data, &Committed::Live)?;
-        }
-
-        // Remove pending
-        debug!("Removing old pending keys");
-        let path = self.base_path(&pending);
-        fs::remove_dir_all(&path).context(error::IoSnafu { path })?;
-
-        Ok(pending_keys)
+        // Bump the revisions of changed keys in the same journal, so they change atomically with
+        // the data.
+        let changed = approved_write
+            .settings
+            .keys()
+            .chain(&approved_write.deleted_settings);
+        let revisions = revision::next_revisions(self, changed)?;
+        approved_write.metadata.extend(revisions);
+
+        let journal = CommitJournal::new(transaction, &approved_write);
+        debug!("Writing commit journal");
+        self.write_journal(&journal)?;
+
+        debug!("Writing pending keys to live");
+        self.replay_journal(&journal)
     }
 
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        self.finish_interrupted_commit()?;
         let pending = Committed::Pending {
             tx: transaction.into(),
         };
         // Get changed keys so we can return the list
         let pending_data = self.get_prefix("settings.", &pending)?;
+        let deleted_keys = self.list_deleted_keys("settings.", &pending)?;
 
         // Pull out just the keys so we can log them and return them
-        let pending_keys = pending_data.into_keys().collect();
+        let pending_keys = pending_data.into_keys().chain(deleted_keys).collect();
         debug!("Found pending keys: {:?}", &pending_keys);
 
         // Delete pending from the filesystem, same as a commit
@@ -620,15 +1420,89 @@ impl DataStore for FilesystemDataStore {
 
         Ok(transactions)
     }
+
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>> {
+        self.list_transactions()?
+            .into_iter()
+            .map(|tx| {
+                let times = self
+                    .transaction_times(&self.base_path(&Committed::Pending { tx: tx.clone() }))?;
+                Ok((tx, times))
+            })
+            .collect()
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId> {
+        self.finish_interrupted_commit()?;
+        self.take_snapshot(None)
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for (path, _) in self.snapshot_dirs(false)? {
+            let info_path = path.join(SNAPSHOT_INFO_FILE_NAME);
+            let info_bytes = fs::read(&info_path).context(error::IoSnafu { path: &info_path })?;
+            let info: Snapshot =
+                serde_json::from_slice(&info_bytes)
+                    .ok()
+                    .context(error::CorruptionSnafu {
+                        msg: "invalid snapshot description",
+                        path: &info_path,
+                    })?;
+            snapshots.push(info);
+        }
+        snapshots.sort_unstable_by_key(|snapshot| snapshot.id);
+        Ok(snapshots)
+    }
+
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        self.finish_interrupted_commit()?;
+        self.restore_snapshot(snapshot_id)
+    }
+
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription> {
+        Subscription::from_directory(prefix, &self.changes_path)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::TestDir;
+    use maplit::hashmap;
+
+    /// Approves every pending setting and metadata change in the transaction.
+    fn approve_all(
+        datastore: &mut FilesystemDataStore,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+            settings: datastore.get_prefix("settings.", committed)?,
+            metadata: datastore
+                .get_metadata_prefix("settings.", committed, &None as &Option<&str>)?
+                .into_iter()
+                .flat_map(|(data_key, metadata)| {
+                    metadata
+                        .into_iter()
+                        .map(move |(metadata_key, value)| (metadata_key, data_key.clone(), value))
+                })
+                .collect(),
+            deleted_settings: datastore.list_deleted_keys("settings.", committed)?,
+            deleted_metadata: datastore
+                .list_deleted_metadata("settings.", committed)?
+                .into_iter()
+                .flat_map(|(data_key, metadata_keys)| {
+                    metadata_keys
+                        .into_iter()
+                        .map(move |metadata_key| (metadata_key, data_key.clone()))
+                })
+                .collect(),
+        })))
+    }
 
     #[test]
     fn data_path() {
-        let f = FilesystemDataStore::new("/base");
+        let f = FilesystemDataStore::new("/base").unwrap();
         let key = Key::new(KeyType::Data, "a.b.c").unwrap();
 
         let tx = "test transaction";
@@ -642,11 +1516,15 @@ mod test {
 
         let live = f.data_path(&key, &Committed::Live).unwrap();
         assert_eq!(live.into_os_string(), "/base/live/a/b/c");
+
+        let key = Key::new(KeyType::Data, "a.b[0][1].c").unwrap();
+        let live = f.data_path(&key, &Committed::Live).unwrap();
+        assert_eq!(live.into_os_string(), "/base/live/a/b/[0]/[1]/c");
     }
 
     #[test]
     fn metadata_path() {
-        let f = FilesystemDataStore::new("/base");
+        let f = FilesystemDataStore::new("/base").unwrap();
         let data_key = Key::new(KeyType::Data, "a.b.c").unwrap();
         let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
 
@@ -686,4 +1564,472 @@ mod test {
         // Invalid UTF-8
         decode_path_component("%C3%28", "").unwrap_err();
     }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        let dir = TestDir::new("prefix");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let host = Key::new(KeyType::Data, "settings.host").unwrap();
+        let host_name = Key::new(KeyType::Data, "settings.host-name").unwrap();
+        let nested = Key::new(KeyType::Data, "settings.network.host").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        for key in [&host, &host_name, &nested] {
+            f.set_key(key, "\"x\"", &Committed::Live).unwrap();
+            f.set_metadata(&meta, key, "[]", &Committed::Live).unwrap();
+        }
+
+        let keys = f
+            .list_populated_keys("settings.host", &Committed::Live)
+            .unwrap();
+        assert_eq!(keys, HashSet::from([host.clone()]));
+        let metadata = f
+            .list_populated_metadata(
+                "settings.host",
+                &Committed::Live,
+                &Some("affected-services"),
+            )
+            .unwrap();
+        assert_eq!(
+            metadata,
+            hashmap!(host.clone() => HashSet::from([meta.clone()]))
+        );
+        let keys = f
+            .list_populated_keys("settings.network.", &Committed::Live)
+            .unwrap();
+        assert_eq!(keys, HashSet::from([nested.clone()]));
+        assert!(f
+            .list_populated_keys("settings.missing", &Committed::Live)
+            .unwrap()
+            .is_empty());
+
+        // String prefixes are still available when asked for.
+        let matches = f
+            .get_string_prefix("settings.host", &Committed::Live)
+            .unwrap();
+        assert_eq!(
+            matches.into_keys().collect::<HashSet<_>>(),
+            HashSet::from([host, host_name])
+        );
+    }
+
+    #[test]
+    fn commit_removes_journal_and_pending() {
+        let dir = TestDir::new("commit");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"value\"", &pending).unwrap();
+
+        let committed = f.commit_transaction("tx", &approve_all).unwrap();
+        assert_eq!(committed, HashSet::from([key.clone()]));
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"value\"".to_string())
+        );
+        assert!(f.list_transactions().unwrap().is_empty());
+        assert!(!f.journal_path.exists());
+        assert!(!f.journal_tmp_path.exists());
+    }
+
+    #[test]
+    fn commit_applies_tombstones() {
+        let dir = TestDir::new("tombstones");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let removed = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let restored = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        f.set_key(&removed, "\"old\"", &Committed::Live).unwrap();
+        f.set_key(&restored, "\"old\"", &Committed::Live).unwrap();
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.unset_key(&removed, &pending).unwrap();
+        // Setting the key again in the transaction cancels the deletion.
+        f.unset_key(&restored, &pending).unwrap();
+        f.set_key(&restored, "\"new\"", &pending).unwrap();
+        assert!(f.list_transactions().unwrap().contains("tx"));
+        assert_eq!(
+            f.list_deleted_keys("settings.", &pending).unwrap(),
+            HashSet::from([removed.clone()])
+        );
+        // Tombstones aren't populated keys.
+        assert_eq!(
+            f.list_populated_keys("", &pending).unwrap(),
+            HashSet::from([restored.clone()])
+        );
+
+        let committed = f.commit_transaction("tx", &approve_all).unwrap();
+        assert_eq!(
+            committed,
+            HashSet::from([removed.clone(), restored.clone()])
+        );
+        assert_eq!(f.get_key(&removed, &Committed::Live).unwrap(), None);
+        assert_eq!(
+            f.get_key(&restored, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sensitive_values_encrypted() {
+        let dir = TestDir::new("sensitive");
+        let key_file = dir.0.join("datastore.key");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_encryption_key(EncryptionKey::load_or_create(&key_file).unwrap());
+        let key = |name| Key::new(KeyType::Data, name).unwrap();
+        let (credentials, token, motd) = (
+            key("settings.aws.credentials"),
+            key("settings.kubernetes.bootstrap-token"),
+            key("settings.motd"),
+        );
+        let on_disk = |path: &str| fs::read_to_string(dir.0.join("live").join(path)).unwrap();
+
+        // Marked sensitive in live, inherited from a parent key.
+        let flag = sensitive::sensitive_key();
+        f.set_metadata(&flag, &key("settings.aws"), "true", &Committed::Live)
+            .unwrap();
+        f.set_key(&credentials, "\"secret\"", &Committed::Live)
+            .unwrap();
+        f.set_key(&motd, "\"hi\"", &Committed::Live).unwrap();
+        // Marked sensitive in the transaction that sets it.
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_metadata(&flag, &token, "true", &pending).unwrap();
+        f.set_key(&token, "\"token\"", &pending).unwrap();
+        f.commit_transaction("tx", &approve_all).unwrap();
+
+        assert!(!on_disk("settings/aws/credentials").contains("secret"));
+        assert!(!on_disk("settings/kubernetes/bootstrap-token").contains("token"));
+        assert_eq!(on_disk("settings/motd"), "\"hi\"");
+        assert_eq!(
+            f.get_key(&credentials, &Committed::Live).unwrap(),
+            Some("\"secret\"".to_string())
+        );
+        assert_eq!(
+            f.get_key(&token, &Committed::Live).unwrap(),
+            Some("\"token\"".to_string())
+        );
+
+        // Without the key, sensitive values can't be read or written.
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        assert!(matches!(
+            f.get_key(&credentials, &Committed::Live),
+            Err(error::Error::MissingEncryptionKey { .. })
+        ));
+        assert!(matches!(
+            f.set_key(&token, "\"new\"", &Committed::Live),
+            Err(error::Error::MissingEncryptionKey { .. })
+        ));
+        assert_eq!(
+            f.get_key(&motd, &Committed::Live).unwrap(),
+            Some("\"hi\"".to_string())
+        );
+    }
+
+    #[test]
+    fn plain_values_never_decrypted() {
+        let dir = TestDir::new("sensitive-lookalike");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let value = "\"encrypted:aes-256-gcm:hello\"";
+        f.set_key(&motd, value, &Committed::Live).unwrap();
+
+        assert_eq!(
+            f.get_key(&motd, &Committed::Live).unwrap(),
+            Some(value.to_string())
+        );
+        assert_eq!(
+            f.get_prefix("settings", &Committed::Live).unwrap(),
+            hashmap!(motd => value.to_string())
+        );
+    }
+
+    #[test]
+    fn pending_metadata_waits_for_commit() {
+        let dir = TestDir::new("pending-metadata");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let data_key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let added = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let removed = Key::new(KeyType::Meta, "setting-generator").unwrap();
+        f.set_metadata(&removed, &data_key, "\"gen\"", &Committed::Live)
+            .unwrap();
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_metadata(&added, &data_key, "[\"ntp\"]", &pending)
+            .unwrap();
+        f.unset_metadata(&removed, &data_key, &pending).unwrap();
+        // A metadata-only transaction is still a transaction.
+        assert!(f.list_transactions().unwrap().contains("tx"));
+        assert_eq!(
+            f.get_metadata_raw(&added, &data_key, &Committed::Live)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            f.get_metadata_raw(&removed, &data_key, &Committed::Live)
+                .unwrap(),
+            Some("\"gen\"".to_string())
+        );
+
+        // Deleting the transaction throws away its metadata changes.
+        f.delete_transaction("tx").unwrap();
+        assert!(f.list_transactions().unwrap().is_empty());
+        assert!(f.list_deleted_metadata("", &pending).unwrap().is_empty());
+
+        f.set_metadata(&added, &data_key, "[\"ntp\"]", &pending)
+            .unwrap();
+        f.unset_metadata(&removed, &data_key, &pending).unwrap();
+        f.commit_transaction("tx", &approve_all).unwrap();
+        assert_eq!(
+            f.get_metadata_raw(&added, &data_key, &Committed::Live)
+                .unwrap(),
+            Some("[\"ntp\"]".to_string())
+        );
+        assert_eq!(
+            f.get_metadata_raw(&removed, &data_key, &Committed::Live)
+                .unwrap(),
+            None
+        );
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn subscription_sees_other_instances() {
+        let dir = TestDir::new("subscribe");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let mut subscription = f.subscribe("settings.motd").unwrap();
+        assert_eq!(subscription.try_recv().unwrap(), None);
+
+        // Commit through a separate instance, as another process would.
+        let base = dir.0.clone();
+        let committer = std::thread::spawn(move || {
+            let mut other = FilesystemDataStore::new(base).unwrap();
+            let key = Key::new(KeyType::Data, "settings.motd").unwrap();
+            let pending = Committed::Pending { tx: "other".into() };
+            other.set_key(&key, "\"hi\"", &pending).unwrap();
+            other.commit_transaction("other", &approve_all).unwrap();
+        });
+
+        let event = subscription
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap()
+            .expect("no event for commit");
+        committer.join().unwrap();
+        assert_eq!(event.transaction, "other");
+        assert_eq!(
+            event.keys,
+            HashSet::from([Key::new(KeyType::Data, "settings.motd").unwrap()])
+        );
+        assert_eq!(subscription.try_recv().unwrap(), None);
+    }
+
+    #[test]
+    fn commit_bumps_revisions() {
+        let dir = TestDir::new("revisions");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let removed = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        f.set_key(&removed, "\"old\"", &Committed::Live).unwrap();
+        let expected = hashmap!(key.clone() => 0, removed.clone() => 1);
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"new\"", &pending).unwrap();
+        f.unset_key(&removed, &pending).unwrap();
+        f.commit_transaction_if_unchanged("tx", &expected, &approve_all)
+            .unwrap();
+        assert_eq!(f.get_revision(&key).unwrap(), 1);
+        assert_eq!(f.get_revision(&removed).unwrap(), 2);
+
+        // The revisions we read are now stale, so another commit conflicts.
+        f.set_key(&key, "\"newer\"", &pending).unwrap();
+        let err = f
+            .commit_transaction_if_unchanged("tx", &expected, &approve_all)
+            .unwrap_err();
+        assert!(matches!(err, error::Error::Conflict { .. }));
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+    }
+
+    #[test]
+    fn recover_replays_complete_journal() {
+        let dir = TestDir::new("recover-complete");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let meta = Key::new(KeyType::Meta, "strength").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"new\"", &pending).unwrap();
+
+        // Simulate a crash right after the journal was written, before live was touched.
+        let approved_write = ApprovedWrite {
+            settings: hashmap!(key.clone() => "\"new\"".to_string()),
+            metadata: vec![(meta.clone(), key.clone(), "\"weak\"".to_string())],
+            deleted_settings: HashSet::new(),
+            deleted_metadata: Vec::new(),
+        };
+        f.write_journal(&CommitJournal::new("tx", &approved_write))
+            .unwrap();
+        assert_eq!(f.get_key(&key, &Committed::Live).unwrap(), None);
+
+        // Opening without recovery reads the data store as it was left.
+        let f = FilesystemDataStore::open_without_recovery(&dir.0);
+        assert_eq!(f.get_key(&key, &Committed::Live).unwrap(), None);
+        assert!(f.journal_path.exists());
+
+        let f = FilesystemDataStore::new(&dir.0).unwrap();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+        assert_eq!(
+            f.get_metadata_raw(&meta, &key, &Committed::Live).unwrap(),
+            Some("\"weak\"".to_string())
+        );
+        assert!(f.list_transactions().unwrap().is_empty());
+        assert!(!f.journal_path.exists());
+    }
+
+    #[test]
+    fn writes_finish_interrupted_commit() {
+        let dir = TestDir::new("recover-on-write");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let other = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"new\"", &pending).unwrap();
+
+        // Another user of the data store was interrupted after writing its journal.
+        let approved_write = ApprovedWrite {
+            settings: hashmap!(key.clone() => "\"new\"".to_string()),
+            metadata: Vec::new(),
+            deleted_settings: HashSet::new(),
+            deleted_metadata: Vec::new(),
+        };
+        let journal = CommitJournal::new("tx", &approved_write);
+        f.write_journal(&journal).unwrap();
+
+        // A second journal can't replace it.
+        assert!(matches!(
+            f.write_journal(&journal),
+            Err(error::Error::JournalExists { .. })
+        ));
+        assert!(!f.journal_tmp_path.exists());
+
+        // Our next write finishes its commit first.
+        f.set_key(&other, "\"other\"", &Committed::Live).unwrap();
+        assert!(!f.journal_path.exists());
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recover_discards_incomplete_journal() {
+        let dir = TestDir::new("recover-incomplete");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"new\"", &pending).unwrap();
+
+        // Simulate a crash while the journal was still being written.
+        fs::write(&f.journal_tmp_path, "{\"transaction\":").unwrap();
+
+        let f = FilesystemDataStore::new(&dir.0).unwrap();
+        assert!(!f.journal_tmp_path.exists());
+        assert_eq!(f.get_key(&key, &Committed::Live).unwrap(), None);
+        assert_eq!(
+            f.get_key(&key, &pending).unwrap(),
+            Some("\"new\"".to_string())
+        );
+    }
+
+    #[test]
+    fn restore_undoes_commit() {
+        let dir = TestDir::new("restore");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_snapshot_retention(5);
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let added = Key::new(KeyType::Data, "settings.a.c").unwrap();
+        let meta = Key::new(KeyType::Meta, "strength").unwrap();
+        f.set_key(&key, "\"old\"", &Committed::Live).unwrap();
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"new\"", &pending).unwrap();
+        f.set_key(&added, "\"added\"", &pending).unwrap();
+        let approve_with_metadata = |ds: &mut FilesystemDataStore, committed: &Committed| {
+            let approved_write = ApprovedWrite {
+                settings: ds.get_prefix("settings.", committed)?,
+                metadata: vec![(meta.clone(), key.clone(), "\"weak\"".to_string())],
+                deleted_settings: HashSet::new(),
+                deleted_metadata: Vec::new(),
+            };
+            Ok(ConstraintCheckResult::from(Some(approved_write)))
+        };
+        f.commit_transaction("tx", &approve_with_metadata).unwrap();
+
+        let snapshots = f.list_snapshots().unwrap();
+        assert_eq!(
+            snapshots,
+            vec![Snapshot {
+                id: 1,
+                transaction: Some("tx".to_string())
+            }]
+        );
+
+        f.restore(1).unwrap();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"old\"".to_string())
+        );
+        assert_eq!(f.get_key(&added, &Committed::Live).unwrap(), None);
+        assert_eq!(
+            f.get_metadata_raw(&meta, &key, &Committed::Live).unwrap(),
+            None
+        );
+        assert!(!f.restore_path.exists());
+        assert!(!f.old_live_path.exists());
+        f.restore(2).unwrap_err();
+    }
+
+    #[test]
+    fn snapshot_retention() {
+        let dir = TestDir::new("snapshot-retention");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_snapshot_retention(2);
+        let ids: Vec<_> = (0..3).map(|_| f.snapshot().unwrap()).collect();
+        let kept: Vec<_> = f
+            .list_snapshots()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(kept, ids[1..]);
+    }
+
+    #[test]
+    fn recover_finishes_interrupted_restore() {
+        let dir = TestDir::new("recover-restore");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        f.set_key(&key, "\"old\"", &Committed::Live).unwrap();
+        let id = f.snapshot().unwrap();
+        f.set_key(&key, "\"new\"", &Committed::Live).unwrap();
+
+        // Simulate a crash after live was moved aside, before the restored copy was swapped in.
+        copy_tree_sync(&dir.0.join("snapshots/1/live"), &f.restore_path).unwrap();
+        fs::rename(&f.live_path, &f.old_live_path).unwrap();
+
+        let f = FilesystemDataStore::new(&dir.0).unwrap();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"old\"".to_string())
+        );
+        assert!(!f.restore_path.exists());
+        assert!(!f.old_live_path.exists());
+        assert_eq!(f.list_snapshots().unwrap()[0].id, id);
+    }
 }
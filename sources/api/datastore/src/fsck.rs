@@ -0,0 +1,498 @@
+//! Checks the on-disk structure of a FilesystemDataStore.
+//!
+//! Damage to the files behind a FilesystemDataStore, for example from editing an image by hand,
+//! otherwise only shows up as `Error::Corruption` when the damaged key is read.  `check` walks
+//! live data and every pending transaction and reports each problem it finds; see
+//! `ProblemKind`.  `repair` does the same, then moves each damaged entry into a numbered
+//! directory under "quarantine" in the base path, at the same path relative to the base, so
+//! nothing is deleted and it can be inspected or put back by hand.
+//!
+//! Snapshots and the commit journal aren't checked; the journal is replayed or discarded by
+//! `FilesystemDataStore::new`.  Nothing else should be using the data store while it's checked.
+
+use serde_json::Value;
+use snafu::ResultExt;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::filesystem::{
+    decode_path_component, KeyPath, LIVE_DIR_NAME, METADATA_KEY_PREFIX, PENDING_DIR_NAME,
+    TOMBSTONES_DIR_NAME, TRANSACTION_TIMES_FILE_NAME,
+};
+use crate::revision::REVISION_METADATA_KEY;
+use crate::{error, Error, Result};
+
+/// Name of the directory holding quarantined entries, next to "live" and "pending".
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// Metadata that's expected to exist without its data key.  Setting generators are recorded
+/// before the setting is generated, and revisions are kept after a key is unset so they keep
+/// increasing if it's set again.
+const UNPOPULATED_METADATA_KEYS: &[&str] = &["setting-generator", REVISION_METADATA_KEY];
+
+/// The kinds of damage `check` can find.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The live directory doesn't exist.  This can't be quarantined.
+    MissingLive,
+    /// A file or directory name isn't valid UTF-8.
+    NonUtf8Name,
+    /// A name's percent-encoding doesn't decode to valid UTF-8.
+    BadEncoding,
+    /// A file name decodes, but not to a valid key.
+    InvalidKey { msg: String },
+    /// A data or metadata value isn't a JSON scalar.
+    InvalidValue { msg: String },
+    /// A metadata file whose data key has no value or child keys.
+    OrphanedMetadata,
+    /// A directory with nothing in it; the data store removes directories as they're emptied.
+    EmptyDirectory,
+    /// A symbolic link; the data store never creates them, and doesn't follow them.
+    Symlink,
+    /// Something other than a transaction directory in "pending", or anything other than a
+    /// regular file or directory elsewhere.
+    StrayFile,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProblemKind::MissingLive => write!(f, "live data directory is missing"),
+            ProblemKind::NonUtf8Name => write!(f, "name is not valid UTF-8"),
+            ProblemKind::BadEncoding => write!(f, "name has invalid percent-encoding"),
+            ProblemKind::InvalidKey { msg } => write!(f, "not a valid key: {msg}"),
+            ProblemKind::InvalidValue { msg } => write!(f, "not a valid value: {msg}"),
+            ProblemKind::OrphanedMetadata => write!(f, "metadata for a key that doesn't exist"),
+            ProblemKind::EmptyDirectory => write!(f, "empty directory"),
+            ProblemKind::Symlink => write!(f, "symbolic link"),
+            ProblemKind::StrayFile => write!(f, "unexpected file"),
+        }
+    }
+}
+
+/// A problem found at a path inside the data store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.kind)
+    }
+}
+
+/// The results of `check` or `repair`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// Every problem found, in the order found.
+    pub problems: Vec<Problem>,
+    /// The original and quarantined paths of each entry moved by `repair`.
+    pub quarantined: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Report {
+    /// Returns true if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Returns the problems that weren't quarantined.
+    pub fn unrepaired(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(move |problem| {
+            !self
+                .quarantined
+                .iter()
+                .any(|(original, _)| *original == problem.path)
+        })
+    }
+}
+
+/// Checks the data store at the given base path, returning every problem found.  Errors are
+/// only returned if the data store couldn't be read.
+pub fn check<P: AsRef<Path>>(base_path: P) -> Result<Report> {
+    Ok(Report {
+        problems: find_problems(base_path.as_ref())?,
+        quarantined: Vec::new(),
+    })
+}
+
+/// Checks the data store at the given base path and moves each damaged entry into a new
+/// directory under "quarantine".  Moving entries can leave their directories empty, so we check
+/// again until nothing more is found.
+pub fn repair<P: AsRef<Path>>(base_path: P) -> Result<Report> {
+    let base = base_path.as_ref();
+    let quarantine = next_quarantine_dir(base)?;
+    let mut report = Report::default();
+
+    loop {
+        let mut moved = false;
+        for problem in find_problems(base)? {
+            if problem.kind != ProblemKind::MissingLive {
+                let relative = problem.path.strip_prefix(base).context(error::PathSnafu)?;
+                let target = quarantine.join(relative);
+                quarantine_entry(&problem, &target)?;
+                report.quarantined.push((problem.path.clone(), target));
+                moved = true;
+            }
+            if !report.problems.contains(&problem) {
+                report.problems.push(problem);
+            }
+        }
+        if !moved {
+            return Ok(report);
+        }
+    }
+}
+
+/// Returns a new, numbered quarantine directory path for this repair, like snapshot IDs.
+fn next_quarantine_dir(base: &Path) -> Result<PathBuf> {
+    let quarantine = base.join(QUARANTINE_DIR_NAME);
+    let entries = match fs::read_dir(&quarantine) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(quarantine.join("1")),
+        Err(e) => return Err(e).context(error::IoSnafu { path: quarantine }),
+    };
+    let mut last = 0;
+    for entry in entries {
+        let entry = entry.context(error::IoSnafu { path: &quarantine })?;
+        if let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.parse::<u64>().ok())
+        {
+            last = last.max(id);
+        }
+    }
+    Ok(quarantine.join((last + 1).to_string()))
+}
+
+/// Moves the entry with the given problem to the given quarantine path.
+fn quarantine_entry(problem: &Problem, target: &Path) -> Result<()> {
+    let path = &problem.path;
+    if problem.kind == ProblemKind::EmptyDirectory {
+        // An earlier pass may have already quarantined entries from inside it.
+        fs::create_dir_all(target).context(error::IoSnafu { path: target })?;
+        return fs::remove_dir(path).context(error::IoSnafu { path });
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context(error::IoSnafu { path: parent })?;
+    }
+    fs::rename(path, target).context(error::IoSnafu { path })
+}
+
+/// Finds the problems in live data and each pending transaction.
+fn find_problems(base: &Path) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    let live = base.join(LIVE_DIR_NAME);
+    if live.is_dir() {
+        check_tree(&live, None, &mut problems)?;
+    } else {
+        problems.push(Problem {
+            path: live.clone(),
+            kind: ProblemKind::MissingLive,
+        });
+    }
+
+    let pending = base.join(PENDING_DIR_NAME);
+    let entries = match fs::read_dir(&pending) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(problems),
+        Err(e) => return Err(e).context(error::IoSnafu { path: pending }),
+    };
+    let mut transactions = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()
+        .context(error::IoSnafu { path: &pending })?;
+    transactions.sort();
+
+    for path in transactions {
+        let file_type = fs::symlink_metadata(&path)
+            .context(error::IoSnafu { path: &path })?
+            .file_type();
+        let kind = if file_type.is_symlink() {
+            Some(ProblemKind::Symlink)
+        } else if !file_type.is_dir() {
+            Some(ProblemKind::StrayFile)
+        } else {
+            check_name(path.file_name().unwrap_or_default(), &path)
+        };
+        match kind {
+            Some(kind) => problems.push(Problem { path, kind }),
+            None => check_tree(&path, Some(&live), &mut problems)?,
+        }
+    }
+    Ok(problems)
+}
+
+/// Checks the tree of keys under `root`.  For a pending transaction, `live` is the live
+/// directory, where the data keys for pending metadata may be instead.
+fn check_tree(root: &Path, live: Option<&Path>, problems: &mut Vec<Problem>) -> Result<()> {
+    let mut walker = WalkDir::new(root)
+        .follow_links(false)
+        .same_file_system(true)
+        .sort_by_file_name()
+        .into_iter();
+
+    while let Some(entry) = walker.next() {
+        let entry = entry.context(error::ListKeysSnafu)?;
+        if entry.depth() == 0 {
+            continue;
+        }
+        let path = entry.path();
+        let mut problem = |kind| {
+            problems.push(Problem {
+                path: path.to_path_buf(),
+                kind,
+            })
+        };
+
+        // Tombstones mirror the paths of the transaction's keys, but have no values.
+        let relative = path.strip_prefix(root).context(error::PathSnafu)?;
+        if live.is_some() && relative == Path::new(TRANSACTION_TIMES_FILE_NAME) {
+            continue;
+        }
+        let tombstone = live.is_some() && relative.starts_with(TOMBSTONES_DIR_NAME);
+        let key_relative = if tombstone {
+            relative
+                .strip_prefix(TOMBSTONES_DIR_NAME)
+                .context(error::PathSnafu)?
+        } else {
+            relative
+        };
+
+        let file_type = entry.file_type();
+        if file_type.is_symlink() {
+            problem(ProblemKind::Symlink);
+        } else if file_type.is_dir() {
+            let name_problem = if key_relative.as_os_str().is_empty() {
+                None
+            } else {
+                check_name(entry.file_name(), path)
+            };
+            if let Some(kind) = name_problem {
+                // Everything under it is unreadable too; report it once.
+                problem(kind);
+                walker.skip_current_dir();
+            } else if is_empty_dir(path)? {
+                problem(ProblemKind::EmptyDirectory);
+            }
+        } else if !file_type.is_file() {
+            problem(ProblemKind::StrayFile);
+        } else if let Some(kind) = check_file(path, key_relative, tombstone, root, live)? {
+            problem(kind);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that a file or directory name is an encoded key segment.
+fn check_name(name: &OsStr, path: &Path) -> Option<ProblemKind> {
+    match name.to_str() {
+        None => Some(ProblemKind::NonUtf8Name),
+        Some(name) => decode_path_component(name, path)
+            .err()
+            .map(|_| ProblemKind::BadEncoding),
+    }
+}
+
+/// Checks a data, metadata, or tombstone file.  `key_relative` is its path relative to the
+/// root of its tree of keys.
+fn check_file(
+    path: &Path,
+    key_relative: &Path,
+    tombstone: bool,
+    root: &Path,
+    live: Option<&Path>,
+) -> Result<Option<ProblemKind>> {
+    if key_relative.to_str().is_none() {
+        return Ok(Some(ProblemKind::NonUtf8Name));
+    }
+    let key_path = match KeyPath::from_path(key_relative) {
+        Ok(key_path) => key_path,
+        Err(Error::Corruption { .. }) => return Ok(Some(ProblemKind::BadEncoding)),
+        Err(e) => return Ok(Some(ProblemKind::InvalidKey { msg: e.to_string() })),
+    };
+    if tombstone {
+        return Ok(None);
+    }
+
+    let bytes = fs::read(path).context(error::IoSnafu { path })?;
+    match serde_json::from_slice::<Value>(&bytes) {
+        Err(e) => return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() })),
+        Ok(Value::Object(_)) => {
+            return Ok(Some(ProblemKind::InvalidValue {
+                msg: "objects must be stored as separate keys".to_string(),
+            }))
+        }
+        Ok(_) => {}
+    }
+
+    if let Some(metadata_key) = key_path.metadata_key {
+        if UNPOPULATED_METADATA_KEYS.contains(&metadata_key.name().as_str()) {
+            return Ok(None);
+        }
+        // The data key's file, or its directory of child keys, sits next to the metadata.
+        let file_name = key_relative
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default();
+        let (data_name, _) = file_name
+            .split_once(METADATA_KEY_PREFIX)
+            .unwrap_or((file_name, ""));
+        let data_relative = key_relative.with_file_name(data_name);
+        let populated = root.join(&data_relative).exists()
+            || live.is_some_and(|live| live.join(&data_relative).exists());
+        if !populated {
+            return Ok(Some(ProblemKind::OrphanedMetadata));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns true if the given directory has no entries.
+fn is_empty_dir(path: &Path) -> Result<bool> {
+    Ok(fs::read_dir(path)
+        .context(error::IoSnafu { path })?
+        .next()
+        .is_none())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TestDir;
+    use crate::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+
+    /// Writes a file under the base path, creating its directories.
+    fn write(base: &Path, relative: &str, value: &str) {
+        let path = base.join(relative);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value).unwrap();
+    }
+
+    /// Returns a data store with a few healthy keys and metadata, including a pending
+    /// transaction with tombstones.
+    fn healthy(name: &str) -> TestDir {
+        let dir = TestDir::new(name);
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = |name| Key::new(KeyType::Data, name).unwrap();
+        let meta = |name| Key::new(KeyType::Meta, name).unwrap();
+        f.set_key(&key("settings.motd"), "\"hi\"", &Committed::Live)
+            .unwrap();
+        f.set_key(&key("settings.ntp.servers"), "[\"a\"]", &Committed::Live)
+            .unwrap();
+        f.set_metadata(
+            &meta("affected-services"),
+            &key("settings.ntp"),
+            "[\"ntp\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+        f.set_metadata(
+            &meta("setting-generator"),
+            &key("settings.seed"),
+            "\"gen\"",
+            &Committed::Live,
+        )
+        .unwrap();
+
+        let pending = Committed::Pending { tx: "my tx".into() };
+        f.set_key(&key("settings.a"), "1", &pending).unwrap();
+        f.unset_key(&key("settings.motd"), &pending).unwrap();
+        f.set_metadata(&meta("m"), &key("settings.motd"), "true", &pending)
+            .unwrap();
+        dir
+    }
+
+    #[test]
+    fn healthy_datastore_is_clean() {
+        let dir = healthy("fsck-clean");
+        assert_eq!(check(&dir.0).unwrap(), Report::default());
+    }
+
+    #[test]
+    fn finds_problems() {
+        let dir = healthy("fsck-problems");
+        let base = &dir.0;
+        write(base, "live/settings/bad%FF", "1");
+        write(base, "live/settings/object", "{\"a\": 1}");
+        write(base, "live/settings/junk", "not json");
+        write(base, "live/settings/gone.affected-services", "[]");
+        write(base, "live/settings/Bad%20Key", "1");
+        write(base, "pending/stray", "");
+        fs::create_dir_all(base.join("live/settings/empty")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", base.join("live/settings/link")).unwrap();
+
+        let problems: Vec<_> = check(base)
+            .unwrap()
+            .problems
+            .into_iter()
+            .map(|problem| {
+                let path = problem.path.strip_prefix(base).unwrap().to_path_buf();
+                (path.display().to_string(), problem.kind)
+            })
+            .collect();
+        let kinds: Vec<_> = problems.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            kinds,
+            [
+                "live/settings/Bad%20Key",
+                "live/settings/bad%FF",
+                "live/settings/empty",
+                "live/settings/gone.affected-services",
+                "live/settings/junk",
+                "live/settings/link",
+                "live/settings/object",
+                "pending/stray",
+            ]
+        );
+        assert!(matches!(problems[0].1, ProblemKind::InvalidKey { .. }));
+        assert_eq!(problems[1].1, ProblemKind::BadEncoding);
+        assert_eq!(problems[2].1, ProblemKind::EmptyDirectory);
+        assert_eq!(problems[3].1, ProblemKind::OrphanedMetadata);
+        assert!(matches!(problems[4].1, ProblemKind::InvalidValue { .. }));
+        assert_eq!(problems[5].1, ProblemKind::Symlink);
+        assert!(matches!(problems[6].1, ProblemKind::InvalidValue { .. }));
+        assert_eq!(problems[7].1, ProblemKind::StrayFile);
+    }
+
+    #[test]
+    fn repair_quarantines() {
+        let dir = healthy("fsck-repair");
+        let base = &dir.0;
+        write(base, "live/broken/junk", "not json");
+
+        let report = repair(base).unwrap();
+        // Moving the file leaves its directory empty, so that's quarantined too.
+        assert_eq!(
+            report.quarantined,
+            [
+                (
+                    base.join("live/broken/junk"),
+                    base.join("quarantine/1/live/broken/junk")
+                ),
+                (
+                    base.join("live/broken"),
+                    base.join("quarantine/1/live/broken")
+                ),
+            ]
+        );
+        assert_eq!(report.unrepaired().count(), 0);
+        assert_eq!(
+            fs::read_to_string(base.join("quarantine/1/live/broken/junk")).unwrap(),
+            "not json"
+        );
+        assert!(check(base).unwrap().is_clean());
+
+        // Each repair gets its own quarantine directory.
+        write(base, "live/junk", "not json");
+        let report = repair(base).unwrap();
+        assert_eq!(report.quarantined[0].1, base.join("quarantine/2/live/junk"));
+    }
+}
@@ -12,6 +12,10 @@ pub const KEY_SEPARATOR: char = '.';
 // String refs are more convenient for some Rust functions
 pub const KEY_SEPARATOR_STR: &str = ".";
 
+/// List indexes are written in brackets after the list's name, e.g. a.b[0].c.
+const INDEX_OPEN: char = '[';
+const INDEX_CLOSE: char = ']';
+
 /// Maximum key name length matches the maximum filename length of 255; if we need to have longer
 /// keys (up to 4096) we could make prefixes not count against this limit.
 const MAX_KEY_NAME_LENGTH: usize = 255;
@@ -29,6 +33,9 @@ pub enum KeyType {
 ///
 /// Keys that need to include dots in the name can quote that segment of the name, for example the
 /// key a."b.c".d has three segments: "a", "b.c", and "d".
+///
+/// Elements of lists that can't be stored as a single value are addressed by index, for example
+/// the key a.b[0].c has four segments: "a", "b", "[0]", and "c".  See `Key::index_segment`.
 #[derive(Clone, Debug)]
 pub struct Key {
     name: String,
@@ -89,42 +96,6 @@ impl Key {
         })
     }
 
-    /// Removes the given prefix from the key name, returning a new Key.
-    ///
-    /// This is intended to remove key name segments from the beginning of the name, therefore
-    /// this only makes sense for Data keys, not Meta keys.  A Data key will be returned.
-    ///
-    /// You should not include an ending separator (dot), it will be removed for you.
-    ///
-    /// If the key name does not begin with the given prefix, the returned key will be
-    /// identical.
-    ///
-    /// Fails if the new key would be invalid, e.g. if the prefix is the entire key.
-    pub(super) fn strip_prefix<S>(&self, prefix: S) -> Result<Self>
-    where
-        S: AsRef<str>,
-    {
-        let prefix = prefix.as_ref();
-        ensure!(
-            prefix != self.name,
-            error::InvalidKeySnafu {
-                name: "",
-                msg: format!("strip_prefix of '{prefix}' matches key")
-            }
-        );
-
-        let strip = prefix.to_string() + ".";
-
-        // Check starts_with so we don't replace in the middle of the string...
-        let name = if self.name.starts_with(&strip) {
-            self.name.replacen(&strip, "", 1)
-        } else {
-            self.name.clone()
-        };
-
-        Self::new(KeyType::Data, name)
-    }
-
     /// Removes the given key segments from the beginning of the key, returning a new Key.
     ///
     /// This only makes sense for Data keys because Meta keys only have one segment.  A Data key
@@ -189,6 +160,27 @@ impl Key {
         Self::from_segments(KeyType::Data, &new_segments)
     }
 
+    /// Returns the key segment that addresses the list element with the given index, e.g. "[0]".
+    pub fn index_segment(index: usize) -> String {
+        format!("{INDEX_OPEN}{index}{INDEX_CLOSE}")
+    }
+
+    /// Returns the list index addressed by the given key segment, if it's an index segment.
+    /// Indexes are written without leading zeros, so each element has exactly one key.
+    pub fn parse_index_segment<S: AsRef<str>>(segment: S) -> Option<usize> {
+        let digits = segment
+            .as_ref()
+            .strip_prefix(INDEX_OPEN)?
+            .strip_suffix(INDEX_CLOSE)?;
+        if digits.is_empty()
+            || !digits.chars().all(|c| c.is_ascii_digit())
+            || (digits.len() > 1 && digits.starts_with('0'))
+        {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
     /// Additional safety checks for parsed or generated keys.
     fn check_key<S1, S2>(key_type: KeyType, name: S1, segments: &[S2]) -> Result<()>
     where
@@ -236,11 +228,13 @@ impl Key {
     }
 
     /// Given a key name, returns a list of its name segments, separated by KEY_SEPARATOR.
-    /// Respects quoting of segments so they can contain dots.
+    /// Respects quoting of segments so they can contain dots, and treats list indexes as separate
+    /// segments.
     ///
     /// Examples:
     /// * a.b.c -> ["a", "b", "c"]
     /// * "a.b".c -> ["a.b", "c"]
+    /// * a.b[0].c -> ["a", "b", "[0]", "c"]
     fn parse_name_segments<S: AsRef<str>>(name: S) -> Result<Vec<String>> {
         let name = name.as_ref();
 
@@ -258,10 +252,61 @@ impl Key {
         let mut segment = String::new();
         // Track whether we're inside a quoted section of the key name
         let mut in_quotes = false;
+        // Track whether the last segment was a list index, which can end the name or be followed
+        // directly by another index, without a separator.
+        let mut after_index = false;
+
+        // Walk through each character, looking for quotes, separators, or indexes to update state
+        let mut chars = name.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == INDEX_OPEN && !in_quotes {
+                // An index follows a segment or another index, or starts a relative key.
+                ensure!(
+                    !segment.is_empty() || after_index || segments.is_empty(),
+                    error::InvalidKeySnafu {
+                        name,
+                        msg: "list index must follow a key segment",
+                    }
+                );
+                if !segment.is_empty() {
+                    segments.push(segment);
+                    segment = String::new();
+                }
 
-        // Walk through each character, looking for quotes or separators to update state
-        for c in name.chars() {
-            if c == '"' {
+                let mut index = String::from(INDEX_OPEN);
+                for c in chars.by_ref() {
+                    index.push(c);
+                    if c == INDEX_CLOSE {
+                        break;
+                    }
+                }
+                ensure!(
+                    Self::parse_index_segment(&index).is_some(),
+                    error::InvalidKeySnafu {
+                        name,
+                        msg: format!("invalid list index '{index}'"),
+                    }
+                );
+                segments.push(index);
+
+                // After an index comes another index, a separator and the next segment, or the
+                // end of the name.
+                after_index = true;
+                match chars.peek() {
+                    None | Some(&INDEX_OPEN) => {}
+                    Some(&KEY_SEPARATOR) => {
+                        chars.next();
+                        after_index = false;
+                    }
+                    Some(_) => {
+                        return error::InvalidKeySnafu {
+                            name,
+                            msg: "list index must be followed by a separator",
+                        }
+                        .fail()
+                    }
+                }
+            } else if c == '"' {
                 // Quotes don't go into the name segments, so we just flip the flag.
                 in_quotes = !in_quotes;
             } else if c == KEY_SEPARATOR {
@@ -303,29 +348,41 @@ impl Key {
                 msg: "unbalanced quotes",
             }
         );
-        ensure!(
-            !segment.is_empty(),
-            error::InvalidKeySnafu {
-                name,
-                msg: "ends with separator",
-            }
-        );
-
-        // Push final segment (keys don't end with a dot, which is when we normally push)
-        segments.push(segment);
+        if segment.is_empty() {
+            // Only an index can end the name without a final segment.
+            ensure!(
+                after_index,
+                error::InvalidKeySnafu {
+                    name,
+                    msg: "ends with separator",
+                }
+            );
+        } else {
+            // Push final segment (keys don't end with a dot, which is when we normally push)
+            segments.push(segment);
+        }
 
         trace!("Parsed key name '{name}' to segments {segments:?}");
         Ok(segments)
     }
 
     /// Given a list of key name segments, encodes them into a name string.  Any segments with
-    /// special characters (like the separator) are quoted.
+    /// special characters (like the separator) are quoted, and list indexes are appended to the
+    /// previous segment without a separator.
     fn encode_name_segments<S: AsRef<str>>(segments: &[S]) -> Result<String> {
         let segments: Vec<_> = segments.iter().map(|s| s.as_ref()).collect();
-        let mut outputs = Vec::new();
+        let mut name = String::new();
 
         // Check whether we need quoting for each segment.
-        for segment in segments.iter() {
+        for (i, segment) in segments.iter().enumerate() {
+            if Self::parse_index_segment(segment).is_some() {
+                name.push_str(segment);
+                continue;
+            }
+            if i > 0 {
+                name.push(KEY_SEPARATOR);
+            }
+
             for chr in segment.chars() {
                 ensure!(
                     chr == KEY_SEPARATOR || Self::valid_character(chr),
@@ -339,19 +396,30 @@ impl Key {
 
             if segment.chars().any(|c| c == KEY_SEPARATOR) {
                 // Includes separator; quote the segment.
-                outputs.push(format!("\"{segment}\""));
+                name.push_str(&format!("\"{segment}\""));
             } else {
                 // No special characters, no escaping needed.
-                outputs.push(segment.to_string());
+                name.push_str(segment);
             }
         }
 
-        // Join the (possibly quoted) segments with our separator.
-        let name = outputs.join(KEY_SEPARATOR_STR);
         trace!("Encoded key '{name}' from segments {segments:?}");
         Ok(name)
     }
 
+    /// Splits a key name prefix into the segments it names, for matching keys with
+    /// `starts_with_segments`.  Prefixes match whole segments, so "a.b" matches "a.b" and "a.b.c"
+    /// but not "a.bc".  A trailing separator is ignored, and an empty prefix has no segments, so
+    /// it matches every key.
+    pub fn prefix_segments<S: AsRef<str>>(prefix: S) -> Result<Vec<String>> {
+        let prefix = prefix.as_ref();
+        let prefix = prefix.strip_suffix(KEY_SEPARATOR).unwrap_or(prefix);
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+        Self::parse_name_segments(prefix)
+    }
+
     pub fn starts_with_segments<S>(&self, segments: &[S]) -> bool
     where
         S: AsRef<str>,
@@ -480,36 +548,6 @@ mod test {
         data_and_meta!(|t| assert!(Key::new(t, "a.").is_err()));
     }
 
-    #[test]
-    fn strip_prefix_ok() {
-        // Remove plain prefix
-        let key = Key::new(KeyType::Data, "a.b.c.d").unwrap();
-        let prefix = "a.b";
-        assert_eq!(key.strip_prefix(prefix).unwrap().name(), "c.d");
-
-        // Don't remove non-matching prefix; no change
-        let key = Key::new(KeyType::Data, "a.b.c.d").unwrap();
-        let prefix = "x.y";
-        assert_eq!(key.strip_prefix(prefix).unwrap().name(), "a.b.c.d");
-
-        // Don't remove prefix that doesn't match whole quoted segment
-        let key = Key::new(KeyType::Data, "a.\"b.c\".d").unwrap();
-        let prefix = "a.b";
-        assert_eq!(key.strip_prefix(prefix).unwrap().name(), "a.\"b.c\".d");
-
-        // Do remove prefix that does match whole quoted segment
-        let key = Key::new(KeyType::Data, "a.\"b.c\".d").unwrap();
-        let prefix = "a.\"b.c\"";
-        assert_eq!(key.strip_prefix(prefix).unwrap().name(), "d");
-    }
-
-    #[test]
-    fn strip_prefix_err() {
-        let key = Key::new(KeyType::Data, "a.b.c.d").unwrap();
-        let prefix = "a.b.c.d";
-        key.strip_prefix(prefix).unwrap_err();
-    }
-
     #[test]
     fn strip_prefix_segments_ok() {
         // Remove plain prefix
@@ -543,6 +581,39 @@ mod test {
         key.strip_prefix_segments(prefix).unwrap_err();
     }
 
+    // Callers used to strip prefixes given as key names; the same names, split with
+    // prefix_segments, must strip the same way.
+    #[test]
+    fn strip_prefix_names_ok() {
+        let strip = |name: &str, prefix: &str| {
+            let key = Key::new(KeyType::Data, name).unwrap();
+            let prefix = Key::prefix_segments(prefix).unwrap();
+            key.strip_prefix_segments(&prefix).unwrap().name().clone()
+        };
+
+        // Remove plain prefix
+        assert_eq!(strip("a.b.c.d", "a.b"), "c.d");
+
+        // Don't remove non-matching prefix; no change
+        assert_eq!(strip("a.b.c.d", "x.y"), "a.b.c.d");
+
+        // Don't remove prefix that doesn't match whole quoted segment
+        assert_eq!(strip("a.\"b.c\".d", "a.b"), "a.\"b.c\".d");
+
+        // Do remove prefix that does match whole quoted segment
+        assert_eq!(strip("a.\"b.c\".d", "a.\"b.c\""), "d");
+
+        // Do remove prefix ending in a list index
+        assert_eq!(strip("a.b[0].c", "a.b[0]"), "c");
+    }
+
+    #[test]
+    fn strip_prefix_names_err() {
+        let key = Key::new(KeyType::Data, "a.b.c.d").unwrap();
+        let prefix = Key::prefix_segments("a.b.c.d").unwrap();
+        key.strip_prefix_segments(&prefix).unwrap_err();
+    }
+
     #[test]
     fn append_segments_ok() {
         let key = Key::new(KeyType::Data, "a.b").unwrap();
@@ -588,4 +659,49 @@ mod test {
         assert!(!key.starts_with_segments(&["\"a.b\""]));
         assert!(!key.starts_with_segments(&["a."]));
     }
+
+    #[test]
+    fn list_index_ok() {
+        let key = Key::new(KeyType::Data, "a.b[0].c").unwrap();
+        assert_eq!(key.segments(), &["a", "b", "[0]", "c"]);
+
+        let key = Key::new(KeyType::Data, "a.\"b.c\"[10][2]").unwrap();
+        assert_eq!(key.segments(), &["a", "b.c", "[10]", "[2]"]);
+
+        // Keys relative to a list start with an index.
+        let key = Key::new(KeyType::Data, "[1].c").unwrap();
+        assert_eq!(key.segments(), &["[1]", "c"]);
+
+        let segments = &["a", "b", "[3]", "[0]", "c.d"];
+        let key = Key::from_segments(KeyType::Data, segments).unwrap();
+        assert_eq!(key.name(), "a.b[3][0].\"c.d\"");
+        assert_eq!(Key::new(KeyType::Data, key.name()).unwrap(), key);
+    }
+
+    #[test]
+    fn list_index_bad_format() {
+        for name in [
+            "a.[0]", "a[0]b", "a[0].", "a[]", "a[01]", "a[x]", "a[0", "a]", "\"a[0]\"",
+        ] {
+            assert!(Key::new(KeyType::Data, name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn index_segment() {
+        assert_eq!(Key::index_segment(7), "[7]");
+        assert_eq!(Key::parse_index_segment("[7]"), Some(7));
+        assert_eq!(Key::parse_index_segment("[0]"), Some(0));
+        assert_eq!(Key::parse_index_segment("7"), None);
+        assert_eq!(Key::parse_index_segment("[07]"), None);
+    }
+
+    #[test]
+    fn prefix_segments() {
+        assert!(Key::prefix_segments("").unwrap().is_empty());
+        assert_eq!(Key::prefix_segments("a.b").unwrap(), vec!["a", "b"]);
+        assert_eq!(Key::prefix_segments("a.b.").unwrap(), vec!["a", "b"]);
+        assert_eq!(Key::prefix_segments("a.\"b.c\"").unwrap(), vec!["a", "b.c"]);
+        Key::prefix_segments("a..").unwrap_err();
+    }
 }
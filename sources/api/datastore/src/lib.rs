@@ -7,7 +7,7 @@ For more detail about their usage, see [apiserver](../apiserver).
 
 # Library
 
-This library provides a trait defining the exact requirements, along with basic implementations for filesystem and memory data stores.
+This library provides a trait defining the exact requirements, along with basic implementations for filesystem, single-file log, and memory data stores.
 
 There's also a common error type and some methods that implementations of DataStore should generally share, like scalar serialization.
 
@@ -20,30 +20,151 @@ The `serialization` module provides code to serialize Rust types into a mapping
 
 The `deserialization` module provides code to deserialize datastore-acceptable keys (a.b.c) and values into Rust types.
 
-# Current limitations
+Lists of scalars are stored as a single value.
+Lists containing structures or maps are stored by index instead, with keys like a.b[0].c, and `set_keys` replaces such a list as a whole, so elements left over from a longer list are removed.
 
-* The user (e.g. apiserver) needs to handle locking.
-* There's no support for rolling back transactions.
-* The `serialization` module can't handle complex types under lists; it assumes lists can be serialized as scalars.
+# Transaction diffs
+
+`diff_transaction` describes what committing a pending transaction would change: the keys it would add, modify, and remove, and the metadata it would change.
+The result can be serialized, for example to JSON, to show the changes before committing.
+
+# Pattern queries
+
+`query` returns the data keys matching a `KeyPattern`, where `*` matches exactly one key segment and `**` matches any number of them, like `settings.host-containers.*.enabled`; `get_metadata_query` does the same for metadata.
+See the `pattern` module.
+
+# Revisions
+
+Each live data key carries a revision counter that increases whenever the key is changed, stored as the reserved metadata key "revision".
+Revisions are left out of metadata listings, so they aren't exported or given to migrations.
+Writers can use `set_key_if_revision` and `commit_transaction_if_unchanged` to make sure nobody changed the keys they read in the meantime; if someone did, they fail with `Error::Conflict`.
+See the `revision` module.
+
+# Export and import
+
+`export` writes a whole data store, including metadata and pending transactions, as a single TOML or JSON document using the same layout as the default settings files, and `import` loads one back.
+See the `document` module.
+
+# Snapshots
+
+Data stores configured to keep snapshots take one of live data and metadata before each commit, so a bad commit can be undone with `restore`; commits don't take snapshots by default.
+Snapshots can also be taken on demand with `snapshot`, and `list_snapshots` shows the transaction each one was taken for.
+Only a configurable number of the most recent snapshots are kept; see the `snapshot` module.
+
+# Single-file log
+
+`LogDataStore` keeps live data and every pending transaction in a single append-only log file, flushed with fsync after each change and compacted periodically, rather than one file per key like `FilesystemDataStore`.
+`copy` copies one data store into another, so an existing node can be switched between the two formats; the `datastore-convert` binary runs it.
+See the `logstore` module.
+
+# Layered defaults
+
+`OverlayDataStore` layers a writable data store over a read-only one holding defaults, so defaults and user-set values are kept separate.
+Reads fall through from the upper layer to the defaults, writes go to the upper layer, and unsetting a key in the upper layer resets it to its default.
+`is_overridden` tells whether a key's value comes from the upper layer.
+See the `overlay` module.
+
+# Change notification
+
+`subscribe` returns a `Subscription` that receives a `ChangeEvent`, naming the transaction and the changed keys, for each commit that touches a key prefix, so services can react to setting changes without polling.
+`FilesystemDataStore` subscriptions watch the data store with inotify, so they also see commits made by other processes.
+See the `watch` module.
+
+# Transaction expiry
+
+Data stores record when each pending transaction was created and last changed, which `list_transaction_times` returns.
+A client that dies before committing its transaction leaves it pending, and migrations run over it like any other, so `expire_transactions` deletes transactions that haven't been changed for a given time.
+The boot-time transaction, `LAUNCH_TRANSACTION`, is never expired.
+See the `expiry` module.
+
+# Sensitive settings
+
+Keys whose metadata has "sensitive" set to `true`, directly or inherited from a parent key, hold secrets like credentials.
+A `FilesystemDataStore` opened `with_encryption_key` encrypts their values at rest with a host-local `EncryptionKey`, and fails rather than storing them in the clear without one.
+The host's key file is kept at `sensitive::DEFAULT_KEY_PATH`.
+Transaction diffs and `Debug` output show their values as `<redacted>`.
+See the `sensitive` module.
+
+# Async access
+
+`AsyncDataStore` offers the same operations as `DataStore` as async functions, for callers on a tokio runtime like the API server.
+`BlockingDataStore` runs any `DataStore`, such as a `FilesystemDataStore`, on tokio's blocking thread pool so its I/O doesn't stall the executor, and `AsyncMemoryDataStore` is an in-memory implementation.
+It's behind the `async` cargo feature, so callers that don't need it don't build tokio.
+See the `asynchronous` module.
+
+# Integrity checks
+
+`fsck::check` walks a `FilesystemDataStore` on disk and reports damage that would otherwise only show up as errors when the damaged keys are read, like badly encoded names, invalid values, and orphaned metadata.
+`fsck::repair` moves damaged entries into a "quarantine" directory rather than deleting them.
+The `datastore-fsck` binary runs either one against a data store, for example in an offline image.
+
+# Constraint checks
+
+`commit_transaction` runs a constraint check on the transaction before committing it.
+A `ConstraintCheck` returns a `Rejection` for each problem it finds, naming the check, the offending key, and the reason, and a rejected commit returns all of them in `Error::ConstraintCheckReject`.
+Checks can be chained with `and`, and a `ConstraintRegistry` holds named checks that only run when a transaction writes under their settings prefix; pass either to `commit_transaction_checked`.
+See the `constraints_check` module.
+
+# Conformance
+
+`conformance::run` takes a function that creates empty data stores and runs a behavioral test suite against them, covering keys, metadata and its inheritance, transactions, constraint checks, and prefix listing.
+Every `DataStore` implementation here runs it, and new implementations should too, so they all behave the same way.
+
+# Locking
+
+`FilesystemDataStore` and `MemoryDataStore` don't lock anything themselves.
+To share a data store between threads or processes, for example the apiserver, migrations, and boot-time services, open it through `LockedDataStore`, which takes shared `flock` locks for reads and exclusive locks for writes.
+See the `lock` module.
 */
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod conformance;
 pub mod constraints_check;
 pub mod deserialization;
+pub mod diff;
+pub mod document;
 pub mod error;
+pub mod expiry;
 pub mod filesystem;
+pub mod fsck;
 pub mod key;
+pub mod lock;
+pub mod logstore;
 pub mod memory;
+pub mod overlay;
+pub mod pattern;
+pub mod revision;
+pub mod sensitive;
 pub mod serialization;
-
-use constraints_check::ConstraintCheckResult;
+pub mod snapshot;
+#[cfg(test)]
+mod test_util;
+pub mod watch;
+
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncDataStore, AsyncMemoryDataStore, BlockingDataStore};
+use constraints_check::{ConstraintCheck, ConstraintCheckResult};
+pub use diff::TransactionDiff;
+pub use document::{copy, export, import, DocumentFormat};
 pub use error::{Error, Result};
+pub use expiry::TransactionTimes;
 pub use filesystem::FilesystemDataStore;
 pub use key::{Key, KeyType, KEY_SEPARATOR, KEY_SEPARATOR_STR};
-
-use log::{info, trace};
+pub use lock::LockedDataStore;
+pub use logstore::LogDataStore;
+pub use overlay::OverlayDataStore;
+pub use pattern::KeyPattern;
+pub use revision::Revision;
+pub use sensitive::EncryptionKey;
+pub use snapshot::{Snapshot, SnapshotId};
+pub use watch::{ChangeEvent, Subscription};
+
+use log::{debug, info, trace};
 use serde::{Deserialize, Serialize};
 use snafu::OptionExt;
 use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 /// Committed represents whether we want to look at pending (uncommitted) or live (committed) data
 /// in the datastore.
@@ -60,15 +181,24 @@ pub trait DataStore {
     /// Returns whether a key is present (has a value) in the datastore.
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool>;
     /// Returns a list of the populated data keys in the datastore whose names start with the given
-    /// prefix.
+    /// prefix.  The prefix matches whole key segments; see `Key::prefix_segments`.
     fn list_populated_keys<S: AsRef<str>>(
         &self,
         prefix: S,
         committed: &Committed,
     ) -> Result<HashSet<Key>>;
+    /// Returns a list of the data keys whose names start with the given prefix that were unset in
+    /// the given pending transaction, and so will be removed from live when it's committed.
+    /// Always empty for live data.
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>>;
     /// Finds all metadata keys that are currently populated in the datastore whose data keys
-    /// start with the given prefix.  If you specify metadata_key_name, only metadata keys with
-    /// that name will be returned.
+    /// start with the given prefix, matching whole key segments.  If you specify
+    /// metadata_key_name, only metadata keys with that name will be returned.  Revisions aren't
+    /// listed; see the `revision` module.
     ///
     /// Returns a mapping of the data keys to the set of populated metadata keys for each.
     fn list_populated_metadata<S1, S2>(
@@ -80,6 +210,15 @@ pub trait DataStore {
     where
         S1: AsRef<str>,
         S2: AsRef<str>;
+    /// Finds the metadata keys unset in the given pending transaction whose data keys start with
+    /// the given prefix, which will be removed from live when the transaction is committed.
+    /// Returns a mapping of the data keys to the set of unset metadata keys for each.  Always
+    /// empty for live data.
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>>;
 
     /// Retrieve the value for a single data key from the datastore.
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>>;
@@ -88,6 +227,10 @@ pub trait DataStore {
     /// Removes the given data key from the datastore.  If we succeeded, we return Ok(()); if
     /// the key didn't exist, we also return Ok(()); we return Err only if we failed to check
     /// or remove the key.
+    ///
+    /// For a pending transaction, this also records that the key should be removed from live
+    /// when the transaction is committed; see `list_deleted_keys`.  Setting the key again in the
+    /// same transaction cancels that.
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()>;
 
     /// Retrieve the value for a single metadata key from the datastore.  Values will inherit from
@@ -135,10 +278,23 @@ pub trait DataStore {
     /// Removes the given metadata key from the given data key in the datastore.  If we
     /// succeeded, we return Ok(()); if the data or metadata key didn't exist, we also return
     /// Ok(()); we return Err only if we failed to check or remove the key.
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()>;
+    ///
+    /// For a pending transaction, this also records that the metadata should be removed from
+    /// live when the transaction is committed; see `list_deleted_metadata`.  Setting the metadata
+    /// again in the same transaction cancels that.
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()>;
 
-    /// Applies pending changes from the given transaction to the live datastore.  Returns the
-    /// list of changed keys.
+    /// Applies pending changes from the given transaction to the live datastore, including
+    /// removing keys and metadata that were unset in the transaction.  Data and metadata are
+    /// committed together.  Returns the list of changed keys.
+    ///
+    /// Implementations configured to keep snapshots take one of live data before applying the
+    /// changes, so the commit can be undone with `restore`.
     fn commit_transaction<S, C>(
         &mut self,
         transaction: S,
@@ -154,23 +310,130 @@ pub trait DataStore {
             Box<dyn std::error::Error + Send + Sync + 'static>,
         >;
 
-    /// Remove the given pending transaction from the datastore.  Returns the list of removed
-    /// keys.  If the transaction doesn't exist, will return Ok with an empty list.
+    /// Remove the given pending transaction, including its metadata, from the datastore.  Returns
+    /// the list of removed keys, including keys the transaction would have unset.  If the
+    /// transaction doesn't exist, will return Ok with an empty list.
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>;
 
+    /// Returns the revision of the given live data key; see the `revision` module.
+    fn get_revision(&self, key: &Key) -> Result<Revision> {
+        let holder = revision::revision_holder(key)?;
+        let value = self.get_metadata_raw(&revision::revision_key(), &holder, &Committed::Live)?;
+        revision::parse(key, value)
+    }
+
+    /// Sets the given live data key, as long as it's still at the given revision; otherwise,
+    /// returns Error::Conflict without changing anything.  Returns the key's new revision.
+    fn set_key_if_revision<S: AsRef<str>>(
+        &mut self,
+        key: &Key,
+        value: S,
+        expected: Revision,
+    ) -> Result<Revision> {
+        revision::check(self, [(key, &expected)])?;
+        self.set_key(key, value, &Committed::Live)?;
+        self.get_revision(key)
+    }
+
+    /// Commits the given pending transaction like `commit_transaction`, as long as each of the
+    /// given live data keys is still at the given revision; otherwise, returns Error::Conflict
+    /// without changing anything.
+    fn commit_transaction_if_unchanged<S, C>(
+        &mut self,
+        transaction: S,
+        expected: &HashMap<Key, Revision>,
+        constraint_check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+        C: Fn(
+            &mut Self,
+            &Committed,
+        ) -> std::result::Result<
+            ConstraintCheckResult,
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        revision::check(self, expected)?;
+        self.commit_transaction(transaction, constraint_check)
+    }
+
+    /// Commits the given pending transaction like `commit_transaction`, checking it with a
+    /// `ConstraintCheck`, such as a `ConstraintRegistry`, rather than a function.  If the check
+    /// finds problems, returns Error::ConstraintCheckReject listing all of them.
+    fn commit_transaction_checked<S, C>(
+        &mut self,
+        transaction: S,
+        check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        Self: Sized,
+        S: Into<String> + AsRef<str>,
+        C: ConstraintCheck<Self>,
+    {
+        self.commit_transaction(
+            transaction,
+            &|datastore: &mut Self, committed: &Committed| check.evaluate(datastore, committed),
+        )
+    }
+
     /// Returns a list of the names of any pending transactions in the data store.
     fn list_transactions(&self) -> Result<HashSet<String>>;
 
+    /// Returns the names of any pending transactions in the data store, with when each was
+    /// created and last changed.
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>>;
+
+    /// Deletes the pending transactions that haven't been changed for longer than `older_than`,
+    /// except for LAUNCH_TRANSACTION; see the `expiry` module.  Returns the names of the deleted
+    /// transactions.
+    fn expire_transactions(&mut self, older_than: Duration) -> Result<HashSet<String>> {
+        let mut expired = HashSet::new();
+        for (transaction, times) in self.list_transaction_times()? {
+            if expiry::expired(&transaction, &times, older_than) {
+                debug!(
+                    "Expiring transaction '{transaction}', idle for {:?}",
+                    times.idle()
+                );
+                self.delete_transaction(&transaction)?;
+                expired.insert(transaction);
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Saves a copy of the live data and metadata that can later be restored.  Returns the ID of
+    /// the new snapshot.
+    fn snapshot(&mut self) -> Result<SnapshotId>;
+
+    /// Returns the snapshots currently available for restoring, oldest first.
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>>;
+
+    /// Replaces the live data and metadata with the contents of the given snapshot.  Pending
+    /// transactions are not affected.  The snapshot remains available afterward.
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()>;
+
+    /// Returns a subscription that receives an event for each later commit that changes data
+    /// keys under the given prefix; see the `watch` module.
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription>;
+
     /// Set multiple data keys at once in the data store.
     ///
+    /// Lists are replaced as a whole.  If the pairs include a list, whether stored as one value
+    /// or by index (see the `serialization` module), any other keys of that list are unset, such
+    /// as the elements past the end of a list that got shorter.
+    ///
     /// Implementers can replace the default implementation if there's a faster way than setting
     /// each key individually.
     fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
     where
         S: AsRef<str>,
     {
+        let stale = stale_list_keys(self, pairs, committed)?;
+        self.unset_keys(&stale, committed)?;
+
         for (key, value) in pairs {
             match committed {
                 Committed::Live => {
@@ -223,6 +486,30 @@ pub trait DataStore {
         Ok(result)
     }
 
+    /// Retrieves all keys whose names start with the given string, returning them in a Key ->
+    /// value map.  Unlike `get_prefix`, the prefix doesn't have to end on a segment boundary, so
+    /// "settings.host" matches "settings.hostname" as well as "settings.host.name".
+    ///
+    /// Only the segments before the last separator in the prefix are used to narrow the search,
+    /// so prefer `get_prefix` where possible.
+    fn get_string_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let find_prefix = find_prefix.as_ref();
+        // Search under the whole segments of the prefix; if they don't parse, e.g. because the
+        // prefix ends inside a quoted segment, search everything.
+        let parent = find_prefix
+            .rsplit_once(KEY_SEPARATOR)
+            .map(|(parent, _)| parent)
+            .filter(|parent| Key::prefix_segments(parent).is_ok())
+            .unwrap_or("");
+        let mut result = self.get_prefix(parent, committed)?;
+        result.retain(|key, _| key.name().starts_with(find_prefix));
+        Ok(result)
+    }
+
     /// Retrieves all metadata for data keys starting with the given prefix.  If you specify
     /// metadata_key_name, only metadata keys with that name will be returned.  Returns a
     /// mapping of each data key to its metadata, where metadata is a mapping of metadata Key to
@@ -275,6 +562,157 @@ pub trait DataStore {
         }
         Ok(result)
     }
+
+    /// Retrieves all data keys matching the given pattern, returning them in a Key -> value map.
+    /// Only keys under the pattern's literal prefix are read; see the `pattern` module.
+    ///
+    /// Like `get_prefix`, can be followed up by a deserialize::from_map call to build a
+    /// structure.
+    fn query(&self, pattern: &KeyPattern, committed: &Committed) -> Result<HashMap<Key, String>> {
+        let mut result = self.get_prefix(pattern.prefix(), committed)?;
+        result.retain(|key, _| pattern.matches(key));
+        Ok(result)
+    }
+
+    /// Retrieves the metadata set on data keys matching the given pattern, like
+    /// `get_metadata_prefix`.  If you specify metadata_key_name, only metadata keys with that
+    /// name will be returned.
+    fn get_metadata_query<S: AsRef<str>>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>> {
+        let mut result =
+            self.get_metadata_prefix(pattern.prefix(), committed, metadata_key_name)?;
+        result.retain(|key, _| pattern.matches(key));
+        Ok(result)
+    }
+
+    /// Compares the given pending transaction to live data, returning the data keys it would
+    /// add, modify, and remove, and the metadata it would change.  Keys set in the transaction to
+    /// their live values aren't included.  Constraint checks can use this to inspect the write
+    /// they're approving.
+    ///
+    /// The diff is meant for showing to people, so the values of sensitive keys are redacted;
+    /// see the `sensitive` module.
+    fn diff_transaction<S: AsRef<str>>(&self, transaction: S) -> Result<TransactionDiff> {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        let mut diff = TransactionDiff::default();
+        let shown = |key: &Key, value: String| -> Result<String> {
+            if sensitive::is_sensitive(self, key, &pending)? {
+                Ok(sensitive::REDACTED.to_string())
+            } else {
+                Ok(value)
+            }
+        };
+
+        for (key, new) in self.get_prefix("", &pending)? {
+            match self.get_key(&key, &Committed::Live)? {
+                None => {
+                    diff.added.insert(key.name().clone(), shown(&key, new)?);
+                }
+                Some(old) if old != new => {
+                    let change = diff::ValueChange {
+                        old: shown(&key, old)?,
+                        new: shown(&key, new)?,
+                    };
+                    diff.modified.insert(key.name().clone(), change);
+                }
+                Some(_) => trace!("Pending key {key} matches live"),
+            }
+        }
+
+        for key in self.list_deleted_keys("", &pending)? {
+            if let Some(old) = self.get_key(&key, &Committed::Live)? {
+                diff.removed.insert(key.name().clone(), shown(&key, old)?);
+            }
+        }
+
+        for (data_key, metadata) in
+            self.get_metadata_prefix("", &pending, &None as &Option<&str>)?
+        {
+            for (metadata_key, new) in metadata {
+                let old = self.get_metadata_raw(&metadata_key, &data_key, &Committed::Live)?;
+                if old.as_ref() != Some(&new) {
+                    diff.metadata.push(diff::MetadataChange {
+                        data_key: data_key.name().clone(),
+                        metadata_key: metadata_key.name().clone(),
+                        old,
+                        new: Some(new),
+                    });
+                }
+            }
+        }
+
+        for (data_key, metadata_keys) in self.list_deleted_metadata("", &pending)? {
+            for metadata_key in metadata_keys {
+                let old = self.get_metadata_raw(&metadata_key, &data_key, &Committed::Live)?;
+                if old.is_some() {
+                    diff.metadata.push(diff::MetadataChange {
+                        data_key: data_key.name().clone(),
+                        metadata_key: metadata_key.name().clone(),
+                        old,
+                        new: None,
+                    });
+                }
+            }
+        }
+        diff.metadata.sort_unstable();
+
+        Ok(diff)
+    }
+}
+
+/// Returns the keys of the lists in `pairs` that aren't in `pairs`, meaning they're left over
+/// from an earlier value of the list.  A list is either a single key whose value is a JSON array,
+/// or keys with an index segment after the list's name.  For a pending transaction, this includes
+/// live keys, so they're removed when the transaction is committed.
+fn stale_list_keys<D, S>(
+    datastore: &D,
+    pairs: &HashMap<Key, S>,
+    committed: &Committed,
+) -> Result<HashSet<Key>>
+where
+    D: DataStore + ?Sized,
+    S: AsRef<str>,
+{
+    let mut lists = HashSet::new();
+    for (key, value) in pairs {
+        let segments = key.segments();
+        match segments
+            .iter()
+            .position(|s| Key::parse_index_segment(s).is_some())
+        {
+            Some(0) => {}
+            Some(index) => {
+                lists.insert(&segments[..index]);
+            }
+            None if value.as_ref().starts_with('[') => {
+                lists.insert(&segments[..]);
+            }
+            None => {}
+        }
+    }
+
+    let mut stale = HashSet::new();
+    for list in lists {
+        let list_key = Key::from_segments(KeyType::Data, list)?;
+        let mut existing = datastore.list_populated_keys(list_key.name(), committed)?;
+        if let Committed::Pending { .. } = committed {
+            existing.extend(datastore.list_populated_keys(list_key.name(), &Committed::Live)?);
+        }
+        stale.extend(existing.into_iter().filter(|key| {
+            let in_list = match key.segments().get(list.len()) {
+                None => true,
+                Some(segment) => Key::parse_index_segment(segment).is_some(),
+            };
+            in_list && !pairs.contains_key(key)
+        }));
+    }
+    Ok(stale)
 }
 
 /////
@@ -317,9 +755,18 @@ pub type Value = serde_json::Value;
 
 #[cfg(test)]
 mod test {
+    use super::constraints_check::{ApprovedWrite, ConstraintCheckResult};
+    use super::deserialization::{from_map, from_map_with_prefix};
     use super::memory::MemoryDataStore;
-    use super::{Committed, DataStore, Key, KeyType};
+    use super::sensitive;
+    use super::serialization::to_pairs_with_prefix;
+    use super::test_util::TestDir;
+    use super::{
+        Committed, DataStore, FilesystemDataStore, Key, KeyPattern, KeyType, LogDataStore,
+    };
     use maplit::{hashmap, hashset};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
 
     #[test]
     fn set_unset_keys() {
@@ -500,4 +947,191 @@ mod test {
             hashmap!(k2 => hashmap!(mk2 => "42".to_string()))
         );
     }
+
+    #[test]
+    fn diff_transaction() {
+        let mut m = MemoryDataStore::new();
+        let pending = Committed::Pending {
+            tx: "test".to_owned(),
+        };
+        let added = Key::new(KeyType::Data, "x.added").unwrap();
+        let modified = Key::new(KeyType::Data, "x.modified").unwrap();
+        let same = Key::new(KeyType::Data, "x.same").unwrap();
+        let removed = Key::new(KeyType::Data, "x.removed").unwrap();
+        let mk = Key::new(KeyType::Meta, "metatest").unwrap();
+        m.set_key(&modified, "1", &Committed::Live).unwrap();
+        m.set_key(&same, "1", &Committed::Live).unwrap();
+        m.set_key(&removed, "1", &Committed::Live).unwrap();
+        m.set_metadata(&mk, &same, "41", &Committed::Live).unwrap();
+
+        m.set_key(&added, "2", &pending).unwrap();
+        m.set_key(&modified, "2", &pending).unwrap();
+        m.set_key(&same, "1", &pending).unwrap();
+        m.unset_key(&removed, &pending).unwrap();
+        m.set_metadata(&mk, &same, "42", &pending).unwrap();
+
+        let diff = m.diff_transaction("test").unwrap();
+        assert_eq!(
+            serde_json::to_value(&diff).unwrap(),
+            serde_json::json!({
+                "added": {"x.added": "2"},
+                "modified": {"x.modified": {"old": "1", "new": "2"}},
+                "removed": {"x.removed": "1"},
+                "metadata": [
+                    {"data_key": "x.same", "metadata_key": "metatest", "old": "41", "new": "42"}
+                ]
+            })
+        );
+
+        // Sensitive values are redacted, whether they're marked in live or in the transaction.
+        let x = Key::new(KeyType::Data, "x").unwrap();
+        m.set_metadata(&sensitive::sensitive_key(), &x, "true", &Committed::Live)
+            .unwrap();
+        let diff = m.diff_transaction("test").unwrap();
+        assert_eq!(diff.added["x.added"], sensitive::REDACTED);
+        assert_eq!(diff.modified["x.modified"].old, sensitive::REDACTED);
+        assert_eq!(diff.removed["x.removed"], sensitive::REDACTED);
+
+        assert!(MemoryDataStore::new()
+            .diff_transaction("test")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Settings {
+        mirrors: Vec<Mirror>,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Mirror {
+        registry: String,
+        endpoints: Option<Vec<String>>,
+    }
+
+    fn approve_all<D: DataStore>(
+        datastore: &mut D,
+        committed: &Committed,
+    ) -> Result<ConstraintCheckResult, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+            settings: datastore.get_prefix("settings.", committed)?,
+            metadata: Vec::new(),
+            deleted_settings: datastore.list_deleted_keys("settings.", committed)?,
+            deleted_metadata: Vec::new(),
+        })))
+    }
+
+    /// Writes a series of lists, directly to live or through a transaction, checking that each
+    /// reads back exactly, with nothing left over from the list before.
+    fn check_list_round_trip<D: DataStore>(datastore: &mut D, through_transaction: bool) {
+        let mirror = |registry: &str, endpoints: Option<&[&str]>| Mirror {
+            registry: registry.to_string(),
+            endpoints: endpoints.map(|e| e.iter().map(|s| s.to_string()).collect()),
+        };
+        let a = mirror("a", Some(&["x", "y"]));
+        let b = mirror("b", None);
+        let c = mirror("c", Some(&["z"]));
+        let lists = [
+            vec![a.clone(), b.clone(), c.clone()],
+            // Reordered, and "a" lost its endpoints.
+            vec![c.clone(), mirror("a", None), b.clone()],
+            // Shorter.
+            vec![b.clone()],
+            // Empty lists are stored as a single value.
+            vec![],
+            vec![a.clone(), c.clone()],
+        ];
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        for mirrors in lists {
+            let settings = Settings { mirrors };
+            let pairs = to_pairs_with_prefix("settings", &settings).unwrap();
+            if through_transaction {
+                datastore.set_keys(&pairs, &pending).unwrap();
+                datastore.commit_transaction("tx", &approve_all).unwrap();
+            } else {
+                datastore.set_keys(&pairs, &Committed::Live).unwrap();
+            }
+
+            let live = datastore.get_prefix("settings", &Committed::Live).unwrap();
+            assert_eq!(live, pairs);
+            assert_eq!(from_map::<_, _, Settings, _>(&live).unwrap(), settings);
+        }
+
+        // A transaction can change a list's form more than once before it's committed.
+        let committed = if through_transaction {
+            pending.clone()
+        } else {
+            Committed::Live
+        };
+        for mirrors in [vec![], vec![b.clone()]] {
+            let pairs = to_pairs_with_prefix("settings", &Settings { mirrors }).unwrap();
+            datastore.set_keys(&pairs, &committed).unwrap();
+        }
+        if through_transaction {
+            datastore.commit_transaction("tx", &approve_all).unwrap();
+        }
+        let live = datastore.get_prefix("settings", &Committed::Live).unwrap();
+        assert_eq!(
+            from_map::<_, _, Settings, _>(&live).unwrap(),
+            Settings { mirrors: vec![b] }
+        );
+    }
+
+    #[test]
+    fn list_round_trip() {
+        check_list_round_trip(&mut MemoryDataStore::new(), false);
+        check_list_round_trip(&mut MemoryDataStore::new(), true);
+
+        let dir = TestDir::new("list-round-trip");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        check_list_round_trip(&mut f, false);
+        check_list_round_trip(&mut f, true);
+
+        let mut l = LogDataStore::new(dir.0.join("datastore.log")).unwrap();
+        check_list_round_trip(&mut l, false);
+        check_list_round_trip(&mut l, true);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct HostContainer {
+        enabled: Option<bool>,
+        source: Option<String>,
+    }
+
+    #[test]
+    fn query_round_trip() {
+        let container = |enabled, source: Option<&str>| HostContainer {
+            enabled: Some(enabled),
+            source: source.map(str::to_string),
+        };
+        let containers = hashmap! {
+            "admin".to_string() => container(true, Some("admin:latest")),
+            "control.v2".to_string() => container(false, Some("control:latest")),
+        };
+        let prefix = "settings.host-containers";
+        let pairs = to_pairs_with_prefix(prefix, &containers).unwrap();
+        let mut m = MemoryDataStore::new();
+        m.set_keys(&pairs, &Committed::Live).unwrap();
+
+        // Serialized keys match the same patterns as stored ones, quoted segments included.
+        let pattern = KeyPattern::new("settings.host-containers.*.enabled").unwrap();
+        let found = m.query(&pattern, &Committed::Live).unwrap();
+        let expected: HashMap<_, _> = pairs
+            .into_iter()
+            .filter(|(key, _)| pattern.matches(key))
+            .collect();
+        assert_eq!(found, expected);
+        assert_eq!(found.len(), 2);
+
+        let enabled: HashMap<String, HostContainer> =
+            from_map_with_prefix(Some(prefix.to_string()), &found).unwrap();
+        assert_eq!(
+            enabled,
+            hashmap! {
+                "admin".to_string() => container(true, None),
+                "control.v2".to_string() => container(false, None),
+            }
+        );
+    }
 }
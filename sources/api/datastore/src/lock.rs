@@ -0,0 +1,620 @@
+//! LockedDataStore wraps another DataStore implementation and makes it safe to share between
+//! threads and processes by taking `flock` locks around each operation.
+//!
+//! Reads take a shared lock, so any number of readers can proceed at once; writes, commits, and
+//! snapshot operations take an exclusive lock.  The lock is taken on a path shared by every user
+//! of the data store -- for `FilesystemDataStore`, the data store's base directory -- so each
+//! thread or process should open its own LockedDataStore on the same path.
+//!
+//! Each call is locked on its own.  To make a series of calls atomic, for example a
+//! read-modify-write, wrap them in `shared` or `exclusive`.  Calls made inside those, including
+//! calls made by constraint checks during `commit_transaction`, reuse the lock that's already
+//! held rather than taking it again.
+
+use log::trace;
+use snafu::ResultExt;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::constraints_check::ConstraintCheckResult;
+use crate::pattern::KeyPattern;
+use crate::revision::{self, Revision};
+use crate::snapshot::{Snapshot, SnapshotId};
+use crate::{
+    error, Committed, DataStore, FilesystemDataStore, Key, Result, Subscription, TransactionDiff,
+    TransactionTimes,
+};
+
+/// The kind of lock needed for an operation.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl LockMode {
+    fn flock_operation(self) -> libc::c_int {
+        match self {
+            LockMode::Shared => libc::LOCK_SH,
+            LockMode::Exclusive => libc::LOCK_EX,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LockedDataStore<D> {
+    inner: D,
+    lock_path: PathBuf,
+    // The lock we currently hold, and how many nested operations are using it.  The lock is
+    // released when the count drops back to zero.
+    held: RefCell<Option<File>>,
+    depth: Cell<usize>,
+}
+
+/// Releases the wrapper's lock when the outermost locked operation finishes.
+struct LockGuard<'a, D> {
+    datastore: &'a LockedDataStore<D>,
+    depth: usize,
+}
+
+impl<D> Drop for LockGuard<'_, D> {
+    fn drop(&mut self) {
+        self.datastore.leave(self.depth);
+    }
+}
+
+impl LockedDataStore<FilesystemDataStore> {
+    /// Opens the FilesystemDataStore at the given base path, locking its base directory.  Any
+    /// interrupted commit is recovered while holding an exclusive lock, so other users never see
+    /// it half-applied.  A commit interrupted after we open is finished by the next write, which
+    /// also holds the exclusive lock.
+    pub fn open<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let base_path = base_path.as_ref();
+        let _lock = flock(base_path, LockMode::Exclusive)?;
+        let inner = FilesystemDataStore::new(base_path)?;
+        Ok(Self::new(inner, base_path))
+    }
+}
+
+impl<D: DataStore> LockedDataStore<D> {
+    /// Wraps the given data store, taking locks on the given path.  The path must exist, and
+    /// every user of the data store must use the same path.
+    pub fn new<P: AsRef<Path>>(inner: D, lock_path: P) -> Self {
+        Self {
+            inner,
+            lock_path: lock_path.as_ref().to_path_buf(),
+            held: RefCell::new(None),
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Returns the wrapped data store.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Runs the given function while holding a shared lock, so that everything it reads comes
+    /// from a consistent view of the data store.
+    pub fn shared<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Self) -> Result<T>,
+    {
+        let _guard = self.lock(LockMode::Shared)?;
+        f(self)
+    }
+
+    /// Runs the given function while holding an exclusive lock, so that no other user of the
+    /// data store can read or write until it's done.  The lock is released even if the function
+    /// panics.
+    pub fn exclusive<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let depth = self.enter(LockMode::Exclusive)?;
+        // The panic is re-raised once the lock is released, so nothing observes a broken
+        // invariant from it that it wouldn't have without the catch.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(self)));
+        self.leave(depth);
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+}
+
+impl<D> LockedDataStore<D> {
+    /// Takes the lock for an operation on &self, returning a guard that releases it.
+    fn lock(&self, mode: LockMode) -> Result<LockGuard<'_, D>> {
+        let depth = self.enter(mode)?;
+        Ok(LockGuard {
+            datastore: self,
+            depth,
+        })
+    }
+
+    /// Takes the lock if we don't already hold it, and returns the nesting depth to give back to
+    /// `leave`.  An operation that needs an exclusive lock always requires &mut self, so it can't
+    /// be nested inside a shared operation, and we never need to upgrade.
+    fn enter(&self, mode: LockMode) -> Result<usize> {
+        let depth = self.depth.get();
+        if depth == 0 {
+            let file = flock(&self.lock_path, mode)?;
+            *self.held.borrow_mut() = Some(file);
+        }
+        self.depth.set(depth + 1);
+        Ok(depth)
+    }
+
+    /// Finishes an operation started with `enter`, releasing the lock if it was the outermost.
+    /// (`exclusive` calls this directly, after catching any panic, because it can't hold a
+    /// LockGuard borrowing self while passing &mut self along.)
+    fn leave(&self, depth: usize) {
+        self.depth.set(depth);
+        if depth == 0 {
+            // Closing the file releases the flock.
+            self.held.borrow_mut().take();
+            trace!("Released lock on {}", self.lock_path.display());
+        }
+    }
+}
+
+/// Opens the given path and takes a flock on it, waiting until it's available.  The lock is
+/// released when the returned File is closed.
+fn flock(path: &Path, mode: LockMode) -> Result<File> {
+    let file = File::open(path).context(error::LockSnafu { path })?;
+    trace!("Waiting for {:?} lock on {}", mode, path.display());
+    loop {
+        // SAFETY: flock only operates on the file descriptor, which stays open as long as
+        // `file` does.
+        let ret = unsafe { libc::flock(file.as_raw_fd(), mode.flock_operation()) };
+        if ret == 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err).context(error::LockSnafu { path });
+        }
+    }
+    trace!("Took {:?} lock on {}", mode, path.display());
+    Ok(file)
+}
+
+impl<D: DataStore> DataStore for LockedDataStore<D> {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.key_populated(key, committed)
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_populated_keys(prefix, committed)
+    }
+
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_deleted_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner
+            .list_populated_metadata(prefix, committed, metadata_key_name)
+    }
+
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_deleted_metadata(prefix, committed)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.get_key(key, committed)
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.exclusive(|ds| ds.inner.set_key(key, value, committed))
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.exclusive(|ds| ds.inner.unset_key(key, committed))
+    }
+
+    fn set_key_if_revision<S: AsRef<str>>(
+        &mut self,
+        key: &Key,
+        value: S,
+        expected: Revision,
+    ) -> Result<Revision> {
+        self.exclusive(|ds| ds.inner.set_key_if_revision(key, value, expected))
+    }
+
+    fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.get_metadata(metadata_key, data_key, committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner
+            .get_metadata_raw(metadata_key, data_key, committed)
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.exclusive(|ds| {
+            ds.inner
+                .set_metadata(metadata_key, data_key, value, committed)
+        })
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.exclusive(|ds| ds.inner.unset_metadata(metadata_key, data_key, committed))
+    }
+
+    /// The constraint check runs against this wrapper while we hold the exclusive lock, and its
+    /// result is handed to the inner data store's commit under the same lock.
+    fn commit_transaction<S, C>(
+        &mut self,
+        transaction: S,
+        constraint_check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+        C: Fn(
+            &mut Self,
+            &Committed,
+        ) -> std::result::Result<
+            ConstraintCheckResult,
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        self.exclusive(|ds| {
+            let pending = Committed::Pending {
+                tx: transaction.as_ref().to_string(),
+            };
+            let check_result = RefCell::new(Some(constraint_check(ds, &pending)));
+            ds.inner
+                .commit_transaction(transaction, &|_: &mut D, _: &Committed| {
+                    check_result
+                        .borrow_mut()
+                        .take()
+                        .unwrap_or_else(|| Err("constraint check result was already used".into()))
+                })
+        })
+    }
+
+    /// The revisions are checked under the same exclusive lock as the commit.
+    fn commit_transaction_if_unchanged<S, C>(
+        &mut self,
+        transaction: S,
+        expected: &HashMap<Key, Revision>,
+        constraint_check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+        C: Fn(
+            &mut Self,
+            &Committed,
+        ) -> std::result::Result<
+            ConstraintCheckResult,
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        self.exclusive(|ds| {
+            revision::check(ds, expected)?;
+            ds.commit_transaction(transaction, constraint_check)
+        })
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.exclusive(|ds| ds.inner.delete_transaction(transaction))
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_transactions()
+    }
+
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_transaction_times()
+    }
+
+    fn expire_transactions(&mut self, older_than: Duration) -> Result<HashSet<String>> {
+        // Hold the lock from listing to deleting, so a transaction changed in between isn't lost.
+        self.exclusive(|ds| ds.inner.expire_transactions(older_than))
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId> {
+        self.exclusive(|ds| ds.inner.snapshot())
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.list_snapshots()
+    }
+
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        self.exclusive(|ds| ds.inner.restore(snapshot_id))
+    }
+
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription> {
+        // Subscribing doesn't read or write data, so it doesn't need the lock.
+        self.inner.subscribe(prefix)
+    }
+
+    fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.exclusive(|ds| ds.inner.set_keys(pairs, committed))
+    }
+
+    fn unset_keys(&mut self, keys: &HashSet<Key>, committed: &Committed) -> Result<()> {
+        self.exclusive(|ds| ds.inner.unset_keys(keys, committed))
+    }
+
+    fn get_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.get_prefix(find_prefix, committed)
+    }
+
+    fn get_metadata_prefix<S1, S2>(
+        &self,
+        find_prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner
+            .get_metadata_prefix(find_prefix, committed, metadata_key_name)
+    }
+
+    fn get_string_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.get_string_prefix(find_prefix, committed)
+    }
+
+    fn query(&self, pattern: &KeyPattern, committed: &Committed) -> Result<HashMap<Key, String>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.query(pattern, committed)
+    }
+
+    fn get_metadata_query<S: AsRef<str>>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner
+            .get_metadata_query(pattern, committed, metadata_key_name)
+    }
+
+    fn diff_transaction<S: AsRef<str>>(&self, transaction: S) -> Result<TransactionDiff> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.diff_transaction(transaction)
+    }
+
+    fn get_revision(&self, key: &Key) -> Result<Revision> {
+        let _guard = self.lock(LockMode::Shared)?;
+        self.inner.get_revision(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LockedDataStore;
+    use crate::constraints_check::{ApprovedWrite, ConstraintCheckResult};
+    use crate::test_util::TestDir;
+    use crate::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    const WORKERS: u64 = 4;
+    const ITERATIONS: u64 = 10;
+    // Tells a child process running the `worker` test which data store to use.
+    const WORKER_ENV: &str = "DATASTORE_LOCK_TEST_PATH";
+
+    fn approve_all(
+        datastore: &mut LockedDataStore<FilesystemDataStore>,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+            settings: datastore.get_prefix("settings.", committed)?,
+            metadata: Vec::new(),
+            deleted_settings: datastore.list_deleted_keys("settings.", committed)?,
+            deleted_metadata: Vec::new(),
+        })))
+    }
+
+    fn read_counter(datastore: &LockedDataStore<FilesystemDataStore>, name: &str) -> u64 {
+        let key = Key::new(KeyType::Data, name).unwrap();
+        datastore
+            .get_key(&key, &Committed::Live)
+            .unwrap()
+            .map(|v| v.parse().unwrap())
+            .unwrap_or(0)
+    }
+
+    /// Increments a counter through a pending transaction, holding the exclusive lock across the
+    /// read and the commit so no increments are lost.  A second copy of the counter is written in
+    /// the same transaction, so readers can check that they never see half of a commit.
+    fn increment(datastore: &mut LockedDataStore<FilesystemDataStore>, tx: &str) {
+        let counter = Key::new(KeyType::Data, "settings.counter").unwrap();
+        let copy = Key::new(KeyType::Data, "settings.copy").unwrap();
+        datastore
+            .exclusive(|ds| {
+                let value = (read_counter(ds, "settings.counter") + 1).to_string();
+                let pending = Committed::Pending { tx: tx.to_string() };
+                ds.set_key(&counter, &value, &pending)?;
+                ds.set_key(&copy, &value, &pending)?;
+                ds.commit_transaction(tx, &approve_all)?;
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn check_consistent(datastore: &LockedDataStore<FilesystemDataStore>) {
+        datastore
+            .shared(|ds| {
+                assert_eq!(
+                    read_counter(ds, "settings.counter"),
+                    read_counter(ds, "settings.copy")
+                );
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn work(path: &Path, name: &str) {
+        let mut datastore = LockedDataStore::open(path).unwrap();
+        for i in 0..ITERATIONS {
+            increment(&mut datastore, &format!("{name}-{i}"));
+            check_consistent(&datastore);
+        }
+    }
+
+    #[test]
+    fn hammer_from_threads() {
+        let dir = TestDir::new("lock-threads");
+        let handles: Vec<_> = (0..WORKERS)
+            .map(|n| {
+                let path = dir.0.clone();
+                thread::spawn(move || work(&path, &format!("thread-{n}")))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let datastore = LockedDataStore::open(&dir.0).unwrap();
+        assert_eq!(
+            read_counter(&datastore, "settings.counter"),
+            WORKERS * ITERATIONS
+        );
+        assert!(datastore.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hammer_from_processes() {
+        let dir = TestDir::new("lock-processes");
+        let exe = std::env::current_exe().unwrap();
+        let children: Vec<_> = (0..WORKERS)
+            .map(|_| {
+                Command::new(&exe)
+                    .args(["--exact", "lock::test::worker", "--ignored", "--quiet"])
+                    .env(WORKER_ENV, &dir.0)
+                    .stdout(Stdio::null())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        for mut child in children {
+            assert!(child.wait().unwrap().success());
+        }
+
+        let datastore = LockedDataStore::open(&dir.0).unwrap();
+        assert_eq!(
+            read_counter(&datastore, "settings.counter"),
+            WORKERS * ITERATIONS
+        );
+    }
+
+    /// Run in child processes by `hammer_from_processes`.
+    #[test]
+    #[ignore = "run by hammer_from_processes"]
+    fn worker() {
+        if let Some(path) = std::env::var_os(WORKER_ENV) {
+            work(Path::new(&path), &format!("process-{}", std::process::id()));
+        }
+    }
+
+    #[test]
+    fn constraint_check_reuses_lock() {
+        let dir = TestDir::new("lock-reentrant");
+        let mut datastore = LockedDataStore::open(&dir.0).unwrap();
+        // The constraint check reads through the wrapper while the commit holds the exclusive
+        // lock; this would deadlock if the nested read tried to take its own lock.
+        increment(&mut datastore, "tx");
+        assert_eq!(read_counter(&datastore, "settings.counter"), 1);
+    }
+
+    #[test]
+    fn panic_releases_lock() {
+        let dir = TestDir::new("lock-panic");
+        let mut datastore = LockedDataStore::open(&dir.0).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            datastore.exclusive(|_| -> crate::Result<()> { panic!("interrupted") })
+        }));
+        assert!(result.is_err());
+        assert_eq!(datastore.depth.get(), 0);
+        assert!(datastore.held.borrow().is_none());
+
+        // Another user of the data store can take the lock again.
+        let mut other = LockedDataStore::open(&dir.0).unwrap();
+        increment(&mut other, "tx");
+        assert_eq!(read_counter(&datastore, "settings.counter"), 1);
+    }
+}
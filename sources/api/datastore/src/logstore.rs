@@ -0,0 +1,835 @@
+//! LogDataStore keeps live data and every pending transaction in a single append-only log file,
+//! rather than one file per key and metadata entry like FilesystemDataStore.
+//!
+//! Each change is appended to the log as one line of JSON describing the operation, and the log
+//! is flushed with fsync before the change is acknowledged.  Opening the data store replays the
+//! log into a MemoryDataStore, which serves as the in-memory index for reads; settings are
+//! small, so the index holds values as well as keys.  A commit is a single line holding the whole
+//! approved write, so live data reflects either all of a transaction or none of it.  A line left
+//! incomplete by a crash is discarded when the log is next opened.
+//!
+//! Once enough operations have been appended, the log is compacted: the current contents,
+//! including snapshots, are written as a single line to a new file, which is flushed and renamed
+//! over the log.  `compact` does the same on demand.
+//!
+//! Because the contents are kept in memory, a LogDataStore must be the only user of its log
+//! file.  Unlike FilesystemDataStore instances, two instances on the same file don't see each
+//! other's changes, and subscriptions only see commits made through the same instance.
+//!
+//! To switch an existing data store to the other format, copy it with `document::copy`, which
+//! the `datastore-convert` binary runs.
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::constraints_check::{ApprovedWrite, ConstraintCheckResult};
+use crate::filesystem::{sync_parent_dir, write_file_sync, CommitJournal};
+use crate::memory::{MemoryDataStore, MemoryState};
+use crate::snapshot::{Snapshot, SnapshotId, DEFAULT_SNAPSHOT_RETENTION};
+use crate::{error, Committed, DataStore, Key, KeyType, Result, Subscription, TransactionTimes};
+
+/// The number of operations appended to the log before it's compacted, unless configured
+/// otherwise.
+pub const DEFAULT_COMPACTION_THRESHOLD: usize = 1000;
+
+/// Suffix added to the log's file name for the new log while it's being written by compaction.
+const COMPACT_SUFFIX: &str = ".compact";
+
+/// Record is an operation in the log.  Keys are stored by name because a Key can't be
+/// deserialized without knowing its type, and a `transaction` of None means live data.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Record {
+    /// The whole contents of the data store, written by compaction.
+    State(Box<MemoryState>),
+    SetKey {
+        key: String,
+        value: String,
+        transaction: Option<String>,
+    },
+    SetKeys {
+        settings: HashMap<String, String>,
+        transaction: Option<String>,
+    },
+    UnsetKeys {
+        keys: Vec<String>,
+        transaction: Option<String>,
+    },
+    SetMetadata {
+        metadata_key: String,
+        data_key: String,
+        value: String,
+        transaction: Option<String>,
+    },
+    UnsetMetadata {
+        metadata_key: String,
+        data_key: String,
+        transaction: Option<String>,
+    },
+    Commit(CommitJournal),
+    DeleteTransaction {
+        transaction: String,
+    },
+    Snapshot,
+    Restore {
+        id: SnapshotId,
+    },
+}
+
+/// Line is one line of the log: a record, and when it was logged, which is replayed as the time
+/// of changes to pending transactions.
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    #[serde(flatten)]
+    record: Record,
+    /// Missing from compacted state, and from logs written before times were recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    time: Option<SystemTime>,
+}
+
+fn transaction(committed: &Committed) -> Option<String> {
+    match committed {
+        Committed::Live => None,
+        Committed::Pending { tx } => Some(tx.clone()),
+    }
+}
+
+fn committed(transaction: Option<String>) -> Committed {
+    match transaction {
+        None => Committed::Live,
+        Some(tx) => Committed::Pending { tx },
+    }
+}
+
+/// Applies the given record to the index.  Returns the changed keys for a commit or deleted
+/// transaction, and an empty set for anything else.
+fn apply_record(index: &mut MemoryDataStore, record: Record) -> Result<HashSet<Key>> {
+    let data_key = |name: &str| Key::new(KeyType::Data, name);
+    let metadata_key = |name: &str| Key::new(KeyType::Meta, name);
+    match record {
+        Record::State(state) => {
+            // Keep the settings and subscriptions, which aren't part of the state.
+            let old = std::mem::take(index);
+            *index = MemoryDataStore::from_state(*state)?.with_settings_from(old);
+        }
+        Record::SetKey {
+            key,
+            value,
+            transaction,
+        } => index.set_key(&data_key(&key)?, value, &committed(transaction))?,
+        Record::SetKeys {
+            settings,
+            transaction,
+        } => {
+            let settings = settings
+                .into_iter()
+                .map(|(name, value)| Ok((data_key(&name)?, value)))
+                .collect::<Result<HashMap<_, _>>>()?;
+            index.set_keys(&settings, &committed(transaction))?;
+        }
+        Record::UnsetKeys { keys, transaction } => {
+            let keys = keys
+                .iter()
+                .map(|name| data_key(name))
+                .collect::<Result<_>>()?;
+            index.unset_keys(&keys, &committed(transaction))?;
+        }
+        Record::SetMetadata {
+            metadata_key: metadata_name,
+            data_key: data_name,
+            value,
+            transaction,
+        } => index.set_metadata(
+            &metadata_key(&metadata_name)?,
+            &data_key(&data_name)?,
+            value,
+            &committed(transaction),
+        )?,
+        Record::UnsetMetadata {
+            metadata_key: metadata_name,
+            data_key: data_name,
+            transaction,
+        } => index.unset_metadata(
+            &metadata_key(&metadata_name)?,
+            &data_key(&data_name)?,
+            &committed(transaction),
+        )?,
+        Record::Commit(journal) => {
+            // The write was approved when the commit was logged.
+            return index.commit_transaction(journal.transaction.clone(), &|_, _| {
+                Ok(ConstraintCheckResult::Approve(journal.approved_write()?))
+            });
+        }
+        Record::DeleteTransaction { transaction } => {
+            return index.delete_transaction(transaction);
+        }
+        Record::Snapshot => {
+            index.snapshot()?;
+        }
+        Record::Restore { id } => index.restore(id)?,
+    }
+    Ok(HashSet::new())
+}
+
+/// Reads the log at the given path into a new index, discarding an incomplete line at the end.
+/// Returns the index and the number of operations logged since the last compaction.
+fn load(path: &Path, snapshot_retention: usize) -> Result<(MemoryDataStore, usize)> {
+    let contents = fs::read(path).context(error::IoSnafu { path })?;
+    // Everything after the last newline is a line we didn't finish writing.
+    let complete = contents
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    if complete < contents.len() {
+        warn!(
+            "Discarding incomplete operation at the end of {}",
+            path.display()
+        );
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .context(error::IoSnafu { path })?;
+        file.set_len(complete as u64)
+            .context(error::IoSnafu { path })?;
+        file.sync_all().context(error::IoSnafu { path })?;
+    }
+
+    let mut index = MemoryDataStore::new().with_snapshot_retention(snapshot_retention);
+    let mut records = 0;
+    for (i, line) in contents[..complete].split(|b| *b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let Line { record, time } =
+            serde_json::from_slice(line).with_context(|_| error::LogSnafu { path, line: i + 1 })?;
+        records = match record {
+            Record::State(_) => 0,
+            _ => records + 1,
+        };
+        index.set_clock(time);
+        apply_record(&mut index, record)?;
+        index.set_clock(None);
+    }
+    debug!(
+        "Loaded {} with {records} operations since compaction",
+        path.display()
+    );
+    Ok((index, records))
+}
+
+/// Opens the log at the given path for appending, creating it if needed.
+fn open_log(path: &Path) -> Result<File> {
+    let existed = path.exists();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(error::IoSnafu { path })?;
+    if !existed {
+        sync_parent_dir(path)?;
+    }
+    Ok(file)
+}
+
+#[derive(Debug)]
+pub struct LogDataStore {
+    path: PathBuf,
+    file: File,
+    // The contents of the log, which serve as its index.
+    index: MemoryDataStore,
+    // The number of operations appended since the log was last compacted.
+    records: usize,
+    compaction_threshold: usize,
+    snapshot_retention: usize,
+}
+
+impl LogDataStore {
+    /// Opens the data store kept in the log file at the given path, creating an empty one if the
+    /// file doesn't exist.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<LogDataStore> {
+        let path = path.as_ref().to_path_buf();
+        // A new log left by interrupted compaction may be incomplete; the old one is intact.
+        let compact_path = compact_path(&path);
+        match fs::remove_file(&compact_path) {
+            Ok(()) => warn!(
+                "Discarded incomplete compaction at {}",
+                compact_path.display()
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::IoSnafu { path: compact_path }),
+        }
+
+        let file = open_log(&path)?;
+        let (index, records) = load(&path, DEFAULT_SNAPSHOT_RETENTION)?;
+        Ok(LogDataStore {
+            path,
+            file,
+            index,
+            records,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            snapshot_retention: DEFAULT_SNAPSHOT_RETENTION,
+        })
+    }
+
+    /// Sets the number of snapshots to keep, and takes one before each commit.  If set to 0, the
+    /// default, no snapshots are taken on commit, and only the most recent snapshot requested
+    /// directly is kept.
+    ///
+    /// Replaying the log only takes the same snapshots as the original operations did if it
+    /// keeps the same number, so the log is loaded again with the new count.
+    pub fn with_snapshot_retention(mut self, count: usize) -> Result<Self> {
+        self.snapshot_retention = count;
+        let (index, records) = load(&self.path, count)?;
+        self.index = index
+            .with_settings_from(std::mem::take(&mut self.index))
+            .with_snapshot_retention(count);
+        self.records = records;
+        Ok(self)
+    }
+
+    /// Sets the number of operations appended to the log before it's compacted.
+    pub fn with_compaction_threshold(mut self, count: usize) -> Self {
+        self.compaction_threshold = count;
+        self
+    }
+
+    /// Rewrites the log with only the current contents of the data store.
+    pub fn compact(&mut self) -> Result<()> {
+        let state = Line {
+            record: Record::State(Box::new(self.index.state())),
+            time: None,
+        };
+        let mut line = serde_json::to_vec(&state).context(error::SerializeSnafu)?;
+        line.push(b'\n');
+
+        let compact_path = compact_path(&self.path);
+        write_file_sync(&compact_path, &line)?;
+        // Open the compacted log before renaming it into place, so we never keep appending to the
+        // old one after it's replaced.
+        let file = open_log(&compact_path)?;
+        fs::rename(&compact_path, &self.path).context(error::IoSnafu { path: &self.path })?;
+        self.file = file;
+        sync_parent_dir(&self.path)?;
+        debug!(
+            "Compacted {} after {} operations",
+            self.path.display(),
+            self.records
+        );
+        self.records = 0;
+        Ok(())
+    }
+
+    /// Applies the given record to the index and appends it to the log, compacting the log if
+    /// it's due.  If either of the first two steps fails, the index is reloaded from the log, so it
+    /// never gets ahead of what's on disk.
+    fn write(&mut self, record: Record) -> Result<HashSet<Key>> {
+        let time = SystemTime::now();
+        let line = Line {
+            record,
+            time: Some(time),
+        };
+        let mut bytes = serde_json::to_vec(&line).context(error::SerializeSnafu)?;
+        bytes.push(b'\n');
+
+        // Record the same time for changes to transactions as a replay of the log will.
+        self.index.set_clock(Some(time));
+        let result = apply_record(&mut self.index, line.record);
+        self.index.set_clock(None);
+        let result = result.and_then(|changed| {
+            let path = &self.path;
+            (&self.file)
+                .write_all(&bytes)
+                .context(error::IoSnafu { path })?;
+            self.file.sync_data().context(error::IoSnafu { path })?;
+            Ok(changed)
+        });
+        let changed = match result {
+            Ok(changed) => changed,
+            Err(e) => {
+                error!(
+                    "Failed to log operation, reloading {}: {e}",
+                    self.path.display()
+                );
+                let (index, records) = load(&self.path, self.snapshot_retention)?;
+                self.index = index.with_settings_from(std::mem::take(&mut self.index));
+                self.records = records;
+                return Err(e);
+            }
+        };
+
+        // The operation is already durable, so a failed compaction doesn't fail it; the log is
+        // just compacted after a later operation instead.
+        self.records += 1;
+        if self.records >= self.compaction_threshold {
+            if let Err(e) = self.compact() {
+                error!("Failed to compact {}: {e}", self.path.display());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+fn compact_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(COMPACT_SUFFIX);
+    PathBuf::from(name)
+}
+
+impl DataStore for LogDataStore {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        self.index.key_populated(key, committed)
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.index.list_populated_keys(prefix, committed)
+    }
+
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.index.list_deleted_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.index
+            .list_populated_metadata(prefix, committed, metadata_key_name)
+    }
+
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.index.list_deleted_metadata(prefix, committed)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        self.index.get_key(key, committed)
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.write(Record::SetKey {
+            key: key.name().clone(),
+            value: value.as_ref().to_string(),
+            transaction: transaction(committed),
+        })?;
+        Ok(())
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.unset_keys(&HashSet::from([key.clone()]), committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.index
+            .get_metadata_raw(metadata_key, data_key, committed)
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.write(Record::SetMetadata {
+            metadata_key: metadata_key.name().clone(),
+            data_key: data_key.name().clone(),
+            value: value.as_ref().to_string(),
+            transaction: transaction(committed),
+        })?;
+        Ok(())
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.write(Record::UnsetMetadata {
+            metadata_key: metadata_key.name().clone(),
+            data_key: data_key.name().clone(),
+            transaction: transaction(committed),
+        })?;
+        Ok(())
+    }
+
+    /// We run the constraint check, then log the approved write as a single operation, so the
+    /// commit is applied in full or not at all.
+    fn commit_transaction<S, C>(
+        &mut self,
+        transaction: S,
+        constraint_check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+        C: Fn(
+            &mut Self,
+            &Committed,
+        ) -> std::result::Result<
+            ConstraintCheckResult,
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        let constraint_check_result =
+            constraint_check(self, &pending).context(error::CheckConstraintExecutionSnafu)?;
+        let approved_write = ApprovedWrite::try_from(constraint_check_result)?;
+        self.write(Record::Commit(CommitJournal::new(
+            transaction,
+            &approved_write,
+        )))
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.write(Record::DeleteTransaction {
+            transaction: transaction.into(),
+        })
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.index.list_transactions()
+    }
+
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>> {
+        self.index.list_transaction_times()
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId> {
+        self.write(Record::Snapshot)?;
+        let snapshot = self.index.list_snapshots()?.pop();
+        Ok(snapshot
+            .context(error::InternalSnafu {
+                msg: "Snapshot missing after taking it",
+            })?
+            .id)
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.index.list_snapshots()
+    }
+
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        // Check first, so we don't log an operation that can't be replayed.
+        ensure!(
+            self.index
+                .list_snapshots()?
+                .iter()
+                .any(|snapshot| snapshot.id == snapshot_id),
+            error::SnapshotNotFoundSnafu { id: snapshot_id }
+        );
+        self.write(Record::Restore { id: snapshot_id })?;
+        Ok(())
+    }
+
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription> {
+        self.index.subscribe(prefix)
+    }
+
+    fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.write(Record::SetKeys {
+            settings: pairs
+                .iter()
+                .map(|(key, value)| (key.name().clone(), value.as_ref().to_string()))
+                .collect(),
+            transaction: transaction(committed),
+        })?;
+        Ok(())
+    }
+
+    fn unset_keys(&mut self, keys: &HashSet<Key>, committed: &Committed) -> Result<()> {
+        self.write(Record::UnsetKeys {
+            keys: keys.iter().map(|key| key.name().clone()).collect(),
+            transaction: transaction(committed),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TestDir;
+    use crate::{document, FilesystemDataStore};
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    fn meta(name: &str) -> Key {
+        Key::new(KeyType::Meta, name).unwrap()
+    }
+
+    fn approve_all(
+        datastore: &mut LogDataStore,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+            settings: datastore.get_prefix("settings", committed)?,
+            metadata: datastore
+                .get_metadata_prefix("settings", committed, &None as &Option<&str>)?
+                .into_iter()
+                .flat_map(|(data_key, metadata)| {
+                    metadata
+                        .into_iter()
+                        .map(move |(metadata_key, value)| (metadata_key, data_key.clone(), value))
+                })
+                .collect(),
+            deleted_settings: datastore.list_deleted_keys("settings", committed)?,
+            deleted_metadata: datastore
+                .list_deleted_metadata("settings", committed)?
+                .into_iter()
+                .flat_map(|(data_key, metadata_keys)| {
+                    metadata_keys
+                        .into_iter()
+                        .map(move |metadata_key| (metadata_key, data_key.clone()))
+                })
+                .collect(),
+        })))
+    }
+
+    /// Fills a data store with live data and metadata, a pending transaction, and a snapshot.
+    fn populate<D: DataStore>(datastore: &mut D) {
+        let live = &Committed::Live;
+        let pending = &Committed::Pending { tx: "tx".into() };
+        datastore
+            .set_key(&key("settings.motd"), "\"hi\"", live)
+            .unwrap();
+        datastore
+            .set_key(&key("settings.hostname"), "\"box\"", live)
+            .unwrap();
+        datastore
+            .set_metadata(
+                &meta("affected-services"),
+                &key("settings"),
+                "[\"all\"]",
+                live,
+            )
+            .unwrap();
+        datastore.snapshot().unwrap();
+        datastore
+            .set_key(&key("settings.motd"), "\"hello\"", pending)
+            .unwrap();
+        datastore
+            .unset_key(&key("settings.hostname"), pending)
+            .unwrap();
+        datastore
+            .unset_metadata(&meta("affected-services"), &key("settings"), pending)
+            .unwrap();
+    }
+
+    #[test]
+    fn commit_survives_reopen() {
+        let dir = TestDir::new("log-reopen");
+        let path = dir.0.join("datastore.log");
+        let mut l = LogDataStore::new(&path)
+            .unwrap()
+            .with_snapshot_retention(5)
+            .unwrap();
+        populate(&mut l);
+        let changed = l.commit_transaction("tx", &approve_all).unwrap();
+        assert_eq!(
+            changed,
+            HashSet::from([key("settings.motd"), key("settings.hostname")])
+        );
+        let before = document::export(&l, document::DocumentFormat::Json).unwrap();
+        drop(l);
+
+        let mut l = LogDataStore::new(&path)
+            .unwrap()
+            .with_snapshot_retention(5)
+            .unwrap();
+        assert_eq!(
+            document::export(&l, document::DocumentFormat::Json).unwrap(),
+            before
+        );
+        assert_eq!(
+            l.get_key(&key("settings.motd"), &Committed::Live).unwrap(),
+            Some("\"hello\"".to_string())
+        );
+        assert_eq!(
+            l.get_key(&key("settings.hostname"), &Committed::Live)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            l.get_metadata(
+                &meta("affected-services"),
+                &key("settings.motd"),
+                &Committed::Live
+            )
+            .unwrap(),
+            None
+        );
+        assert!(l.list_transactions().unwrap().is_empty());
+
+        // Both the snapshot taken directly and the one taken before the commit were replayed.
+        let snapshots = l.list_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        l.restore(snapshots[0].id).unwrap();
+        assert_eq!(
+            l.get_key(&key("settings.hostname"), &Committed::Live)
+                .unwrap(),
+            Some("\"box\"".to_string())
+        );
+    }
+
+    #[test]
+    fn incomplete_operation_discarded() {
+        let dir = TestDir::new("log-torn");
+        let path = dir.0.join("datastore.log");
+        let mut l = LogDataStore::new(&path).unwrap();
+        l.set_key(&key("settings.motd"), "\"hi\"", &Committed::Live)
+            .unwrap();
+        drop(l);
+
+        // Simulate a crash partway through appending an operation.
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"op\":\"set-key\",\"key\":\"settings.mo")
+            .unwrap();
+        drop(file);
+
+        let mut l = LogDataStore::new(&path).unwrap();
+        assert_eq!(
+            l.get_key(&key("settings.motd"), &Committed::Live).unwrap(),
+            Some("\"hi\"".to_string())
+        );
+        l.set_key(&key("settings.hostname"), "\"box\"", &Committed::Live)
+            .unwrap();
+        drop(l);
+        let l = LogDataStore::new(&path).unwrap();
+        assert_eq!(
+            l.list_populated_keys("", &Committed::Live).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn transaction_times_survive_reopen() {
+        let dir = TestDir::new("log-times");
+        let path = dir.0.join("datastore.log");
+        let mut l = LogDataStore::new(&path).unwrap();
+        populate(&mut l);
+        let times = l.list_transaction_times().unwrap();
+        drop(l);
+
+        let mut l = LogDataStore::new(&path).unwrap();
+        assert_eq!(l.list_transaction_times().unwrap(), times);
+        l.compact().unwrap();
+        drop(l);
+        let l = LogDataStore::new(&path).unwrap();
+        assert_eq!(l.list_transaction_times().unwrap(), times);
+    }
+
+    #[test]
+    fn compaction_keeps_contents() {
+        let dir = TestDir::new("log-compact");
+        let path = dir.0.join("datastore.log");
+        let mut l = LogDataStore::new(&path)
+            .unwrap()
+            .with_compaction_threshold(5);
+        populate(&mut l);
+        for i in 0..10 {
+            l.set_key(&key("settings.counter"), i.to_string(), &Committed::Live)
+                .unwrap();
+        }
+        let lines = fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines < 5, "log wasn't compacted: {lines} lines");
+
+        let before = document::export(&l, document::DocumentFormat::Json).unwrap();
+        let snapshots = l.list_snapshots().unwrap();
+        drop(l);
+        let l = LogDataStore::new(&path).unwrap();
+        assert_eq!(
+            document::export(&l, document::DocumentFormat::Json).unwrap(),
+            before
+        );
+        assert_eq!(l.list_snapshots().unwrap(), snapshots);
+        assert_eq!(l.get_revision(&key("settings.counter")).unwrap(), 10);
+    }
+
+    #[test]
+    fn failed_compaction_keeps_write() {
+        let dir = TestDir::new("log-compact-fail");
+        let path = dir.0.join("datastore.log");
+        let mut l = LogDataStore::new(&path)
+            .unwrap()
+            .with_compaction_threshold(1);
+        // Compaction can't write its temporary file where there's a directory.
+        fs::create_dir(compact_path(&path)).unwrap();
+        l.set_key(&key("settings.motd"), "\"hi\"", &Committed::Live)
+            .unwrap();
+
+        fs::remove_dir(compact_path(&path)).unwrap();
+        l.set_key(&key("settings.hostname"), "\"box\"", &Committed::Live)
+            .unwrap();
+        drop(l);
+        let l = LogDataStore::new(&path).unwrap();
+        assert_eq!(
+            l.list_populated_keys("", &Committed::Live).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn convert_round_trip() {
+        let dir = TestDir::new("log-convert");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        populate(&mut f);
+        let mut l = LogDataStore::new(dir.0.join("datastore.log")).unwrap();
+        document::copy(&f, &mut l).unwrap();
+
+        let mut back = FilesystemDataStore::create(dir.0.join("back")).unwrap();
+        document::copy(&l, &mut back).unwrap();
+        let expected = document::export(&f, document::DocumentFormat::Json).unwrap();
+        assert_eq!(
+            document::export(&l, document::DocumentFormat::Json).unwrap(),
+            expected
+        );
+        assert_eq!(
+            document::export(&back, document::DocumentFormat::Json).unwrap(),
+            expected
+        );
+        // Revisions aren't exported, but each copy moves them on.
+        let motd = key("settings.motd");
+        assert_eq!(f.get_revision(&motd).unwrap(), 1);
+        assert_eq!(l.get_revision(&motd).unwrap(), 2);
+        assert_eq!(back.get_revision(&motd).unwrap(), 3);
+    }
+}
@@ -1,26 +1,122 @@
 //! In-memory datastore for use in testing other modules.
 //!
-//! Mimics some of the decisions made for FilesystemDataStore, e.g. metadata being committed
-//! immediately.
+//! Mimics the decisions made for FilesystemDataStore, e.g. keys and metadata unset in a pending
+//! transaction being recorded so the commit can remove them from live.
+//!
+//! Its contents can be saved and loaded as a `MemoryState`, which lets LogDataStore use it as
+//! the in-memory index of its log.
 
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc::{self, Sender};
+use std::time::SystemTime;
 
 use crate::constraints_check::{ApprovedWrite, ConstraintCheckResult};
+use crate::expiry::TransactionTimes;
+use crate::revision;
+use crate::sensitive;
+use crate::snapshot::{expired_snapshots, Snapshot, SnapshotId, DEFAULT_SNAPSHOT_RETENTION};
 
-use super::{Committed, DataStore, Key, Result};
+use super::{error, ChangeEvent, Committed, DataStore, Key, KeyType, Result, Subscription};
 
-#[derive(Debug, Default)]
 pub struct MemoryDataStore {
     // Transaction name -> (key -> data)
     pending: HashMap<String, HashMap<Key, String>>,
+    // Transaction name -> keys unset in that transaction, to be removed from live on commit.
+    // Transactions are listed by the keys of `pending`, so an entry is kept there too.
+    pending_deletions: HashMap<String, HashSet<Key>>,
     // Committed (live) data.
     live: HashMap<Key, String>,
     // Map of data keys to their metadata, which in turn is a mapping of metadata keys to
     // arbitrary (string/serialized) values.
     metadata: HashMap<Key, HashMap<Key, String>>,
-    // Map of data keys to their metadata, which in turn is a mapping of metadata keys to
-    // arbitrary (string/serialized) values in pending transaction
-    pending_metadata: HashMap<Key, HashMap<Key, String>>,
+    // Transaction name -> map of data keys to their metadata, which in turn is a mapping of
+    // metadata keys to arbitrary (string/serialized) values in that pending transaction.
+    pending_metadata: HashMap<String, HashMap<Key, HashMap<Key, String>>>,
+    // Transaction name -> (metadata key, data key) pairs unset in that transaction, to be
+    // removed from live on commit.
+    pending_metadata_deletions: HashMap<String, HashSet<(Key, Key)>>,
+    // Copies of live data and metadata, oldest first.
+    snapshots: Vec<MemorySnapshot>,
+    // The ID to give the next snapshot.
+    next_snapshot_id: SnapshotId,
+    // The number of snapshots to keep.
+    snapshot_retention: usize,
+    // Channels to subscriptions, which are told about every commit and filter by their prefix.
+    subscribers: Vec<Sender<ChangeEvent>>,
+    // Transaction name -> when it was created and last changed.
+    transaction_times: HashMap<String, TransactionTimes>,
+    // The time to record for changes to transactions, if not now; set while replaying a log.
+    clock: Option<SystemTime>,
+}
+
+struct MemorySnapshot {
+    info: Snapshot,
+    live: HashMap<Key, String>,
+    metadata: HashMap<Key, HashMap<Key, String>>,
+}
+
+// Written out rather than derived, so the values of sensitive keys are redacted; see the
+// `sensitive` module.  Snapshots are only listed.
+impl fmt::Debug for MemoryDataStore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shown = |values: &HashMap<Key, String>, committed: &Committed| {
+            let mut values = values.clone();
+            if sensitive::redact(self, &mut values, committed).is_err() {
+                // If we can't tell which keys are sensitive, don't show any values.
+                values
+                    .values_mut()
+                    .for_each(|value| *value = sensitive::REDACTED.to_string());
+            }
+            values
+        };
+        let pending: HashMap<_, _> = self
+            .pending
+            .iter()
+            .map(|(tx, values)| {
+                let committed = Committed::Pending { tx: tx.clone() };
+                (tx, shown(values, &committed))
+            })
+            .collect();
+        let snapshots: Vec<_> = self.snapshots.iter().map(|s| &s.info).collect();
+        f.debug_struct("MemoryDataStore")
+            .field("pending", &pending)
+            .field("pending_deletions", &self.pending_deletions)
+            .field("live", &shown(&self.live, &Committed::Live))
+            .field("metadata", &self.metadata)
+            .field("pending_metadata", &self.pending_metadata)
+            .field(
+                "pending_metadata_deletions",
+                &self.pending_metadata_deletions,
+            )
+            .field("snapshots", &snapshots)
+            .field("next_snapshot_id", &self.next_snapshot_id)
+            .field("snapshot_retention", &self.snapshot_retention)
+            .field("subscribers", &self.subscribers.len())
+            .field("transaction_times", &self.transaction_times)
+            .finish()
+    }
+}
+
+impl Default for MemoryDataStore {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            pending_deletions: HashMap::new(),
+            live: HashMap::new(),
+            metadata: HashMap::new(),
+            pending_metadata: HashMap::new(),
+            pending_metadata_deletions: HashMap::new(),
+            snapshots: Vec::new(),
+            next_snapshot_id: 1,
+            snapshot_retention: DEFAULT_SNAPSHOT_RETENTION,
+            subscribers: Vec::new(),
+            transaction_times: HashMap::new(),
+            clock: None,
+        }
+    }
 }
 
 impl MemoryDataStore {
@@ -28,6 +124,129 @@ impl MemoryDataStore {
         Default::default()
     }
 
+    /// Sets the number of snapshots to keep, and takes one before each commit.  If set to 0, the
+    /// default, no snapshots are taken on commit, and only the most recent snapshot requested
+    /// directly is kept.
+    pub fn with_snapshot_retention(mut self, count: usize) -> Self {
+        self.snapshot_retention = count;
+        self
+    }
+
+    /// Returns the data store's contents in a serializable form.
+    pub(crate) fn state(&self) -> MemoryState {
+        let deletions = |keys: &HashSet<Key>| keys.iter().map(|key| key.name().clone()).collect();
+        MemoryState {
+            live: key_names(&self.live),
+            metadata: metadata_names(&self.metadata),
+            pending: map_values(&self.pending, key_names),
+            pending_deletions: map_values(&self.pending_deletions, deletions),
+            pending_metadata: map_values(&self.pending_metadata, metadata_names),
+            pending_metadata_deletions: map_values(&self.pending_metadata_deletions, |pairs| {
+                pairs
+                    .iter()
+                    .map(|(metadata_key, data_key)| {
+                        (metadata_key.name().clone(), data_key.name().clone())
+                    })
+                    .collect()
+            }),
+            snapshots: self
+                .snapshots
+                .iter()
+                .map(|snapshot| SnapshotState {
+                    info: snapshot.info.clone(),
+                    live: key_names(&snapshot.live),
+                    metadata: metadata_names(&snapshot.metadata),
+                })
+                .collect(),
+            next_snapshot_id: self.next_snapshot_id,
+            transaction_times: self.transaction_times.clone(),
+        }
+    }
+
+    /// Returns a data store with the given contents.
+    pub(crate) fn from_state(state: MemoryState) -> Result<Self> {
+        let mut datastore = Self::new();
+        datastore.live = parse_key_names(state.live)?;
+        datastore.metadata = parse_metadata_names(state.metadata)?;
+        for (tx, data) in state.pending {
+            datastore.pending.insert(tx, parse_key_names(data)?);
+        }
+        for (tx, names) in state.pending_deletions {
+            let keys = names
+                .iter()
+                .map(|name| Key::new(KeyType::Data, name))
+                .collect::<Result<_>>()?;
+            datastore.pending_deletions.insert(tx, keys);
+        }
+        for (tx, metadata) in state.pending_metadata {
+            datastore
+                .pending_metadata
+                .insert(tx, parse_metadata_names(metadata)?);
+        }
+        for (tx, pairs) in state.pending_metadata_deletions {
+            let pairs = pairs
+                .iter()
+                .map(|(metadata_name, data_name)| {
+                    Ok((
+                        Key::new(KeyType::Meta, metadata_name)?,
+                        Key::new(KeyType::Data, data_name)?,
+                    ))
+                })
+                .collect::<Result<_>>()?;
+            datastore.pending_metadata_deletions.insert(tx, pairs);
+        }
+        for snapshot in state.snapshots {
+            datastore.snapshots.push(MemorySnapshot {
+                info: snapshot.info,
+                live: parse_key_names(snapshot.live)?,
+                metadata: parse_metadata_names(snapshot.metadata)?,
+            });
+        }
+        datastore.next_snapshot_id = state.next_snapshot_id;
+        datastore.transaction_times = state.transaction_times;
+        Ok(datastore)
+    }
+
+    /// Takes the settings and subscriptions of the given data store, which aren't part of its
+    /// state.
+    pub(crate) fn with_settings_from(mut self, other: MemoryDataStore) -> Self {
+        self.snapshot_retention = other.snapshot_retention;
+        self.subscribers = other.subscribers;
+        self
+    }
+
+    /// Sets the time recorded for changes to transactions, or None to use the current time.  A
+    /// log sets this to the time of each operation as it's replayed.
+    pub(crate) fn set_clock(&mut self, time: Option<SystemTime>) {
+        self.clock = time;
+    }
+
+    /// Records a change to the given transaction.
+    fn touch(&mut self, tx: &str) {
+        let now = self.clock.unwrap_or_else(SystemTime::now);
+        self.transaction_times
+            .entry(tx.to_string())
+            .or_insert_with(|| TransactionTimes::new(now))
+            .touched = now;
+    }
+
+    fn take_snapshot(&mut self, transaction: Option<String>) -> SnapshotId {
+        let id = self.next_snapshot_id;
+        self.next_snapshot_id += 1;
+        self.snapshots.push(MemorySnapshot {
+            info: Snapshot { id, transaction },
+            live: self.live.clone(),
+            metadata: self.metadata.clone(),
+        });
+
+        let expired = expired_snapshots(
+            self.snapshots.iter().map(|s| s.info.id),
+            self.snapshot_retention,
+        );
+        self.snapshots.retain(|s| !expired.contains(&s.info.id));
+        id
+    }
+
     fn dataset(&self, committed: &Committed) -> Option<&HashMap<Key, String>> {
         match committed {
             Committed::Live => Some(&self.live),
@@ -38,7 +257,33 @@ impl MemoryDataStore {
     fn dataset_mut(&mut self, committed: &Committed) -> &mut HashMap<Key, String> {
         match committed {
             Committed::Live => &mut self.live,
-            Committed::Pending { tx } => self.pending.entry(tx.clone()).or_default(),
+            Committed::Pending { tx } => {
+                self.touch(tx);
+                self.pending.entry(tx.clone()).or_default()
+            }
+        }
+    }
+
+    fn metadata_set(&self, committed: &Committed) -> Option<&HashMap<Key, HashMap<Key, String>>> {
+        match committed {
+            Committed::Live => Some(&self.metadata),
+            Committed::Pending { tx } => self.pending_metadata.get(tx),
+        }
+    }
+
+    fn metadata_set_mut(
+        &mut self,
+        committed: &Committed,
+    ) -> &mut HashMap<Key, HashMap<Key, String>> {
+        match committed {
+            Committed::Live => &mut self.metadata,
+            Committed::Pending { tx } => {
+                // Transactions are listed by the keys of `pending`, so make sure there's an entry
+                // even if the transaction only changes metadata.
+                self.touch(tx);
+                self.pending.entry(tx.clone()).or_default();
+                self.pending_metadata.entry(tx.clone()).or_default()
+            }
         }
     }
 }
@@ -49,12 +294,31 @@ impl DataStore for MemoryDataStore {
         prefix: S,
         committed: &Committed,
     ) -> Result<HashSet<Key>> {
+        let prefix = Key::prefix_segments(prefix)?;
         let empty = HashMap::new();
         let dataset = self.dataset(committed).unwrap_or(&empty);
         Ok(dataset
             .keys()
             // Make sure the data keys start with the given prefix.
-            .filter(|k| k.name().starts_with(prefix.as_ref()))
+            .filter(|k| k.starts_with_segments(&prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let prefix = Key::prefix_segments(prefix)?;
+        let deleted = match committed {
+            Committed::Live => None,
+            Committed::Pending { tx } => self.pending_deletions.get(tx),
+        };
+        Ok(deleted
+            .into_iter()
+            .flatten()
+            .filter(|k| k.starts_with_segments(&prefix))
             .cloned()
             .collect())
     }
@@ -69,21 +333,20 @@ impl DataStore for MemoryDataStore {
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        let metadata_to_use = match committed {
-            Committed::Live => &self.metadata,
-            Committed::Pending { .. } => &self.pending_metadata,
-        };
-
+        let prefix = Key::prefix_segments(prefix)?;
         let mut result = HashMap::new();
 
-        for (data_key, meta_map) in metadata_to_use.iter() {
+        for (data_key, meta_map) in self.metadata_set(committed).into_iter().flatten() {
             // Confirm data key matches requested prefix.
-            if !data_key.name().starts_with(prefix.as_ref()) {
+            if !data_key.starts_with_segments(&prefix) {
                 continue;
             }
 
             let mut meta_for_data = HashSet::new();
             for meta_key in meta_map.keys() {
+                if revision::is_revision_key(meta_key) {
+                    continue;
+                }
                 // Confirm metadata key matches requested name, if any.
                 if let Some(name) = metadata_key_name {
                     if name.as_ref() != meta_key.name() {
@@ -101,6 +364,29 @@ impl DataStore for MemoryDataStore {
         Ok(result)
     }
 
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        let prefix = Key::prefix_segments(prefix)?;
+        let deleted = match committed {
+            Committed::Live => None,
+            Committed::Pending { tx } => self.pending_metadata_deletions.get(tx),
+        };
+
+        let mut result: HashMap<Key, HashSet<Key>> = HashMap::new();
+        for (metadata_key, data_key) in deleted.into_iter().flatten() {
+            if data_key.starts_with_segments(&prefix) {
+                result
+                    .entry(data_key.clone())
+                    .or_default()
+                    .insert(metadata_key.clone());
+            }
+        }
+        Ok(result)
+    }
+
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
         let empty = HashMap::new();
         let dataset = self.dataset(committed).unwrap_or(&empty);
@@ -108,13 +394,29 @@ impl DataStore for MemoryDataStore {
     }
 
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        if let Committed::Pending { tx } = committed {
+            if let Some(deleted) = self.pending_deletions.get_mut(tx) {
+                deleted.remove(key);
+            }
+        }
         self.dataset_mut(committed)
             .insert(key.clone(), value.as_ref().to_owned());
+        if let Committed::Live = committed {
+            revision::bump(self, key)?;
+        }
         Ok(())
     }
 
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
         self.dataset_mut(committed).remove(key);
+        if let Committed::Pending { tx } = committed {
+            self.pending_deletions
+                .entry(tx.clone())
+                .or_default()
+                .insert(key.clone());
+        } else {
+            revision::bump(self, key)?;
+        }
         Ok(())
     }
 
@@ -130,12 +432,9 @@ impl DataStore for MemoryDataStore {
         data_key: &Key,
         committed: &Committed,
     ) -> Result<Option<String>> {
-        let metadata_to_use = match committed {
-            Committed::Live => &self.metadata,
-            Committed::Pending { .. } => &self.pending_metadata,
-        };
-
-        let metadata_for_data = metadata_to_use.get(data_key);
+        let metadata_for_data = self
+            .metadata_set(committed)
+            .and_then(|metadata| metadata.get(data_key));
 
         // If we have a metadata entry for this data key, then we can try fetching the requested
         // metadata key, otherwise we'll return early with Ok(None).
@@ -150,19 +449,35 @@ impl DataStore for MemoryDataStore {
         value: S,
         committed: &Committed,
     ) -> Result<()> {
-        match committed {
-            Committed::Live => set_metadata_raw(&mut self.metadata, metadata_key, data_key, value),
-            Committed::Pending { .. } => {
-                set_metadata_raw(&mut self.pending_metadata, metadata_key, data_key, value)
+        if let Committed::Pending { tx } = committed {
+            if let Some(deleted) = self.pending_metadata_deletions.get_mut(tx) {
+                deleted.remove(&(metadata_key.clone(), data_key.clone()));
             }
         }
+        set_metadata_raw(
+            self.metadata_set_mut(committed),
+            metadata_key,
+            data_key,
+            value,
+        )
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
         // If we have any metadata for this data key, remove the given metadata key.
-        if let Some(metadata_for_data) = self.metadata.get_mut(data_key) {
+        if let Some(metadata_for_data) = self.metadata_set_mut(committed).get_mut(data_key) {
             metadata_for_data.remove(metadata_key);
         }
+        if let Committed::Pending { tx } = committed {
+            self.pending_metadata_deletions
+                .entry(tx.clone())
+                .or_default()
+                .insert((metadata_key.clone(), data_key.clone()));
+        }
         Ok(())
     }
 
@@ -185,11 +500,21 @@ impl DataStore for MemoryDataStore {
         let pending = Committed::Pending { tx: tx.into() };
 
         let constraint_check_result =
-            constraint_check(self, &pending).unwrap_or(ConstraintCheckResult::Reject(
-                "Check constraint function rejected the transaction. Aborting commit".to_string(),
-            ));
+            constraint_check(self, &pending).context(error::CheckConstraintExecutionSnafu)?;
         let approved_write = ApprovedWrite::try_from(constraint_check_result)?;
 
+        if self.snapshot_retention > 0 {
+            self.take_snapshot(Some(tx.to_string()));
+        }
+
+        // Apply metadata changes along with the data.
+        for (metadata_key, data_key) in &approved_write.deleted_metadata {
+            self.unset_metadata(metadata_key, data_key, &Committed::Live)?;
+        }
+        for (metadata_key, data_key, value) in &approved_write.metadata {
+            self.set_metadata(metadata_key, data_key, value, &Committed::Live)?;
+        }
+
         let mut pending_keys: HashSet<Key> = Default::default();
         // Remove anything pending for this transaction
 
@@ -201,7 +526,26 @@ impl DataStore for MemoryDataStore {
             self.set_keys(&approved_write.settings, &Committed::Live)?;
         }
 
+        if !approved_write.deleted_settings.is_empty() {
+            self.unset_keys(&approved_write.deleted_settings, &Committed::Live)?;
+            pending_keys.extend(approved_write.deleted_settings);
+        }
+
         self.pending.remove(tx);
+        self.pending_deletions.remove(tx);
+        self.pending_metadata.remove(tx);
+        self.pending_metadata_deletions.remove(tx);
+        self.transaction_times.remove(tx);
+
+        if !pending_keys.is_empty() {
+            let event = ChangeEvent {
+                transaction: tx.to_string(),
+                keys: pending_keys.clone(),
+            };
+            // Forget subscriptions that were dropped.
+            self.subscribers
+                .retain(|sender| sender.send(event.clone()).is_ok());
+        }
 
         // Return keys that were committed
         Ok(pending_keys)
@@ -212,9 +556,16 @@ impl DataStore for MemoryDataStore {
         S: Into<String> + AsRef<str>,
     {
         // Remove anything pending for this transaction
+        self.transaction_times.remove(transaction.as_ref());
+        self.pending_metadata.remove(transaction.as_ref());
+        self.pending_metadata_deletions.remove(transaction.as_ref());
+        let deleted = self
+            .pending_deletions
+            .remove(transaction.as_ref())
+            .unwrap_or_default();
         if let Some(pending) = self.pending.remove(transaction.as_ref()) {
             // Return the old pending keys
-            Ok(pending.keys().cloned().collect())
+            Ok(pending.keys().cloned().chain(deleted).collect())
         } else {
             Ok(HashSet::new())
         }
@@ -223,6 +574,124 @@ impl DataStore for MemoryDataStore {
     fn list_transactions(&self) -> Result<HashSet<String>> {
         Ok(self.pending.keys().cloned().collect())
     }
+
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>> {
+        Ok(self
+            .pending
+            .keys()
+            .map(|tx| {
+                let times = self.transaction_times.get(tx).copied();
+                // Transactions from logs written before times were recorded count as new.
+                (
+                    tx.clone(),
+                    times.unwrap_or_else(|| TransactionTimes::new(SystemTime::now())),
+                )
+            })
+            .collect())
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId> {
+        Ok(self.take_snapshot(None))
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        Ok(self.snapshots.iter().map(|s| s.info.clone()).collect())
+    }
+
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        let snapshot = self
+            .snapshots
+            .iter()
+            .find(|s| s.info.id == snapshot_id)
+            .context(error::SnapshotNotFoundSnafu { id: snapshot_id })?;
+        self.live = snapshot.live.clone();
+        self.metadata = snapshot.metadata.clone();
+        Ok(())
+    }
+
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription> {
+        let (sender, receiver) = mpsc::channel();
+        let subscription = Subscription::from_channel(prefix, receiver)?;
+        self.subscribers.push(sender);
+        Ok(subscription)
+    }
+}
+
+/// MemoryState holds the contents of a MemoryDataStore, other than its settings and
+/// subscriptions, in a serializable form.  Keys are stored by name because a Key can't be
+/// deserialized without knowing its type.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct MemoryState {
+    live: HashMap<String, String>,
+    /// Data key name -> metadata key name -> value.
+    metadata: HashMap<String, HashMap<String, String>>,
+    /// The rest are keyed by transaction name.
+    pending: HashMap<String, HashMap<String, String>>,
+    pending_deletions: HashMap<String, Vec<String>>,
+    pending_metadata: HashMap<String, HashMap<String, HashMap<String, String>>>,
+    /// (Metadata key name, data key name) pairs.
+    pending_metadata_deletions: HashMap<String, Vec<(String, String)>>,
+    snapshots: Vec<SnapshotState>,
+    next_snapshot_id: SnapshotId,
+    /// Missing from logs compacted before transaction times were recorded.
+    #[serde(default)]
+    transaction_times: HashMap<String, TransactionTimes>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct SnapshotState {
+    info: Snapshot,
+    live: HashMap<String, String>,
+    metadata: HashMap<String, HashMap<String, String>>,
+}
+
+fn map_values<V, T, F>(map: &HashMap<String, V>, f: F) -> HashMap<String, T>
+where
+    F: Fn(&V) -> T,
+{
+    map.iter()
+        .map(|(name, value)| (name.clone(), f(value)))
+        .collect()
+}
+
+fn key_names(data: &HashMap<Key, String>) -> HashMap<String, String> {
+    data.iter()
+        .map(|(key, value)| (key.name().clone(), value.clone()))
+        .collect()
+}
+
+fn metadata_names(
+    metadata: &HashMap<Key, HashMap<Key, String>>,
+) -> HashMap<String, HashMap<String, String>> {
+    metadata
+        .iter()
+        .map(|(data_key, values)| (data_key.name().clone(), key_names(values)))
+        .collect()
+}
+
+/// Parses the data key names of the given data back into Keys.
+fn parse_key_names(data: HashMap<String, String>) -> Result<HashMap<Key, String>> {
+    parse_names(data, KeyType::Data)
+}
+
+fn parse_names(data: HashMap<String, String>, key_type: KeyType) -> Result<HashMap<Key, String>> {
+    data.into_iter()
+        .map(|(name, value)| Ok((Key::new(key_type, name)?, value)))
+        .collect()
+}
+
+fn parse_metadata_names(
+    metadata: HashMap<String, HashMap<String, String>>,
+) -> Result<HashMap<Key, HashMap<Key, String>>> {
+    metadata
+        .into_iter()
+        .map(|(name, values)| {
+            Ok((
+                Key::new(KeyType::Data, name)?,
+                parse_names(values, KeyType::Meta)?,
+            ))
+        })
+        .collect()
 }
 
 fn set_metadata_raw<S: AsRef<str>>(
@@ -244,12 +713,12 @@ fn set_metadata_raw<S: AsRef<str>>(
 
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
 
     use super::super::{Committed, DataStore, Key, KeyType};
     use super::MemoryDataStore;
     use crate::constraints_check::{ApprovedWrite, ConstraintCheckResult};
-    use crate::{deserialize_scalar, serialize_scalar, ScalarError};
+    use crate::{deserialize_scalar, serialize_scalar, Error, ScalarError};
     use maplit::hashset;
 
     fn constraint_check(
@@ -261,6 +730,16 @@ mod test {
             .get_metadata_prefix("settings.", committed, &None as &Option<&str>)
             .unwrap();
 
+        let deleted_metadata = datastore
+            .list_deleted_metadata("settings.", committed)?
+            .into_iter()
+            .flat_map(|(data_key, metadata_keys)| {
+                metadata_keys
+                    .into_iter()
+                    .map(move |metadata_key| (metadata_key, data_key.clone()))
+            })
+            .collect();
+
         let settings_to_commit: HashMap<Key, String> = match committed {
             Committed::Pending { tx: transaction } => datastore
                 .pending
@@ -288,6 +767,8 @@ mod test {
         let approved_write = ApprovedWrite {
             settings: settings_to_commit,
             metadata: metadata_to_commit,
+            deleted_settings: datastore.list_deleted_keys("settings.", committed)?,
+            deleted_metadata,
         };
 
         Ok(ConstraintCheckResult::from(Some(approved_write)))
@@ -333,7 +814,7 @@ mod test {
             Some(md.to_string())
         );
 
-        m.unset_metadata(&mdkey, &k).unwrap();
+        m.unset_metadata(&mdkey, &k, &Committed::Live).unwrap();
         assert_eq!(
             m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
             None
@@ -404,4 +885,170 @@ mod test {
         // Assure other transactions were not deleted
         assert!(m.key_populated(&k2, &pending2).unwrap());
     }
+
+    #[test]
+    fn commit_unset_key() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.x.y.z").unwrap();
+        m.set_key(&k, "\"old\"", &Committed::Live).unwrap();
+        m.set_key(&k2, "\"old\"", &Committed::Live).unwrap();
+
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.unset_key(&k, &pending).unwrap();
+        // Setting the key again in the transaction cancels the deletion.
+        m.unset_key(&k2, &pending).unwrap();
+        m.set_key(&k2, "\"new\"", &pending).unwrap();
+        assert!(m.list_transactions().unwrap().contains(tx));
+        assert_eq!(
+            m.list_deleted_keys("settings.", &pending).unwrap(),
+            hashset!(k.clone())
+        );
+
+        let changed = m.commit_transaction(tx, &constraint_check).unwrap();
+        assert_eq!(changed, hashset!(k.clone(), k2.clone()));
+        assert!(!m.key_populated(&k, &Committed::Live).unwrap());
+        assert_eq!(
+            m.get_key(&k2, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+        assert!(m.list_deleted_keys("", &pending).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pending_metadata_waits_for_commit() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        let strength = Key::new(KeyType::Meta, "strength").unwrap();
+        let generator = Key::new(KeyType::Meta, "setting-generator").unwrap();
+        m.set_metadata(&generator, &k, "\"gen\"", &Committed::Live)
+            .unwrap();
+
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.set_metadata(&strength, &k, "\"weak\"", &pending).unwrap();
+        m.unset_metadata(&generator, &k, &pending).unwrap();
+        assert!(m.list_transactions().unwrap().contains(tx));
+        assert_eq!(
+            m.get_metadata_raw(&strength, &k, &Committed::Live).unwrap(),
+            None
+        );
+        assert!(m
+            .get_metadata_raw(&generator, &k, &Committed::Live)
+            .unwrap()
+            .is_some());
+
+        // Deleting the transaction throws away its metadata changes.
+        m.delete_transaction(tx).unwrap();
+        assert!(m.list_transactions().unwrap().is_empty());
+        assert_eq!(m.get_metadata_raw(&strength, &k, &pending).unwrap(), None);
+
+        m.set_metadata(&strength, &k, "\"weak\"", &pending).unwrap();
+        m.unset_metadata(&generator, &k, &pending).unwrap();
+        m.commit_transaction(tx, &constraint_check).unwrap();
+        assert_eq!(
+            m.get_metadata_raw(&strength, &k, &Committed::Live).unwrap(),
+            Some("\"weak\"".to_string())
+        );
+        assert_eq!(
+            m.get_metadata_raw(&generator, &k, &Committed::Live)
+                .unwrap(),
+            None
+        );
+        assert!(m.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn debug_redacts_sensitive_values() {
+        let mut m = MemoryDataStore::new();
+        let token = Key::new(KeyType::Data, "settings.kubernetes.bootstrap-token").unwrap();
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        m.set_metadata(
+            &crate::sensitive::sensitive_key(),
+            &Key::new(KeyType::Data, "settings.kubernetes").unwrap(),
+            "true",
+            &Committed::Live,
+        )
+        .unwrap();
+        m.set_key(&token, "\"live-secret\"", &Committed::Live)
+            .unwrap();
+        m.set_key(&token, "\"pending-secret\"", &pending).unwrap();
+        m.set_key(&motd, "\"hi\"", &Committed::Live).unwrap();
+
+        let debug = format!("{m:?}");
+        assert!(!debug.contains("secret"), "{debug}");
+        assert!(debug.contains("hi"), "{debug}");
+    }
+
+    #[test]
+    fn subscribers_get_commits_under_prefix() {
+        let mut m = MemoryDataStore::new();
+        let mut motd = m.subscribe("settings.motd").unwrap();
+        let mut everything = m.subscribe("").unwrap();
+        let motd_key = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let ntp_key = Key::new(KeyType::Data, "settings.ntp.servers").unwrap();
+
+        let pending = Committed::Pending { tx: "tx1".into() };
+        m.set_key(&ntp_key, "\"[]\"", &pending).unwrap();
+        m.commit_transaction("tx1", &constraint_check).unwrap();
+        let pending = Committed::Pending { tx: "tx2".into() };
+        m.set_key(&motd_key, "\"hi\"", &pending).unwrap();
+        m.unset_key(&ntp_key, &pending).unwrap();
+        m.commit_transaction("tx2", &constraint_check).unwrap();
+
+        // Commits that don't touch the prefix are skipped.
+        let event = motd.try_recv().unwrap().unwrap();
+        assert_eq!(event.transaction, "tx2");
+        assert_eq!(event.keys, HashSet::from([motd_key.clone()]));
+        assert_eq!(motd.try_recv().unwrap(), None);
+
+        assert_eq!(everything.recv().unwrap().transaction, "tx1");
+        let event = everything.recv().unwrap();
+        assert_eq!(event.keys, HashSet::from([motd_key, ntp_key]));
+
+        drop(m);
+        assert!(matches!(motd.recv(), Err(Error::SubscriptionClosed)));
+    }
+
+    #[test]
+    fn restore_undoes_commit() {
+        let mut m = MemoryDataStore::new().with_snapshot_retention(5);
+        let k = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        m.set_key(&k, "\"old\"", &Committed::Live).unwrap();
+
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.set_key(&k, "\"new\"", &pending).unwrap();
+        m.commit_transaction(tx, &constraint_check).unwrap();
+        assert_eq!(
+            m.get_key(&k, &Committed::Live).unwrap(),
+            Some("\"new\"".to_string())
+        );
+
+        let snapshots = m.list_snapshots().unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].transaction, Some(tx.to_string()));
+
+        m.restore(snapshots[0].id).unwrap();
+        assert_eq!(
+            m.get_key(&k, &Committed::Live).unwrap(),
+            Some("\"old\"".to_string())
+        );
+        m.restore(snapshots[0].id + 1).unwrap_err();
+    }
+
+    #[test]
+    fn snapshot_retention() {
+        let mut m = MemoryDataStore::new().with_snapshot_retention(2);
+        let ids: Vec<_> = (0..3).map(|_| m.snapshot().unwrap()).collect();
+        let kept: Vec<_> = m
+            .list_snapshots()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.id)
+            .collect();
+        assert_eq!(kept, ids[1..]);
+    }
 }
@@ -0,0 +1,465 @@
+//! OverlayDataStore layers a writable data store over a read-only one, so defaults and user-set
+//! values are kept physically separate.
+//!
+//! Live reads fall through from the upper layer to the lower layer, so the lower layer provides
+//! defaults that the upper layer overrides.  All writes go to the upper layer; the lower layer is
+//! never changed through the overlay.  Unsetting a key in the upper layer removes the override
+//! and reveals the default again, so defaults can be reset but not removed.  `is_overridden`
+//! tells whether a key's value comes from the upper layer.
+//!
+//! Lists are replaced as a whole, so once the upper layer has any key of a list, the lower
+//! layer's keys of that list are hidden, whether the list is stored as one value or by index.
+//!
+//! Metadata falls through one metadata key at a time, so `get_metadata` inherits metadata from
+//! earlier in the tree in either layer, and a more specific value in one layer beats a less
+//! specific one in the other.
+//!
+//! Pending transactions, snapshots, and subscriptions only involve the upper layer, since the
+//! lower layer doesn't change.  A key's revision is the sum of its revisions in the two layers,
+//! so it still increases each time the key is written.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use crate::constraints_check::{ConstraintCheckResult, Rejection};
+use crate::{
+    Committed, DataStore, Key, KeyType, Result, Revision, Snapshot, SnapshotId, Subscription,
+    TransactionTimes,
+};
+
+#[derive(Debug)]
+pub struct OverlayDataStore<L, U> {
+    lower: L,
+    upper: U,
+}
+
+impl<L: DataStore, U: DataStore> OverlayDataStore<L, U> {
+    /// Layers `upper`, which receives all writes, over the defaults in `lower`.
+    pub fn new(lower: L, upper: U) -> Self {
+        Self { lower, upper }
+    }
+
+    /// Returns the read-only lower layer.
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+
+    /// Returns the upper layer.
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    /// Returns the lower and upper layers.
+    pub fn into_inner(self) -> (L, U) {
+        (self.lower, self.upper)
+    }
+
+    /// Returns whether the live value of the given data key comes from the upper layer rather
+    /// than the lower layer, including when the upper layer replaced the list the key is in.
+    /// Unsetting an overridden key resets it to its default.
+    pub fn is_overridden(&self, key: &Key) -> Result<bool> {
+        if self.upper.key_populated(key, &Committed::Live)? {
+            return Ok(true);
+        }
+        Ok(self
+            .upper_list_roots(key.segments())?
+            .contains(list_root(key.segments())))
+    }
+
+    /// Returns the list roots (see `list_root`) of the upper layer's live keys that could share
+    /// a root with keys under the given segments.
+    fn upper_list_roots(&self, segments: &[String]) -> Result<HashSet<Vec<String>>> {
+        let root = list_root(segments);
+        let prefix = if root.is_empty() {
+            String::new()
+        } else {
+            Key::from_segments(KeyType::Data, root)?.name().clone()
+        };
+        Ok(self
+            .upper
+            .list_populated_keys(prefix, &Committed::Live)?
+            .iter()
+            .map(|key| list_root(key.segments()).to_vec())
+            .collect())
+    }
+}
+
+/// Returns the segments naming the list the given key is an element of, or the whole key if it
+/// isn't in a list stored by index.  Keys with the same root belong to the same list, so a
+/// lower key is hidden if an upper key has its root.
+fn list_root(segments: &[String]) -> &[String] {
+    let end = segments
+        .iter()
+        .position(|s| Key::parse_index_segment(s).is_some())
+        .unwrap_or(segments.len());
+    &segments[..end]
+}
+
+impl<L: DataStore, U: DataStore> DataStore for OverlayDataStore<L, U> {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        if self.upper.key_populated(key, committed)? {
+            return Ok(true);
+        }
+        match committed {
+            Committed::Live => {
+                Ok(self.lower.key_populated(key, committed)? && !self.is_overridden(key)?)
+            }
+            Committed::Pending { .. } => Ok(false),
+        }
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let mut keys = self.upper.list_populated_keys(&prefix, committed)?;
+        if let Committed::Live = committed {
+            let roots = self.upper_list_roots(&Key::prefix_segments(&prefix)?)?;
+            keys.extend(
+                self.lower
+                    .list_populated_keys(&prefix, committed)?
+                    .into_iter()
+                    .filter(|key| !roots.contains(list_root(key.segments()))),
+            );
+        }
+        Ok(keys)
+    }
+
+    fn list_deleted_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.upper.list_deleted_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let mut metadata =
+            self.upper
+                .list_populated_metadata(&prefix, committed, metadata_key_name)?;
+        if let Committed::Live = committed {
+            for (data_key, metadata_keys) in
+                self.lower
+                    .list_populated_metadata(&prefix, committed, metadata_key_name)?
+            {
+                metadata.entry(data_key).or_default().extend(metadata_keys);
+            }
+        }
+        Ok(metadata)
+    }
+
+    fn list_deleted_metadata<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.upper.list_deleted_metadata(prefix, committed)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        if let Some(value) = self.upper.get_key(key, committed)? {
+            return Ok(Some(value));
+        }
+        match committed {
+            Committed::Live if !self.is_overridden(key)? => self.lower.get_key(key, committed),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.upper.set_key(key, value, committed)
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.upper.unset_key(key, committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let value = self
+            .upper
+            .get_metadata_raw(metadata_key, data_key, committed)?;
+        match (value, committed) {
+            (None, Committed::Live) => {
+                self.lower
+                    .get_metadata_raw(metadata_key, data_key, committed)
+            }
+            (value, _) => Ok(value),
+        }
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.upper
+            .set_metadata(metadata_key, data_key, value, committed)
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.upper.unset_metadata(metadata_key, data_key, committed)
+    }
+
+    /// Runs the constraint check against the overlay, so it sees the defaults, then commits the
+    /// approved write to the upper layer.
+    fn commit_transaction<S, C>(
+        &mut self,
+        transaction: S,
+        constraint_check: &C,
+    ) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+        C: Fn(
+            &mut Self,
+            &Committed,
+        ) -> std::result::Result<
+            ConstraintCheckResult,
+            Box<dyn std::error::Error + Send + Sync + 'static>,
+        >,
+    {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        let result = RefCell::new(Some(constraint_check(self, &pending)));
+        self.upper.commit_transaction(transaction, &|_, _| {
+            result.borrow_mut().take().unwrap_or_else(|| {
+                Ok(ConstraintCheckResult::Reject(vec![Rejection::new(
+                    "overlay",
+                    "Constraint check result was already used",
+                )]))
+            })
+        })
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.upper.delete_transaction(transaction)
+    }
+
+    fn get_revision(&self, key: &Key) -> Result<Revision> {
+        Ok(self.lower.get_revision(key)? + self.upper.get_revision(key)?)
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.upper.list_transactions()
+    }
+
+    fn list_transaction_times(&self) -> Result<HashMap<String, TransactionTimes>> {
+        self.upper.list_transaction_times()
+    }
+
+    fn snapshot(&mut self) -> Result<SnapshotId> {
+        self.upper.snapshot()
+    }
+
+    fn list_snapshots(&self) -> Result<Vec<Snapshot>> {
+        self.upper.list_snapshots()
+    }
+
+    fn restore(&mut self, snapshot_id: SnapshotId) -> Result<()> {
+        self.upper.restore(snapshot_id)
+    }
+
+    fn subscribe<S: AsRef<str>>(&mut self, prefix: S) -> Result<Subscription> {
+        self.upper.subscribe(prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::constraints_check::ApprovedWrite;
+    use crate::memory::MemoryDataStore;
+    use crate::test_util::TestDir;
+    use crate::FilesystemDataStore;
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    fn meta(name: &str) -> Key {
+        Key::new(KeyType::Meta, name).unwrap()
+    }
+
+    /// Returns a lower layer with defaults for a motd, an NTP server list stored by index, and
+    /// inherited metadata.
+    fn defaults() -> MemoryDataStore {
+        let mut lower = MemoryDataStore::new();
+        let live = &Committed::Live;
+        lower
+            .set_key(&key("settings.motd"), "\"hi\"", live)
+            .unwrap();
+        lower
+            .set_key(&key("settings.ntp[0].host"), "\"a\"", live)
+            .unwrap();
+        lower
+            .set_key(&key("settings.ntp[1].host"), "\"b\"", live)
+            .unwrap();
+        lower
+            .set_metadata(
+                &meta("affected-services"),
+                &key("settings"),
+                "[\"all\"]",
+                live,
+            )
+            .unwrap();
+        lower
+    }
+
+    fn approve_all<D: DataStore>(
+        datastore: &mut D,
+        committed: &Committed,
+    ) -> std::result::Result<ConstraintCheckResult, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(ConstraintCheckResult::from(Some(ApprovedWrite {
+            settings: datastore.get_prefix("settings", committed)?,
+            metadata: Vec::new(),
+            deleted_settings: datastore.list_deleted_keys("settings", committed)?,
+            deleted_metadata: Vec::new(),
+        })))
+    }
+
+    #[test]
+    fn override_and_reset() {
+        let mut o = OverlayDataStore::new(defaults(), MemoryDataStore::new());
+        let motd = key("settings.motd");
+        let live = &Committed::Live;
+        assert_eq!(o.get_key(&motd, live).unwrap(), Some("\"hi\"".to_string()));
+        assert!(!o.is_overridden(&motd).unwrap());
+        let default_revision = o.get_revision(&motd).unwrap();
+
+        o.set_key(&motd, "\"hello\"", live).unwrap();
+        assert_eq!(
+            o.get_key(&motd, live).unwrap(),
+            Some("\"hello\"".to_string())
+        );
+        assert!(o.is_overridden(&motd).unwrap());
+        assert_eq!(
+            o.lower().get_key(&motd, live).unwrap(),
+            Some("\"hi\"".to_string())
+        );
+
+        o.unset_key(&motd, live).unwrap();
+        assert_eq!(o.get_key(&motd, live).unwrap(), Some("\"hi\"".to_string()));
+        assert!(!o.is_overridden(&motd).unwrap());
+        // Both writes count, even though the default is back.
+        assert_eq!(o.get_revision(&motd).unwrap(), default_revision + 2);
+    }
+
+    #[test]
+    fn lists_replaced_whole() {
+        let mut o = OverlayDataStore::new(defaults(), MemoryDataStore::new());
+        let live = &Committed::Live;
+        o.set_key(&key("settings.ntp[0].host"), "\"c\"", live)
+            .unwrap();
+        assert_eq!(
+            o.list_populated_keys("settings.ntp", live).unwrap(),
+            HashSet::from([key("settings.ntp[0].host")])
+        );
+        assert!(o.is_overridden(&key("settings.ntp[1].host")).unwrap());
+        assert!(!o.key_populated(&key("settings.ntp[1].host"), live).unwrap());
+
+        // Stored as one value, the list still hides the default elements.
+        o.unset_key(&key("settings.ntp[0].host"), live).unwrap();
+        o.set_key(&key("settings.ntp"), "[]", live).unwrap();
+        assert_eq!(
+            o.list_populated_keys("settings", live).unwrap(),
+            HashSet::from([key("settings.motd"), key("settings.ntp")])
+        );
+
+        o.unset_key(&key("settings.ntp"), live).unwrap();
+        assert_eq!(
+            o.list_populated_keys("settings.ntp", live).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn metadata_inherits_across_layers() {
+        let mut o = OverlayDataStore::new(defaults(), MemoryDataStore::new());
+        let live = &Committed::Live;
+        let services = meta("affected-services");
+        let motd = key("settings.motd");
+        assert_eq!(
+            o.get_metadata(&services, &motd, live).unwrap(),
+            Some("[\"all\"]".to_string())
+        );
+
+        o.set_metadata(&services, &motd, "[\"motd\"]", live)
+            .unwrap();
+        assert_eq!(
+            o.get_metadata(&services, &motd, live).unwrap(),
+            Some("[\"motd\"]".to_string())
+        );
+        let listed = o
+            .list_populated_metadata("settings", live, &Some("affected-services"))
+            .unwrap();
+        assert_eq!(listed.len(), 2);
+
+        o.unset_metadata(&services, &motd, live).unwrap();
+        assert_eq!(
+            o.get_metadata(&services, &motd, live).unwrap(),
+            Some("[\"all\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn commit_to_upper_layer() {
+        let dir = TestDir::new("overlay-commit");
+        let upper = FilesystemDataStore::new(&dir.0).unwrap();
+        let mut o = OverlayDataStore::new(defaults(), upper);
+        let motd = key("settings.motd");
+        let hostname = key("settings.hostname");
+        let pending = Committed::Pending { tx: "tx".into() };
+
+        o.set_key(&hostname, "\"box\"", &pending).unwrap();
+        o.unset_key(&motd, &pending).unwrap();
+        assert_eq!(o.get_key(&motd, &pending).unwrap(), None);
+        assert_eq!(
+            o.list_transactions().unwrap(),
+            HashSet::from(["tx".to_string()])
+        );
+        let diff = o.diff_transaction("tx").unwrap();
+        assert!(diff.added.contains_key("settings.hostname"));
+
+        let changed = o.commit_transaction("tx", &approve_all).unwrap();
+        assert_eq!(changed, HashSet::from([hostname.clone(), motd.clone()]));
+        assert!(o.is_overridden(&hostname).unwrap());
+        // Removing a key in the upper layer only resets it.
+        assert_eq!(
+            o.get_key(&motd, &Committed::Live).unwrap(),
+            Some("\"hi\"".to_string())
+        );
+        assert_eq!(
+            o.upper().get_key(&hostname, &Committed::Live).unwrap(),
+            Some("\"box\"".to_string())
+        );
+        assert_eq!(
+            o.lower().get_key(&hostname, &Committed::Live).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,221 @@
+//! KeyPattern matches data key names with wildcards, for finding keys like "every
+//! `settings.host-containers.*.enabled`" without listing a parent and filtering by hand.
+//!
+//! Patterns are written like key names, with two wildcards that each take up a whole segment:
+//!
+//! * `*` matches exactly one segment, so `settings.host-containers.*.enabled` matches
+//!   `settings.host-containers.admin.enabled` but not `settings.host-containers.enabled`.
+//! * `**` matches any number of segments, including none, so `settings.kubernetes.**` matches
+//!   `settings.kubernetes` and every key under it.
+//!
+//! Other segments match literally, and can be quoted like in key names, so `"a.b".*` matches
+//! `"a.b".c` and not `a.b.c`.  List indexes are segments too, so `settings.list.*.name` matches
+//! `settings.list[0].name`.
+//!
+//! `DataStore::query` and `DataStore::get_metadata_query` return the keys matching a pattern,
+//! reading only the keys under the segments before its first wildcard.
+
+use snafu::ensure;
+use std::fmt;
+
+use crate::{error, Key, KeyType, Result, KEY_SEPARATOR};
+
+/// Matches exactly one segment.
+const ONE: &str = "*";
+/// Matches any number of segments.
+const ANY: &str = "**";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    Segment(String),
+    One,
+    Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPattern {
+    pattern: String,
+    elements: Vec<Element>,
+    prefix: String,
+}
+
+impl KeyPattern {
+    pub fn new<S: AsRef<str>>(pattern: S) -> Result<Self> {
+        let pattern = pattern.as_ref();
+        let invalid = |msg: &'static str| error::InvalidKeyPatternSnafu { pattern, msg };
+        ensure!(!pattern.is_empty(), invalid("cannot be empty"));
+
+        let mut elements = Vec::new();
+        for part in split_unquoted(pattern) {
+            match part {
+                "" => return invalid("empty key segment").fail(),
+                ONE => elements.push(Element::One),
+                // Consecutive `**` match the same keys as one.
+                ANY if elements.last() == Some(&Element::Any) => {}
+                ANY => elements.push(Element::Any),
+                _ => {
+                    ensure!(
+                        !part.contains('*'),
+                        invalid("wildcards must be a whole segment")
+                    );
+                    let key = Key::new(KeyType::Data, part).map_err(|e| {
+                        error::InvalidKeyPatternSnafu {
+                            pattern,
+                            msg: e.to_string(),
+                        }
+                        .build()
+                    })?;
+                    elements.extend(key.segments().iter().cloned().map(Element::Segment));
+                }
+            }
+        }
+
+        let literal: Vec<_> = elements
+            .iter()
+            .map_while(|element| match element {
+                Element::Segment(segment) => Some(segment),
+                _ => None,
+            })
+            .collect();
+        let prefix = if literal.is_empty() {
+            String::new()
+        } else {
+            Key::from_segments(KeyType::Data, &literal)?.name().clone()
+        };
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            elements,
+            prefix,
+        })
+    }
+
+    /// Returns the name of the key made of the pattern's segments before its first wildcard, or
+    /// an empty string if it starts with one.  Every matching key starts with this prefix.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// Returns whether the given key's name matches the pattern.
+    pub fn matches(&self, key: &Key) -> bool {
+        matches_segments(&self.elements, key.segments())
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// Splits a pattern at separators outside quotes.  Quotes are kept, so each part can be parsed as
+/// a key name.
+fn split_unquoted(pattern: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            KEY_SEPARATOR if !in_quotes => {
+                parts.push(&pattern[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&pattern[start..]);
+    parts
+}
+
+fn matches_segments(elements: &[Element], segments: &[String]) -> bool {
+    match elements.split_first() {
+        None => segments.is_empty(),
+        Some((Element::Any, rest)) => {
+            (0..=segments.len()).any(|skip| matches_segments(rest, &segments[skip..]))
+        }
+        Some((element, rest)) => match segments.split_first() {
+            None => false,
+            Some((segment, remaining)) => {
+                let matched = match element {
+                    Element::Segment(literal) => literal == segment,
+                    _ => true,
+                };
+                matched && matches_segments(rest, remaining)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let key = Key::new(KeyType::Data, name).unwrap();
+        KeyPattern::new(pattern).unwrap().matches(&key)
+    }
+
+    #[test]
+    fn one_segment() {
+        let pattern = "settings.host-containers.*.enabled";
+        assert!(matches(pattern, "settings.host-containers.admin.enabled"));
+        assert!(!matches(pattern, "settings.host-containers.enabled"));
+        assert!(!matches(pattern, "settings.host-containers.a.b.enabled"));
+        assert!(!matches(pattern, "settings.host-containers.admin.source"));
+        assert!(matches("*", "motd"));
+        assert!(!matches("*", "settings.motd"));
+    }
+
+    #[test]
+    fn any_segments() {
+        let pattern = "settings.kubernetes.**";
+        assert!(matches(pattern, "settings.kubernetes"));
+        assert!(matches(
+            pattern,
+            "settings.kubernetes.node-taints.dedicated"
+        ));
+        assert!(!matches(pattern, "settings.kubernetes-extra.x"));
+        assert!(matches("**.enabled", "enabled"));
+        assert!(matches("**.enabled", "settings.a.b.enabled"));
+        assert!(matches("settings.**.**.x", "settings.x"));
+        assert!(matches("settings.**.*", "settings.a.b"));
+        assert!(!matches("settings.**.*", "settings"));
+    }
+
+    #[test]
+    fn quoted_and_indexed_segments() {
+        assert!(matches("\"a.b\".*", "\"a.b\".c"));
+        assert!(!matches("\"a.b\".*", "a.b.c"));
+        assert!(matches("a.*", "a.\"b.c\""));
+        assert!(matches("settings.list.*.name", "settings.list[0].name"));
+        assert!(matches("settings.list[1].*", "settings.list[1].name"));
+        assert!(!matches("settings.list[1].*", "settings.list[0].name"));
+    }
+
+    #[test]
+    fn prefix() {
+        let prefix = |pattern| KeyPattern::new(pattern).unwrap().prefix().to_string();
+        assert_eq!(
+            prefix("settings.host-containers.*.enabled"),
+            "settings.host-containers"
+        );
+        assert_eq!(prefix("\"a.b\".c.**"), "\"a.b\".c");
+        assert_eq!(prefix("a.b[0].*"), "a.b[0]");
+        assert_eq!(prefix("**.enabled"), "");
+        assert_eq!(prefix("settings.motd"), "settings.motd");
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        for pattern in ["", "a..b", "a.", "a.b*", "a.*b", "***", "\"a.*", "a.b!"] {
+            assert!(
+                matches!(
+                    KeyPattern::new(pattern),
+                    Err(error::Error::InvalidKeyPattern { .. })
+                ),
+                "{pattern}"
+            );
+        }
+    }
+}
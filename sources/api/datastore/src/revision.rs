@@ -0,0 +1,197 @@
+//! Revisions let writers detect that live data changed underneath them.
+//!
+//! Each live data key has a revision counter, stored as the reserved metadata key "revision" on
+//! the data key.  A key that's never been written is at revision 0, and the revision increases
+//! each time the key is set or unset in live, including by a commit.
+//!
+//! Revisions are bookkeeping rather than user metadata, so they're left out of metadata listings
+//! like `DataStore::list_populated_metadata` and `DataStore::get_metadata_prefix`, and with them,
+//! out of exported documents and migration input.  Code that copies live data to another data
+//! store, like a migration, carries revisions over with `carry`.
+//!
+//! Lists stored by index are replaced as a whole, so their elements share the list's revision:
+//! the revision of "a.b[0].c" is the revision of "a.b".  This also keeps revisions of removed
+//! elements from lingering under the list, which a FilesystemDataStore would need to remove
+//! before storing the list as a single value again.
+//!
+//! Writers read a key's revision with `DataStore::get_revision`, then write with
+//! `DataStore::set_key_if_revision` or `DataStore::commit_transaction_if_unchanged`, which fail
+//! with `Error::Conflict` if the revision has moved on.
+
+use snafu::{ensure, OptionExt};
+use std::collections::HashSet;
+
+use crate::{error, Committed, DataStore, Key, KeyType, Result};
+
+/// A data key's revision counter.
+pub type Revision = u64;
+
+/// Name of the metadata key that holds each data key's revision.  Don't set it directly.
+pub const REVISION_METADATA_KEY: &str = "revision";
+
+/// Returns the metadata key that holds each data key's revision.
+pub(crate) fn revision_key() -> Key {
+    Key::new(KeyType::Meta, REVISION_METADATA_KEY)
+        .unwrap_or_else(|_| unreachable!("Invalid revision metadata key"))
+}
+
+/// Returns whether the given metadata key holds revisions, and should be left out of listings.
+pub(crate) fn is_revision_key(metadata_key: &Key) -> bool {
+    metadata_key.name() == REVISION_METADATA_KEY
+}
+
+/// Returns the data key that holds the revision of the given data key: the key itself, or for
+/// an element of a list stored by index, the list.
+pub(crate) fn revision_holder(key: &Key) -> Result<Key> {
+    let segments = key.segments();
+    match segments
+        .iter()
+        .position(|s| Key::parse_index_segment(s).is_some())
+    {
+        Some(index) if index > 0 => Key::from_segments(KeyType::Data, &segments[..index]),
+        _ => Ok(key.clone()),
+    }
+}
+
+/// Parses the stored revision of the given data key; no stored value means revision 0.
+pub(crate) fn parse(key: &Key, value: Option<String>) -> Result<Revision> {
+    match value {
+        None => Ok(0),
+        Some(value) => value.parse().ok().context(error::InvalidRevisionSnafu {
+            key: key.name(),
+            value,
+        }),
+    }
+}
+
+/// Increments the revision of the given live data key, returning the new revision.
+pub(crate) fn bump<D: DataStore + ?Sized>(datastore: &mut D, key: &Key) -> Result<Revision> {
+    let revision = datastore.get_revision(key)? + 1;
+    let holder = revision_holder(key)?;
+    datastore.set_metadata(
+        &revision_key(),
+        &holder,
+        revision.to_string(),
+        &Committed::Live,
+    )?;
+    Ok(revision)
+}
+
+/// Returns the metadata writes that increment the revisions of the given live data keys, in the
+/// form used by `ApprovedWrite::metadata`, so they can be applied along with a commit.
+pub(crate) fn next_revisions<'a, D, I>(datastore: &D, keys: I) -> Result<Vec<(Key, Key, String)>>
+where
+    D: DataStore + ?Sized,
+    I: IntoIterator<Item = &'a Key>,
+{
+    let revision_key = revision_key();
+    let holders = keys
+        .into_iter()
+        .map(revision_holder)
+        .collect::<Result<HashSet<_>>>()?;
+    holders
+        .into_iter()
+        .map(|holder| {
+            let revision = datastore.get_revision(&holder)? + 1;
+            Ok((revision_key.clone(), holder, revision.to_string()))
+        })
+        .collect()
+}
+
+/// Sets the revision of the given live data key in the target to one past its revision in the
+/// source, for code that copies live data between data stores.  The copy may have changed, so a
+/// writer still holding the source's revision gets a conflict rather than a match.  Call this
+/// after writing the key to the target, since writing it bumps its revision.
+pub fn carry<S, T>(source: &S, target: &mut T, key: &Key) -> Result<Revision>
+where
+    S: DataStore + ?Sized,
+    T: DataStore + ?Sized,
+{
+    let revision = source.get_revision(key)? + 1;
+    let holder = revision_holder(key)?;
+    target.set_metadata(
+        &revision_key(),
+        &holder,
+        revision.to_string(),
+        &Committed::Live,
+    )?;
+    Ok(revision)
+}
+
+/// Returns Error::Conflict unless each of the given live data keys is at the given revision.
+pub(crate) fn check<'a, D, I>(datastore: &D, expected: I) -> Result<()>
+where
+    D: DataStore + ?Sized,
+    I: IntoIterator<Item = (&'a Key, &'a Revision)>,
+{
+    for (key, &expected) in expected {
+        let actual = datastore.get_revision(key)?;
+        ensure!(
+            actual == expected,
+            error::ConflictSnafu {
+                key: key.name(),
+                expected,
+                actual,
+            }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::memory::MemoryDataStore;
+    use crate::{Committed, DataStore, Error, Key, KeyType};
+    use maplit::hashmap;
+
+    #[test]
+    fn set_key_if_revision() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        assert_eq!(m.get_revision(&k).unwrap(), 0);
+        assert_eq!(m.set_key_if_revision(&k, "1", 0).unwrap(), 1);
+
+        // Someone else writes the key, so our revision is stale.
+        m.set_key(&k, "2", &Committed::Live).unwrap();
+        match m.set_key_if_revision(&k, "3", 1) {
+            Err(Error::Conflict {
+                expected, actual, ..
+            }) => assert_eq!((expected, actual), (1, 2)),
+            other => panic!("expected conflict, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(
+            m.get_key(&k, &Committed::Live).unwrap(),
+            Some("2".to_string())
+        );
+
+        // Unsetting counts as a change too.
+        m.unset_key(&k, &Committed::Live).unwrap();
+        assert_eq!(m.get_revision(&k).unwrap(), 3);
+    }
+
+    #[test]
+    fn revisions_not_listed() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        let mk = Key::new(KeyType::Meta, "affected-services").unwrap();
+        m.set_key(&k, "1", &Committed::Live).unwrap();
+        m.set_metadata(&mk, &k, "[]", &Committed::Live).unwrap();
+        assert_eq!(m.get_revision(&k).unwrap(), 1);
+
+        let listed = m
+            .get_metadata_prefix("settings", &Committed::Live, &None as &Option<&str>)
+            .unwrap();
+        assert_eq!(listed, hashmap!(k => hashmap!(mk => "[]".to_string())));
+    }
+
+    #[test]
+    fn list_elements_share_revision() {
+        let mut m = MemoryDataStore::new();
+        let list = Key::new(KeyType::Data, "settings.l").unwrap();
+        let element = Key::new(KeyType::Data, "settings.l[0].x").unwrap();
+        m.set_key(&element, "1", &Committed::Live).unwrap();
+        assert_eq!(m.get_revision(&list).unwrap(), 1);
+        m.set_key(&list, "[]", &Committed::Live).unwrap();
+        assert_eq!(m.get_revision(&element).unwrap(), 2);
+    }
+}
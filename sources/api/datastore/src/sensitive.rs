@@ -0,0 +1,286 @@
+//! Sensitive settings, like credentials and bootstrap tokens, are marked with the metadata key
+//! "sensitive" set to `true`.  Like other metadata, the flag is inherited, so marking
+//! "settings.aws" marks every key under it; see `DataStore::get_metadata`.
+//!
+//! A FilesystemDataStore opened with an `EncryptionKey`, loaded from a host-local key file at
+//! DEFAULT_KEY_PATH unless told otherwise, encrypts the values of sensitive keys before writing
+//! them to disk, and decrypts them when they're read, so callers only ever see plain values.
+//! Encrypted values are stored as JSON strings, so the data store stays valid JSON throughout.
+//! Marking a key sensitive doesn't encrypt a value that's already stored; it's encrypted the next
+//! time it's written.  Writing a sensitive value, or reading an encrypted one, without a key is an
+//! error, rather than quietly falling back to storing it in the clear.
+//!
+//! Values of sensitive keys are replaced with `REDACTED` wherever the data store shows values
+//! rather than returning them, like a `TransactionDiff` or `Debug` output.  Callers that log
+//! values should redact them first with `redact`.
+
+use aws_lc_rs::aead::{Aad, Nonce, RandomizedNonceKey, AES_256_GCM, NONCE_LEN};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::filesystem::sync_parent_dir;
+use crate::{error, Committed, DataStore, Key, KeyType, Result};
+
+/// Name of the metadata key that marks data keys as sensitive.
+pub const SENSITIVE_METADATA_KEY: &str = "sensitive";
+
+/// Where the host's key file is kept.  It's outside the data store directory, so it's shared by
+/// every version of the data store, including the ones migrations write.
+pub const DEFAULT_KEY_PATH: &str = "/var/lib/bottlerocket/datastore.key";
+
+/// Shown in place of the values of sensitive keys.
+pub const REDACTED: &str = "<redacted>";
+
+/// Encrypted values are JSON strings starting with this prefix, followed by the base64 encoding
+/// of the nonce and the sealed value.
+const ENCRYPTED_PREFIX: &str = "encrypted:aes-256-gcm:";
+
+/// The length of an AES-256 key, which is the whole contents of a key file.
+const KEY_LEN: usize = 32;
+
+/// Returns the metadata key that marks data keys as sensitive.
+pub fn sensitive_key() -> Key {
+    Key::new(KeyType::Meta, SENSITIVE_METADATA_KEY)
+        .unwrap_or_else(|_| unreachable!("Invalid sensitive metadata key"))
+}
+
+/// Returns whether the given data key is sensitive, in the given transaction or in live data.
+/// Pending keys are also sensitive if they're marked in live, so a transaction can't unmark a key
+/// before it's committed.
+pub fn is_sensitive<D>(datastore: &D, key: &Key, committed: &Committed) -> Result<bool>
+where
+    D: DataStore + ?Sized,
+{
+    let flagged = |committed| {
+        datastore
+            .get_metadata(&sensitive_key(), key, committed)
+            .map(|value| value.is_some_and(|value| value.trim() == "true"))
+    };
+    match committed {
+        Committed::Live => flagged(&Committed::Live),
+        pending => Ok(flagged(pending)? || flagged(&Committed::Live)?),
+    }
+}
+
+/// Replaces the values of sensitive keys in the given map with REDACTED.
+pub fn redact<D>(
+    datastore: &D,
+    values: &mut HashMap<Key, String>,
+    committed: &Committed,
+) -> Result<()>
+where
+    D: DataStore + ?Sized,
+{
+    for (key, value) in values.iter_mut() {
+        if is_sensitive(datastore, key, committed)? {
+            *value = REDACTED.to_string();
+        }
+    }
+    Ok(())
+}
+
+/// A key for encrypting sensitive values, loaded from a key file that holds the raw key.
+pub struct EncryptionKey {
+    key: RandomizedNonceKey,
+}
+
+impl EncryptionKey {
+    /// Loads the key from the given file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).context(error::IoSnafu { path })?;
+        ensure!(
+            bytes.len() == KEY_LEN,
+            error::InvalidEncryptionKeySnafu {
+                path,
+                msg: format!("expected {KEY_LEN} bytes, found {}", bytes.len()),
+            }
+        );
+        Self::from_bytes(&bytes).context(error::InvalidEncryptionKeySnafu {
+            path,
+            msg: "not an AES-256 key",
+        })
+    }
+
+    /// Loads the key from the given file, first generating a new random key in it if it doesn't
+    /// exist.  The file is only readable by its owner.
+    pub fn load_or_create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut bytes = [0; KEY_LEN];
+        aws_lc_rs::rand::fill(&mut bytes)
+            .ok()
+            .context(error::GenerateEncryptionKeySnafu { path })?;
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(&bytes)?;
+                file.sync_all()
+            });
+        match created {
+            Ok(()) => sync_parent_dir(path)?,
+            // Someone else created it first; use theirs.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                // Don't leave a partial key behind to be loaded next time.
+                let _ = fs::remove_file(path);
+                return Err(e).context(error::IoSnafu { path });
+            }
+        }
+        Self::load(path)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let key = RandomizedNonceKey::new(&AES_256_GCM, bytes).ok()?;
+        Some(Self { key })
+    }
+
+    /// Encrypts the value of the given data key, returning the value to store.  The key's name
+    /// is bound to the result, so it can't be moved to another key.
+    pub(crate) fn encrypt(&self, key: &Key, value: &str) -> Result<String> {
+        let mut sealed = value.as_bytes().to_vec();
+        let nonce = self
+            .key
+            .seal_in_place_append_tag(Aad::from(key.name().as_bytes()), &mut sealed)
+            .ok()
+            .context(error::EncryptionSnafu { key: key.name() })?;
+        let mut bytes = nonce.as_ref().to_vec();
+        bytes.extend(sealed);
+        let encrypted = format!("{ENCRYPTED_PREFIX}{}", BASE64.encode(bytes));
+        serde_json::to_string(&encrypted).context(error::SerializeSnafu)
+    }
+
+    /// Decrypts a stored value of the given data key that was encrypted by `encrypt`.
+    fn decrypt(&self, key: &Key, encoded: &str) -> Result<String> {
+        let decryption_error = || error::DecryptionSnafu { key: key.name() };
+        let mut bytes = BASE64.decode(encoded).ok().with_context(decryption_error)?;
+        ensure!(bytes.len() >= NONCE_LEN, decryption_error());
+        let mut sealed = bytes.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&bytes)
+            .ok()
+            .with_context(decryption_error)?;
+        let value = self
+            .key
+            .open_in_place(nonce, Aad::from(key.name().as_bytes()), &mut sealed)
+            .ok()
+            .with_context(decryption_error)?;
+        String::from_utf8(value.to_vec())
+            .ok()
+            .with_context(decryption_error)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("key", &REDACTED)
+            .finish()
+    }
+}
+
+/// If the given stored value of a data key was encrypted, returns the part after the prefix.
+fn encrypted_part(value: &str) -> Option<String> {
+    if !value.starts_with(&format!("\"{ENCRYPTED_PREFIX}")) {
+        return None;
+    }
+    let value: String = serde_json::from_str(value).ok()?;
+    value.strip_prefix(ENCRYPTED_PREFIX).map(str::to_string)
+}
+
+/// Returns the plain value of the given data key from its stored value, decrypting it with the
+/// given key if it was encrypted.  Only values of sensitive keys are ever encrypted, so a value of
+/// any other key is returned as it is, even if a user happened to set it to something that looks
+/// encrypted.
+pub(crate) fn decrypt<D>(
+    datastore: &D,
+    encryption_key: Option<&EncryptionKey>,
+    key: &Key,
+    committed: &Committed,
+    value: String,
+) -> Result<String>
+where
+    D: DataStore + ?Sized,
+{
+    match encrypted_part(&value) {
+        Some(encoded) if is_sensitive(datastore, key, committed)? => encryption_key
+            .context(error::MissingEncryptionKeySnafu { key: key.name() })?
+            .decrypt(key, &encoded),
+        _ => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::test_util::TestDir;
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TestDir::new("sensitive-key");
+        let path = dir.0.join("datastore.key");
+        let encryption_key = EncryptionKey::load_or_create(&path).unwrap();
+        let token = key("settings.kubernetes.bootstrap-token");
+        let other = key("settings.motd");
+        let mut datastore = MemoryDataStore::new();
+        for name in [&token, &other] {
+            datastore
+                .set_metadata(&sensitive_key(), name, "true", &Committed::Live)
+                .unwrap();
+        }
+        let decrypt = |encryption_key, key, value| {
+            decrypt(&datastore, encryption_key, key, &Committed::Live, value)
+        };
+
+        let encrypted = encryption_key.encrypt(&token, "\"secret\"").unwrap();
+        assert!(!encrypted.contains("secret"));
+        assert!(serde_json::from_str::<String>(&encrypted).is_ok());
+        // A key loaded again from the file can decrypt it.
+        let loaded = EncryptionKey::load_or_create(&path).unwrap();
+        assert_eq!(
+            decrypt(Some(&loaded), &token, encrypted.clone()).unwrap(),
+            "\"secret\""
+        );
+
+        // The value is bound to its key, and can't be read without the key file.
+        assert!(matches!(
+            decrypt(Some(&loaded), &other, encrypted.clone()),
+            Err(error::Error::Decryption { .. })
+        ));
+        assert!(matches!(
+            decrypt(None, &token, encrypted),
+            Err(error::Error::MissingEncryptionKey { .. })
+        ));
+        // Plain values are returned as they are.
+        assert_eq!(decrypt(None, &other, "\"hi\"".into()).unwrap(), "\"hi\"");
+        // Values of keys that aren't sensitive are never decrypted, whatever they look like.
+        let motd = serde_json::to_string(&format!("{ENCRYPTED_PREFIX}hello")).unwrap();
+        let plain = key("settings.plain");
+        assert_eq!(decrypt(None, &plain, motd.clone()).unwrap(), motd);
+        assert!(!format!("{loaded:?}").contains(&format!("{:?}", fs::read(&path).unwrap())));
+    }
+
+    #[test]
+    fn bad_key_file() {
+        let dir = TestDir::new("sensitive-bad-key");
+        let path = dir.0.join("datastore.key");
+        fs::write(&path, "short").unwrap();
+        assert!(matches!(
+            EncryptionKey::load_or_create(&path),
+            Err(error::Error::InvalidEncryptionKey { .. })
+        ));
+    }
+}
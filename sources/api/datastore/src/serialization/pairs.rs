@@ -54,15 +54,11 @@ where
 /// Serializer does most of the work by recursively serializing compound structures, and trivially
 /// serializing scalars.
 ///
-/// Caveat: for a list/tuple, the elements inside only have indexes, which doesn't work well with
-/// the data store.  Lists are common enough that we need some answer, so we say that lists can
-/// only contain scalars, not further compound objects.  That way we can serialize the list
-/// directly (see FlatSerializer) rather than as a compound.
-///
-/// (We could handle lists as proper compound structures by improving the data store such that it
-/// can store unnamed sub-components, perhaps by using a visible index ("a.b.c[0]", "a.b.c[1]").
-/// It's more common to use a HashMap in the model, and then to use named keys instead of indexes,
-/// which works fine.)
+/// Caveat: for a list/tuple, the elements inside only have indexes, which don't have names like
+/// the rest of the data store.  Lists of scalars are most common, so we store them directly, as a
+/// single value.  Lists containing compound structures are stored by index instead, with a key
+/// per element like "a.b.c[0].d"; see ListSerializer.  (It's still more common to use a HashMap
+/// in the model, and then to use named keys instead of indexes.)
 struct Serializer<'a> {
     output: &'a mut HashMap<Key, String>,
     prefix: Option<Key>,
@@ -113,7 +109,7 @@ impl<'a> ser::Serializer for Serializer<'a> {
     type Error = Error;
 
     // See the docs on Serializer for reasoning about this.
-    type SerializeSeq = FlatSerializer<'a>;
+    type SerializeSeq = ListSerializer<'a>;
     type SerializeTuple = ser::Impossible<(), Error>;
     type SerializeTupleStruct = ser::Impossible<(), Error>;
     type SerializeTupleVariant = ser::Impossible<(), Error>;
@@ -187,7 +183,7 @@ impl<'a> ser::Serializer for Serializer<'a> {
 
     // Compound types
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        Ok(FlatSerializer::new(
+        Ok(ListSerializer::new(
             self.output,
             expect_prefix(self.prefix, "seq")?,
         ))
@@ -418,33 +414,49 @@ impl ser::SerializeStruct for Serializer<'_> {
 
 /////
 
-/// This serializes compound structures into a flat blob, for cases where recursively serializing
-/// compound structures doesn't make sense.  See Serializer for detail on why it uses this.
+/// This serializes lists.  Lists of scalars are stored as a single JSON value under the list's
+/// key, as they always have been.  If any element is a compound structure, the list is instead
+/// stored by index: each element is serialized recursively with an index segment added to the
+/// list's key, so the second element's field "d" of list "a.b.c" is stored at "a.b.c[1].d".
 ///
-/// Warning; this requires hacks.  serde gives you three callbacks during serialization - starting
-/// the structure, for each element, and ending the structure.  There's no option to handle an
-/// entire structure at once, which is exactly what we'd want.  We can't clone the elements, since
-/// we only have a Serialize bound, and I couldn't figure out how to store the references, so we do
-/// the unthinkable - serialize each element to a String, store those in a list during the
-/// serialization steps, and then at the end, deserialize the strings back into a list of the
-/// original type, and serialize the entire list.  Sorry.
-struct FlatSerializer<'a> {
+/// serde gives us the elements one at a time, and we can't tell how to store the list until we've
+/// seen all of them.  We can't keep the elements themselves, since we only have a Serialize bound,
+/// so we keep a JSON copy of each one for the flat form, and serialize compound elements under
+/// their index as we go.  At the end we output whichever form the list needs.
+struct ListSerializer<'a> {
     output: &'a mut HashMap<Key, String>,
     prefix: Key,
-    list: Vec<String>,
+    list: Vec<serde_json::Value>,
+    indexed: HashMap<Key, String>,
 }
 
-impl<'a> FlatSerializer<'a> {
+impl<'a> ListSerializer<'a> {
     fn new(output: &'a mut HashMap<Key, String>, prefix: Key) -> Self {
-        FlatSerializer {
+        ListSerializer {
             output,
             prefix,
             list: Vec::new(),
+            indexed: HashMap::new(),
         }
     }
+
+    /// Returns the key for the element at the given index.
+    fn element_key(&self, index: usize) -> Result<Key> {
+        self.prefix
+            .append_segments(&[Key::index_segment(index)])
+            .map_err(|e| {
+                error::InvalidKeySnafu {
+                    msg: format!(
+                        "list element {index} of '{}' is invalid as Key: {e}",
+                        self.prefix
+                    ),
+                }
+                .into_error(NoSource)
+            })
+    }
 }
 
-impl ser::SerializeSeq for FlatSerializer<'_> {
+impl ser::SerializeSeq for ListSerializer<'_> {
     type Ok = ();
     type Error = Error;
 
@@ -453,30 +465,45 @@ impl ser::SerializeSeq for FlatSerializer<'_> {
         T: ?Sized + Serialize,
     {
         trace!("Serializing element of list");
-        self.list.push(
-            serde_json::to_string(value).context(error::SerializationSnafu {
-                given: "list element",
-            })?,
-        );
+        let element = serde_json::to_value(value).context(error::SerializationSnafu {
+            given: "list element",
+        })?;
+        if element.is_object() {
+            let key = self.element_key(self.list.len())?;
+            let before = self.indexed.len();
+            value.serialize(Serializer::new(&mut self.indexed, Some(key.clone())))?;
+            // An element with no fields set has no keys of its own, which would leave a gap in
+            // the list, so store it as an empty object under its index instead.
+            if self.indexed.len() == before {
+                self.indexed.insert(key, "{}".to_string());
+            }
+        }
+        self.list.push(element);
         Ok(())
     }
 
-    fn end(self) -> Result<()> {
-        let mut originals: Vec<serde_json::Value> = Vec::new();
-        trace!("Deserializing elements of list");
-        for original in self.list {
-            originals.push(original.parse().context(error::DeserializationSnafu {
-                given: "list element",
-            })?);
+    fn end(mut self) -> Result<()> {
+        if !self.list.iter().any(|element| element.is_object()) {
+            trace!("Serializing list of scalars");
+            self.output.insert(
+                self.prefix,
+                serde_json::to_string(&self.list)
+                    .context(error::SerializationSnafu { given: "list" })?,
+            );
+            return Ok(());
         }
 
-        trace!("Serializing list");
-        self.output.insert(
-            self.prefix,
-            serde_json::to_string(&originals)
-                .context(error::SerializationSnafu { given: "list" })?,
-        );
-
+        trace!("Serializing list by index");
+        // Compound elements were already serialized; add any scalars mixed in with them.
+        for (index, element) in self.list.iter().enumerate() {
+            if !element.is_object() {
+                let value = serde_json::to_string(element).context(error::SerializationSnafu {
+                    given: "list element",
+                })?;
+                self.indexed.insert(self.element_key(index)?, value);
+            }
+        }
+        self.output.extend(self.indexed);
         Ok(())
     }
 }
@@ -484,9 +511,10 @@ impl ser::SerializeSeq for FlatSerializer<'_> {
 #[cfg(test)]
 mod test {
     use super::{to_pairs, to_pairs_with_prefix};
+    use crate::deserialization::from_map_with_prefix;
     use crate::{Key, KeyType};
     use maplit::hashmap;
-    use serde::Serialize;
+    use serde::{Deserialize, Serialize};
 
     // Helper macro for making a data Key for testing whose name we know is valid.
     macro_rules! key {
@@ -608,6 +636,86 @@ mod test {
         );
     }
 
+    #[derive(Serialize)]
+    struct Mirror {
+        registry: String,
+        endpoints: Vec<String>,
+        nested: Vec<C>,
+    }
+
+    #[derive(Serialize)]
+    struct C {
+        boolean: bool,
+    }
+
+    #[test]
+    fn list_of_structs() {
+        let m = hashmap!(
+            key!("mirrors") => vec![
+                Mirror {
+                    registry: "a".to_string(),
+                    endpoints: vec!["x".to_string()],
+                    nested: vec![C { boolean: true }],
+                },
+                Mirror {
+                    registry: "b".to_string(),
+                    endpoints: vec![],
+                    nested: vec![],
+                },
+            ],
+        );
+        let keys = to_pairs_with_prefix("settings", &m).unwrap();
+        assert_eq!(
+            keys,
+            hashmap!(
+                key!("settings.mirrors[0].registry") => "\"a\"".to_string(),
+                key!("settings.mirrors[0].endpoints") => "[\"x\"]".to_string(),
+                key!("settings.mirrors[0].nested[0].boolean") => "true".to_string(),
+                key!("settings.mirrors[1].registry") => "\"b\"".to_string(),
+                key!("settings.mirrors[1].endpoints") => "[]".to_string(),
+                key!("settings.mirrors[1].nested") => "[]".to_string(),
+            )
+        );
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OptionalMirrors {
+        mirrors: Vec<OptionalMirror>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OptionalMirror {
+        registry: Option<String>,
+        endpoints: Option<Vec<String>>,
+    }
+
+    #[test]
+    fn list_element_without_fields_round_trips() {
+        let m = OptionalMirrors {
+            mirrors: vec![
+                OptionalMirror {
+                    registry: None,
+                    endpoints: None,
+                },
+                OptionalMirror {
+                    registry: Some("b".to_string()),
+                    endpoints: None,
+                },
+            ],
+        };
+        let keys = to_pairs_with_prefix("settings", &m).unwrap();
+        assert_eq!(
+            keys,
+            hashmap!(
+                key!("settings.mirrors[0]") => "{}".to_string(),
+                key!("settings.mirrors[1].registry") => "\"b\"".to_string(),
+            )
+        );
+        let back: OptionalMirrors =
+            from_map_with_prefix(Some("settings".to_string()), &keys).unwrap();
+        assert_eq!(back, m);
+    }
+
     #[derive(Serialize)]
     #[serde(rename_all = "kebab-case")]
     enum TestEnum {
@@ -0,0 +1,59 @@
+//! Snapshots are point-in-time copies of the live data and metadata in a data store.
+//!
+//! Snapshots can be taken on demand.  A data store with a snapshot retention count above 0 also
+//! takes a snapshot of live data before applying each commit, recording the name of the
+//! transaction being committed, so that a bad commit can be undone by restoring the snapshot
+//! taken just before it.  That copies all of live data on every commit, so it's off by default.
+//!
+//! Only a limited number of snapshots are kept; once there are more than the data store's
+//! retention count, the oldest are removed.
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies a snapshot.  IDs increase with each snapshot taken, so a higher ID is a more
+/// recent snapshot.
+pub type SnapshotId = u64;
+
+/// The number of snapshots a data store keeps unless configured otherwise: none, so commits
+/// don't take snapshots unless asked to.
+pub const DEFAULT_SNAPSHOT_RETENTION: usize = 0;
+
+/// Describes a snapshot of live data and metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: SnapshotId,
+    /// The transaction whose commit this snapshot was taken before, or None if the snapshot was
+    /// requested directly.
+    pub transaction: Option<String>,
+}
+
+/// Given the IDs of existing snapshots, returns the ones that should be removed to keep only the
+/// newest `retention` snapshots.  At least one snapshot is always kept, so a snapshot that was
+/// just taken remains available even if retention is 0.
+pub(crate) fn expired_snapshots<I>(ids: I, retention: usize) -> Vec<SnapshotId>
+where
+    I: IntoIterator<Item = SnapshotId>,
+{
+    let mut ids: Vec<_> = ids.into_iter().collect();
+    // Newest first.
+    ids.sort_unstable_by(|a, b| b.cmp(a));
+    ids.into_iter().skip(retention.max(1)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::expired_snapshots;
+
+    #[test]
+    fn expires_oldest() {
+        let mut expired = expired_snapshots(vec![3, 1, 4, 2, 5], 2);
+        expired.sort_unstable();
+        assert_eq!(expired, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn keeps_at_least_one() {
+        assert_eq!(expired_snapshots(vec![1, 2], 0), vec![1]);
+        assert!(expired_snapshots(vec![], 0).is_empty());
+    }
+}
@@ -0,0 +1,478 @@
+//! Subscriptions let services react to setting changes as they're committed, rather than polling
+//! the data store.
+//!
+//! `DataStore::subscribe` takes a key prefix, matched by segment like other prefix queries, and
+//! returns a `Subscription`.  Each commit that changes data keys under the prefix produces a
+//! `ChangeEvent` naming the transaction and the changed keys under the prefix.  Only commits
+//! made after subscribing are reported.
+//!
+//! MemoryDataStore delivers events to subscriptions in the same process over a channel.
+//! FilesystemDataStore records each commit in a numbered file under "changes" in its base path,
+//! and subscriptions watch that directory with inotify, so they also see commits made through
+//! other FilesystemDataStore instances, including ones in other processes.  Only the most recent
+//! records are kept, so a subscription that falls far behind can miss events.  A commit that's
+//! interrupted and replayed when the data store is next opened may be reported twice.
+
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::collections::{HashSet, VecDeque};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+use crate::{error, Key, KeyType, Result};
+
+/// Describes the keys changed by one commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The name of the committed transaction.
+    pub transaction: String,
+    /// The changed data keys under the subscription's prefix, whether set or removed.
+    pub keys: HashSet<Key>,
+}
+
+/// Receives a ChangeEvent for each commit that changes keys under a prefix; see
+/// `DataStore::subscribe`.
+#[derive(Debug)]
+pub struct Subscription {
+    prefix: Vec<String>,
+    source: Source,
+}
+
+/// Where a subscription gets events about every commit, before filtering by prefix.
+#[derive(Debug)]
+enum Source {
+    Channel(Receiver<ChangeEvent>),
+    Directory(ChangeWatcher),
+}
+
+impl Subscription {
+    /// Returns a subscription to the events sent on the given channel.
+    pub(crate) fn from_channel<S: AsRef<str>>(
+        prefix: S,
+        receiver: Receiver<ChangeEvent>,
+    ) -> Result<Self> {
+        Ok(Self {
+            prefix: Key::prefix_segments(prefix)?,
+            source: Source::Channel(receiver),
+        })
+    }
+
+    /// Returns a subscription to the change records written to the given directory; see
+    /// `write_change`.
+    pub(crate) fn from_directory<S: AsRef<str>>(prefix: S, dir: &Path) -> Result<Self> {
+        Ok(Self {
+            prefix: Key::prefix_segments(prefix)?,
+            source: Source::Directory(ChangeWatcher::new(dir)?),
+        })
+    }
+
+    /// Waits for the next change under the prefix.
+    pub fn recv(&mut self) -> Result<ChangeEvent> {
+        loop {
+            if let Some(event) = self.next(None)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Waits up to the given time for the next change under the prefix, returning None if there
+    /// wasn't one.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<ChangeEvent>> {
+        self.next(Some(Instant::now() + timeout))
+    }
+
+    /// Returns the next change under the prefix if there's one waiting, without blocking.
+    pub fn try_recv(&mut self) -> Result<Option<ChangeEvent>> {
+        self.next(Some(Instant::now()))
+    }
+
+    /// Returns the next event with keys under the prefix, waiting until the deadline, or forever
+    /// if there's no deadline.
+    fn next(&mut self, deadline: Option<Instant>) -> Result<Option<ChangeEvent>> {
+        loop {
+            let event = match &mut self.source {
+                Source::Channel(receiver) => receive(receiver, deadline)?,
+                Source::Directory(watcher) => watcher.next(deadline)?,
+            };
+            let Some(mut event) = event else {
+                return Ok(None);
+            };
+            event
+                .keys
+                .retain(|key| key.starts_with_segments(&self.prefix));
+            if !event.keys.is_empty() {
+                return Ok(Some(event));
+            }
+            trace!(
+                "Skipping commit of transaction '{}' with no keys under {:?}",
+                event.transaction,
+                self.prefix
+            );
+        }
+    }
+}
+
+/// Receives from the channel, waiting until the deadline, or forever if there's no deadline.
+fn receive(
+    receiver: &Receiver<ChangeEvent>,
+    deadline: Option<Instant>,
+) -> Result<Option<ChangeEvent>> {
+    let closed = || error::SubscriptionClosedSnafu.fail();
+    match deadline {
+        None => receiver.recv().map(Some).or_else(|_| closed()),
+        Some(deadline) => {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                match receiver.try_recv() {
+                    Ok(event) => Ok(Some(event)),
+                    Err(TryRecvError::Empty) => Ok(None),
+                    Err(TryRecvError::Disconnected) => closed(),
+                }
+            } else {
+                match receiver.recv_timeout(timeout) {
+                    Ok(event) => Ok(Some(event)),
+                    Err(RecvTimeoutError::Timeout) => Ok(None),
+                    Err(RecvTimeoutError::Disconnected) => closed(),
+                }
+            }
+        }
+    }
+}
+
+/// The number of change records a FilesystemDataStore keeps.
+pub(crate) const CHANGE_RETENTION: u64 = 100;
+
+/// Suffix of a change record while it's being written; it's linked to its ID once complete, so
+/// watchers never read a partial record.
+const CHANGE_TMP_SUFFIX: &str = ".tmp";
+
+/// Counts the records written by this process, to give each one's temporary file its own name.
+static CHANGE_TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// ChangeRecord is the on-disk form of a ChangeEvent.  Keys are stored by name because a Key
+/// can't be deserialized without knowing its type.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChangeRecord {
+    transaction: String,
+    keys: Vec<String>,
+}
+
+/// Writes a record of a commit to the given directory for watchers to find, named by the next ID,
+/// and removes records beyond CHANGE_RETENTION.
+///
+/// Writers needn't hold a lock, so the record is written to a temporary file of its own, then
+/// hard-linked to the next free ID.  Linking fails rather than replacing a record, so if another
+/// writer took the ID first, we look again and try the one after it.
+pub(crate) fn write_change(dir: &Path, transaction: &str, keys: &HashSet<Key>) -> Result<()> {
+    fs::create_dir_all(dir).context(error::IoSnafu { path: dir })?;
+
+    let record = ChangeRecord {
+        transaction: transaction.to_string(),
+        keys: keys.iter().map(|key| key.name().clone()).collect(),
+    };
+    let bytes = serde_json::to_vec(&record).context(error::SerializeSnafu)?;
+    let tmp_path = dir.join(format!(
+        "{}-{}{CHANGE_TMP_SUFFIX}",
+        process::id(),
+        CHANGE_TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp_path, bytes).context(error::IoSnafu { path: &tmp_path })?;
+    let linked = link_next_id(dir, &tmp_path);
+    if let Err(e) = fs::remove_file(&tmp_path) {
+        warn!(
+            "Failed to remove temporary change record {}: {e}",
+            tmp_path.display()
+        );
+    }
+    let (id, ids) = linked?;
+    trace!("Recorded commit of transaction '{transaction}' as change {id}");
+
+    for expired in ids.into_iter().filter(|old| old + CHANGE_RETENTION <= id) {
+        let path = dir.join(expired.to_string());
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).context(error::IoSnafu { path })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Links the given complete record to the next free ID in the given directory.  Returns the ID,
+/// and the IDs of the records that were already there.
+fn link_next_id(dir: &Path, tmp_path: &Path) -> Result<(u64, Vec<u64>)> {
+    loop {
+        let ids = change_ids(dir)?;
+        let id = ids.iter().max().copied().unwrap_or(0) + 1;
+        let path = dir.join(id.to_string());
+        match fs::hard_link(tmp_path, &path) {
+            Ok(()) => return Ok((id, ids)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                trace!("Change {id} was taken by another writer; trying the next one");
+            }
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        }
+    }
+}
+
+/// Returns the IDs of the completed change records in the given directory.
+fn change_ids(dir: &Path) -> Result<Vec<u64>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(error::IoSnafu { path: dir }),
+    };
+    let mut ids = Vec::new();
+    for entry in entries {
+        let entry = entry.context(error::IoSnafu { path: dir })?;
+        match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        {
+            Some(id) => ids.push(id),
+            None => trace!("Skipping non-record entry {}", entry.path().display()),
+        }
+    }
+    Ok(ids)
+}
+
+/// Watches a directory of change records with inotify.  We only use inotify to wake up; each time
+/// it does, we read any records with IDs above the last one we saw, so we don't depend on
+/// inotify's event queue not overflowing.
+#[derive(Debug)]
+struct ChangeWatcher {
+    inotify: OwnedFd,
+    dir: PathBuf,
+    last_seen: u64,
+    // Events read from records but not yet returned.
+    queue: VecDeque<ChangeEvent>,
+}
+
+impl ChangeWatcher {
+    fn new(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).context(error::IoSnafu { path: dir })?;
+        let watch_error = || error::WatchSnafu { path: dir };
+
+        // SAFETY: inotify_init1 takes no pointers; we check the result before using it.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context(watch_error());
+        }
+        // SAFETY: the descriptor was just created and nothing else owns it.
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let c_path = CString::new(dir.as_os_str().as_bytes())
+            .map_err(io::Error::from)
+            .context(watch_error())?;
+        // Records are linked into place once they're complete.
+        // SAFETY: the path is a valid C string that outlives the call.
+        let wd = unsafe {
+            libc::inotify_add_watch(inotify.as_raw_fd(), c_path.as_ptr(), libc::IN_CREATE)
+        };
+        if wd < 0 {
+            return Err(io::Error::last_os_error()).context(watch_error());
+        }
+
+        let last_seen = change_ids(dir)?.into_iter().max().unwrap_or(0);
+        Ok(Self {
+            inotify,
+            dir: dir.to_path_buf(),
+            last_seen,
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// Returns the next recorded commit, waiting until the deadline, or forever if there's no
+    /// deadline.
+    fn next(&mut self, deadline: Option<Instant>) -> Result<Option<ChangeEvent>> {
+        loop {
+            if let Some(event) = self.queue.pop_front() {
+                return Ok(Some(event));
+            }
+            // Clear pending wakeups before reading, so a record that lands after we read still
+            // wakes us up.
+            self.drain()?;
+            self.read_new_records()?;
+            if !self.queue.is_empty() {
+                continue;
+            }
+
+            let timeout = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(None);
+                    }
+                    // Round up, so we don't spin with a zero timeout just before the deadline.
+                    let millis = remaining.as_micros().div_ceil(1000);
+                    libc::c_int::try_from(millis).unwrap_or(libc::c_int::MAX)
+                }
+            };
+            self.wait(timeout)?;
+        }
+    }
+
+    /// Queues events for the records with IDs above the last one we saw, oldest first.
+    fn read_new_records(&mut self) -> Result<()> {
+        let mut ids: Vec<_> = change_ids(&self.dir)?
+            .into_iter()
+            .filter(|id| *id > self.last_seen)
+            .collect();
+        ids.sort_unstable();
+
+        for id in ids {
+            self.last_seen = id;
+            let path = self.dir.join(id.to_string());
+            let bytes = match fs::read(&path) {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    warn!("Change record {id} was removed before we read it; events were missed");
+                    continue;
+                }
+                Err(e) => return Err(e).context(error::IoSnafu { path }),
+            };
+            let record: ChangeRecord =
+                serde_json::from_slice(&bytes).context(error::ChangeRecordSnafu { path: &path })?;
+            let keys = record
+                .keys
+                .iter()
+                .map(|name| Key::new(KeyType::Data, name))
+                .collect::<Result<_>>()?;
+            self.queue.push_back(ChangeEvent {
+                transaction: record.transaction,
+                keys,
+            });
+        }
+        Ok(())
+    }
+
+    /// Waits up to the given number of milliseconds, or forever if negative, for inotify to
+    /// report a new record.
+    fn wait(&self, timeout: libc::c_int) -> Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: we pass a single valid pollfd, which outlives the call.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            // Interruptions look like spurious wakeups; the caller checks the deadline again.
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err).context(error::WatchSnafu { path: &self.dir });
+            }
+        }
+        Ok(())
+    }
+
+    /// Discards the inotify events waiting to be read.
+    fn drain(&self) -> Result<()> {
+        // Room for many events; each is a small header plus the file name.
+        let mut buf = [0u8; 4096];
+        loop {
+            // SAFETY: we pass the buffer's real length, and it outlives the call.
+            let ret =
+                unsafe { libc::read(self.inotify.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if ret > 0 {
+                continue;
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => return Ok(()),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(err).context(error::WatchSnafu { path: &self.dir }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    #[test]
+    fn old_records_expire() {
+        let dir = TestDir::new("watch-expire");
+        let keys = HashSet::from([key("a")]);
+        for i in 0..CHANGE_RETENTION + 3 {
+            write_change(&dir.0, &format!("tx{i}"), &keys).unwrap();
+        }
+        let mut ids = change_ids(&dir.0).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids.len() as u64, CHANGE_RETENTION);
+        assert_eq!(ids[0], 4);
+    }
+
+    #[test]
+    fn concurrent_writers_keep_every_record() {
+        let dir = TestDir::new("watch-concurrent");
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let dir = dir.0.clone();
+                std::thread::spawn(move || {
+                    for i in 0..20 {
+                        let keys = HashSet::from([key("a")]);
+                        write_change(&dir, &format!("tx{writer}-{i}"), &keys).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut ids = change_ids(&dir.0).unwrap();
+        ids.sort_unstable();
+        assert_eq!(ids, (1..=80).collect::<Vec<_>>());
+        let mut transactions = HashSet::new();
+        for id in ids {
+            let record: ChangeRecord =
+                serde_json::from_slice(&fs::read(dir.0.join(id.to_string())).unwrap()).unwrap();
+            transactions.insert(record.transaction);
+        }
+        assert_eq!(transactions.len(), 80);
+        // Temporary files are all cleaned up.
+        assert!(!fs::read_dir(&dir.0).unwrap().any(|entry| entry
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .ends_with(CHANGE_TMP_SUFFIX)));
+    }
+
+    #[test]
+    fn directory_subscription() {
+        let dir = TestDir::new("watch-directory");
+        write_change(&dir.0, "before", &HashSet::from([key("a.b")])).unwrap();
+
+        let mut subscription = Subscription::from_directory("a", &dir.0).unwrap();
+        assert_eq!(subscription.try_recv().unwrap(), None);
+
+        write_change(&dir.0, "other", &HashSet::from([key("c")])).unwrap();
+        write_change(&dir.0, "tx", &HashSet::from([key("a.b"), key("c")])).unwrap();
+        let event = subscription
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.transaction, "tx");
+        assert_eq!(event.keys, HashSet::from([key("a.b")]));
+        assert_eq!(subscription.try_recv().unwrap(), None);
+    }
+}
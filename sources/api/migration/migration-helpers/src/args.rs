@@ -1,15 +1,30 @@
 //! Helpers for parsing arguments common to migrations.
 
+use datastore::sensitive::DEFAULT_KEY_PATH;
 use std::env;
+use std::path::Path;
 use std::process;
 
+use crate::report::ReportFormat;
 use crate::{MigrationType, Result};
 
 /// Stores user-supplied arguments.
 pub struct Args {
     pub source_datastore: String,
-    pub target_datastore: String,
+    /// Not needed for a dry run, which doesn't write a target.
+    pub target_datastore: Option<String>,
     pub migration_type: MigrationType,
+    /// Print the changes the migration would make instead of writing the target data store.
+    pub dry_run: bool,
+    /// Write the changes the migration makes to this file.
+    pub report: Option<String>,
+    pub report_format: ReportFormat,
+    /// Check forward-migrated data against the new version's model.  On by default; turned off
+    /// with `--no-validate`.
+    pub validate: bool,
+    /// The host's key file for encrypting sensitive values; see `datastore::sensitive`.  Defaults
+    /// to `DEFAULT_KEY_PATH` if that file exists.
+    pub encryption_key: Option<String>,
 }
 
 /// Informs the user about proper usage of the program and exits.
@@ -18,8 +33,21 @@ fn usage() -> ! {
     eprintln!(
         r"Usage: {program_name}
             --source-datastore PATH
-            --target-datastore PATH
-            ( --forward | --backward )"
+            [ --target-datastore PATH ]
+            ( --forward | --backward )
+            [ --dry-run ]
+            [ --report PATH ]
+            [ --report-format ( text | json ) ]
+            [ --encryption-key PATH ]
+            [ --no-validate ]
+
+    --target-datastore is required unless --dry-run is given.
+    --dry-run prints what the migration would change, without writing the target data store.
+    --report writes what the migration changed to a file.
+    --encryption-key gives the host's key file, needed if the data store has sensitive values.
+        Defaults to {DEFAULT_KEY_PATH} if that file exists.
+    --no-validate skips checking forward-migrated data against the new version's model, for a
+        migration whose output a later migration still has to fix up."
     );
     process::exit(2);
 }
@@ -35,6 +63,11 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
     let mut migration_type = None;
     let mut source_datastore = None;
     let mut target_datastore = None;
+    let mut dry_run = false;
+    let mut report = None;
+    let mut report_format = ReportFormat::Text;
+    let mut encryption_key = None;
+    let mut validate = true;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -56,18 +89,57 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
             "--forward" => migration_type = Some(MigrationType::Forward),
             "--backward" => migration_type = Some(MigrationType::Backward),
 
+            "--dry-run" => dry_run = true,
+            "--no-validate" => validate = false,
+
+            "--report" => {
+                report = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --report")),
+                )
+            }
+
+            "--report-format" => {
+                report_format = match iter.next().as_deref() {
+                    Some("text") => ReportFormat::Text,
+                    Some("json") => ReportFormat::Json,
+                    _ => usage_msg("--report-format must be 'text' or 'json'"),
+                }
+            }
+
+            "--encryption-key" => {
+                encryption_key = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --encryption-key")),
+                )
+            }
+
             _ => usage(),
         }
     }
 
+    if target_datastore.is_none() && !dry_run {
+        usage_msg("--target-datastore is required unless --dry-run is given");
+    }
     // In no other case should they be the same; we use it for compatibility checks.
-    if source_datastore == target_datastore {
+    if target_datastore.is_some() && source_datastore == target_datastore {
         usage_msg("--source-datastore and --target-datastore cannot be the same");
     }
 
     Ok(Args {
         source_datastore: source_datastore.unwrap_or_else(|| usage()),
-        target_datastore: target_datastore.unwrap_or_else(|| usage()),
+        target_datastore,
         migration_type: migration_type.unwrap_or_else(|| usage()),
+        dry_run,
+        report,
+        report_format,
+        validate,
+        // Without a key, a data store with sensitive values can't be migrated at all, so use the
+        // host's if it has one.
+        encryption_key: encryption_key.or_else(|| {
+            Path::new(DEFAULT_KEY_PATH)
+                .exists()
+                .then(|| DEFAULT_KEY_PATH.to_string())
+        }),
     })
 }
@@ -0,0 +1,148 @@
+//! This module lets one migration binary run several migrations, for a release that needs a few
+//! small steps that don't deserve a binary each.
+
+use snafu::ResultExt;
+
+use crate::report::{diff, render_diff};
+use crate::{error, Migration, MigrationData, Result};
+
+/// Runs a list of named migrations as one.  Steps run in order on upgrade, and in reverse order
+/// on downgrade, each on the output of the one before, so a chain undoes its steps in the
+/// opposite order it applied them.  What each step changed is printed, and if a step fails, the
+/// error names it.
+///
+/// ```no_run
+/// use migration_helpers::common_migrations::{AddSettingsMigration, RemoveSettingsMigration};
+/// use migration_helpers::{migrate, MigrationChain};
+///
+/// migrate(MigrationChain(vec![
+///     ("add-new", Box::new(AddSettingsMigration(&["settings.new"]))),
+///     ("remove-old", Box::new(RemoveSettingsMigration(&["settings.old"]))),
+/// ]))
+/// .unwrap();
+/// ```
+pub struct MigrationChain<'a>(pub Vec<(&'static str, Box<dyn Migration + 'a>)>);
+
+impl MigrationChain<'_> {
+    /// Runs one step, printing what it changed.
+    fn run_step<F>(&mut self, index: usize, input: MigrationData, run: F) -> Result<MigrationData>
+    where
+        F: FnOnce(&mut dyn Migration, MigrationData) -> Result<MigrationData>,
+    {
+        let steps = self.0.len();
+        let (name, migration) = &mut self.0[index];
+        println!(
+            "Running migration step '{}' ({}/{})",
+            name,
+            index + 1,
+            steps
+        );
+
+        let before = input.clone();
+        let output =
+            run(migration.as_mut(), input).context(error::MigrationStepSnafu { step: *name })?;
+
+        let mut text = String::new();
+        render_diff(&mut text, &diff(&before, &output, None)?);
+        print!("Migration step '{name}' changes:\n{text}");
+        Ok(output)
+    }
+}
+
+impl Migration for MigrationChain<'_> {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for index in 0..self.0.len() {
+            input = self.run_step(index, input, |migration, input| migration.forward(input))?;
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for index in (0..self.0.len()).rev() {
+            input = self.run_step(index, input, |migration, input| migration.backward(input))?;
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MigrationChain;
+    use crate::error::{self, Error};
+    use crate::{Migration, MigrationData, Result};
+    use maplit::hashmap;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    /// Appends its name to the "steps" list on either direction, or fails if `fail` is set.
+    struct Step {
+        name: &'static str,
+        fail: bool,
+    }
+
+    impl Step {
+        fn run(&self, mut input: MigrationData) -> Result<MigrationData> {
+            if self.fail {
+                return error::MigrationSnafu { msg: "failed" }.fail();
+            }
+            if let Some(Value::Array(steps)) = input.data.get_mut("steps") {
+                steps.push(self.name.into());
+            }
+            Ok(input)
+        }
+    }
+
+    impl Migration for Step {
+        fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+            self.run(input)
+        }
+
+        fn backward(&mut self, input: MigrationData) -> Result<MigrationData> {
+            self.run(input)
+        }
+    }
+
+    fn chain(fail: Option<&'static str>) -> MigrationChain<'static> {
+        MigrationChain(
+            ["a", "b", "c"]
+                .into_iter()
+                .map(|name| {
+                    let step = Step {
+                        name,
+                        fail: fail == Some(name),
+                    };
+                    (name, Box::new(step) as Box<dyn Migration>)
+                })
+                .collect(),
+        )
+    }
+
+    fn data() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "steps".into() => Value::Array(Vec::new()),
+            },
+            metadata: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let result = chain(None).forward(data()).unwrap();
+        assert_eq!(result.data["steps"], serde_json::json!(["a", "b", "c"]));
+    }
+
+    #[test]
+    fn backward() {
+        let result = chain(None).backward(data()).unwrap();
+        assert_eq!(result.data["steps"], serde_json::json!(["c", "b", "a"]));
+    }
+
+    #[test]
+    fn failing_step() {
+        match chain(Some("b")).forward(data()) {
+            Err(Error::MigrationStep { step, .. }) => assert_eq!(step, "b"),
+            other => panic!("Expected step 'b' to fail, got {other:?}"),
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use crate::{error, Migration, MigrationData, Result};
+use crate::{defaults, error, Migration, MigrationData, Result};
+use datastore::{Key, KeyType};
 use regex::Regex;
+use serde_json::Value;
 use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
 
 /// We use this migration when we add settings and want to make sure they're removed before we go
 /// back to old versions that don't understand them.
@@ -777,3 +780,1124 @@ impl Migration for RemoveMatchingString {
         Ok(input)
     }
 }
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// We use this migration when the defaults for a section of settings change in a way that
+/// existing values should follow, for example when the old defaults no longer work.  Every key
+/// under the given prefixes is replaced with the new version's defaults, along with their default
+/// metadata.  Prefixes match whole key segments, so "settings.ntp" doesn't reset "settings.ntpd".
+///
+/// User changes under the prefixes are lost, so only use this when that's intended.
+pub struct ResetToDefaultsMigration(pub Vec<&'static str>);
+
+impl ResetToDefaultsMigration {
+    /// Resets the keys under our prefixes in the input to the defaults from the given loader.  A
+    /// prefix is only reset if the input has data under it, so we don't add defaults to unrelated
+    /// pending transactions, and the defaults are only loaded once we know we need them.
+    fn reset<F>(&self, mut input: MigrationData, mut load_defaults: F) -> Result<MigrationData>
+    where
+        F: FnMut() -> Result<MigrationData>,
+    {
+        let mut loaded = None;
+        for prefix in &self.0 {
+            let segments = defaults::prefix_segments(prefix)?;
+            let mut old = HashMap::new();
+            for name in keys_under(&input.data, &segments)? {
+                if let Some(value) = input.data.remove(&name) {
+                    old.insert(name, value);
+                }
+            }
+            if old.is_empty() {
+                println!("Found no {prefix} to reset");
+                continue;
+            }
+
+            let all_defaults = match loaded {
+                Some(ref all_defaults) => all_defaults,
+                None => loaded.insert(load_defaults()?),
+            };
+            let new = defaults::subtree(all_defaults, prefix)?;
+            for (name, value) in &old {
+                match new.data.get(name) {
+                    Some(default) if default == value => {}
+                    Some(_) => println!("Reset {name} to its default"),
+                    None => println!("Removed {name}, which has no default"),
+                }
+            }
+            for name in new.data.keys().filter(|name| !old.contains_key(*name)) {
+                println!("Added default for {name}");
+            }
+
+            input.data.extend(new.data);
+            for (name, meta_map) in new.metadata {
+                input.metadata.entry(name).or_default().extend(meta_map);
+            }
+        }
+        Ok(input)
+    }
+}
+
+impl Migration for ResetToDefaultsMigration {
+    /// Replaces the settings with the new version's defaults.
+    fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        self.reset(input, || defaults::load_defaults(defaults::DEFAULTS_PATH))
+    }
+
+    /// We don't have the old version's defaults; we remove the settings so that the old version
+    /// populates its own defaults for them.
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for prefix in &self.0 {
+            let segments = defaults::prefix_segments(prefix)?;
+            for name in keys_under(&input.data, &segments)? {
+                if let Some(data) = input.data.remove(&name) {
+                    println!("Removed {name}, which was set to '{data}'");
+                }
+            }
+        }
+        Ok(input)
+    }
+}
+
+/// Returns the names of the data keys under the path with the given segments.
+fn keys_under(data: &HashMap<String, Value>, segments: &[String]) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for name in data.keys() {
+        if defaults::is_under(name, segments)? {
+            names.push(name.clone());
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test_reset_to_defaults_migration {
+    use super::ResetToDefaultsMigration;
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+    use std::collections::HashMap;
+
+    fn defaults() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.ntp.time-servers".into() => vec!["new.example.com"].into(),
+                "settings.ntp.options".into() => vec!["iburst"].into(),
+                "settings.motd".into() => "Welcome".into(),
+            },
+            metadata: hashmap! {
+                "settings.ntp".into() => hashmap! {
+                    "affected-services".into() => vec!["chronyd"].into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.ntp.time-servers".into() => vec!["old.example.com"].into(),
+                "settings.ntp.custom".into() => true.into(),
+                "settings.ntpd.enabled".into() => true.into(),
+                "settings.motd".into() => "Hello".into(),
+            },
+            metadata: hashmap! {
+                "settings.ntpd".into() => hashmap! {
+                    "affected-services".into() => vec!["ntpd"].into(),
+                },
+            },
+        };
+        let result = ResetToDefaultsMigration(vec!["settings.ntp"])
+            .reset(data, || Ok(defaults()))
+            .unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.ntp.time-servers".into() => vec!["new.example.com"].into(),
+                "settings.ntp.options".into() => vec!["iburst"].into(),
+                "settings.ntpd.enabled".into() => true.into(),
+                "settings.motd".into() => "Hello".into(),
+            }
+        );
+        assert_eq!(
+            result.metadata,
+            hashmap! {
+                "settings.ntp".into() => hashmap! {
+                    "affected-services".into() => vec!["chronyd"].into(),
+                },
+                "settings.ntpd".into() => hashmap! {
+                    "affected-services".into() => vec!["ntpd"].into(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn forward_without_data() {
+        // A pending transaction that doesn't touch the prefix is left alone, without even loading
+        // the defaults.
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.motd".into() => "Hello".into(),
+            },
+            metadata: HashMap::new(),
+        };
+        let result = ResetToDefaultsMigration(vec!["settings.ntp"])
+            .reset(data.clone(), || {
+                panic!("Loaded defaults without data to reset")
+            })
+            .unwrap();
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn backward() {
+        let data = MigrationData {
+            data: hashmap! {
+                "settings.ntp.time-servers".into() => vec!["new.example.com"].into(),
+                "settings.ntpd.enabled".into() => true.into(),
+            },
+            metadata: HashMap::new(),
+        };
+        let result = ResetToDefaultsMigration(vec!["settings.ntp"])
+            .backward(data)
+            .unwrap();
+        assert_eq!(
+            result.data,
+            hashmap! {
+                "settings.ntpd.enabled".into() => true.into(),
+            }
+        );
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Names metadata keys of a setting, for the metadata migrations below.
+pub struct SettingMetadata {
+    pub metadata: &'static [&'static str],
+    pub setting: &'static str,
+}
+
+/// Removes the named metadata from the input, printing what was removed.
+fn remove_metadata(input: &mut MigrationData, settings: &[SettingMetadata]) {
+    for setting_metadata in settings {
+        let setting = setting_metadata.setting;
+        if let Some(found) = input.metadata.get_mut(setting) {
+            for metadata in setting_metadata.metadata {
+                if let Some(value) = found.remove(*metadata) {
+                    println!("Removed {metadata} from {setting}, which was set to '{value}'");
+                } else {
+                    println!("Found no {metadata} for {setting} to remove");
+                }
+            }
+            if found.is_empty() {
+                input.metadata.remove(setting);
+            }
+        } else {
+            println!("Found no metadata for {setting} to remove");
+        }
+    }
+}
+
+/// We use this migration when we add metadata to settings, like "affected-services" or
+/// "setting-generator", and want to make sure it's removed before we go back to old versions that
+/// don't understand it.
+pub struct AddMetadataMigration<'a>(pub &'a [SettingMetadata]);
+
+impl Migration for AddMetadataMigration<'_> {
+    /// New versions must have a default for the metadata; we don't need to do anything.
+    fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        println!("AddMetadataMigration has no work to do on upgrade.");
+        Ok(input)
+    }
+
+    /// Older versions might act on metadata they don't expect, for example running a generator
+    /// that doesn't exist; we remove it so that old versions don't see it.
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        remove_metadata(&mut input, self.0);
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_add_metadata_migration {
+    use super::{AddMetadataMigration, SettingMetadata};
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+
+    fn data() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "x".into(),
+            },
+            metadata: hashmap! {
+                "settings.a".into() => hashmap! {
+                    "setting-generator".into() => "gen-a".into(),
+                    "affected-services".into() => vec!["a"].into(),
+                },
+                "settings.b".into() => hashmap! {
+                    "setting-generator".into() => "gen-b".into(),
+                },
+            },
+        }
+    }
+
+    const ADDED: &[SettingMetadata] = &[
+        SettingMetadata {
+            metadata: &["setting-generator"],
+            setting: "settings.a",
+        },
+        SettingMetadata {
+            metadata: &["setting-generator"],
+            setting: "settings.b",
+        },
+        SettingMetadata {
+            metadata: &["setting-generator"],
+            setting: "settings.not-found",
+        },
+    ];
+
+    #[test]
+    fn forward() {
+        let result = AddMetadataMigration(ADDED).forward(data()).unwrap();
+        assert_eq!(result, data());
+    }
+
+    #[test]
+    fn backward() {
+        let result = AddMetadataMigration(ADDED).backward(data()).unwrap();
+        assert_eq!(result.data, data().data);
+        assert_eq!(
+            result.metadata,
+            hashmap! {
+                "settings.a".into() => hashmap! {
+                    "affected-services".into() => vec!["a"].into(),
+                },
+            }
+        );
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// We use this migration when we remove metadata from settings, so the new version doesn't see it
+/// and act on it, for example by running a generator that no longer exists.
+pub struct RemoveMetadataMigration<'a>(pub &'a [SettingMetadata]);
+
+impl Migration for RemoveMetadataMigration<'_> {
+    /// Newer versions don't want the metadata; we remove it so that new versions don't see it.
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        remove_metadata(&mut input, self.0);
+        Ok(input)
+    }
+
+    /// Old versions must have a default for the metadata; we don't need to do anything.
+    fn backward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        println!("RemoveMetadataMigration has no work to do on downgrade.");
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_remove_metadata_migration {
+    use super::{RemoveMetadataMigration, SettingMetadata};
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+
+    fn data() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "x".into(),
+            },
+            metadata: hashmap! {
+                "settings.a".into() => hashmap! {
+                    "setting-generator".into() => "gen-a".into(),
+                    "affected-services".into() => vec!["a"].into(),
+                },
+            },
+        }
+    }
+
+    const REMOVED: &[SettingMetadata] = &[SettingMetadata {
+        metadata: &["setting-generator", "not-found"],
+        setting: "settings.a",
+    }];
+
+    #[test]
+    fn forward() {
+        let result = RemoveMetadataMigration(REMOVED).forward(data()).unwrap();
+        assert_eq!(
+            result.metadata,
+            hashmap! {
+                "settings.a".into() => hashmap! {
+                    "affected-services".into() => vec!["a"].into(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn backward() {
+        let result = RemoveMetadataMigration(REMOVED).backward(data()).unwrap();
+        assert_eq!(result, data());
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// We use this migration when we replace a setting's old string metadata value with a new one,
+/// for example to change the command in a "setting-generator".
+pub struct MetadataReplacement {
+    pub setting: &'static str,
+    pub metadata: &'static str,
+    pub old_val: &'static str,
+    pub new_val: &'static str,
+}
+
+impl MetadataReplacement {
+    /// Replaces the metadata value if it's `from`; `direction` is only used for messages.
+    fn replace(&self, input: &mut MigrationData, from: &str, to: &str, direction: &str) {
+        let Some(value) = input
+            .metadata
+            .get_mut(self.setting)
+            .and_then(|metadata| metadata.get_mut(self.metadata))
+        else {
+            println!(
+                "Found no metadata '{}' for '{}' to change on {}",
+                self.metadata, self.setting, direction
+            );
+            return;
+        };
+        match value {
+            Value::String(value) if value == from => {
+                to.clone_into(value);
+                println!(
+                    "Changed metadata '{}' for '{}' from '{}' to '{}' on {}",
+                    self.metadata, self.setting, from, to, direction
+                );
+            }
+            Value::String(_) => {
+                println!(
+                    "Metadata '{}' for '{}' is not set to '{}', leaving alone",
+                    self.metadata, self.setting, from
+                );
+            }
+            _ => {
+                println!(
+                    "Metadata '{}' for '{}' is set to non-string value '{}'; ReplaceMetadataMigration only handles strings",
+                    self.metadata, self.setting, value
+                );
+            }
+        }
+    }
+}
+
+pub struct ReplaceMetadataMigration(pub Vec<MetadataReplacement>);
+
+impl Migration for ReplaceMetadataMigration {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for replacement in &self.0 {
+            replacement.replace(
+                &mut input,
+                replacement.old_val,
+                replacement.new_val,
+                "upgrade",
+            );
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for replacement in &self.0 {
+            replacement.replace(
+                &mut input,
+                replacement.new_val,
+                replacement.old_val,
+                "downgrade",
+            );
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_replace_metadata_migration {
+    use super::{MetadataReplacement, ReplaceMetadataMigration};
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+    use std::collections::HashMap;
+
+    fn migration() -> ReplaceMetadataMigration {
+        ReplaceMetadataMigration(vec![
+            MetadataReplacement {
+                setting: "settings.a",
+                metadata: "setting-generator",
+                old_val: "schnauzer settings.a",
+                new_val: "schnauzer-v2 render --template '{{ settings.b }}'",
+            },
+            MetadataReplacement {
+                setting: "settings.not-found",
+                metadata: "setting-generator",
+                old_val: "old",
+                new_val: "new",
+            },
+        ])
+    }
+
+    fn data(generator: &str, other: &str) -> MigrationData {
+        MigrationData {
+            data: HashMap::new(),
+            metadata: hashmap! {
+                "settings.a".into() => hashmap! {
+                    "setting-generator".into() => generator.into(),
+                },
+                "settings.b".into() => hashmap! {
+                    "setting-generator".into() => other.into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let result = migration()
+            .forward(data("schnauzer settings.a", "schnauzer settings.a"))
+            .unwrap();
+        assert_eq!(
+            result,
+            data(
+                "schnauzer-v2 render --template '{{ settings.b }}'",
+                "schnauzer settings.a"
+            )
+        );
+    }
+
+    #[test]
+    fn backward() {
+        let result = migration()
+            .backward(data(
+                "schnauzer-v2 render --template '{{ settings.b }}'",
+                "other",
+            ))
+            .unwrap();
+        assert_eq!(result, data("schnauzer settings.a", "other"));
+    }
+
+    #[test]
+    fn no_match() {
+        let input = data("custom", "other");
+        assert_eq!(migration().forward(input.clone()).unwrap(), input);
+        assert_eq!(migration().backward(input.clone()).unwrap(), input);
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// We use this migration when we need to replace metadata that contains lists of string values;
+/// for example, when a release changes the "affected-services" of a setting.
+pub struct MetadataListReplacement {
+    pub setting: &'static str,
+    pub metadata: &'static str,
+    pub old_vals: &'static [&'static str],
+    pub new_vals: &'static [&'static str],
+}
+
+impl MetadataListReplacement {
+    /// Replaces the metadata list if it's `from`; `direction` is only used for messages.
+    fn replace(
+        &self,
+        input: &mut MigrationData,
+        from: &[&str],
+        to: &[&str],
+        direction: &str,
+    ) -> Result<()> {
+        let Some(value) = input
+            .metadata
+            .get_mut(self.setting)
+            .and_then(|metadata| metadata.get_mut(self.metadata))
+        else {
+            println!(
+                "Found no metadata '{}' for '{}' to change on {}",
+                self.metadata, self.setting, direction
+            );
+            return Ok(());
+        };
+        match value {
+            Value::Array(data) => {
+                // We only handle string lists; convert each value to a str we can compare.
+                let list: Vec<&str> = data
+                    .iter()
+                    .map(|v| v.as_str())
+                    .collect::<Option<Vec<&str>>>()
+                    .with_context(|| error::ReplaceMetadataListContentsSnafu {
+                        setting: self.setting,
+                        metadata: self.metadata,
+                        data: data.clone(),
+                    })?;
+
+                if list == from {
+                    *data = to.iter().map(|s| (*s).into()).collect();
+                    println!(
+                        "Changed metadata '{}' for '{}' from {:?} to {:?} on {}",
+                        self.metadata, self.setting, from, to, direction
+                    );
+                } else {
+                    println!(
+                        "Metadata '{}' for '{}' is not set to {:?}, leaving alone",
+                        self.metadata, self.setting, from
+                    );
+                }
+            }
+            _ => {
+                println!(
+                    "Metadata '{}' for '{}' is set to non-list value '{}'; ReplaceMetadataListsMigration only handles lists",
+                    self.metadata, self.setting, value
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct ReplaceMetadataListsMigration(pub Vec<MetadataListReplacement>);
+
+impl Migration for ReplaceMetadataListsMigration {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for replacement in &self.0 {
+            replacement.replace(
+                &mut input,
+                replacement.old_vals,
+                replacement.new_vals,
+                "upgrade",
+            )?;
+        }
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for replacement in &self.0 {
+            replacement.replace(
+                &mut input,
+                replacement.new_vals,
+                replacement.old_vals,
+                "downgrade",
+            )?;
+        }
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_replace_metadata_lists_migration {
+    use super::{MetadataListReplacement, ReplaceMetadataListsMigration};
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    fn migration() -> ReplaceMetadataListsMigration {
+        ReplaceMetadataListsMigration(vec![MetadataListReplacement {
+            setting: "settings.ntp",
+            metadata: "affected-services",
+            old_vals: &["chronyd"],
+            new_vals: &["chronyd", "ntp-sync"],
+        }])
+    }
+
+    fn data(services: Value) -> MigrationData {
+        MigrationData {
+            data: HashMap::new(),
+            metadata: hashmap! {
+                "settings.ntp".into() => hashmap! {
+                    "affected-services".into() => services,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let result = migration().forward(data(vec!["chronyd"].into())).unwrap();
+        assert_eq!(result, data(vec!["chronyd", "ntp-sync"].into()));
+    }
+
+    #[test]
+    fn backward() {
+        let result = migration()
+            .backward(data(vec!["chronyd", "ntp-sync"].into()))
+            .unwrap();
+        assert_eq!(result, data(vec!["chronyd"].into()));
+    }
+
+    #[test]
+    fn no_match() {
+        let input = data(vec!["custom"].into());
+        assert_eq!(migration().forward(input.clone()).unwrap(), input);
+        assert_eq!(migration().backward(input.clone()).unwrap(), input);
+    }
+
+    #[test]
+    fn non_string_item() {
+        assert!(migration().forward(data(vec![1, 2].into())).is_err());
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Name of the metadata key holding the template a setting is rendered from.
+const TEMPLATE_METADATA_KEY: &str = "template";
+
+/// We use this migration when we replace the template a setting is rendered from, found in its
+/// "template" metadata.  If the setting's value is what the old template renders to, it's
+/// re-rendered from the new template; otherwise the user changed it, and we leave it alone.
+///
+/// Templates are rendered with Handlebars against the data being migrated, so they can refer to
+/// other keys, like `{{ settings.aws.region }}`, and can use the helpers in
+/// `template_registry`.  Both templates are rendered before the value is changed.  If either
+/// can't be rendered, for example because the host's region isn't one `ecr-prefix` knows, the
+/// template is still replaced, but the value is left alone, so the upgrade isn't blocked.
+pub struct ReplaceTemplateMigration {
+    pub setting: &'static str,
+    pub old_template: &'static str,
+    pub new_template: &'static str,
+}
+
+impl ReplaceTemplateMigration {
+    /// Replaces the setting's template if it's `from`, and its value if it was rendered from it;
+    /// `direction` is only used for messages.
+    fn replace(
+        &self,
+        input: &mut MigrationData,
+        from: &str,
+        to: &str,
+        direction: &str,
+    ) -> Result<()> {
+        let template = input
+            .metadata
+            .get(self.setting)
+            .and_then(|metadata| metadata.get(TEMPLATE_METADATA_KEY));
+        match template {
+            None => {
+                println!(
+                    "Found no template for '{}' to change on {}",
+                    self.setting, direction
+                );
+                return Ok(());
+            }
+            Some(template) if template.as_str() != Some(from) => {
+                println!(
+                    "Template for '{}' is not set to '{}', leaving alone",
+                    self.setting, from
+                );
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+
+        let tree = template_data(&input.data)?;
+        let registry = template_registry();
+        let render = |template: &str| {
+            registry
+                .render_template(template, &tree)
+                .context(error::RenderTemplateSnafu {
+                    setting: self.setting,
+                    template,
+                })
+        };
+        let rendered = render(from).and_then(|old| Ok((old, render(to)?)));
+
+        if let Some(metadata) = input.metadata.get_mut(self.setting) {
+            metadata.insert(TEMPLATE_METADATA_KEY.to_string(), to.into());
+        }
+        println!(
+            "Changed template for '{}' from '{}' to '{}' on {}",
+            self.setting, from, to, direction
+        );
+
+        let (old, new) = match rendered {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                println!("Leaving value of '{}' alone: {}", self.setting, e);
+                return Ok(());
+            }
+        };
+        match input.data.get_mut(self.setting) {
+            Some(value) if value.as_str() == Some(&old) => {
+                println!(
+                    "Changed value of '{}' from '{}' to '{}' on {}",
+                    self.setting, old, new, direction
+                );
+                *value = new.into();
+            }
+            Some(_) => println!("'{}' is not set to '{}', leaving alone", self.setting, old),
+            None => println!("Found no '{}' to change on {}", self.setting, direction),
+        }
+        Ok(())
+    }
+}
+
+impl Migration for ReplaceTemplateMigration {
+    fn forward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        self.replace(&mut input, self.old_template, self.new_template, "upgrade")?;
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        self.replace(
+            &mut input,
+            self.new_template,
+            self.old_template,
+            "downgrade",
+        )?;
+        Ok(input)
+    }
+}
+
+/// The account that hosts Bottlerocket's container images in most regions.
+const ECR_ACCOUNT: &str = "328549459982";
+
+/// Regions whose ECR registry is in ECR_ACCOUNT, as used by templates from before the
+/// `ecr-prefix` helper.  Other regions have their own accounts, which we don't know here.
+const ECR_REGIONS: &[&str] = &[
+    "ap-northeast-1",
+    "ap-northeast-2",
+    "ap-south-1",
+    "ap-southeast-1",
+    "ap-southeast-2",
+    "ca-central-1",
+    "eu-central-1",
+    "eu-north-1",
+    "eu-west-1",
+    "eu-west-2",
+    "eu-west-3",
+    "sa-east-1",
+    "us-east-1",
+    "us-east-2",
+    "us-west-1",
+    "us-west-2",
+];
+
+/// Returns a Handlebars registry that renders templates the way settings are rendered: values
+/// are inserted as they are, without HTML escaping, and the `ecr-prefix` helper is available.
+pub fn template_registry() -> handlebars::Handlebars<'static> {
+    let mut registry = handlebars::Handlebars::new();
+    registry.register_escape_fn(handlebars::no_escape);
+    registry.register_helper("ecr-prefix", Box::new(ecr_prefix));
+    registry
+}
+
+/// Renders the ECR registry for the region given as its parameter, like
+/// `{{ ecr-prefix settings.aws.region }}`.  Fails for regions whose registry we don't know, so a
+/// migration never guesses at an image source; ReplaceTemplateMigration then leaves the value for
+/// the settings renderer, which knows every region.
+fn ecr_prefix(
+    helper: &handlebars::Helper,
+    _: &handlebars::Handlebars,
+    _: &handlebars::Context,
+    _: &mut handlebars::RenderContext,
+    out: &mut dyn handlebars::Output,
+) -> handlebars::HelperResult {
+    let region = helper
+        .param(0)
+        .and_then(|param| param.value().as_str())
+        .ok_or_else(|| handlebars::RenderError::new("ecr-prefix requires a region"))?;
+    if !ECR_REGIONS.contains(&region) {
+        return Err(handlebars::RenderError::new(format!(
+            "no known ECR registry for region '{region}'"
+        )));
+    }
+    out.write(&format!("{ECR_ACCOUNT}.dkr.ecr.{region}.amazonaws.com"))?;
+    Ok(())
+}
+
+/// Nests the given data by key segment, so templates can refer to keys by their dotted names.
+fn template_data(data: &HashMap<String, Value>) -> Result<Value> {
+    let mut tree = serde_json::Map::new();
+    for (name, value) in data {
+        let key = Key::new(KeyType::Data, name).context(error::InvalidKeySnafu {
+            key_type: KeyType::Data,
+            key: name,
+        })?;
+        let (last, parents) = key
+            .segments()
+            .split_last()
+            .context(error::MissingDataSnafu { key: name })?;
+        let mut table = &mut tree;
+        for segment in parents {
+            let entry = table
+                .entry(segment.clone())
+                .or_insert_with(|| Value::Object(serde_json::Map::new()));
+            if !entry.is_object() {
+                *entry = Value::Object(serde_json::Map::new());
+            }
+            table = entry
+                .as_object_mut()
+                .context(error::MissingDataSnafu { key: name })?;
+        }
+        table.insert(last.clone(), value.clone());
+    }
+    Ok(Value::Object(tree))
+}
+
+#[cfg(test)]
+mod test_replace_template_migration {
+    use super::ReplaceTemplateMigration;
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+
+    const OLD_TEMPLATE: &str = "registry.{{ settings.aws.region }}.example.com/admin:v1";
+    const NEW_TEMPLATE: &str = "registry.{{ settings.aws.region }}.example.org/admin:v1";
+
+    fn migration() -> ReplaceTemplateMigration {
+        ReplaceTemplateMigration {
+            setting: "settings.host-containers.admin.source",
+            old_template: OLD_TEMPLATE,
+            new_template: NEW_TEMPLATE,
+        }
+    }
+
+    fn data(source: &str, template: &str) -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.aws.region".into() => "us-west-2".into(),
+                "settings.host-containers.admin.source".into() => source.into(),
+            },
+            metadata: hashmap! {
+                "settings.host-containers.admin.source".into() => hashmap! {
+                    "template".into() => template.into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let result = migration()
+            .forward(data(
+                "registry.us-west-2.example.com/admin:v1",
+                OLD_TEMPLATE,
+            ))
+            .unwrap();
+        assert_eq!(
+            result,
+            data("registry.us-west-2.example.org/admin:v1", NEW_TEMPLATE)
+        );
+    }
+
+    #[test]
+    fn backward() {
+        let result = migration()
+            .backward(data(
+                "registry.us-west-2.example.org/admin:v1",
+                NEW_TEMPLATE,
+            ))
+            .unwrap();
+        assert_eq!(
+            result,
+            data("registry.us-west-2.example.com/admin:v1", OLD_TEMPLATE)
+        );
+    }
+
+    #[test]
+    fn user_value() {
+        // The template is replaced, but a value the user set is left alone.
+        let result = migration()
+            .forward(data("my-registry/admin:v1", OLD_TEMPLATE))
+            .unwrap();
+        assert_eq!(result, data("my-registry/admin:v1", NEW_TEMPLATE));
+    }
+
+    #[test]
+    fn helper_and_no_escape() {
+        let result = ReplaceTemplateMigration {
+            setting: "settings.host-containers.admin.source",
+            old_template: "328549459982.dkr.ecr.{{ settings.aws.region }}.amazonaws.com/a:v1",
+            new_template: "{{ ecr-prefix settings.aws.region }}/a:v1?x=1&y=<2>",
+        }
+        .forward(data(
+            "328549459982.dkr.ecr.us-west-2.amazonaws.com/a:v1",
+            "328549459982.dkr.ecr.{{ settings.aws.region }}.amazonaws.com/a:v1",
+        ))
+        .unwrap();
+        assert_eq!(
+            result.data["settings.host-containers.admin.source"],
+            "328549459982.dkr.ecr.us-west-2.amazonaws.com/a:v1?x=1&y=<2>"
+        );
+    }
+
+    #[test]
+    fn unrenderable_template() {
+        // The template is replaced, but the value is left alone if either template can't be
+        // rendered.
+        let new_template = "{{ unknown-helper settings.aws.region }}/admin:v1";
+        let source = "registry.us-west-2.example.com/admin:v1";
+        let result = ReplaceTemplateMigration {
+            setting: "settings.host-containers.admin.source",
+            old_template: OLD_TEMPLATE,
+            new_template,
+        }
+        .forward(data(source, OLD_TEMPLATE))
+        .unwrap();
+        assert_eq!(result, data(source, new_template));
+    }
+
+    #[test]
+    fn unknown_region() {
+        let old_template = "328549459982.dkr.ecr.{{ settings.aws.region }}.amazonaws.com/a:v1";
+        let new_template = "{{ ecr-prefix settings.aws.region }}/a:v2";
+        let mut migration = ReplaceTemplateMigration {
+            setting: "settings.host-containers.admin.source",
+            old_template,
+            new_template,
+        };
+
+        // A region without a registry we know doesn't block the upgrade.
+        let source = "328549459982.dkr.ecr.ap-east-1.amazonaws.com/a:v1";
+        let mut input = data(source, old_template);
+        input
+            .data
+            .insert("settings.aws.region".into(), "ap-east-1".into());
+        let mut expected = input.clone();
+        expected.metadata.insert(
+            "settings.host-containers.admin.source".into(),
+            hashmap! { "template".into() => new_template.into() },
+        );
+        assert_eq!(migration.forward(input).unwrap(), expected);
+
+        // Neither does a missing region.
+        let mut input = data(source, old_template);
+        input.data.remove("settings.aws.region");
+        let mut expected = input.clone();
+        expected.metadata.insert(
+            "settings.host-containers.admin.source".into(),
+            hashmap! { "template".into() => new_template.into() },
+        );
+        assert_eq!(migration.forward(input).unwrap(), expected);
+    }
+
+    #[test]
+    fn other_template() {
+        let input = data("my-registry/admin:v1", "my-registry/{{ settings.x }}");
+        assert_eq!(migration().forward(input.clone()).unwrap(), input);
+    }
+}
+
+// =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
+
+/// Name of the metadata key holding the strength of a setting, "weak" or "strong".
+const STRENGTH_METADATA_KEY: &str = "strength";
+/// Name of the metadata key holding the generator of a setting.
+const SETTING_GENERATOR_METADATA_KEY: &str = "setting-generator";
+
+/// A setting generator given as a table, with the command to run and the strength of the
+/// settings it generates.  Older versions only understand the command as a plain string.
+#[derive(Debug, serde::Deserialize)]
+struct SettingsGenerator {
+    command: String,
+}
+
+/// We use this migration when going back to versions that don't understand setting strength.
+/// Weak settings were set by a generator and can be replaced, but old versions would treat them
+/// like settings the user chose, so we remove them and let the old version generate them again.
+/// The "strength" metadata is removed, and setting generators given as tables are replaced with
+/// their command.
+pub struct RemoveMetadataAndWeakSettingsMigration;
+
+impl Migration for RemoveMetadataAndWeakSettingsMigration {
+    /// New versions understand strength; we don't need to do anything.
+    fn forward(&mut self, input: MigrationData) -> Result<MigrationData> {
+        println!("RemoveMetadataAndWeakSettingsMigration has no work to do on upgrade.");
+        Ok(input)
+    }
+
+    fn backward(&mut self, mut input: MigrationData) -> Result<MigrationData> {
+        for (setting, metadata) in input.metadata.iter_mut() {
+            if let Some(strength) = metadata.remove(STRENGTH_METADATA_KEY) {
+                if strength.as_str() == Some("weak") {
+                    if let Some(data) = input.data.remove(setting) {
+                        println!("Removed weak setting {setting}, which was set to '{data}'");
+                    }
+                }
+                println!("Removed strength of {setting}, which was set to '{strength}'");
+            }
+
+            if let Some(generator) = metadata.get_mut(SETTING_GENERATOR_METADATA_KEY) {
+                if generator.is_object() {
+                    let SettingsGenerator { command } =
+                        serde_json::from_value(generator.clone())
+                            .context(error::DeserializeSettingsGeneratorSnafu)?;
+                    println!("Changed setting-generator of {setting} to '{command}'");
+                    *generator = command.into();
+                }
+            }
+        }
+        input.metadata.retain(|_, metadata| !metadata.is_empty());
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod test_remove_metadata_and_weak_settings_migration {
+    use super::RemoveMetadataAndWeakSettingsMigration;
+    use crate::{Migration, MigrationData};
+    use maplit::hashmap;
+    use serde_json::json;
+
+    fn data() -> MigrationData {
+        MigrationData {
+            data: hashmap! {
+                "settings.weak".into() => "generated".into(),
+                "settings.strong".into() => "chosen".into(),
+                "settings.plain".into() => "plain".into(),
+            },
+            metadata: hashmap! {
+                "settings.weak".into() => hashmap! {
+                    "strength".into() => "weak".into(),
+                    "setting-generator".into() => json!({
+                        "command": "gen-weak",
+                        "strength": "weak",
+                    }),
+                },
+                "settings.strong".into() => hashmap! {
+                    "strength".into() => "strong".into(),
+                },
+                "settings.plain".into() => hashmap! {
+                    "setting-generator".into() => "gen-plain".into(),
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn forward() {
+        let result = RemoveMetadataAndWeakSettingsMigration
+            .forward(data())
+            .unwrap();
+        assert_eq!(result, data());
+    }
+
+    #[test]
+    fn backward() {
+        let result = RemoveMetadataAndWeakSettingsMigration
+            .backward(data())
+            .unwrap();
+        assert_eq!(
+            result,
+            MigrationData {
+                data: hashmap! {
+                    "settings.strong".into() => "chosen".into(),
+                    "settings.plain".into() => "plain".into(),
+                },
+                metadata: hashmap! {
+                    "settings.weak".into() => hashmap! {
+                        "setting-generator".into() => "gen-weak".into(),
+                    },
+                    "settings.plain".into() => hashmap! {
+                        "setting-generator".into() => "gen-plain".into(),
+                    },
+                },
+            }
+        );
+    }
+}
@@ -6,6 +6,8 @@ use snafu::ResultExt;
 use std::collections::HashMap;
 
 use crate::{error, MigrationData, Result};
+use datastore::revision;
+use datastore::sensitive::SENSITIVE_METADATA_KEY;
 use datastore::{
     deserialize_scalar, serialization::to_pairs_with_prefix, serialize_scalar, Committed,
     DataStore, Key, KeyType,
@@ -15,10 +17,12 @@ use datastore::{
 // breaking changes in the basic data store API would be a major-version migration of the data
 // store, and that would be handled separately.  This method is private to the crate, so we can
 // reconsider as needed.
-/// Retrieves data from the specified data store in a consistent format for easy modification.
+/// Retrieves data from the specified data store in a consistent format for easy modification,
+/// along with the "os.*" values of the given release.
 pub(crate) fn get_input_data<D: DataStore>(
     datastore: &D,
     committed: &Committed,
+    release: &BottlerocketRelease,
 ) -> Result<MigrationData> {
     let raw_data = datastore
         .get_prefix("", committed)
@@ -40,33 +44,29 @@ pub(crate) fn get_input_data<D: DataStore>(
     }
 
     // We also want to make "os.*" values, like variant and arch, available to migrations.
-    let release = BottlerocketRelease::new().context(error::BottlerocketReleaseSnafu)?;
-    let os_pairs = to_pairs_with_prefix("os", &release).context(error::SerializeReleaseSnafu)?;
+    let os_pairs = to_pairs_with_prefix("os", release).context(error::SerializeReleaseSnafu)?;
     for (data_key, value_str) in os_pairs.into_iter() {
         let value =
             deserialize_scalar(&value_str).context(error::DeserializeSnafu { input: value_str })?;
         data.insert(data_key.name().clone(), value);
     }
 
-    // Metadata isn't committed, it goes live immediately, so we only populate the metadata
-    // output for Committed::Live.
+    // Metadata is committed along with data, so pending transactions have their own metadata.
     let mut metadata = HashMap::new();
-    if let Committed::Live = committed {
-        let raw_metadata = datastore
-            .get_metadata_prefix("", committed, &None as &Option<&str>)
-            .context(error::GetMetadataSnafu)?;
-        for (data_key, meta_map) in raw_metadata.into_iter() {
-            // See notes above about storing key Strings and Values.
-            let data_key_name = data_key.name();
-            let data_entry = metadata
-                .entry(data_key_name.clone())
-                .or_insert_with(HashMap::new);
-            for (metadata_key, value_str) in meta_map.into_iter() {
-                let metadata_key_name = metadata_key.name();
-                let value = deserialize_scalar(&value_str)
-                    .context(error::DeserializeSnafu { input: value_str })?;
-                data_entry.insert(metadata_key_name.clone(), value);
-            }
+    let raw_metadata = datastore
+        .get_metadata_prefix("", committed, &None as &Option<&str>)
+        .context(error::GetMetadataSnafu)?;
+    for (data_key, meta_map) in raw_metadata.into_iter() {
+        // See notes above about storing key Strings and Values.
+        let data_key_name = data_key.name();
+        let data_entry = metadata
+            .entry(data_key_name.clone())
+            .or_insert_with(HashMap::new);
+        for (metadata_key, value_str) in meta_map.into_iter() {
+            let metadata_key_name = metadata_key.name();
+            let value = deserialize_scalar(&value_str)
+                .context(error::DeserializeSnafu { input: value_str })?;
+            data_entry.insert(metadata_key_name.clone(), value);
         }
     }
 
@@ -93,6 +93,12 @@ pub(crate) fn set_output_data<D: DataStore>(
         data.insert(data_key, value);
     }
 
+    // Sensitive flags must be set before data, so the values they cover are encrypted as
+    // they're written, and never reach the target in the clear.
+    set_output_metadata(datastore, input, committed, |name| {
+        name == SENSITIVE_METADATA_KEY
+    })?;
+
     // This is one of the rare cases where we want to set keys directly in the datastore:
     // * We're operating on a temporary copy of the datastore, so no concurrency issues
     // * We're either about to reboot or just have, and the settings applier will run afterward
@@ -100,13 +106,91 @@ pub(crate) fn set_output_data<D: DataStore>(
         .set_keys(&data, committed)
         .context(error::DataStoreWriteSnafu)?;
 
-    // Set metadata in a loop (currently no batch API)
+    set_output_metadata(datastore, input, committed, |name| {
+        name != SENSITIVE_METADATA_KEY
+    })?;
+
+    Ok(())
+}
+
+/// Carries the keys and metadata unset in the given pending transaction over from the source
+/// data store to the target, so that committing the transaction still removes them from live.
+/// Deletions aren't part of migration input, so they're carried as they are.  Call this before
+/// writing the migrated data, so anything the migration sets again wins, as it would if it were
+/// set after being unset.
+pub(crate) fn carry_deletions<S, T>(source: &S, target: &mut T, committed: &Committed) -> Result<()>
+where
+    S: DataStore,
+    T: DataStore,
+{
+    let deleted_keys =
+        source
+            .list_deleted_keys("", committed)
+            .with_context(|_| error::GetDataSnafu {
+                committed: committed.clone(),
+            })?;
+    for data_key in deleted_keys {
+        target
+            .unset_key(&data_key, committed)
+            .context(error::DataStoreWriteSnafu)?;
+    }
+
+    let deleted_metadata = source
+        .list_deleted_metadata("", committed)
+        .context(error::GetMetadataSnafu)?;
+    for (data_key, metadata_keys) in deleted_metadata {
+        for metadata_key in metadata_keys {
+            target
+                .unset_metadata(&metadata_key, &data_key, committed)
+                .context(error::DataStoreWriteSnafu)?;
+        }
+    }
+    Ok(())
+}
+
+/// Carries the revisions of the given (migrated) live data over from the source data store to the
+/// target, after it's been written.  Revisions aren't part of migration input; see
+/// `datastore::revision`.
+pub(crate) fn carry_revisions<S, T>(
+    source: &S,
+    target: &mut T,
+    migrated: &MigrationData,
+) -> Result<()>
+where
+    S: DataStore,
+    T: DataStore,
+{
+    for data_key_name in migrated.data.keys() {
+        let data_key = Key::new(KeyType::Data, data_key_name).context(error::InvalidKeySnafu {
+            key_type: KeyType::Data,
+            key: data_key_name,
+        })?;
+        revision::carry(source, target, &data_key).context(error::DataStoreWriteSnafu)?;
+    }
+    Ok(())
+}
+
+/// Sets the metadata in the given (migrated) data whose metadata key names match the given filter.
+fn set_output_metadata<D, F>(
+    datastore: &mut D,
+    input: &MigrationData,
+    committed: &Committed,
+    filter: F,
+) -> Result<()>
+where
+    D: DataStore,
+    F: Fn(&str) -> bool,
+{
+    // Set metadata in a loop (currently no batch API).
     for (data_key_name, meta_map) in &input.metadata {
         let data_key = Key::new(KeyType::Data, data_key_name).context(error::InvalidKeySnafu {
             key_type: KeyType::Data,
             key: data_key_name,
         })?;
         for (metadata_key_name, raw_value) in meta_map.iter() {
+            if !filter(metadata_key_name) {
+                continue;
+            }
             let metadata_key =
                 Key::new(KeyType::Meta, metadata_key_name).context(error::InvalidKeySnafu {
                     key_type: KeyType::Meta,
@@ -118,6 +202,113 @@ pub(crate) fn set_output_data<D: DataStore>(
                 .context(error::DataStoreWriteSnafu)?;
         }
     }
-
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{carry_deletions, carry_revisions, set_output_data};
+    use crate::MigrationData;
+    use datastore::memory::MemoryDataStore;
+    use datastore::{Committed, DataStore, EncryptionKey, FilesystemDataStore, Key, KeyType};
+    use maplit::hashmap;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn revisions_survive() {
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let element = Key::new(KeyType::Data, "settings.l[1].b").unwrap();
+        let mut source = MemoryDataStore::new();
+        for _ in 0..7 {
+            source.set_key(&key, "\"x\"", &Committed::Live).unwrap();
+        }
+        source.set_key(&element, "1", &Committed::Live).unwrap();
+
+        let mut target = MemoryDataStore::new();
+        let input = MigrationData {
+            data: hashmap! {
+                "settings.a".into() => "x".into(),
+                "settings.l[0].b".into() => 0.into(),
+                "settings.l[1].b".into() => 1.into(),
+            },
+            metadata: HashMap::new(),
+        };
+        set_output_data(&mut target, &input, &Committed::Live).unwrap();
+        carry_revisions(&source, &mut target, &input).unwrap();
+
+        // Each key moves past its revision in the source; list elements share the list's.
+        assert_eq!(target.get_revision(&key).unwrap(), 8);
+        assert_eq!(target.get_revision(&element).unwrap(), 2);
+    }
+
+    #[test]
+    fn pending_deletions_survive() {
+        let dir = std::env::temp_dir().join(format!("migration-deletions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let key = |name| Key::new(KeyType::Data, name).unwrap();
+        let live = Committed::Live;
+        let pending = Committed::Pending { tx: "tx".into() };
+        let mut source = FilesystemDataStore::create(dir.join("source")).unwrap();
+        for name in ["settings.a", "settings.b", "settings.c"] {
+            source.set_key(&key(name), "\"x\"", &live).unwrap();
+        }
+        source.unset_key(&key("settings.a"), &pending).unwrap();
+        source.unset_key(&key("settings.b"), &pending).unwrap();
+        let affected = Key::new(KeyType::Meta, "affected-services").unwrap();
+        source
+            .set_metadata(&affected, &key("settings.c"), "[\"c\"]", &live)
+            .unwrap();
+        source
+            .unset_metadata(&affected, &key("settings.c"), &pending)
+            .unwrap();
+
+        // The migration sets settings.b again in the transaction, which wins over unsetting it.
+        let mut target = FilesystemDataStore::create(dir.join("target")).unwrap();
+        let input = MigrationData {
+            data: hashmap! {
+                "settings.b".into() => "y".into(),
+            },
+            metadata: HashMap::new(),
+        };
+        let result = carry_deletions(&source, &mut target, &pending)
+            .and_then(|()| set_output_data(&mut target, &input, &pending));
+        let deleted = target.list_deleted_keys("", &pending);
+        let deleted_metadata = target.list_deleted_metadata("", &pending);
+        let _ = fs::remove_dir_all(&dir);
+
+        result.unwrap();
+        assert_eq!(deleted.unwrap(), [key("settings.a")].into());
+        assert_eq!(
+            deleted_metadata.unwrap(),
+            hashmap! { key("settings.c") => [affected].into() }
+        );
+    }
+
+    #[test]
+    fn sensitive_values_encrypted() {
+        let dir = std::env::temp_dir().join(format!("migration-output-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let encryption_key = EncryptionKey::load_or_create(dir.with_extension("key")).unwrap();
+        let mut datastore = FilesystemDataStore::create(&dir)
+            .unwrap()
+            .with_encryption_key(encryption_key);
+        let input = MigrationData {
+            data: hashmap! {
+                "settings.aws.credentials".into() => "secret".into(),
+            },
+            metadata: hashmap! {
+                "settings.aws".into() => hashmap!{
+                    "sensitive".into() => true.into(),
+                },
+            },
+        };
+        let result = set_output_data(&mut datastore, &input, &Committed::Live);
+        let on_disk = fs::read_to_string(dir.join("live/settings/aws/credentials"));
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_file(dir.with_extension("key"));
+
+        result.unwrap();
+        assert!(!on_disk.unwrap().contains("secret"));
+    }
+}
@@ -0,0 +1,215 @@
+//! Loads a variant's default settings, so migrations can reset part of the data store to the new
+//! version's defaults.
+//!
+//! A variant's defaults are built from the TOML files in its `defaults.d` directory, most of them
+//! shared from `shared-defaults`.  The files are merged in name order: tables are merged key by
+//! key, and any other value in a later file replaces the one before it.  The build installs the
+//! merged result at DEFAULTS_PATH; `load_defaults` also accepts a `defaults.d` directory and
+//! merges it the same way.
+//!
+//! Defaults use the layout of `datastore::document`, with metadata in a top-level "metadata"
+//! table, and are loaded through it, so their keys and values are exactly what the data store
+//! would hold after populating them.
+
+use serde_json::Value;
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{error, MigrationData, Result};
+use datastore::memory::MemoryDataStore;
+use datastore::{deserialize_scalar, Committed, DataStore, DocumentFormat, Key, KeyType};
+
+/// Where the variant's merged defaults are installed.
+pub const DEFAULTS_PATH: &str = "/etc/storewolf/defaults.toml";
+
+/// Loads all default data and metadata from the given file, or from the `*.toml` files in the
+/// given directory, merged in name order.
+pub fn load_defaults<P: AsRef<Path>>(path: P) -> Result<MigrationData> {
+    let path = path.as_ref();
+    let mut merged = Value::Object(Default::default());
+    for layer in layers(path)? {
+        let contents =
+            fs::read_to_string(&layer).context(error::ReadDefaultsSnafu { path: &layer })?;
+        let value: Value =
+            toml::from_str(&contents).context(error::ParseDefaultsSnafu { path: &layer })?;
+        merge(&mut merged, value);
+    }
+
+    let mut datastore = MemoryDataStore::new();
+    datastore::import(&mut datastore, &merged.to_string(), DocumentFormat::Json)
+        .context(error::LoadDefaultsSnafu { path })?;
+
+    let mut data = HashMap::new();
+    let raw_data = datastore
+        .get_prefix("", &Committed::Live)
+        .context(error::LoadDefaultsSnafu { path })?;
+    for (data_key, value_str) in raw_data {
+        let value =
+            deserialize_scalar(&value_str).context(error::DeserializeSnafu { input: value_str })?;
+        data.insert(data_key.name().clone(), value);
+    }
+
+    let mut metadata = HashMap::new();
+    let raw_metadata = datastore
+        .get_metadata_prefix("", &Committed::Live, &None as &Option<&str>)
+        .context(error::LoadDefaultsSnafu { path })?;
+    for (data_key, meta_map) in raw_metadata {
+        let mut data_entry = HashMap::new();
+        for (metadata_key, value_str) in meta_map {
+            let value = deserialize_scalar(&value_str)
+                .context(error::DeserializeSnafu { input: value_str })?;
+            data_entry.insert(metadata_key.name().clone(), value);
+        }
+        if !data_entry.is_empty() {
+            metadata.insert(data_key.name().clone(), data_entry);
+        }
+    }
+
+    Ok(MigrationData { data, metadata })
+}
+
+/// Returns the data and metadata in the given defaults for keys under the given path.  Paths
+/// match whole key segments, so "settings.ntp" includes "settings.ntp.time-servers" but not
+/// "settings.ntpd".  An empty path returns everything.
+pub fn subtree<S: AsRef<str>>(defaults: &MigrationData, path: S) -> Result<MigrationData> {
+    let segments = prefix_segments(path.as_ref())?;
+
+    let mut subtree = MigrationData {
+        data: HashMap::new(),
+        metadata: HashMap::new(),
+    };
+    for (name, value) in &defaults.data {
+        if is_under(name, &segments)? {
+            subtree.data.insert(name.clone(), value.clone());
+        }
+    }
+    for (name, meta_map) in &defaults.metadata {
+        if is_under(name, &segments)? {
+            subtree.metadata.insert(name.clone(), meta_map.clone());
+        }
+    }
+    Ok(subtree)
+}
+
+/// Splits a key path into the segments it names, for use with `is_under`.
+pub(crate) fn prefix_segments(path: &str) -> Result<Vec<String>> {
+    Key::prefix_segments(path).context(error::InvalidKeySnafu {
+        key_type: KeyType::Data,
+        key: path,
+    })
+}
+
+/// Returns whether the data key with the given name is under the path with the given segments.
+pub(crate) fn is_under(name: &str, segments: &[String]) -> Result<bool> {
+    let key = Key::new(KeyType::Data, name).context(error::InvalidKeySnafu {
+        key_type: KeyType::Data,
+        key: name,
+    })?;
+    Ok(key.starts_with_segments(segments))
+}
+
+/// Returns the files to merge for the given path: the path itself if it's a file, or the
+/// `*.toml` files in it, in name order, if it's a directory.
+fn layers(path: &Path) -> Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut layers = Vec::new();
+    for entry in fs::read_dir(path).context(error::ReadDefaultsSnafu { path })? {
+        let entry = entry.context(error::ReadDefaultsSnafu { path })?;
+        let layer = entry.path();
+        if layer
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            layers.push(layer);
+        }
+    }
+    layers.sort_unstable();
+    Ok(layers)
+}
+
+/// Merges a later layer of defaults into the ones before it.
+fn merge(merged: &mut Value, layer: Value) {
+    match (merged, layer) {
+        (Value::Object(merged), Value::Object(layer)) => {
+            for (name, value) in layer {
+                match merged.get_mut(&name) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        merged.insert(name, value);
+                    }
+                }
+            }
+        }
+        (merged, layer) => *merged = layer,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_defaults, subtree};
+    use maplit::hashmap;
+    use std::fs;
+
+    #[test]
+    fn layered() {
+        let dir = std::env::temp_dir().join(format!("defaults-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("10-defaults.toml"),
+            r#"
+            [settings]
+            motd = "Welcome"
+            [settings.ntp]
+            time-servers = ["a.example.com"]
+            [metadata.settings.ntp]
+            affected-services = ["chronyd"]
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("50-variant.toml"),
+            r#"
+            [settings.ntp]
+            time-servers = ["b.example.com"]
+            [settings.ntpd]
+            enabled = true
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.join("README"), "not a layer").unwrap();
+
+        let defaults = load_defaults(&dir);
+        let _ = fs::remove_dir_all(&dir);
+        let defaults = defaults.unwrap();
+        assert_eq!(
+            defaults.data,
+            hashmap! {
+                "settings.motd".into() => "Welcome".into(),
+                "settings.ntp.time-servers".into() => vec!["b.example.com"].into(),
+                "settings.ntpd.enabled".into() => true.into(),
+            }
+        );
+
+        let ntp = subtree(&defaults, "settings.ntp").unwrap();
+        assert_eq!(
+            ntp.data,
+            hashmap! {
+                "settings.ntp.time-servers".into() => vec!["b.example.com"].into(),
+            }
+        );
+        assert_eq!(
+            ntp.metadata,
+            hashmap! {
+                "settings.ntp".into() => hashmap! {
+                    "affected-services".into() => vec!["chronyd"].into(),
+                },
+            }
+        );
+        assert_eq!(subtree(&defaults, "").unwrap(), defaults);
+    }
+}
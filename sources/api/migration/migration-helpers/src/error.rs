@@ -10,6 +10,23 @@ pub enum Error {
     #[snafu(display("Unable to get system release data: {}", source))]
     BottlerocketRelease { source: bottlerocket_release::Error },
 
+    #[snafu(display("Unable to open data store at '{}': {}", path, source))]
+    OpenDataStore {
+        path: String,
+        #[snafu(source(from(datastore::Error, Box::new)))]
+        source: Box<datastore::Error>,
+    },
+
+    #[snafu(display("No target data store given for a migration that isn't a dry run"))]
+    MissingTargetDataStore,
+
+    #[snafu(display("Unable to load encryption key from '{}': {}", path, source))]
+    LoadEncryptionKey {
+        path: String,
+        #[snafu(source(from(datastore::Error, Box::new)))]
+        source: Box<datastore::Error>,
+    },
+
     #[snafu(display("Unable to get {:?} data for migration: {}", committed, source))]
     GetData {
         committed: datastore::Committed,
@@ -35,6 +52,19 @@ pub enum Error {
     #[snafu(display("Unable to serialize datastore for rendering templates: {}", source))]
     SerializeTemplateData { source: serde_json::Error },
 
+    #[snafu(display(
+        "Unable to render template '{}' for '{}': {}",
+        template,
+        setting,
+        source
+    ))]
+    RenderTemplate {
+        setting: String,
+        template: String,
+        #[snafu(source(from(handlebars::RenderError, Box::new)))]
+        source: Box<handlebars::RenderError>,
+    },
+
     #[snafu(display("Unable to serialize release data: {}", source))]
     SerializeRelease {
         source: datastore::serialization::Error,
@@ -53,13 +83,48 @@ pub enum Error {
         source: Box<datastore::Error>,
     },
 
-    #[snafu(display("Migrated data failed validation: {}", msg))]
-    Validation { msg: String },
+    #[snafu(display("Unable to read defaults from '{}': {}", path.display(), source))]
+    ReadDefaults {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Unable to parse defaults in '{}': {}", path.display(), source))]
+    ParseDefaults {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Unable to load defaults from '{}': {}", path.display(), source))]
+    LoadDefaults {
+        path: PathBuf,
+        #[snafu(source(from(datastore::Error, Box::new)))]
+        source: Box<datastore::Error>,
+    },
+
+    #[snafu(display("Unable to serialize migration report: {}", source))]
+    SerializeReport { source: serde_json::Error },
+
+    #[snafu(display("Unable to write migration report to '{}': {}", path.display(), source))]
+    WriteReport {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Migrated data failed validation at '{}': {}", key, msg))]
+    Validation { key: String, msg: String },
 
     // Generic error variant for migration authors
     #[snafu(display("Migration returned error: {}", msg))]
     Migration { msg: String },
 
+    #[snafu(display("Migration step '{}' failed: {}", step, source))]
+    MigrationStep {
+        step: String,
+        #[snafu(source(from(Error, Box::new)))]
+        source: Box<Error>,
+    },
+
     // More specific error variants for migration authors to handle common cases
     #[snafu(display("Migration requires missing key: {}", key))]
     MissingData { key: String },
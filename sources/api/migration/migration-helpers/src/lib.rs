@@ -10,21 +10,30 @@
 // name, and running in parallel would violate that.
 
 mod args;
+pub mod chain;
 pub mod common_migrations;
 mod datastore_helper;
+pub mod defaults;
 pub mod error;
+pub mod report;
+mod validation;
 
+use bottlerocket_release::BottlerocketRelease;
 use snafu::ResultExt;
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
+use std::fs;
 
-use datastore::{Committed, Value};
+use datastore::{Committed, EncryptionKey, Value};
 pub use datastore::{DataStore, FilesystemDataStore};
 
 use args::{parse_args, Args};
-use datastore_helper::{get_input_data, set_output_data};
+pub use chain::MigrationChain;
+use datastore_helper::{carry_deletions, carry_revisions, get_input_data, set_output_data};
 pub use error::Result;
+use report::MigrationReport;
+use validation::validate_migrated_data;
 
 /// The data store implementation currently in use.  Used by the simpler `migrate` interface; can
 /// be overridden by using the `run_migration` interface.
@@ -68,25 +77,47 @@ pub struct MigrationData {
 
 /// Returns the default settings for a given path so you can easily replace a given section of the
 /// datastore with new defaults.  For example, you could request "settings" to get all new default
-/// settings, or "settings.serviceX.subsection" to scope it down.
-pub fn defaults_for<S: AsRef<str>>(_path: S) -> Result<Value> {
-    unimplemented!()
-}
-
-/// Ensures we can use the migrated data in the new data store.  Can use this result to stop the
-/// migration process before saving any data.
-fn validate_migrated_data(_migrated: &MigrationData) -> Result<()> {
-    // No validations yet.
-    // You can check the migrated data and throw error::Validation if anything seems wrong.
-    Ok(())
+/// settings, or "settings.serviceX.subsection" to scope it down.  The result includes the default
+/// metadata of the keys under the path, like "affected-services".
+///
+/// Defaults are read from the new version's merged defaults; see the `defaults` module.
+pub fn defaults_for<S: AsRef<str>>(path: S) -> Result<MigrationData> {
+    let defaults = defaults::load_defaults(defaults::DEFAULTS_PATH)?;
+    defaults::subtree(&defaults, path)
 }
 
 /// If you need a little more control over a migration than with migrate, or you're using this
 /// module as a library, you can call run_migration directly with the arguments that would
 /// normally be parsed from the migration binary's command line.
-pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
-    let source = DataStoreImplementation::new(&args.source_datastore);
-    let mut target = DataStoreImplementation::new(&args.target_datastore);
+///
+/// Migrations only see the data and metadata that are set; keys and metadata unset in a pending
+/// transaction are carried over to the target as they are, so committing it still removes them.
+///
+/// In a dry run, the target data store isn't opened or written, and the source isn't changed
+/// either, not even to recover an interrupted commit; the changes the migration would make are
+/// printed instead.  With a report path, the same changes are written to that file.  (Only data
+/// store writes are skipped; a migration that also changes the filesystem still does.)
+///
+/// If the migrated data fails validation, nothing more is written to the target, but the report
+/// still covers everything before the error is returned.
+pub fn run_migration(migration: impl Migration, args: &Args) -> Result<()> {
+    let release = BottlerocketRelease::new().context(error::BottlerocketReleaseSnafu)?;
+    run_migration_for_release(migration, args, &release)
+}
+
+/// Runs the migration as run_migration does, giving it the "os.*" values of the given release
+/// rather than the running host's.
+fn run_migration_for_release(
+    mut migration: impl Migration,
+    args: &Args,
+    release: &BottlerocketRelease,
+) -> Result<()> {
+    let source = open_datastore(&args.source_datastore, args)?;
+    let mut target = match (args.dry_run, &args.target_datastore) {
+        (true, _) => None,
+        (false, Some(path)) => Some(open_datastore(path, args)?),
+        (false, None) => return error::MissingTargetDataStoreSnafu.fail(),
+    };
 
     // Run for live data and for each pending transaction
     let mut committeds = vec![Committed::Live];
@@ -95,8 +126,15 @@ pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
         .context(error::ListTransactionsSnafu)?;
     committeds.extend(transactions.into_iter().map(|tx| Committed::Pending { tx }));
 
+    // Pending keys are also sensitive if they're marked in live, so reports need live metadata.
+    let live_input = get_input_data(&source, &Committed::Live, release)?;
+    let mut report = MigrationReport::default();
+    let mut validation_error = None;
     for committed in committeds {
-        let input = get_input_data(&source, &committed)?;
+        let input = match committed {
+            Committed::Live => live_input.clone(),
+            Committed::Pending { .. } => get_input_data(&source, &committed, release)?,
+        };
 
         let mut migrated = input.clone();
         migrated = match args.migration_type {
@@ -104,11 +142,57 @@ pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
             MigrationType::Backward => migration.backward(migrated),
         }?;
 
-        validate_migrated_data(&migrated)?;
+        // Only going forward do we know the model the output has to match.
+        if args.validate && matches!(args.migration_type, MigrationType::Forward) {
+            if let Err(e) = validate_migrated_data(&migrated) {
+                validation_error.get_or_insert(e);
+            }
+        }
+
+        if args.dry_run || args.report.is_some() {
+            report.add(&committed, &input, &migrated, &live_input)?;
+        }
+        if let (Some(target), None) = (target.as_mut(), &validation_error) {
+            if let Committed::Pending { .. } = committed {
+                carry_deletions(&source, target, &committed)?;
+            }
+            set_output_data(target, &migrated, &committed)?;
+            if let Committed::Live = committed {
+                carry_revisions(&source, target, &migrated)?;
+            }
+        }
+    }
 
-        set_output_data(&mut target, &migrated, &committed)?;
+    if args.dry_run {
+        print!("{}", report.render(args.report_format)?);
+    }
+    if let Some(path) = &args.report {
+        fs::write(path, report.render(args.report_format)?)
+            .context(error::WriteReportSnafu { path })?;
+    }
+    match validation_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Opens the data store at the given path, with the host's encryption key if there is one, so
+/// sensitive values can be read, and are encrypted when they're written.  In a dry run, only the
+/// source is opened, and it's left as it is, without recovering an interrupted commit.
+fn open_datastore(path: &str, args: &Args) -> Result<DataStoreImplementation> {
+    let datastore = if args.dry_run {
+        DataStoreImplementation::open_without_recovery(path)
+    } else {
+        DataStoreImplementation::new(path).context(error::OpenDataStoreSnafu { path })?
+    };
+    match &args.encryption_key {
+        None => Ok(datastore),
+        Some(key_path) => {
+            let key = EncryptionKey::load(key_path)
+                .context(error::LoadEncryptionKeySnafu { path: key_path })?;
+            Ok(datastore.with_encryption_key(key))
+        }
     }
-    Ok(())
 }
 
 /// Represents the type of migration, so we know which Migration trait method to call.
@@ -135,3 +219,63 @@ pub fn migrate(migration: impl Migration) -> Result<()> {
     let args = parse_args(env::args())?;
     run_migration(migration, &args)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{run_migration_for_release, Args, MigrationType};
+    use crate::common_migrations::NoOpMigration;
+    use crate::report::ReportFormat;
+    use bottlerocket_release::BottlerocketRelease;
+    use datastore::{Committed, DataStore, EncryptionKey, FilesystemDataStore, Key, KeyType};
+    use std::fs;
+
+    #[test]
+    fn migrates_sensitive_value() {
+        let dir = std::env::temp_dir().join(format!("migration-sensitive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let key_path = dir.join("datastore.key");
+        let settings = Key::new(KeyType::Data, "settings.aws").unwrap();
+        let credentials = Key::new(KeyType::Data, "settings.aws.credentials").unwrap();
+        let sensitive = Key::new(KeyType::Meta, "sensitive").unwrap();
+        let mut source = FilesystemDataStore::create(dir.join("source"))
+            .unwrap()
+            .with_encryption_key(EncryptionKey::load_or_create(&key_path).unwrap());
+        source
+            .set_metadata(&sensitive, &settings, "true", &Committed::Live)
+            .unwrap();
+        source
+            .set_key(&credentials, "\"secret\"", &Committed::Live)
+            .unwrap();
+        FilesystemDataStore::create(dir.join("target")).unwrap();
+
+        let args = Args {
+            source_datastore: dir.join("source").display().to_string(),
+            target_datastore: Some(dir.join("target").display().to_string()),
+            migration_type: MigrationType::Forward,
+            dry_run: false,
+            report: None,
+            report_format: ReportFormat::Text,
+            validate: false,
+            encryption_key: Some(key_path.display().to_string()),
+        };
+        let release = BottlerocketRelease {
+            pretty_name: "Bottlerocket OS 1.0.0".into(),
+            variant_id: "aws-dev".into(),
+            version_id: "1.0.0".parse().unwrap(),
+            build_id: "0".into(),
+            arch: "x86_64".into(),
+        };
+        let result = run_migration_for_release(NoOpMigration, &args, &release);
+        let migrated = FilesystemDataStore::new(dir.join("target")).and_then(|target| {
+            target
+                .with_encryption_key(EncryptionKey::load(&key_path).unwrap())
+                .get_key(&credentials, &Committed::Live)
+        });
+        let on_disk = fs::read_to_string(dir.join("target/live/settings/aws/credentials"));
+        let _ = fs::remove_dir_all(&dir);
+
+        result.unwrap();
+        assert_eq!(migrated.unwrap().as_deref(), Some("\"secret\""));
+        assert!(!on_disk.unwrap().contains("secret"));
+    }
+}
@@ -0,0 +1,352 @@
+//! This module describes what a migration changed, for a dry run that shouldn't write anything, or
+//! for a report to keep from a real run.
+//!
+//! Changes are described with the data store's TransactionDiff, comparing the data a migration
+//! was given with the data it returned, once for live data and once for each pending transaction.
+//! Values are shown the way they're stored in the data store, and like in a TransactionDiff, the
+//! values of sensitive keys are redacted.  As in `datastore::sensitive::is_sensitive`, a key in a
+//! pending transaction is also sensitive if it's marked in live data.
+
+use serde::Serialize;
+use snafu::ResultExt;
+use std::fmt::Write;
+
+use crate::{error, MigrationData, Result};
+use datastore::diff::{MetadataChange, ValueChange};
+use datastore::sensitive::{REDACTED, SENSITIVE_METADATA_KEY};
+use datastore::{serialize_scalar, Committed, Key, KeyType, TransactionDiff, Value};
+
+/// The formats a report can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// The changes a migration made to live data and to each pending transaction.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationReport {
+    pub changes: Vec<CommittedChanges>,
+}
+
+/// The changes a migration made to live data, if `transaction` is None, or to the named pending
+/// transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommittedChanges {
+    pub transaction: Option<String>,
+    pub diff: TransactionDiff,
+}
+
+impl MigrationReport {
+    /// Adds the changes from the given input to the given migrated data.  The live input is
+    /// given too, so keys marked sensitive in live are redacted in pending transactions.
+    pub fn add(
+        &mut self,
+        committed: &Committed,
+        input: &MigrationData,
+        migrated: &MigrationData,
+        live: &MigrationData,
+    ) -> Result<()> {
+        let transaction = match committed {
+            Committed::Live => None,
+            Committed::Pending { tx } => Some(tx.clone()),
+        };
+        let diff = diff(input, migrated, Some(live))?;
+        self.changes.push(CommittedChanges { transaction, diff });
+        Ok(())
+    }
+
+    /// Renders the report in the given format.
+    pub fn render(&self, format: ReportFormat) -> Result<String> {
+        match format {
+            ReportFormat::Json => {
+                serde_json::to_string_pretty(self).context(error::SerializeReportSnafu)
+            }
+            ReportFormat::Text => Ok(self.render_text()),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut text = String::new();
+        for changes in &self.changes {
+            match &changes.transaction {
+                None => text.push_str("Live data:\n"),
+                Some(tx) => {
+                    let _ = writeln!(text, "Pending transaction '{tx}':");
+                }
+            }
+            render_diff(&mut text, &changes.diff);
+        }
+        text
+    }
+}
+
+/// Adds a line to the given text for each change in the given diff, or a line saying there are
+/// no changes.
+pub(crate) fn render_diff(text: &mut String, diff: &TransactionDiff) {
+    if diff.is_empty() {
+        text.push_str("  no changes\n");
+    }
+    for (name, value) in &diff.added {
+        let _ = writeln!(text, "  + {name} = {value}");
+    }
+    for (name, ValueChange { old, new }) in &diff.modified {
+        let _ = writeln!(text, "  ~ {name} = {old} -> {new}");
+    }
+    for (name, value) in &diff.removed {
+        let _ = writeln!(text, "  - {name} (was {value})");
+    }
+    for change in &diff.metadata {
+        let MetadataChange {
+            data_key,
+            metadata_key,
+            old,
+            new,
+        } = change;
+        let _ = match (old, new) {
+            (None, Some(new)) => writeln!(text, "  + {data_key} metadata {metadata_key} = {new}"),
+            (Some(old), Some(new)) => {
+                writeln!(
+                    text,
+                    "  ~ {data_key} metadata {metadata_key} = {old} -> {new}"
+                )
+            }
+            (Some(old), None) => {
+                writeln!(text, "  - {data_key} metadata {metadata_key} (was {old})")
+            }
+            (None, None) => Ok(()),
+        };
+    }
+}
+
+/// Compares the data a migration was given with the data it returned.  Keys marked sensitive in
+/// either, or in the given live data, are redacted.
+pub(crate) fn diff(
+    input: &MigrationData,
+    migrated: &MigrationData,
+    live: Option<&MigrationData>,
+) -> Result<TransactionDiff> {
+    let mut diff = TransactionDiff::default();
+    let shown = |name: &str, value: &Value| -> Result<String> {
+        let sensitive = is_sensitive(input, name)?
+            || is_sensitive(migrated, name)?
+            || live.map(|live| is_sensitive(live, name)).transpose()? == Some(true);
+        if sensitive {
+            Ok(REDACTED.to_string())
+        } else {
+            serialize_scalar(value).context(error::SerializeSnafu)
+        }
+    };
+
+    for (name, new) in &migrated.data {
+        match input.data.get(name) {
+            None => {
+                diff.added.insert(name.clone(), shown(name, new)?);
+            }
+            Some(old) if old != new => {
+                let change = ValueChange {
+                    old: shown(name, old)?,
+                    new: shown(name, new)?,
+                };
+                diff.modified.insert(name.clone(), change);
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, old) in &input.data {
+        if !migrated.data.contains_key(name) {
+            diff.removed.insert(name.clone(), shown(name, old)?);
+        }
+    }
+
+    let no_metadata = Default::default();
+    for (data_key, new_metadata) in &migrated.metadata {
+        let old_metadata = input.metadata.get(data_key).unwrap_or(&no_metadata);
+        for (metadata_key, new) in new_metadata {
+            let old = old_metadata.get(metadata_key);
+            if old != Some(new) {
+                diff.metadata.push(MetadataChange {
+                    data_key: data_key.clone(),
+                    metadata_key: metadata_key.clone(),
+                    old: old
+                        .map(|old| serialize_scalar(old).context(error::SerializeSnafu))
+                        .transpose()?,
+                    new: Some(serialize_scalar(new).context(error::SerializeSnafu)?),
+                });
+            }
+        }
+    }
+    for (data_key, old_metadata) in &input.metadata {
+        let new_metadata = migrated.metadata.get(data_key).unwrap_or(&no_metadata);
+        for (metadata_key, old) in old_metadata {
+            if !new_metadata.contains_key(metadata_key) {
+                diff.metadata.push(MetadataChange {
+                    data_key: data_key.clone(),
+                    metadata_key: metadata_key.clone(),
+                    old: Some(serialize_scalar(old).context(error::SerializeSnafu)?),
+                    new: None,
+                });
+            }
+        }
+    }
+    diff.metadata.sort_unstable();
+
+    Ok(diff)
+}
+
+/// Returns whether the given data key, or any key above it, is marked sensitive in the given data.
+fn is_sensitive(data: &MigrationData, name: &str) -> Result<bool> {
+    let key = Key::new(KeyType::Data, name).context(error::InvalidKeySnafu {
+        key_type: KeyType::Data,
+        key: name,
+    })?;
+    let segments = key.segments();
+    for len in 1..=segments.len() {
+        let marked =
+            Key::from_segments(KeyType::Data, &segments[..len]).context(error::NewKeySnafu)?;
+        let flagged = data
+            .metadata
+            .get(marked.name())
+            .and_then(|metadata| metadata.get(SENSITIVE_METADATA_KEY))
+            .is_some_and(|value| value == &Value::Bool(true));
+        if flagged {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MigrationReport, ReportFormat};
+    use crate::MigrationData;
+    use datastore::Committed;
+    use maplit::hashmap;
+    use std::collections::HashMap;
+
+    fn report() -> MigrationReport {
+        let input = MigrationData {
+            data: hashmap! {
+                "settings.motd".into() => "hi".into(),
+                "settings.old".into() => 1.into(),
+                "settings.same".into() => true.into(),
+                "settings.aws.credentials".into() => "secret".into(),
+            },
+            metadata: hashmap! {
+                "settings.aws".into() => hashmap! {
+                    "sensitive".into() => true.into(),
+                },
+                "settings.motd".into() => hashmap! {
+                    "affected-services".into() => vec!["motd"].into(),
+                },
+            },
+        };
+        let migrated = MigrationData {
+            data: hashmap! {
+                "settings.motd".into() => "hello".into(),
+                "settings.new".into() => 2.into(),
+                "settings.same".into() => true.into(),
+                "settings.aws.credentials".into() => "rotated".into(),
+            },
+            metadata: hashmap! {
+                "settings.aws".into() => hashmap! {
+                    "sensitive".into() => true.into(),
+                },
+                "settings.new".into() => hashmap! {
+                    "affected-services".into() => vec!["new"].into(),
+                },
+            },
+        };
+
+        let mut report = MigrationReport::default();
+        report
+            .add(&Committed::Live, &input, &migrated, &input)
+            .unwrap();
+        let pending = Committed::Pending {
+            tx: "apiserver".into(),
+        };
+        report.add(&pending, &input, &input, &input).unwrap();
+        report
+    }
+
+    #[test]
+    fn diff() {
+        let report = report();
+        let live = &report.changes[0];
+        assert_eq!(live.transaction, None);
+        assert_eq!(live.diff.added["settings.new"], "2");
+        assert_eq!(live.diff.removed["settings.old"], "1");
+        assert_eq!(live.diff.modified["settings.motd"].old, "\"hi\"");
+        assert_eq!(live.diff.modified["settings.motd"].new, "\"hello\"");
+        assert!(!live.diff.modified.contains_key("settings.same"));
+        assert_eq!(live.diff.metadata.len(), 2);
+
+        let pending = &report.changes[1];
+        assert_eq!(pending.transaction.as_deref(), Some("apiserver"));
+        assert!(pending.diff.is_empty());
+    }
+
+    #[test]
+    fn sensitive_values_redacted() {
+        let report = report();
+        for format in [ReportFormat::Text, ReportFormat::Json] {
+            let rendered = report.render(format).unwrap();
+            assert!(rendered.contains("settings.aws.credentials"));
+            assert!(!rendered.contains("secret"), "{rendered}");
+            assert!(!rendered.contains("rotated"), "{rendered}");
+        }
+    }
+
+    #[test]
+    fn sensitive_in_live_redacted() {
+        let live = MigrationData {
+            data: hashmap! {
+                "settings.kubernetes.bootstrap-token".into() => "old-token".into(),
+            },
+            metadata: hashmap! {
+                "settings.kubernetes.bootstrap-token".into() => hashmap! {
+                    "sensitive".into() => true.into(),
+                },
+            },
+        };
+        // The pending transaction doesn't mark the key itself.
+        let input = MigrationData {
+            data: hashmap! {
+                "settings.kubernetes.bootstrap-token".into() => "new-token".into(),
+            },
+            metadata: HashMap::new(),
+        };
+        let mut migrated = input.clone();
+        migrated.data.insert(
+            "settings.kubernetes.bootstrap-token".into(),
+            "migrated-token".into(),
+        );
+
+        let mut report = MigrationReport::default();
+        let pending = Committed::Pending { tx: "tx".into() };
+        report.add(&pending, &input, &migrated, &live).unwrap();
+        for format in [ReportFormat::Text, ReportFormat::Json] {
+            let rendered = report.render(format).unwrap();
+            assert!(rendered.contains("settings.kubernetes.bootstrap-token"));
+            assert!(!rendered.contains("new-token"), "{rendered}");
+            assert!(!rendered.contains("migrated-token"), "{rendered}");
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            report().render(ReportFormat::Text).unwrap(),
+            r#"Live data:
+  + settings.new = 2
+  ~ settings.aws.credentials = <redacted> -> <redacted>
+  ~ settings.motd = "hi" -> "hello"
+  - settings.old (was 1)
+  - settings.motd metadata affected-services (was ["motd"])
+  + settings.new metadata affected-services = ["new"]
+Pending transaction 'apiserver':
+  no changes
+"#
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! This module checks migrated data against the new version's model before it's written, so a
+//! migration that produces data the model can't deserialize fails right away, rather than later at
+//! boot, when the API server loads the data store.
+//!
+//! Migrations are built with the new version's model, so by default every forward run checks its
+//! output against it.  Data migrated backward matches an older version, so it isn't checked.
+//! The check is skipped with `--no-validate`, for a migration whose output isn't valid until a
+//! later migration in the same upgrade fixes it up.
+
+use serde::de::DeserializeOwned;
+use snafu::ResultExt;
+use std::collections::HashMap;
+
+use crate::{error, MigrationData, Result};
+use datastore::deserialization::{self, from_map_with_prefix};
+use datastore::{serialize_scalar, Key, KeyType};
+use model::{ConfigurationFiles, Services, Settings};
+
+/// Ensures we can use the migrated data in the new data store, by deserializing the "settings",
+/// "services", and "configuration-files" trees into the model's types.  Returns an
+/// error::Validation naming the offending key if any of them fail.
+pub(crate) fn validate_migrated_data(migrated: &MigrationData) -> Result<()> {
+    let mut data = HashMap::new();
+    for (data_key_name, raw_value) in &migrated.data {
+        let data_key = Key::new(KeyType::Data, data_key_name).context(error::InvalidKeySnafu {
+            key_type: KeyType::Data,
+            key: data_key_name,
+        })?;
+        let value = serialize_scalar(raw_value).context(error::SerializeSnafu)?;
+        data.insert(data_key, value);
+    }
+
+    check::<Settings>(&data, "settings")?;
+    check::<Services>(&data, "services")?;
+    check::<ConfigurationFiles>(&data, "configuration-files")?;
+    Ok(())
+}
+
+/// Deserializes the keys under the given top-level prefix into T.  Pending transactions usually
+/// don't have every key, so a missing tree isn't an error.
+///
+/// serde's errors name a field at most, so if deserialization fails, we look for the key to
+/// blame: first the entry under the prefix that fails on its own, like "settings.ntp", then the
+/// key in that entry without which it succeeds.  If no one key is to blame, for example because
+/// a required key is missing or invalid, the error names the entry.
+fn check<T: DeserializeOwned>(data: &HashMap<Key, String>, prefix: &str) -> Result<()> {
+    let tree = keys_under(data, &[prefix]);
+    if tree.is_empty() {
+        return Ok(());
+    }
+    let tree_error = match deserialize::<T>(prefix, &tree) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+
+    let mut entries: Vec<_> = tree
+        .keys()
+        .filter_map(|key| key.segments().get(..2))
+        .collect();
+    entries.sort_unstable();
+    entries.dedup();
+    for entry in entries {
+        let entry_data = keys_under(&tree, entry);
+        let entry_error = match deserialize::<T>(prefix, &entry_data) {
+            Ok(()) => continue,
+            Err(e) => e,
+        };
+
+        let mut keys: Vec<_> = entry_data.keys().collect();
+        keys.sort_unstable_by_key(|key| key.name());
+        for key in keys {
+            let mut without = entry_data.clone();
+            without.remove(key);
+            // Without any keys, the entry is gone, which tells us nothing.
+            if !without.is_empty() && deserialize::<T>(prefix, &without).is_ok() {
+                return error::ValidationSnafu {
+                    key: key.name(),
+                    msg: entry_error.to_string(),
+                }
+                .fail();
+            }
+        }
+
+        let entry_key = Key::from_segments(KeyType::Data, entry).context(error::NewKeySnafu)?;
+        return error::ValidationSnafu {
+            key: entry_key.name(),
+            msg: entry_error.to_string(),
+        }
+        .fail();
+    }
+
+    error::ValidationSnafu {
+        key: prefix,
+        msg: tree_error.to_string(),
+    }
+    .fail()
+}
+
+/// Deserializes the given keys, which are under the given prefix, into T.
+fn deserialize<T: DeserializeOwned>(
+    prefix: &str,
+    data: &HashMap<Key, String>,
+) -> deserialization::Result<()> {
+    from_map_with_prefix::<_, _, T, _>(Some(prefix.to_string()), data).map(|_| ())
+}
+
+/// Returns the keys in the given data under the path with the given segments.
+fn keys_under<S: AsRef<str>>(data: &HashMap<Key, String>, segments: &[S]) -> HashMap<Key, String> {
+    data.iter()
+        .filter(|(key, _)| key.starts_with_segments(segments))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::check;
+    use crate::error::Error;
+    use datastore::{Key, KeyType};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields, rename_all = "kebab-case")]
+    #[allow(dead_code)]
+    struct Service {
+        configuration_files: Vec<String>,
+        restart_commands: Vec<String>,
+        timeout: Option<u64>,
+    }
+
+    type Services = HashMap<String, Service>;
+
+    fn data(pairs: &[(&str, &str)]) -> HashMap<Key, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (Key::new(KeyType::Data, name).unwrap(), value.to_string()))
+            .collect()
+    }
+
+    fn blamed(pairs: &[(&str, &str)]) -> String {
+        match check::<Services>(&data(pairs), "services") {
+            Err(Error::Validation { key, .. }) => key,
+            other => panic!("Expected a validation error, got {other:?}"),
+        }
+    }
+
+    const VALID: &[(&str, &str)] = &[
+        ("services.a.configuration-files", "[\"a\"]"),
+        ("services.a.restart-commands", "[]"),
+        ("services.b.configuration-files", "[]"),
+        ("services.b.restart-commands", "[\"/bin/b\"]"),
+        ("settings.motd", "\"hi\""),
+    ];
+
+    #[test]
+    fn valid() {
+        assert!(check::<Services>(&data(VALID), "services").is_ok());
+        // Data without the tree at all, like most pending transactions, is fine.
+        assert!(check::<Services>(&data(&[("settings.motd", "\"hi\"")]), "services").is_ok());
+    }
+
+    #[test]
+    fn unknown_key() {
+        let mut pairs = VALID.to_vec();
+        pairs.push(("services.b.restart-command", "[]"));
+        assert_eq!(blamed(&pairs), "services.b.restart-command");
+    }
+
+    #[test]
+    fn invalid_value() {
+        let mut pairs = VALID.to_vec();
+        pairs.push(("services.a.timeout", "\"soon\""));
+        assert_eq!(blamed(&pairs), "services.a.timeout");
+        // A required key can't be left out to find the problem, so the entry is named.
+        let mut pairs = VALID.to_vec();
+        pairs[0] = ("services.a.configuration-files", "42");
+        assert_eq!(blamed(&pairs), "services.a");
+    }
+
+    #[test]
+    fn missing_key() {
+        let mut pairs = VALID.to_vec();
+        pairs.remove(3);
+        assert_eq!(blamed(&pairs), "services.b");
+    }
+}